@@ -0,0 +1,118 @@
+//! Measures the overhead of hashing request bodies of various sizes, with each hash algorithm.
+//!
+//! Run with `cargo bench --bench hash`.
+
+use axum::Router;
+use axum::body::{Body, Bytes, HttpBody};
+use axum::extract::Request;
+use axum::routing::post;
+use axum_idempotent::{HashAlgorithm, IdempotencyStore, IdempotentLayer, IdempotentOptions};
+use criterion::{BenchmarkId, Criterion, Throughput, criterion_group, criterion_main};
+use http_body::Frame;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::error::Error;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower::ServiceExt;
+
+/// A store that keeps nothing, so only the middleware itself is measured.
+#[derive(Clone)]
+struct NullStore;
+
+impl IdempotencyStore for NullStore {
+    type Key = str;
+
+    async fn get(&self, _key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        Ok(None)
+    }
+
+    async fn set(
+        &self,
+        _key: &str,
+        _value: Vec<u8>,
+        _ttl_secs: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    async fn remove(&self, _key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+}
+
+/// A JSON payload of roughly `size` bytes.
+fn payload(size: usize) -> Bytes {
+    let mut json = String::from(r#"{"items":["#);
+    while json.len() < size {
+        json.push_str(r#"{"sku":"ABC-123","quantity":1,"price":"9.99"},"#);
+    }
+    json.push_str("null]}");
+    Bytes::from(json)
+}
+
+/// A body yielding its chunks one frame at a time, like a large upload would.
+struct ChunkedBody(VecDeque<Bytes>);
+
+impl HttpBody for ChunkedBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, Infallible>>> {
+        Poll::Ready(self.0.pop_front().map(|chunk| Ok(Frame::data(chunk))))
+    }
+}
+
+/// A body streaming `payload` in chunks of 16 KiB.
+fn chunked(payload: &Bytes) -> Body {
+    let chunks = payload
+        .chunks(16 * 1024)
+        .map(|chunk| payload.slice_ref(chunk))
+        .collect();
+    Body::new(ChunkedBody(chunks))
+}
+
+fn app(algorithm: HashAlgorithm) -> Router {
+    Router::new()
+        .route("/orders", post(|| async { "created" }))
+        .layer(IdempotentLayer::with_store(
+            NullStore,
+            IdempotentOptions::default().hash_algorithm(algorithm),
+        ))
+}
+
+fn hash_body(c: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+
+    let mut group = c.benchmark_group("hash_body");
+    for algorithm in [
+        HashAlgorithm::Blake3,
+        HashAlgorithm::Sha256,
+        HashAlgorithm::Xxh3,
+    ] {
+        let app = app(algorithm);
+        for size in [1024, 64 * 1024, 1024 * 1024] {
+            let payload = payload(size);
+            let id = BenchmarkId::new(format!("{algorithm:?}"), size);
+            group.throughput(Throughput::Bytes(payload.len() as u64));
+            group.bench_with_input(id, &payload, |b, payload| {
+                b.to_async(&runtime).iter(|| {
+                    let req = Request::builder()
+                        .method("POST")
+                        .uri("/orders")
+                        .header("content-type", "application/json")
+                        .body(chunked(payload))
+                        .unwrap();
+                    app.clone().oneshot(req)
+                });
+            });
+        }
+    }
+    group.finish();
+}
+
+criterion_group!(benches, hash_body);
+criterion_main!(benches);
@@ -0,0 +1,392 @@
+use crate::error::IdempotencyError;
+use crate::manager::IdempotencyManager;
+use crate::store::IdempotencyStore;
+use axum::body::Bytes;
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Counters of the requests handled by the middleware, and the keys currently being executed,
+/// for operations tooling such as [`admin_router`].
+///
+/// Register the same handle on the options of every layer to observe with
+/// [`IdempotentOptions::stats`](crate::IdempotentOptions::stats). Clones share the same
+/// counters. They are kept in memory, per process.
+#[derive(Clone, Debug, Default)]
+pub struct IdempotencyStats {
+    inner: Arc<StatsInner>,
+}
+
+#[derive(Debug, Default)]
+struct StatsInner {
+    total: Counters,
+    routes: DashMap<String, Counters>,
+    in_flight: DashMap<u64, InFlightKey>,
+    next_id: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+    conflicts: AtomicU64,
+    store_errors: AtomicU64,
+}
+
+impl Counters {
+    fn snapshot(&self) -> RouteStats {
+        RouteStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            conflicts: self.conflicts.load(Ordering::Relaxed),
+            store_errors: self.store_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The counters of an [`IdempotencyStats`], overall or for one route.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[non_exhaustive]
+pub struct RouteStats {
+    /// Responses replayed from the cache.
+    pub hits: u64,
+    /// Requests without a cached response, forwarded to the inner service.
+    pub misses: u64,
+    /// Requests rejected or forwarded because a request with the same key was in flight.
+    pub conflicts: u64,
+    /// Failed store operations.
+    pub store_errors: u64,
+}
+
+/// A point-in-time copy of an [`IdempotencyStats`], as served by [`admin_router`].
+#[derive(Clone, Debug, Serialize)]
+#[non_exhaustive]
+pub struct StatsSnapshot {
+    /// The counters of all requests.
+    #[serde(flatten)]
+    pub total: RouteStats,
+    /// The number of keys being executed.
+    pub in_flight: usize,
+    /// The counters of the requests to each route template, for layers running after routing.
+    pub routes: BTreeMap<String, RouteStats>,
+}
+
+/// A key whose request is being executed by the inner service.
+#[derive(Clone, Debug, Serialize)]
+#[non_exhaustive]
+pub struct InFlightKey {
+    /// The key the entry is stored under.
+    pub key: String,
+    /// The route template of the request, when the layer runs after routing.
+    pub route: Option<String>,
+    /// When the execution started, as a Unix timestamp in seconds.
+    pub started_at: u64,
+}
+
+/// The counter an event increments.
+#[derive(Clone, Copy)]
+pub(crate) enum StatsEvent {
+    Hit,
+    Miss,
+    Conflict,
+    StoreError,
+}
+
+impl IdempotencyStats {
+    /// Creates a handle with zeroed counters.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns a copy of the counters.
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let routes = self.inner.routes.iter();
+        StatsSnapshot {
+            total: self.inner.total.snapshot(),
+            in_flight: self.inner.in_flight.len(),
+            routes: routes
+                .map(|entry| (entry.key().clone(), entry.value().snapshot()))
+                .collect(),
+        }
+    }
+
+    /// Returns the keys being executed, the oldest first.
+    pub fn in_flight(&self) -> Vec<InFlightKey> {
+        let mut keys: Vec<_> = self
+            .inner
+            .in_flight
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().clone()))
+            .collect();
+        keys.sort_by_key(|(id, _)| *id);
+        keys.into_iter().map(|(_, key)| key).collect()
+    }
+
+    pub(crate) fn record(&self, event: StatsEvent, route: Option<&str>) {
+        let increment = |counters: &Counters| {
+            let counter = match event {
+                StatsEvent::Hit => &counters.hits,
+                StatsEvent::Miss => &counters.misses,
+                StatsEvent::Conflict => &counters.conflicts,
+                StatsEvent::StoreError => &counters.store_errors,
+            };
+            counter.fetch_add(1, Ordering::Relaxed);
+        };
+        increment(&self.inner.total);
+        if let Some(route) = route {
+            match self.inner.routes.get(route) {
+                Some(counters) => increment(&counters),
+                None => increment(&self.inner.routes.entry(route.to_owned()).or_default()),
+            }
+        }
+    }
+
+    /// Records that the request for `key` started executing at `now`, until the returned
+    /// guard is dropped.
+    pub(crate) fn start(&self, key: &str, route: Option<&str>, now: SystemTime) -> InFlightGuard {
+        let id = self.inner.next_id.fetch_add(1, Ordering::Relaxed);
+        let started_at = now.duration_since(UNIX_EPOCH).unwrap_or_default();
+        self.inner.in_flight.insert(
+            id,
+            InFlightKey {
+                key: key.to_owned(),
+                route: route.map(str::to_owned),
+                started_at: started_at.as_secs(),
+            },
+        );
+        InFlightGuard {
+            stats: self.clone(),
+            id,
+        }
+    }
+}
+
+/// Removes a key from the in-flight keys of an [`IdempotencyStats`] when dropped.
+pub(crate) struct InFlightGuard {
+    stats: IdempotencyStats,
+    id: u64,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        self.stats.inner.in_flight.remove(&self.id);
+    }
+}
+
+/// Returns a [`Router`] exposing the counters of `stats` as JSON, for operations tooling.
+///
+/// - `GET /idempotency/stats` returns the hit, miss, conflict and store error counters,
+///   overall and per route, with the number of in-flight keys (see [`StatsSnapshot`]).
+/// - `GET /idempotency/in-flight` lists the keys being executed (see [`InFlightKey`]).
+///
+/// The router performs no authentication: keys may be sensitive, so protect it with your own
+/// authentication middleware, and mount it where only operators can reach it.
+///
+/// # Example
+/// ```rust
+/// use axum::{Router, routing::post};
+/// use axum::middleware::{self, Next};
+/// use axum::extract::Request;
+/// use axum::http::StatusCode;
+/// use axum::response::Response;
+/// use axum_idempotent::{
+///     IdempotencyStats, IdempotentLayer, IdempotentOptions, MemoryIdempotencyStore, admin_router,
+/// };
+///
+/// async fn require_admin(req: Request, next: Next) -> Result<Response, StatusCode> {
+///     match req.headers().get("x-admin-token") {
+///         Some(token) if token == "secret" => Ok(next.run(req).await),
+///         _ => Err(StatusCode::UNAUTHORIZED),
+///     }
+/// }
+///
+/// let stats = IdempotencyStats::new();
+/// let options = IdempotentOptions::default()
+///     .use_idempotency_key_header(None)
+///     .stats(stats.clone());
+/// let app: Router = Router::new()
+///     .route("/payments", post(|| async { "Payment processed" }))
+///     .layer(IdempotentLayer::with_store(MemoryIdempotencyStore::new(), options))
+///     .nest(
+///         "/admin",
+///         admin_router(stats).layer(middleware::from_fn(require_admin)),
+///     );
+/// ```
+pub fn admin_router<S>(stats: IdempotencyStats) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route(
+            "/idempotency/stats",
+            get(|State(stats): State<IdempotencyStats>| async move { Json(stats.snapshot()) }),
+        )
+        .route(
+            "/idempotency/in-flight",
+            get(|State(stats): State<IdempotencyStats>| async move { Json(stats.in_flight()) }),
+        )
+        .with_state(stats)
+}
+
+/// The state of the entry stored under a key, as returned by
+/// [`IdempotencyManager::inspect`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+#[non_exhaustive]
+pub enum KeyState {
+    /// The response of the request is cached, and replayed to requests with the key.
+    Completed,
+    /// The request is still being processed.
+    InFlight,
+    /// The response was too large to be cached, and requests with the key are rejected (see
+    /// [`OversizedResponse`](crate::OversizedResponse)).
+    Oversized,
+}
+
+/// The metadata of the entry stored under a key, as returned by
+/// [`IdempotencyManager::inspect`] and served by [`inspect_router`].
+#[derive(Clone, Debug, Serialize)]
+#[non_exhaustive]
+pub struct KeyInfo {
+    /// The key the entry is stored under, without the prefix of the options.
+    pub key: String,
+    /// Whether the request completed, is in flight, or had a response too large to cache.
+    pub state: KeyState,
+    /// The status code of the cached response, or the status code requests are rejected with
+    /// for an [`Oversized`](KeyState::Oversized) entry.
+    #[serde(serialize_with = "serialize_status")]
+    pub status: Option<StatusCode>,
+    /// When the response was stored, or the request started for an
+    /// [`InFlight`](KeyState::InFlight) entry, as a Unix timestamp in seconds.
+    pub created_at: Option<u64>,
+    /// When the entry expires from the store, as a Unix timestamp in seconds, if known.
+    pub expires_at: Option<u64>,
+    /// The number of seconds before the entry expires, if known.
+    pub ttl_remaining: Option<u64>,
+    /// The fingerprint of the request that produced the response, if any.
+    pub fingerprint: Option<String>,
+    /// How many times the response was replayed, if counted.
+    pub replays: u32,
+    /// The body of the cached response, only when requested, serialized in base64.
+    #[serde(
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_body"
+    )]
+    pub body: Option<Bytes>,
+}
+
+impl KeyInfo {
+    pub(crate) fn new(key: &str, state: KeyState) -> Self {
+        Self {
+            key: key.to_owned(),
+            state,
+            status: None,
+            created_at: None,
+            expires_at: None,
+            ttl_remaining: None,
+            fingerprint: None,
+            replays: 0,
+            body: None,
+        }
+    }
+}
+
+fn serialize_status<S: Serializer>(
+    status: &Option<StatusCode>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    status.map(|status| status.as_u16()).serialize(serializer)
+}
+
+fn serialize_body<S: Serializer>(body: &Option<Bytes>, serializer: S) -> Result<S::Ok, S::Error> {
+    body.as_ref()
+        .map(|body| BASE64_STANDARD.encode(body))
+        .serialize(serializer)
+}
+
+/// The query parameters of the key inspection endpoint.
+#[derive(Deserialize)]
+struct InspectQuery {
+    #[serde(default)]
+    body: bool,
+}
+
+/// Returns a [`Router`] exposing the metadata of the entries managed by `manager` as JSON, so
+/// support engineers can check whether a request was already processed.
+///
+/// `GET /idempotency/{key}` returns the [`KeyInfo`] of the entry stored under `key`: its state,
+/// status code, creation time, remaining TTL, fingerprint and replay count, or `404 Not Found`
+/// without one. Keys are those of [`IdempotencyManager`]. The body of the cached response is
+/// only included, in base64, with the `?body=true` query parameter. Store failures are
+/// reported with `503 Service Unavailable`.
+///
+/// Like [`admin_router`], which it can be merged with, the router performs no
+/// authentication, so protect it with your own authentication middleware.
+///
+/// # Example
+/// ```rust
+/// use axum::Router;
+/// use axum::middleware::{self, Next};
+/// use axum::extract::Request;
+/// use axum::http::StatusCode;
+/// use axum::response::Response;
+/// use axum_idempotent::{
+///     IdempotencyStats, IdempotentLayer, IdempotentOptions, MemoryIdempotencyStore, admin_router,
+///     inspect_router,
+/// };
+///
+/// async fn require_admin(req: Request, next: Next) -> Result<Response, StatusCode> {
+///     match req.headers().get("x-admin-token") {
+///         Some(token) if token == "secret" => Ok(next.run(req).await),
+///         _ => Err(StatusCode::UNAUTHORIZED),
+///     }
+/// }
+///
+/// let stats = IdempotencyStats::new();
+/// let options = IdempotentOptions::default()
+///     .use_idempotency_key_header(None)
+///     .stats(stats.clone());
+/// let layer = IdempotentLayer::with_store(MemoryIdempotencyStore::new(), options);
+///
+/// let admin: Router = admin_router(stats)
+///     .merge(inspect_router(layer.manager()))
+///     .layer(middleware::from_fn(require_admin));
+/// ```
+pub fn inspect_router<S, St>(manager: IdempotencyManager<St>) -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+    St: IdempotencyStore,
+{
+    Router::new()
+        .route("/idempotency/{key}", get(inspect::<St>))
+        .with_state(manager)
+}
+
+async fn inspect<St: IdempotencyStore>(
+    State(manager): State<IdempotencyManager<St>>,
+    Path(key): Path<String>,
+    Query(query): Query<InspectQuery>,
+) -> Response {
+    match manager.inspect(&key, query.body).await {
+        Ok(Some(info)) => Json(info).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err @ IdempotencyError::Store { .. }) => {
+            tracing::error!("Failed to inspect idempotency key {key}: {err}");
+            StatusCode::SERVICE_UNAVAILABLE.into_response()
+        }
+        Err(err) => {
+            tracing::error!("Failed to inspect idempotency key {key}: {err}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
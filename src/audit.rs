@@ -0,0 +1,114 @@
+use crate::clock::Clock;
+use crate::config::Hook;
+use axum::extract::Request;
+use axum::http::{Method, StatusCode};
+use std::time::SystemTime;
+
+/// Receives an append-only audit record for every request with an idempotency key, e.g. to
+/// meet financial audit requirements.
+///
+/// Register a sink with
+/// [`IdempotentOptions::audit_sink`](crate::IdempotentOptions::audit_sink). Records are handed
+/// to the sink on the request path, so it should hand them off (e.g. to a channel drained into
+/// a Kafka topic or a database table) rather than block.
+///
+/// This requires the `audit` feature.
+///
+/// # Example
+/// ```rust
+/// use axum_idempotent::{AuditRecord, AuditSink, IdempotentOptions};
+/// use std::sync::Mutex;
+/// use std::sync::mpsc::Sender;
+///
+/// struct ChannelSink(Mutex<Sender<AuditRecord>>);
+///
+/// impl AuditSink for ChannelSink {
+///     fn record(&self, record: AuditRecord) {
+///         let _ = self.0.lock().unwrap().send(record);
+///     }
+/// }
+///
+/// let (tx, _rx) = std::sync::mpsc::channel();
+/// let options = IdempotentOptions::default().audit_sink(ChannelSink(Mutex::new(tx)));
+/// ```
+pub trait AuditSink: Send + Sync + 'static {
+    /// Appends `record` to the audit log.
+    fn record(&self, record: AuditRecord);
+}
+
+/// The audit record of a request with an idempotency key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct AuditRecord {
+    /// The idempotency key of the request (or its hash, in hashing mode).
+    pub key: String,
+    /// The method of the request.
+    pub method: Method,
+    /// The path of the request.
+    pub path: String,
+    /// How the request was handled.
+    pub outcome: AuditOutcome,
+    /// When the middleware received the request.
+    pub received_at: SystemTime,
+    /// When the middleware produced the response.
+    pub completed_at: SystemTime,
+}
+
+/// How a request with an idempotency key was handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum AuditOutcome {
+    /// The request was executed by the inner service, which responded with the status code.
+    /// `cached` tells whether the response was stored for replays.
+    Executed { status: StatusCode, cached: bool },
+    /// A cached response with the status code was replayed.
+    Replayed(StatusCode),
+    /// The request was rejected by the middleware with the status code, e.g. because its key
+    /// was in flight or reused for another request.
+    Rejected(StatusCode),
+}
+
+/// Writes the audit record of a request to the configured sink, if any.
+#[derive(Default)]
+pub(crate) struct Audit(Option<AuditContext>);
+
+struct AuditContext {
+    sink: Hook<dyn AuditSink>,
+    key: String,
+    method: Method,
+    path: String,
+    received_at: SystemTime,
+    clock: Hook<dyn Clock>,
+}
+
+impl Audit {
+    pub(crate) fn new(
+        sink: Option<&Hook<dyn AuditSink>>,
+        key: Option<&str>,
+        req: &Request,
+        received_at: SystemTime,
+        clock: &Hook<dyn Clock>,
+    ) -> Self {
+        Self(sink.zip(key).map(|(sink, key)| AuditContext {
+            sink: sink.clone(),
+            key: key.to_owned(),
+            method: req.method().clone(),
+            path: req.uri().path().to_owned(),
+            received_at,
+            clock: clock.clone(),
+        }))
+    }
+
+    pub(crate) fn record(&self, outcome: AuditOutcome) {
+        if let Some(context) = &self.0 {
+            context.sink.0.record(AuditRecord {
+                key: context.key.clone(),
+                method: context.method.clone(),
+                path: context.path.clone(),
+                outcome,
+                received_at: context.received_at,
+                completed_at: context.clock.0.now(),
+            });
+        }
+    }
+}
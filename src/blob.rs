@@ -0,0 +1,130 @@
+use axum::body::{Body, Bytes};
+use futures_util::future::BoxFuture;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+
+/// External storage for the bodies of large responses, e.g. an S3 or GCS bucket.
+///
+/// With [`IdempotentOptions::blob_store`](crate::IdempotentOptions::blob_store), the bodies of
+/// responses larger than a threshold are uploaded to the blob store, and the idempotency entry
+/// only holds a [`BlobPointer`] next to the status code and headers. Replays stream the body
+/// back from the blob store, so multi-megabyte exports do not fill up Redis.
+///
+/// Blobs are keyed by a hash of their content, so responses with identical bodies share a
+/// blob. The middleware never removes blobs: they should expire after the TTL passed to
+/// [`put`](Self::put), e.g. through a bucket lifecycle rule, which may keep them longer.
+///
+/// # Example
+/// ```rust
+/// use axum::body::{Body, Bytes};
+/// use axum_idempotent::{BlobStore, IdempotentOptions};
+/// use std::collections::HashMap;
+/// use std::error::Error;
+/// use std::sync::{Arc, Mutex};
+///
+/// #[derive(Clone, Default)]
+/// struct Bucket(Arc<Mutex<HashMap<String, Bytes>>>);
+///
+/// impl BlobStore for Bucket {
+///     async fn put(
+///         &self,
+///         key: &str,
+///         body: Bytes,
+///         _ttl_secs: i64,
+///     ) -> Result<(), Box<dyn Error + Send + Sync>> {
+///         self.0.lock().unwrap().insert(key.to_owned(), body);
+///         Ok(())
+///     }
+///
+///     async fn get(&self, key: &str) -> Result<Option<Body>, Box<dyn Error + Send + Sync>> {
+///         Ok(self.0.lock().unwrap().get(key).cloned().map(Body::from))
+///     }
+/// }
+///
+/// // Bodies over 1 MiB are kept in the bucket
+/// let options = IdempotentOptions::default().blob_store(Bucket::default(), 1 << 20);
+/// ```
+pub trait BlobStore: Send + Sync + 'static {
+    /// Uploads `body` under `key`, to be kept for at least `ttl_secs` seconds.
+    fn put(
+        &self,
+        key: &str,
+        body: Bytes,
+        ttl_secs: i64,
+    ) -> impl Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send;
+
+    /// Returns the body stored under `key`, if any, streamed from the blob store.
+    fn get(
+        &self,
+        key: &str,
+    ) -> impl Future<Output = Result<Option<Body>, Box<dyn Error + Send + Sync>>> + Send;
+}
+
+/// Where the body of a cached response is kept when it was uploaded to a [`BlobStore`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct BlobPointer {
+    /// The key of the blob in the blob store.
+    pub key: String,
+    /// The length of the body, in bytes.
+    pub len: u64,
+}
+
+impl BlobPointer {
+    /// Creates a pointer to the blob of `len` bytes stored under `key`.
+    pub fn new(key: impl Into<String>, len: u64) -> Self {
+        Self {
+            key: key.into(),
+            len,
+        }
+    }
+}
+
+/// A [`BlobStore`] usable as a trait object.
+pub(crate) trait DynBlobStore: Send + Sync {
+    fn put(
+        &self,
+        key: String,
+        body: Bytes,
+        ttl_secs: i64,
+    ) -> BoxFuture<'_, Result<(), Box<dyn Error + Send + Sync>>>;
+
+    fn get(&self, key: String)
+    -> BoxFuture<'_, Result<Option<Body>, Box<dyn Error + Send + Sync>>>;
+}
+
+impl<B: BlobStore> DynBlobStore for B {
+    fn put(
+        &self,
+        key: String,
+        body: Bytes,
+        ttl_secs: i64,
+    ) -> BoxFuture<'_, Result<(), Box<dyn Error + Send + Sync>>> {
+        Box::pin(async move { BlobStore::put(self, &key, body, ttl_secs).await })
+    }
+
+    fn get(
+        &self,
+        key: String,
+    ) -> BoxFuture<'_, Result<Option<Body>, Box<dyn Error + Send + Sync>>> {
+        Box::pin(async move { BlobStore::get(self, &key).await })
+    }
+}
+
+/// Returns the key a body is uploaded under: the hash of its content, after `prefix`.
+pub(crate) fn blob_key(prefix: &str, body: &[u8]) -> String {
+    format!("{prefix}blob:{}", blake3::hash(body).to_hex())
+}
+
+/// The error returned when replaying a response whose blob is missing from the blob store.
+#[derive(Debug)]
+pub(crate) struct MissingBlob(pub(crate) String);
+
+impl fmt::Display for MissingBlob {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "blob {} of the cached response is missing", self.0)
+    }
+}
+
+impl Error for MissingBlob {}
@@ -0,0 +1,230 @@
+use axum::BoxError;
+use axum::body::{Body, Bytes, HttpBody};
+use axum::extract::Request;
+use axum::http::{self, HeaderMap};
+use axum::response::Response;
+use http_body::{Frame, SizeHint};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tower_service::Service;
+
+/// Marker inserted into the request extensions when its body exceeds
+/// [`IdempotentOptions::max_body_bytes`](crate::IdempotentOptions::max_body_bytes).
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct BodyLimitExceeded;
+
+/// The data chunks and trailers of a body read into memory.
+#[derive(Default)]
+pub(crate) struct Buffered {
+    chunks: VecDeque<Bytes>,
+    len: usize,
+    trailers: Option<HeaderMap>,
+}
+
+impl Buffered {
+    fn push(&mut self, chunk: Bytes) {
+        self.len += chunk.len();
+        self.chunks.push_back(chunk);
+    }
+
+    /// Copies the chunks into a single contiguous buffer.
+    pub(crate) fn to_bytes(&self) -> Bytes {
+        match self.chunks.len() {
+            0 => Bytes::new(),
+            1 => self.chunks[0].clone(),
+            _ => {
+                let mut bytes = Vec::with_capacity(self.len);
+                for chunk in &self.chunks {
+                    bytes.extend_from_slice(chunk);
+                }
+                Bytes::from(bytes)
+            }
+        }
+    }
+
+    /// The trailers of the body, if it had any.
+    pub(crate) fn trailers(&self) -> Option<&HeaderMap> {
+        self.trailers.as_ref()
+    }
+
+    /// Converts the chunks and trailers back into a body, without copying them.
+    pub(crate) fn into_body(self) -> Body {
+        Body::new(BufferedBody {
+            buffered: self.chunks,
+            rest: None,
+            trailers: self.trailers,
+        })
+    }
+}
+
+/// Returns a body yielding `bytes`, followed by `trailers` if any.
+pub(crate) fn with_trailers(bytes: Bytes, trailers: Option<HeaderMap>) -> Body {
+    if trailers.is_none() {
+        return Body::from(bytes);
+    }
+
+    let mut buffered = Buffered {
+        trailers,
+        ..Buffered::default()
+    };
+    if !bytes.is_empty() {
+        buffered.push(bytes);
+    }
+    buffered.into_body()
+}
+
+/// A body that could not be read into memory.
+///
+/// It holds a body equivalent to the original one (the data read so far, followed by the
+/// unread rest), so it can still be forwarded.
+pub(crate) enum Uncollected {
+    /// The body exceeded the limit.
+    TooLarge(Body),
+    /// Reading the body failed.
+    Failed(Body),
+}
+
+impl Uncollected {
+    pub(crate) fn into_body(self) -> Body {
+        match self {
+            Uncollected::TooLarge(body) | Uncollected::Failed(body) => body,
+        }
+    }
+}
+
+/// Reads `body` into memory frame by frame, stopping as soon as it exceeds `limit` bytes.
+///
+/// Every data chunk is passed to `on_chunk` as it arrives, so it can be processed (e.g.
+/// hashed) while the rest of the body is still streaming in. Trailers are kept, but not passed
+/// to `on_chunk`.
+pub(crate) async fn collect_body(
+    mut body: Body,
+    limit: Option<usize>,
+    mut on_chunk: impl FnMut(&[u8]),
+) -> Result<Buffered, Uncollected> {
+    let mut buffered = Buffered::default();
+
+    loop {
+        let frame = std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await;
+        let chunk = match frame {
+            Some(Ok(frame)) => match frame.into_data() {
+                Ok(chunk) => chunk,
+                Err(frame) => {
+                    if let Ok(trailers) = frame.into_trailers() {
+                        match &mut buffered.trailers {
+                            Some(buffered) => buffered.extend(trailers),
+                            None => buffered.trailers = Some(trailers),
+                        }
+                    }
+                    continue;
+                }
+            },
+            Some(Err(err)) => {
+                tracing::warn!("Failed to read body: {err:?}");
+                return Err(Uncollected::Failed(prefixed(buffered, body)));
+            }
+            None => return Ok(buffered),
+        };
+
+        let exceeded = limit.is_some_and(|limit| buffered.len + chunk.len() > limit);
+        if !exceeded {
+            on_chunk(&chunk);
+        }
+        buffered.push(chunk);
+        if exceeded {
+            return Err(Uncollected::TooLarge(prefixed(buffered, body)));
+        }
+    }
+}
+
+/// A body yielding the `buffered` chunks before the frames of `rest`.
+fn prefixed(buffered: Buffered, rest: Body) -> Body {
+    Body::new(BufferedBody {
+        buffered: buffered.chunks,
+        rest: Some(rest),
+        trailers: buffered.trailers,
+    })
+}
+
+struct BufferedBody {
+    buffered: VecDeque<Bytes>,
+    rest: Option<Body>,
+    /// Trailers yielded once `buffered` and `rest` are exhausted.
+    trailers: Option<HeaderMap>,
+}
+
+impl HttpBody for BufferedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        if let Some(chunk) = self.buffered.pop_front() {
+            return Poll::Ready(Some(Ok(Frame::data(chunk))));
+        }
+
+        if let Some(rest) = &mut self.rest {
+            match Pin::new(rest).poll_frame(cx) {
+                Poll::Ready(None) => self.rest = None,
+                frame => return frame,
+            }
+        }
+
+        Poll::Ready(
+            self.trailers
+                .take()
+                .map(|trailers| Ok(Frame::trailers(trailers))),
+        )
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.buffered.is_empty()
+            && self.rest.as_ref().is_none_or(Body::is_end_stream)
+            && self.trailers.is_none()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        let buffered: u64 = self.buffered.iter().map(|chunk| chunk.len() as u64).sum();
+        let rest = self
+            .rest
+            .as_ref()
+            .map_or_else(|| SizeHint::with_exact(0), Body::size_hint);
+
+        let mut hint = SizeHint::new();
+        hint.set_lower(rest.lower() + buffered);
+        if let Some(upper) = rest.upper() {
+            hint.set_upper(upper + buffered);
+        }
+        hint
+    }
+}
+
+/// Adapts an inner service responding with any body to the [`Response`](axum::response::Response)
+/// type the idempotency pipeline works with.
+#[derive(Clone)]
+pub(crate) struct AxumService<S>(pub(crate) S);
+
+impl<S, B> Service<Request> for AxumService<S>
+where
+    S: Service<Request, Response = http::Response<B>>,
+    S::Future: Send + 'static,
+    B: HttpBody<Data = Bytes> + Send + 'static,
+    B::Error: Into<BoxError>,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.0.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        let future = self.0.call(req);
+        Box::pin(async move { Ok(future.await?.map(Body::new)) })
+    }
+}
@@ -0,0 +1,548 @@
+use crate::blob::BlobPointer;
+use crate::body::with_trailers;
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+use crate::config::Compression;
+use axum::body::Bytes;
+use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::response::Response;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Magic bytes starting serialized responses, followed by the version of their format.
+///
+/// Entries written before the format was versioned lack them, and start with a status code
+/// instead, whose first byte is at most 3.
+const MAGIC: [u8; 2] = [0xfe, 0xed];
+
+/// The version of the format serialized responses are written in.
+///
+/// Version 0 is the unversioned format of earlier releases, holding no storage time, version 1
+/// adds the storage time in the envelope, and version 2 is MessagePack. Decoders for at least
+/// the previous version are kept, so entries written by the previous release can still be
+/// replayed during a rolling deploy, and upgraded with `migrate::upgrade_entries`.
+pub(crate) const FORMAT_VERSION: u8 = 2;
+
+/// The latest timestamp entries may hold, in seconds: the end of year 9999, after which times
+/// cannot be formatted as HTTP dates.
+const MAX_TIMESTAMP: u64 = 253_402_300_799;
+
+/// Length of the fixed-size prefix: status code (2 bytes) and `stored_at` (8 bytes).
+const PREFIX_LEN: usize = 10;
+
+/// Prefix of the marker stored while the original request is still being processed.
+///
+/// Serialized responses start with a status code of at least 100, so it cannot collide.
+const PENDING_PREFIX: [u8; 2] = [0, 0];
+
+/// Prefix of the tombstone stored in place of a response too large to be cached.
+const TOMBSTONE_PREFIX: [u8; 2] = [0, 1];
+
+/// Prefix of entries compressed with gzip.
+///
+/// Serialized responses start with the magic byte `0xfe`, or a status code whose first byte is
+/// at most 3, so compressed entries are told apart by a first byte of `0xff`.
+const GZIP_PREFIX: [u8; 2] = [0xff, b'g'];
+
+/// Prefix of entries compressed with Zstandard.
+const ZSTD_PREFIX: [u8; 2] = [0xff, b'z'];
+
+/// Returns the marker stored under a key while its request, started at `now`, is in flight.
+pub(crate) fn pending_marker(now: SystemTime) -> Vec<u8> {
+    let started_at = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+
+    let mut marker = PENDING_PREFIX.to_vec();
+    marker.extend_from_slice(&started_at.to_be_bytes());
+    marker
+}
+
+/// Returns the tombstone stored in place of a response too large to be cached, telling that
+/// replays are rejected with `status`.
+pub(crate) fn tombstone(status: StatusCode) -> Vec<u8> {
+    let mut tombstone = TOMBSTONE_PREFIX.to_vec();
+    tombstone.extend_from_slice(&status.as_u16().to_be_bytes());
+    tombstone
+}
+
+/// Returns the status code replays are rejected with, if the stored `bytes` are a tombstone.
+pub(crate) fn tombstone_status(bytes: &[u8]) -> Option<StatusCode> {
+    let status = bytes.strip_prefix(&TOMBSTONE_PREFIX)?;
+    let status = u16::from_be_bytes(status.try_into().ok()?);
+    StatusCode::from_u16(status).ok()
+}
+
+/// Whether the stored `bytes` are an in-flight marker rather than a response.
+pub(crate) fn is_pending(bytes: &[u8]) -> bool {
+    bytes.starts_with(&PENDING_PREFIX)
+}
+
+/// Returns when the request that stored the in-flight marker `bytes` started.
+pub(crate) fn pending_since(bytes: &[u8]) -> Option<SystemTime> {
+    let started_at = bytes.strip_prefix(&PENDING_PREFIX)?;
+    let started_at = u64::from_be_bytes(started_at.try_into().ok()?);
+    Some(UNIX_EPOCH + Duration::from_secs(started_at))
+}
+
+/// Returns the format version of the serialized response `bytes`, decompressing them if needed,
+/// or `None` if they are an in-flight marker or a tombstone.
+pub(crate) fn format_version(bytes: &[u8]) -> Result<Option<u8>, Box<dyn Error + Send + Sync>> {
+    if is_pending(bytes) || tombstone_status(bytes).is_some() {
+        return Ok(None);
+    }
+    let bytes = match bytes.first() {
+        Some(0xff) => Cow::Owned(decompress(bytes)?),
+        _ => Cow::Borrowed(bytes),
+    };
+
+    match bytes.strip_prefix(&MAGIC) {
+        Some([version, ..]) => Ok(Some(*version)),
+        Some([]) => Err("Invalid cached response: missing format version".into()),
+        // Entries written before the format was versioned
+        None => Ok(Some(0)),
+    }
+}
+
+/// A response as stored in, and read back from, the cache.
+///
+/// This is the deserialized form of the entries written by the middleware. It is returned by
+/// inspection APIs and hooks such as [`ReplicatedEntry::response`](crate::ReplicatedEntry::response).
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct CachedResponse {
+    /// The status code of the original response.
+    pub status: StatusCode,
+    /// The headers of the original response.
+    pub headers: HeaderMap,
+    /// The body of the original response.
+    pub body: Bytes,
+    /// The trailers of the original response, e.g. `grpc-status` for gRPC-web, if it had any.
+    pub trailers: Option<HeaderMap>,
+    /// When the response was stored, with a precision of one second.
+    pub stored_at: SystemTime,
+    /// A fingerprint of the request that produced the response, e.g. its hash in hashing mode.
+    pub fingerprint: Option<String>,
+    /// When the entry expires from the store, if known.
+    pub expires_at: Option<SystemTime>,
+    /// The TTL the entry was stored with, if known. With
+    /// [`IdempotentOptions::sliding_expiration`](crate::IdempotentOptions::sliding_expiration),
+    /// every replay extends the entry by it.
+    pub ttl: Option<Duration>,
+    /// How many times the response was replayed, if counted (see
+    /// [`IdempotentOptions::count_replays`](crate::IdempotentOptions::count_replays)).
+    pub replays: u32,
+    /// Where the body is kept if it was uploaded to a [`BlobStore`](crate::BlobStore), in which
+    /// case [`body`](Self::body) is empty.
+    pub blob: Option<BlobPointer>,
+}
+
+/// The serialized form of a [`CachedResponse`], in version 2 of the format.
+#[derive(Serialize, Deserialize)]
+struct Entry<'a> {
+    status: u16,
+    #[serde(borrow)]
+    headers: Vec<Field<'a>>,
+    #[serde(borrow, default)]
+    trailers: Option<Vec<Field<'a>>>,
+    #[serde(borrow, with = "serde_bytes")]
+    body: Cow<'a, [u8]>,
+    stored_at: u64,
+    #[serde(borrow, default)]
+    fingerprint: Option<Cow<'a, str>>,
+    #[serde(default)]
+    expires_at: Option<u64>,
+    // Omitted when unset, so entries without it are encoded as before it was added
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    ttl: Option<u64>,
+    #[serde(default)]
+    replays: u32,
+    #[serde(borrow, default)]
+    blob: Option<Blob<'a>>,
+}
+
+/// The [`BlobPointer`] of an [`Entry`].
+#[derive(Serialize, Deserialize)]
+struct Blob<'a> {
+    #[serde(borrow)]
+    key: Cow<'a, str>,
+    len: u64,
+}
+
+/// A header or trailer field of an [`Entry`].
+#[derive(Serialize, Deserialize)]
+struct Field<'a> {
+    #[serde(borrow)]
+    name: Cow<'a, str>,
+    #[serde(borrow, with = "serde_bytes")]
+    value: Cow<'a, [u8]>,
+}
+
+/// Converts a Unix timestamp of an entry, in seconds, to a time.
+fn timestamp(secs: u64) -> Result<SystemTime, Box<dyn Error + Send + Sync>> {
+    if secs > MAX_TIMESTAMP {
+        return Err(format!("Invalid cached response: timestamp {secs} out of range").into());
+    }
+    Ok(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+fn fields(headers: &HeaderMap) -> Vec<Field<'_>> {
+    headers
+        .iter()
+        .map(|(name, value)| Field {
+            name: Cow::Borrowed(name.as_str()),
+            value: Cow::Borrowed(value.as_bytes()),
+        })
+        .collect()
+}
+
+fn header_map(fields: Vec<Field<'_>>) -> Result<HeaderMap, Box<dyn Error + Send + Sync>> {
+    let mut headers = HeaderMap::with_capacity(fields.len());
+    for field in fields {
+        headers.append(
+            HeaderName::from_str(&field.name)?,
+            HeaderValue::from_bytes(&field.value)?,
+        );
+    }
+    Ok(headers)
+}
+
+impl CachedResponse {
+    /// Creates a new `CachedResponse` stored at the current time.
+    pub fn new(status: StatusCode, headers: HeaderMap, body: impl Into<Bytes>) -> Self {
+        Self {
+            status,
+            headers,
+            body: body.into(),
+            trailers: None,
+            stored_at: SystemTime::now(),
+            fingerprint: None,
+            expires_at: None,
+            ttl: None,
+            replays: 0,
+            blob: None,
+        }
+    }
+
+    /// Sets when the response was stored.
+    pub(crate) fn stored_at(mut self, stored_at: SystemTime) -> Self {
+        self.stored_at = stored_at;
+        self
+    }
+
+    /// Sets the trailers of the response.
+    pub fn with_trailers(mut self, trailers: HeaderMap) -> Self {
+        self.trailers = Some(trailers);
+        self
+    }
+
+    /// Sets the fingerprint of the request that produced the response.
+    pub fn with_fingerprint(mut self, fingerprint: impl Into<String>) -> Self {
+        self.fingerprint = Some(fingerprint.into());
+        self
+    }
+
+    /// Sets where the body is kept in a [`BlobStore`](crate::BlobStore), emptying it.
+    pub fn with_blob(mut self, blob: BlobPointer) -> Self {
+        self.body = Bytes::new();
+        self.blob = Some(blob);
+        self
+    }
+
+    /// Sets the TTL of the entry, which expires from the store `ttl_secs` seconds after it was
+    /// stored.
+    pub fn with_ttl(mut self, ttl_secs: i64) -> Self {
+        let ttl = Duration::from_secs(ttl_secs.max(0) as u64);
+        self.expires_at = Some(self.stored_at + ttl);
+        self.ttl = Some(ttl);
+        self
+    }
+
+    /// The number of seconds from `now` until the entry expires from the store, if known.
+    pub(crate) fn remaining_ttl_secs(&self, now: SystemTime) -> Option<i64> {
+        let remaining = self.expires_at?.duration_since(now).ok()?;
+        Some(remaining.as_secs() as i64)
+    }
+
+    /// Serializes the response into the format used in the store.
+    ///
+    /// The response is encoded as a MessagePack map, following the magic bytes `0xfe 0xed` and
+    /// the format version, so entries can be read by other services and debugging tools. The
+    /// map holds the `status` code, the `headers` and `trailers` as lists of `name` and `value`
+    /// maps, the `body`, the `stored_at` and `expires_at` Unix timestamps in seconds, the `ttl`
+    /// in seconds, the `fingerprint`, the number of `replays`, and the `blob` holding the body,
+    /// as a map of its `key` and `len`, if it was uploaded to a [`BlobStore`](crate::BlobStore).
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let timestamp = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        };
+        let entry = Entry {
+            status: self.status.as_u16(),
+            headers: fields(&self.headers),
+            trailers: self.trailers.as_ref().map(fields),
+            body: Cow::Borrowed(&self.body),
+            stored_at: timestamp(self.stored_at),
+            fingerprint: self.fingerprint.as_deref().map(Cow::Borrowed),
+            expires_at: self.expires_at.map(timestamp),
+            ttl: self.ttl.map(|ttl| ttl.as_secs()),
+            replays: self.replays,
+            blob: self.blob.as_ref().map(|blob| Blob {
+                key: Cow::Borrowed(&blob.key),
+                len: blob.len,
+            }),
+        };
+
+        let mut result = MAGIC.to_vec();
+        result.push(FORMAT_VERSION);
+        // Entries only hold types MessagePack can represent, and writing to a `Vec` cannot fail
+        rmp_serde::encode::write_named(&mut result, &entry)
+            .expect("cached responses are serializable");
+        result
+    }
+
+    /// Deserializes a response from the format used in the store.
+    ///
+    /// Entries compressed by the middleware (see `IdempotentOptions::compress_over_bytes`)
+    /// are decompressed first. Entries written in a format version this release does not know,
+    /// e.g. by a newer release, cannot be deserialized.
+    ///
+    /// Entries written before the format was versioned did not record when they were stored,
+    /// which is then set to the current time.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        Self::decode(bytes, SystemTime::now())
+    }
+
+    /// Deserializes a response from the format used in the store, setting when entries that
+    /// did not record it were stored to `now`.
+    pub(crate) fn decode(
+        bytes: &[u8],
+        now: SystemTime,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let bytes = match bytes.first() {
+            Some(0xff) => Cow::Owned(decompress(bytes)?),
+            _ => Cow::Borrowed(bytes),
+        };
+
+        match bytes.strip_prefix(&MAGIC) {
+            Some([2, payload @ ..]) => Self::decode_v2(payload),
+            Some([1, payload @ ..]) => Self::decode_v1(payload),
+            Some([version, ..]) => Err(UnsupportedVersion(*version).into()),
+            Some([]) => Err("Invalid cached response: missing format version".into()),
+            // Entries written before the format was versioned
+            None => Self::decode_v0(&bytes, now),
+        }
+    }
+
+    /// Deserializes a response in version 2 of the format.
+    fn decode_v2(bytes: &[u8]) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let entry: Entry<'_> = rmp_serde::from_slice(bytes)?;
+
+        Ok(Self {
+            status: StatusCode::from_u16(entry.status)?,
+            headers: header_map(entry.headers)?,
+            body: Bytes::copy_from_slice(&entry.body),
+            trailers: entry.trailers.map(header_map).transpose()?,
+            stored_at: timestamp(entry.stored_at)?,
+            fingerprint: entry.fingerprint.map(Cow::into_owned),
+            expires_at: entry.expires_at.map(timestamp).transpose()?,
+            ttl: entry.ttl.map(Duration::from_secs),
+            replays: entry.replays,
+            blob: entry
+                .blob
+                .map(|blob| BlobPointer::new(blob.key.into_owned(), blob.len)),
+        })
+    }
+
+    /// Deserializes a response in version 1 of the format: the status code, `stored_at`, the
+    /// header lines (trailer names prefixed with a colon), a blank line and the body.
+    fn decode_v1(bytes: &[u8]) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        if bytes.len() < PREFIX_LEN {
+            return Err("Invalid cached response: too short".into());
+        }
+
+        let status = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let status = StatusCode::from_u16(status)?;
+
+        let mut stored_at = [0; 8];
+        stored_at.copy_from_slice(&bytes[2..PREFIX_LEN]);
+        let stored_at = timestamp(u64::from_be_bytes(stored_at))?;
+
+        // End of headers (double CRLF: \r\n\r\n)
+        let header_end = bytes[PREFIX_LEN..]
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map(|position| position + PREFIX_LEN)
+            .ok_or("Invalid header format: missing double CRLF")?;
+
+        let (headers, trailers) = parse_headers(&bytes[PREFIX_LEN..header_end])?;
+
+        // Skip both CRLFs after the header section (skip header_end + 4)
+        let body = Bytes::copy_from_slice(&bytes[(header_end + 4)..]);
+
+        Ok(Self {
+            status,
+            headers,
+            body,
+            trailers,
+            stored_at,
+            fingerprint: None,
+            expires_at: None,
+            ttl: None,
+            replays: 0,
+            blob: None,
+        })
+    }
+
+    /// Deserializes a response in version 0 of the format, written before it was versioned:
+    /// the status code, the header lines, a blank line and the body. It holds no storage time,
+    /// so the response is considered stored at `now`.
+    fn decode_v0(bytes: &[u8], now: SystemTime) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        if bytes.len() < 2 {
+            return Err("Invalid cached response: too short".into());
+        }
+
+        let status = u16::from_be_bytes([bytes[0], bytes[1]]);
+        let status = StatusCode::from_u16(status)?;
+
+        // End of headers (double CRLF: \r\n\r\n)
+        let header_end = bytes[2..]
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")
+            .map(|position| position + 2)
+            .ok_or("Invalid header format: missing double CRLF")?;
+
+        let (headers, _) = parse_headers(&bytes[2..header_end])?;
+
+        // Skip both CRLFs after the header section (skip header_end + 4)
+        let body = Bytes::copy_from_slice(&bytes[(header_end + 4)..]);
+
+        Ok(Self::new(status, headers, body).stored_at(now))
+    }
+
+    /// Converts the cached response into a response that can be replayed.
+    pub fn into_response(self) -> Response {
+        let mut response = Response::new(with_trailers(self.body, self.trailers));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+
+        response
+    }
+}
+
+/// The error returned when deserializing an entry written in an unknown format version.
+#[derive(Debug)]
+pub(crate) struct UnsupportedVersion(pub(crate) u8);
+
+impl fmt::Display for UnsupportedVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unsupported cached response format version {}", self.0)
+    }
+}
+
+impl Error for UnsupportedVersion {}
+
+/// Compresses a serialized response, prefixing it with the marker of `compression`.
+///
+/// The entry is returned uncompressed if compression fails.
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+pub(crate) fn compress(bytes: Vec<u8>, compression: Compression) -> Vec<u8> {
+    #[cfg(feature = "gzip")]
+    use std::io::Write;
+
+    let compressed = match compression {
+        #[cfg(feature = "gzip")]
+        Compression::Gzip => {
+            let mut encoder =
+                flate2::write::GzEncoder::new(GZIP_PREFIX.to_vec(), flate2::Compression::default());
+            encoder.write_all(&bytes).and_then(|()| encoder.finish())
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd => {
+            let mut compressed = ZSTD_PREFIX.to_vec();
+            zstd::stream::copy_encode(&bytes[..], &mut compressed, 0).map(|()| compressed)
+        }
+    };
+
+    match compressed {
+        Ok(compressed) => compressed,
+        Err(err) => {
+            tracing::warn!("Failed to compress cached response: {err:?}");
+            bytes
+        }
+    }
+}
+
+/// Returns the compression of an entry written by `compress`, if it is compressed.
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+pub(crate) fn compression_of(bytes: &[u8]) -> Option<Compression> {
+    match bytes.get(..2)? {
+        #[cfg(feature = "gzip")]
+        prefix if prefix == GZIP_PREFIX => Some(Compression::Gzip),
+        #[cfg(feature = "zstd")]
+        prefix if prefix == ZSTD_PREFIX => Some(Compression::Zstd),
+        _ => None,
+    }
+}
+
+/// Decompresses an entry written by `compress`.
+#[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+fn decompress(bytes: &[u8]) -> Result<Vec<u8>, Box<dyn Error + Send + Sync>> {
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    use std::io::Read;
+
+    let (prefix, compressed) = bytes.split_at(bytes.len().min(2));
+    match prefix {
+        #[cfg(feature = "gzip")]
+        prefix if prefix == GZIP_PREFIX => {
+            let mut decompressed = Vec::new();
+            flate2::read::GzDecoder::new(compressed).read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
+        #[cfg(feature = "zstd")]
+        prefix if prefix == ZSTD_PREFIX => {
+            let mut decompressed = Vec::new();
+            zstd::stream::Decoder::new(compressed)?.read_to_end(&mut decompressed)?;
+            Ok(decompressed)
+        }
+        prefix if prefix == GZIP_PREFIX || prefix == ZSTD_PREFIX => {
+            Err("Compressed cached response, but its compression feature is disabled".into())
+        }
+        _ => Err("Invalid cached response: unknown compression".into()),
+    }
+}
+
+/// Parse headers from bytes.
+fn parse_headers(
+    header_bytes: &[u8],
+) -> Result<(HeaderMap, Option<HeaderMap>), Box<dyn Error + Send + Sync>> {
+    let mut headers = HeaderMap::new();
+    let mut trailers: Option<HeaderMap> = None;
+    let header_str = std::str::from_utf8(header_bytes)?;
+
+    for line in header_str.split("\r\n") {
+        if line.is_empty() {
+            continue;
+        }
+
+        let parts: Vec<&str> = line.splitn(2, ": ").collect();
+        if parts.len() != 2 {
+            return Err("Invalid header format".into());
+        }
+
+        let value = parts[1].parse()?;
+        match parts[0].strip_prefix(':') {
+            Some(name) => {
+                let trailers = trailers.get_or_insert_with(HeaderMap::new);
+                trailers.append(HeaderName::from_str(name)?, value);
+            }
+            None => {
+                headers.append(HeaderName::from_str(parts[0])?, value);
+            }
+        }
+    }
+
+    Ok((headers, trailers))
+}
@@ -0,0 +1,45 @@
+use std::time::SystemTime;
+
+/// The source of the current time used by the middleware.
+///
+/// It timestamps cached responses and in-flight markers, and computes their age (replayed in
+/// the `Age` header and compared to [`IdempotentOptions::soft_ttl`](crate::IdempotentOptions::soft_ttl))
+/// and their expiration time. Inject another clock with
+/// [`IdempotentOptions::clock`](crate::IdempotentOptions::clock), e.g. to test TTL logic
+/// without sleeping, or on targets without a system clock. The store expires entries by its
+/// own time.
+///
+/// Closures returning a [`SystemTime`] implement this trait.
+///
+/// # Example
+/// ```rust
+/// use std::time::{Duration, SystemTime};
+/// use axum_idempotent::IdempotentOptions;
+///
+/// // A clock running an hour ahead
+/// let options =
+///     IdempotentOptions::default().clock(|| SystemTime::now() + Duration::from_secs(3600));
+/// ```
+pub trait Clock: Send + Sync + 'static {
+    /// Returns the current time.
+    fn now(&self) -> SystemTime;
+}
+
+/// The system clock, used by default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+impl<F> Clock for F
+where
+    F: Fn() -> SystemTime + Send + Sync + 'static,
+{
+    fn now(&self) -> SystemTime {
+        self()
+    }
+}
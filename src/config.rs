@@ -1,5 +1,373 @@
-use axum::http::{HeaderMap, HeaderName, HeaderValue, StatusCode};
+use axum::extract::Request;
+use axum::http::header;
+use axum::http::request::Parts;
+use axum::http::{Extensions, HeaderMap, HeaderName, HeaderValue, Method, StatusCode};
+use axum::response::{IntoResponse, Response};
+use serde::Deserialize;
+use std::borrow::Cow;
 use std::collections::HashSet;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use crate::admin::IdempotencyStats;
+#[cfg(feature = "audit")]
+use crate::audit::AuditSink;
+use crate::blob::{BlobStore, DynBlobStore};
+use crate::clock::{Clock, SystemClock};
+use crate::error::{ConfigError, ErrorAction, IdempotencyError};
+use crate::extension::{BypassIdempotency, IdempotencyTtl};
+use crate::flight::SingleFlight;
+#[cfg(feature = "front-cache")]
+use crate::front::{FrontCache, FrontCacheLimit};
+#[cfg(feature = "jwt")]
+use crate::jwt::{JwtClaimKey, JwtClaimMode};
+use crate::normalize::BodyNormalizer;
+use crate::observer::IdempotencyObserver;
+use crate::rejection::RejectionCache;
+use crate::replication::ReplicatedEntry;
+use crate::settings::Settings;
+use crate::shutdown::{DEFAULT_MAX_PENDING_WRITES, ShutdownHandle, WriteBehind};
+use crate::store::{IdempotencyStore, store_key};
+use crate::throttle::KeyRateLimiter;
+use crate::utils::path_matches;
+#[cfg(feature = "webhook")]
+use crate::webhook::WebhookDedup;
+
+/// A user-provided callback stored in [`IdempotentOptions`].
+pub(crate) struct Hook<F: ?Sized>(pub(crate) Arc<F>);
+
+impl<F: ?Sized> Clone for Hook<F> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<F: ?Sized> fmt::Debug for Hook<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Hook(..)")
+    }
+}
+
+type TtlPolicy = dyn Fn(&Extensions) -> Option<i64> + Send + Sync;
+type ReplicationHook = dyn Fn(ReplicatedEntry) + Send + Sync;
+type ReplayHook = dyn Fn(&mut Response) + Send + Sync;
+type EnabledPredicate = dyn Fn(&Parts) -> bool + Send + Sync;
+type CachePredicate = dyn Fn(&Response) -> bool + Send + Sync;
+type MissingKeyResponse = dyn Fn() -> Response + Send + Sync;
+type ScopeFn = dyn Fn(&Extensions) -> Option<String> + Send + Sync;
+type ErrorHook = dyn Fn(&IdempotencyError) -> ErrorAction + Send + Sync;
+type ExtensionHash = dyn Fn(&Extensions, &mut dyn Hasher) + Send + Sync;
+
+/// How a request is handled when an identical request is still being processed.
+///
+/// See [`IdempotentOptions::on_conflict`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictBehavior {
+    /// Wait up to the given duration for the original response and replay it.
+    ///
+    /// If the original request completes without caching its response, the waiting request
+    /// is executed. If the duration elapses first, the request is rejected with a
+    /// `409 Conflict`.
+    Wait(Duration),
+    /// Reject the request right away with the given status code and a `Retry-After` header.
+    Reject(StatusCode),
+    /// Reject the request right away with a `425 Too Early`, as some bank APIs do.
+    ///
+    /// The `Retry-After` header tells the client to retry once the original request is expected
+    /// to have completed: the given duration after it started.
+    TooEarly(Duration),
+    /// Execute the request anyway, without caching its response.
+    Passthrough,
+}
+
+/// How requests are handled when the store fails before they are executed.
+///
+/// See [`IdempotentOptions::on_store_error`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreErrorPolicy {
+    /// Forward the request to the inner service as if no response was cached.
+    FailOpen,
+    /// Reject the request with the given status code, e.g. `503 Service Unavailable`.
+    FailClosed(StatusCode),
+}
+
+/// The algorithm used to compress cached responses.
+///
+/// See [`IdempotentOptions::compress_over_bytes`].
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    /// gzip (requires the `gzip` feature).
+    #[cfg(feature = "gzip")]
+    Gzip,
+    /// Zstandard, which is faster and compresses better than gzip (requires the `zstd`
+    /// feature). This is the default when enabled.
+    #[cfg(feature = "zstd")]
+    Zstd,
+}
+
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+impl Default for Compression {
+    fn default() -> Self {
+        #[cfg(feature = "zstd")]
+        return Compression::Zstd;
+        #[cfg(not(feature = "zstd"))]
+        return Compression::Gzip;
+    }
+}
+
+/// How the keys of requests are derived, as returned by [`IdempotentOptions::key_mode`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum KeyMode {
+    /// The idempotency key header is the key.
+    Direct,
+    /// The key is the hash of the request.
+    Hash,
+    /// The key is a claim of the bearer JWT, or is scoped by one. With `hashed`, it ends with
+    /// the hash of the request, as in `{claim}:{hash}`.
+    #[cfg(feature = "jwt")]
+    Jwt { hashed: bool },
+    /// The key is the event ID of a webhook delivery.
+    #[cfg(feature = "webhook")]
+    Webhook,
+}
+
+impl KeyMode {
+    /// The name of the mode, as recorded in the `idempotency.check` span.
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            KeyMode::Direct => "direct",
+            KeyMode::Hash => "hash",
+            #[cfg(feature = "jwt")]
+            KeyMode::Jwt { .. } => "jwt",
+            #[cfg(feature = "webhook")]
+            KeyMode::Webhook => "webhook",
+        }
+    }
+
+    /// Whether keys end with the hash of the request.
+    pub(crate) fn is_hashed(self) -> bool {
+        match self {
+            KeyMode::Hash => true,
+            #[cfg(feature = "jwt")]
+            KeyMode::Jwt { hashed } => hashed,
+            _ => false,
+        }
+    }
+}
+
+/// How requests whose body exceeds [`IdempotentOptions::max_body_bytes`] are handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OversizedBody {
+    /// Forward the request to the inner service without idempotency.
+    Bypass,
+    /// Reject the request with a `413 Payload Too Large`.
+    Reject,
+}
+
+/// The hash function used to compute idempotency keys in hashing mode.
+///
+/// See [`IdempotentOptions::hash_algorithm`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// BLAKE3, a fast cryptographic hash. This is the default.
+    Blake3,
+    /// SHA-256, for deployments that must use a standardized cryptographic hash.
+    Sha256,
+    /// 128-bit XXH3, the fastest option. It is not a cryptographic hash: a client can craft a
+    /// request whose key collides with another client's, and have its response replayed.
+    /// Only use it when all clients are trusted.
+    Xxh3,
+}
+
+/// How idempotency keys are isolated between clients.
+///
+/// See [`IdempotentOptions::key_scope`].
+#[derive(Clone)]
+pub enum KeyScope {
+    /// Keys are only isolated by the storage backend: per session with session stores, and
+    /// not at all with stores passed to
+    /// [`IdempotentLayer::with_store`](crate::IdempotentLayer::with_store). This is the default.
+    Store,
+    /// Keys are isolated per session: the same key sent from another session misses the
+    /// cache.
+    ///
+    /// This requires a layer backed by a session store, as created with
+    /// [`IdempotentLayer::try_new`](crate::IdempotentLayer::try_new). Layers created with
+    /// [`IdempotentLayer::with_store`](crate::IdempotentLayer::with_store) have no session,
+    /// and reject this scope when they are created.
+    Session,
+    /// A single key space shared by all clients, e.g. for server-to-server clients without a
+    /// stable session.
+    ///
+    /// Layers backed by a session store keep entries in the store of the `SessionLayer`, under
+    /// a scope shared by all sessions, so requests need not carry a session cookie. They
+    /// require a [`hash_seed`](IdempotentOptions::hash_seed), from which the id of this scope is
+    /// derived.
+    Global,
+    /// Keys are isolated per principal, as returned by the function from the request
+    /// extensions (e.g. a user ID inserted by an authentication layer). Requests for which it
+    /// returns `None` are handled according to [`IdempotentOptions::on_missing_scope`].
+    ///
+    /// The principal replaces the session: layers backed by a session store keep entries in
+    /// the key space shared by all sessions, as with [`KeyScope::Global`], so a principal gets
+    /// its responses replayed from any session, and require a
+    /// [`hash_seed`](IdempotentOptions::hash_seed) as well.
+    Custom(Arc<ScopeFn>),
+}
+
+impl KeyScope {
+    /// Scopes keys by the principal returned by `scope`.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::KeyScope;
+    ///
+    /// #[derive(Clone)]
+    /// struct UserId(u64);
+    ///
+    /// let scope = KeyScope::custom(|extensions| {
+    ///     extensions.get::<UserId>().map(|user| user.0.to_string())
+    /// });
+    /// ```
+    pub fn custom<F>(scope: F) -> Self
+    where
+        F: Fn(&Extensions) -> Option<String> + Send + Sync + 'static,
+    {
+        KeyScope::Custom(Arc::new(scope))
+    }
+
+    /// Scopes keys by the value of type `T` in the request extensions, formatted with
+    /// [`Display`](fmt::Display), e.g. a tenant ID inserted by an earlier middleware.
+    pub fn extension<T>() -> Self
+    where
+        T: fmt::Display + Send + Sync + 'static,
+    {
+        KeyScope::custom(|extensions| extensions.get::<T>().map(T::to_string))
+    }
+}
+
+/// How requests carrying an idempotency key are handled when their [`KeyScope::Custom`] scope
+/// returns `None`, e.g. because the layer inserting the tenant ID is missing or was added
+/// after the idempotency layer.
+///
+/// See [`IdempotentOptions::on_missing_scope`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissingScope {
+    /// Log a warning and forward the request without idempotency, so a retry may execute the
+    /// handler again.
+    Forward,
+    /// Log a warning and reject the request with a `500 Internal Server Error` explaining the
+    /// misconfiguration. This is the default.
+    Reject,
+}
+
+impl fmt::Debug for KeyScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            KeyScope::Store => f.write_str("Store"),
+            KeyScope::Session => f.write_str("Session"),
+            KeyScope::Global => f.write_str("Global"),
+            KeyScope::Custom(_) => f.write_str("Custom(..)"),
+        }
+    }
+}
+
+/// How responses whose body exceeds [`IdempotentOptions::max_response_bytes`] are handled.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OversizedResponse {
+    /// Forward the response without caching it, so a retried request is executed again.
+    Skip,
+    /// Forward the response, and store a tombstone under its key, so retried requests are
+    /// rejected with the given status code (e.g. `410 Gone`) telling the client the original
+    /// result cannot be replayed.
+    Tombstone(StatusCode),
+}
+
+/// Which parts of responses are stored.
+///
+/// See [`IdempotentOptions::store_mode`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum StoreMode {
+    /// Store the whole response: status code, headers, body and trailers.
+    #[default]
+    Full,
+    /// Store only the status code, replaying responses with an empty body.
+    StatusOnly,
+    /// Store only the status code and the given headers, e.g. `Location`.
+    StatusWithHeaders(Vec<HeaderName>),
+}
+
+/// How requests are handled once their cached response was replayed
+/// [`IdempotentOptions::max_replays`] times.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplayLimit {
+    /// Reject the request with the given status code, e.g. `429 Too Many Requests`.
+    Reject(StatusCode),
+    /// Execute the request again, caching its response in place of the exhausted one.
+    Reexecute,
+}
+
+/// Whether responses with a status code are cached, as returned by
+/// [`IdempotentOptions::status_caching`].
+///
+/// The variants are listed by precedence: a status code is cached [`Always`](Self::Always) if
+/// it was set with [`IdempotentOptions::always_cache_status`], regardless of the allow-list and
+/// ignored status codes, which are consulted next. In all cases, responses are only cached if
+/// [`IdempotentOptions::cache_if`] accepts them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StatusCaching {
+    /// Cached deliberately, overriding the allow-list and ignored status codes (see
+    /// [`IdempotentOptions::always_cache_status`]).
+    Always,
+    /// Cached, because the status code is in the allow-list (see
+    /// [`IdempotentOptions::cache_only_status_codes`]) or, without an allow-list, is not
+    /// ignored.
+    Cached,
+    /// Not cached, because the status code is ignored (see
+    /// [`IdempotentOptions::ignore_response_status_code`]) or missing from the allow-list.
+    Ignored,
+}
+
+/// The characters allowed in client-supplied idempotency keys.
+///
+/// See [`IdempotentOptions::key_format`].
+#[derive(Clone, Copy, Debug)]
+pub enum KeyFormat {
+    /// Any visible ASCII characters.
+    Any,
+    /// Only characters for which the function returns `true`.
+    Chars(fn(char) -> bool),
+    /// A hyphenated UUID, e.g. `67e55044-10b1-426f-9247-bb680e5fe0c8`.
+    Uuid,
+}
+
+impl KeyFormat {
+    /// Checks `key` against the format, returning a description of the expected format if it
+    /// does not match.
+    fn check(&self, key: &str) -> Result<(), &'static str> {
+        let valid = match self {
+            KeyFormat::Any => true,
+            KeyFormat::Chars(allowed) => key.chars().all(allowed),
+            KeyFormat::Uuid => {
+                key.len() == 36
+                    && key.char_indices().all(|(i, c)| match i {
+                        8 | 13 | 18 | 23 => c == '-',
+                        _ => c.is_ascii_hexdigit(),
+                    })
+            }
+        };
+
+        match (valid, self) {
+            (true, _) => Ok(()),
+            (false, KeyFormat::Uuid) => Err("must be a UUID"),
+            (false, _) => Err("contains characters that are not allowed"),
+        }
+    }
+}
 
 /// Configuration options for the idempotency layer.
 ///
@@ -20,19 +388,116 @@ use std::collections::HashSet;
 ///
 /// let options_2 = IdempotentOptions::new(60);
 /// ```
-#[derive(Clone, Debug)]
+///
+/// # Loading from configuration
+///
+/// Options can be deserialized with `serde`, e.g. from a YAML or TOML file, or the environment
+/// with `envy`, so TTLs can be tuned per environment without recompiling. The supported
+/// settings are `ttl_secs`, `soft_ttl_secs`, `sliding_expiration`, `idempotency_key_header`
+/// (which enables direct key mode), `require_key`, `max_key_length`, `key_prefix`,
+/// `namespace`, `lock_in_flight_secs`, `max_body_bytes`, `ignore_body`, `ignored_headers`,
+/// `ignored_status_codes`, `methods`, `include_paths` and `exclude_paths`. Settings left out
+/// keep their default, and the result is validated with [`Self::build`].
+///
+/// ```rust
+/// use axum_idempotent::IdempotentOptions;
+///
+/// let options: IdempotentOptions = serde_json::from_str(
+///     r#"{ "ttl_secs": 3600, "ignored_headers": ["x-request-id"], "methods": ["POST"] }"#,
+/// )
+/// .unwrap();
+/// ```
+#[derive(Clone, Debug, Deserialize)]
+#[serde(try_from = "Settings")]
 pub struct IdempotentOptions {
     pub(crate) use_idempotency_key: bool,
+    pub(crate) require_key: bool,
+    pub(crate) missing_key_response: Option<Hook<MissingKeyResponse>>,
+    pub(crate) validate_fingerprint: bool,
+    pub(crate) problem_details: bool,
+    pub(crate) problem_type_base_uri: Option<String>,
+    pub(crate) max_key_len: Option<usize>,
+    pub(crate) key_format: KeyFormat,
     pub(crate) idempotency_key_header: String,
     pub(crate) replay_header_name: HeaderName,
+    pub(crate) original_date_header: bool,
+    pub(crate) original_timestamp_header: bool,
+    pub(crate) negotiate_content_encoding: bool,
+    pub(crate) clock: Hook<dyn Clock>,
     pub(crate) ignore_body: bool,
+    pub(crate) hash_content_digest: bool,
+    pub(crate) verify_content_digest: bool,
+    pub(crate) ignore_query: bool,
+    pub(crate) hash_matched_path: bool,
+    pub(crate) hashed_extensions: Vec<Hook<ExtensionHash>>,
+    pub(crate) sort_query: bool,
+    pub(crate) ignored_query_params: Vec<String>,
+    pub(crate) body_normalizers: Vec<(String, Hook<dyn BodyNormalizer>)>,
+    pub(crate) max_body_bytes: Option<usize>,
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    pub(crate) compression: Compression,
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    pub(crate) compress_over_bytes: Option<usize>,
+    pub(crate) oversized_body: OversizedBody,
+    pub(crate) max_response_bytes: Option<usize>,
+    pub(crate) oversized_response: OversizedResponse,
+    pub(crate) blob_store: Option<(Hook<dyn DynBlobStore>, usize)>,
+    pub(crate) methods: HashSet<Method>,
+    pub(crate) include_paths: Vec<String>,
+    pub(crate) exclude_paths: Vec<String>,
     pub(crate) ignored_req_headers: HashSet<HeaderName>,
+    pub(crate) ignored_req_header_patterns: Vec<String>,
+    pub(crate) hashed_req_headers: Option<HashSet<HeaderName>>,
+    pub(crate) stripped_res_headers: HashSet<HeaderName>,
     pub(crate) ignored_res_status_codes: HashSet<StatusCode>,
+    pub(crate) cached_res_status_codes: Option<HashSet<StatusCode>>,
+    pub(crate) always_cached_res_status_codes: HashSet<StatusCode>,
+    pub(crate) cache_if: Option<Hook<CachePredicate>>,
     pub(crate) ignored_header_values: HeaderMap,
     pub(crate) ignore_all_headers: bool,
     pub(crate) body_cache_ttl_secs: i64,
+    pub(crate) soft_ttl_secs: Option<i64>,
+    pub(crate) hash_algorithm: HashAlgorithm,
+    pub(crate) key_prefix: String,
+    pub(crate) namespace: Option<String>,
+    pub(crate) key_scope: KeyScope,
+    pub(crate) on_missing_scope: MissingScope,
+    pub(crate) hash_seed: Option<[u8; 32]>,
+    pub(crate) write_retries: u32,
+    pub(crate) complete_on_disconnect: bool,
+    pub(crate) background: ShutdownHandle,
+    pub(crate) write_behind: Option<WriteBehind>,
+    pub(crate) in_flight_lock_ttl_secs: Option<i64>,
+    pub(crate) on_conflict: ConflictBehavior,
+    pub(crate) rejection_cache: Option<RejectionCache>,
+    pub(crate) key_rate_limiter: Option<KeyRateLimiter>,
+    pub(crate) single_flight: Option<SingleFlight>,
+    pub(crate) count_replays: bool,
+    pub(crate) sliding_expiration: bool,
+    pub(crate) max_replays: Option<(u32, ReplayLimit)>,
+    pub(crate) on_store_error: StoreErrorPolicy,
+    pub(crate) on_error: Option<Hook<ErrorHook>>,
+    pub(crate) write_retry_backoff: Duration,
+    pub(crate) ttl_policy: Option<Hook<TtlPolicy>>,
+    pub(crate) max_client_ttl_secs: Option<i64>,
+    pub(crate) ttl_from_response_headers: bool,
+    pub(crate) replication_hook: Option<Hook<ReplicationHook>>,
+    pub(crate) on_replay: Option<Hook<ReplayHook>>,
+    pub(crate) observer: Option<Hook<dyn IdempotencyObserver>>,
+    pub(crate) stats: Option<IdempotencyStats>,
+    #[cfg(feature = "audit")]
+    pub(crate) audit_sink: Option<Hook<dyn AuditSink>>,
+    pub(crate) enabled_when: Option<Hook<EnabledPredicate>>,
+    pub(crate) bypass_header: Option<(HeaderName, blake3::Hash)>,
     #[cfg(feature = "layered-store")]
     pub(crate) layered_hot_cache_ttl_secs: Option<i64>,
+    #[cfg(feature = "jwt")]
+    pub(crate) jwt_claim_key: Option<JwtClaimKey>,
+    #[cfg(feature = "webhook")]
+    pub(crate) webhook_dedup: Option<WebhookDedup>,
+    pub(crate) store_mode: StoreMode,
+    #[cfg(feature = "front-cache")]
+    pub(crate) front_cache: Option<FrontCache>,
 }
 
 impl IdempotentOptions {
@@ -43,29 +508,1117 @@ impl IdempotentOptions {
         }
     }
 
+    /// Options for payment and other money-moving endpoints, where executing a request twice
+    /// is costly:
+    ///
+    /// - Keys are read from the `Idempotency-Key` header (see
+    ///   [`Self::use_idempotency_key_header`]) and required (see [`Self::require_key`]).
+    /// - Reusing a key with a different request is rejected (see
+    ///   [`Self::validate_fingerprint`]).
+    /// - Responses are kept for 24 hours, covering the retry windows of clients.
+    /// - Requests with an in-flight key get a `409 Conflict` (see [`Self::lock_in_flight`],
+    ///   here with a lock of 60 seconds).
+    /// - Requests are rejected with a `503 Service Unavailable` while the store is unavailable,
+    ///   rather than executed without protection (see [`StoreErrorPolicy::FailClosed`]).
+    ///
+    /// Settings can still be adjusted afterwards.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::payments().include_paths(["/payments/*"]);
+    /// ```
+    pub fn payments() -> Self {
+        Self::default()
+            .use_idempotency_key_header(None)
+            .require_key(true)
+            .validate_fingerprint(true)
+            .expire_after(24 * 60 * 60)
+            .lock_in_flight(60)
+            .on_store_error(StoreErrorPolicy::FailClosed(
+                StatusCode::SERVICE_UNAVAILABLE,
+            ))
+    }
+
+    /// Options for webhook receivers, deduplicating deliveries by the event ID of their
+    /// provider (see [`Self::webhook_dedup`]):
+    ///
+    /// - Only the status code of the acknowledgment is stored (see [`StoreMode::StatusOnly`]).
+    /// - Entries are kept for 3 days, covering the retry schedules of providers such as
+    ///   Stripe.
+    /// - Redeliveries arriving while the event is being processed get a `409 Conflict`, which
+    ///   providers retry later (see [`Self::lock_in_flight`], here with a lock of 60 seconds).
+    ///
+    /// Settings can still be adjusted afterwards. This requires the `webhook` feature.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::{IdempotentOptions, WebhookDedup};
+    ///
+    /// let options = IdempotentOptions::webhooks(WebhookDedup::stripe());
+    /// ```
+    #[cfg(feature = "webhook")]
+    pub fn webhooks(provider: WebhookDedup) -> Self {
+        Self::default()
+            .webhook_dedup(provider)
+            .expire_after(3 * 24 * 60 * 60)
+            .lock_in_flight(60)
+    }
+
+    /// Options for general API endpoints, deduplicating identical requests sent within a short
+    /// window, e.g. by a double-click or a client retry:
+    ///
+    /// - Keys are a hash of the request, so clients need not send an idempotency key.
+    /// - Responses are kept for 5 minutes.
+    ///
+    /// The mode and TTL match [`IdempotentOptions::default`], but are kept if the defaults
+    /// change. Settings can still be adjusted afterwards.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::http::Method;
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::api_default().only_methods([Method::POST]);
+    /// ```
+    pub fn api_default() -> Self {
+        Self {
+            use_idempotency_key: false,
+            ignore_body: false,
+            body_cache_ttl_secs: 5 * 60,
+            ..Self::default()
+        }
+    }
+
+    /// Validates the options, returning them if their settings are consistent.
+    ///
+    /// The setters accept any value, so that options can be built up step by step; this checks
+    /// the result, e.g. that the expiration time is positive and that direct key mode was not
+    /// combined with body hashing. Every constructor of
+    /// [`IdempotentLayer`](crate::IdempotentLayer) calls it.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::{ConfigError, IdempotentOptions};
+    ///
+    /// let result = IdempotentOptions::default()
+    ///     .use_idempotency_key_header(None)
+    ///     .ignore_body(false)
+    ///     .build();
+    /// assert_eq!(result.unwrap_err(), ConfigError::DirectKeyHashesRequest);
+    /// ```
+    pub fn build(self) -> Result<Self, ConfigError> {
+        if self.body_cache_ttl_secs <= 0 {
+            return Err(ConfigError::InvalidTtl(self.body_cache_ttl_secs));
+        }
+        if let Some(soft_ttl_secs) = self.soft_ttl_secs {
+            if soft_ttl_secs >= self.body_cache_ttl_secs {
+                return Err(ConfigError::SoftTtlNotBelowTtl {
+                    soft_ttl_secs,
+                    ttl_secs: self.body_cache_ttl_secs,
+                });
+            }
+        }
+        if let Some(lock_ttl_secs) = self.in_flight_lock_ttl_secs {
+            if lock_ttl_secs <= 0 {
+                return Err(ConfigError::InvalidLockTtl(lock_ttl_secs));
+            }
+        }
+        if HeaderName::try_from(&self.idempotency_key_header).is_err() {
+            return Err(ConfigError::InvalidKeyHeader(
+                self.idempotency_key_header.clone(),
+            ));
+        }
+        if self.key_mode() == KeyMode::Direct && !(self.ignore_body && self.ignore_all_headers) {
+            return Err(ConfigError::DirectKeyHashesRequest);
+        }
+        if let Some((_, secret)) = &self.bypass_header {
+            if *secret == blake3::hash(b"") {
+                return Err(ConfigError::EmptyBypassSecret);
+            }
+        }
+
+        Ok(self)
+    }
+
+    /// Validates the options with [`Self::build`] for a layer backed by a session store, which
+    /// also requires a [`Self::hash_seed`] when keys are kept in a scope shared by all sessions.
+    #[cfg(feature = "session")]
+    pub(crate) fn build_for_sessions(self) -> Result<Self, ConfigError> {
+        let shared = matches!(self.key_scope, KeyScope::Global | KeyScope::Custom(_));
+        if shared && self.hash_seed.is_none() {
+            return Err(ConfigError::MissingHashSeed);
+        }
+
+        self.build()
+    }
+
     /// Sets the expiration time in seconds for cached responses.
     pub fn expire_after(mut self, seconds: i64) -> Self {
         self.body_cache_ttl_secs = seconds;
         self
     }
 
-    /// Whether the request body should be ignored when calculating the idempotency key.
+    /// Enables stale-while-revalidate for responses cached for longer than `seconds`.
+    ///
+    /// Such responses are still replayed immediately, but the request is also forwarded to the
+    /// inner service in a background task, whose response replaces the cached one. This suits
+    /// idempotent but refreshable endpoints. `seconds` should be lower than the expiration time.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default().expire_after(60 * 60).soft_ttl(5 * 60);
+    /// ```
+    pub fn soft_ttl(mut self, seconds: i64) -> Self {
+        self.soft_ttl_secs = Some(seconds);
+        self
+    }
+
+    /// Returns the current time of the configured [`Clock`].
+    pub(crate) fn now(&self) -> SystemTime {
+        self.clock.0.now()
+    }
+
+    /// Whether a response cached for `age` should be refreshed (see [`Self::soft_ttl`]).
+    pub(crate) fn is_stale(&self, age: Duration) -> bool {
+        self.soft_ttl_secs
+            .is_some_and(|secs| age.as_secs() >= u64::try_from(secs).unwrap_or_default())
+    }
+
+    /// Sets a callback deriving the expiration time in seconds from the request extensions.
+    ///
+    /// This allows mapping the authenticated principal (inserted by an authentication layer)
+    /// to its own replay window, e.g. giving partners with contractual 24-hour retry windows
+    /// a longer TTL than anonymous traffic. Returning `None` falls back to [`Self::expire_after`].
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// #[derive(Clone)]
+    /// struct Partner;
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .ttl_policy(|extensions| extensions.get::<Partner>().map(|_| 60 * 60 * 24));
+    /// ```
+    pub fn ttl_policy<F>(mut self, policy: F) -> Self
+    where
+        F: Fn(&Extensions) -> Option<i64> + Send + Sync + 'static,
+    {
+        self.ttl_policy = Some(Hook(Arc::new(policy)));
+        self
+    }
+
+    /// Lets clients request their own expiration time in seconds through the
+    /// `Idempotency-TTL` request header, up to `max_secs`.
+    ///
+    /// This is useful for batch clients with known retry schedules. Requested values above
+    /// `max_secs` are capped, and invalid or non-positive values are ignored. A valid header
+    /// takes precedence over [`Self::expire_after`], but can only shorten the expiration time
+    /// chosen for the route by an [`IdempotencyTtl`] request extension or [`Self::ttl_policy`],
+    /// and does not override an [`IdempotencyTtl`] response extension.
+    pub fn allow_client_ttl(mut self, max_secs: i64) -> Self {
+        self.max_client_ttl_secs = Some(max_secs);
+        self
+    }
+
+    /// Lets handlers set the expiration time of their response in seconds through response
+    /// headers: `x-idempotency-ttl: N`, or else `Cache-Control: max-age=N`.
+    ///
+    /// The `x-idempotency-ttl` header is removed from the response. Invalid or non-positive
+    /// values are ignored. A valid header takes precedence over the expiration time of the
+    /// request (see [`Self::allow_client_ttl`], [`Self::ttl_policy`] and
+    /// [`Self::expire_after`]), but not over an [`IdempotencyTtl`] response extension or an
+    /// [`IdempotencyDirective`](crate::IdempotencyDirective).
+    pub fn ttl_from_response_headers(mut self, enabled: bool) -> Self {
+        self.ttl_from_response_headers = enabled;
+        self
+    }
+
+    /// Returns the expiration time set by the response `headers`, removing the
+    /// `x-idempotency-ttl` header (see [`Self::ttl_from_response_headers`]).
+    pub(crate) fn response_ttl(&self, headers: &mut HeaderMap) -> Option<i64> {
+        if !self.ttl_from_response_headers {
+            return None;
+        }
+
+        let positive = |value: &str| value.trim().parse::<i64>().ok().filter(|secs| *secs > 0);
+        let ttl = headers
+            .remove("x-idempotency-ttl")
+            .and_then(|value| value.to_str().ok().and_then(positive));
+        ttl.or_else(|| {
+            headers
+                .get_all(header::CACHE_CONTROL)
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .find_map(|directive| {
+                    let (name, value) = directive.split_once('=')?;
+                    let max_age = name.trim().eq_ignore_ascii_case("max-age");
+                    if max_age { positive(value) } else { None }
+                })
+        })
+    }
+
+    /// Returns the expiration time for `req`.
+    pub(crate) fn ttl_for(&self, req: &Request) -> i64 {
+        let chosen = req
+            .extensions()
+            .get::<IdempotencyTtl>()
+            .map(IdempotencyTtl::as_secs)
+            .or_else(|| {
+                let policy = self.ttl_policy.as_ref()?;
+                (policy.0)(req.extensions())
+            });
+        let requested = self.max_client_ttl_secs.and_then(|max_secs| {
+            req.headers()
+                .get("idempotency-ttl")
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse::<i64>().ok())
+                .filter(|secs| *secs > 0)
+                .map(|secs| secs.min(max_secs))
+        });
+
+        match (requested, chosen) {
+            // Clients cannot keep entries longer than the route allows
+            (Some(requested), Some(chosen)) => requested.min(chosen),
+            (Some(requested), None) => requested,
+            (None, chosen) => chosen.unwrap_or(self.body_cache_ttl_secs),
+        }
+    }
+
+    /// Sets the request methods idempotency applies to.
+    ///
+    /// Requests with other methods are forwarded to the inner service without hashing or
+    /// store access. Defaults to `POST`, `PATCH` and `DELETE`, since `GET`, `HEAD`, `PUT` and
+    /// `OPTIONS` requests are idempotent by definition.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::http::Method;
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default().only_methods([Method::POST, Method::PATCH]);
+    /// ```
+    pub fn only_methods(mut self, methods: impl IntoIterator<Item = Method>) -> Self {
+        self.methods = methods.into_iter().collect();
+        self
+    }
+
+    /// Restricts idempotency to requests whose path matches one of `patterns`.
+    ///
+    /// Requests to other paths are forwarded to the inner service without hashing, session
+    /// extraction or store access. This allows applying the layer at the router root while
+    /// only acting on some routes. Can be called multiple times to add patterns.
+    ///
+    /// Patterns are matched against the request path segment by segment: `*` and axum-style
+    /// parameters such as `{id}` match a single segment, while `**` and wildcard parameters
+    /// such as `{*rest}` match any number of remaining segments.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .include_paths(["/payments/**", "/orders/{id}"])
+    ///     .exclude_paths(["/payments/webhooks/**"]);
+    /// ```
+    pub fn include_paths<I, P>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.include_paths
+            .extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// Excludes requests whose path matches one of `patterns` from idempotency.
+    ///
+    /// Exclusions take precedence over [`Self::include_paths`]. See there for the pattern
+    /// syntax. Can be called multiple times to add patterns.
+    pub fn exclude_paths<I, P>(mut self, patterns: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<String>,
+    {
+        self.exclude_paths
+            .extend(patterns.into_iter().map(Into::into));
+        self
+    }
+
+    /// Whether idempotency applies to requests to `path`.
+    pub(crate) fn applies_to_path(&self, path: &str) -> bool {
+        let included = self.include_paths.is_empty()
+            || self
+                .include_paths
+                .iter()
+                .any(|pattern| path_matches(pattern, path));
+
+        included
+            && !self
+                .exclude_paths
+                .iter()
+                .any(|pattern| path_matches(pattern, path))
+    }
+
+    /// Sets a predicate deciding whether idempotency applies to a request at all.
+    ///
+    /// The predicate is evaluated before any buffering or store access. Requests for which it
+    /// returns `false` are forwarded to the inner service untouched, e.g. internal
+    /// service-to-service calls identified by an mTLS or extension marker.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// #[derive(Clone)]
+    /// struct InternalCaller;
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .enabled_when(|parts| parts.extensions.get::<InternalCaller>().is_none());
+    /// ```
+    pub fn enabled_when<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&Parts) -> bool + Send + Sync + 'static,
+    {
+        self.enabled_when = Some(Hook(Arc::new(predicate)));
+        self
+    }
+
+    /// Skips idempotency for requests whose `name` header holds `secret`, e.g. for operators
+    /// replaying a request or load tests that must hit the handlers.
+    ///
+    /// Such requests are forwarded to the inner service without computing a key, looking up
+    /// or storing a response. The header is removed from every request before it is
+    /// forwarded, so the secret does not reach handlers or logs, and compared in constant
+    /// time. Requests with another value are handled as usual. [`Self::build`] rejects an
+    /// empty secret. Code running on the server can insert a [`BypassIdempotency`] request
+    /// extension instead.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::http::HeaderName;
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let secret = std::env::var("IDEMPOTENCY_BYPASS_SECRET").unwrap_or_default();
+    /// let options = IdempotentOptions::default()
+    ///     .bypass_header(HeaderName::from_static("x-idempotency-bypass"), secret);
+    /// ```
+    pub fn bypass_header(mut self, name: HeaderName, secret: impl AsRef<[u8]>) -> Self {
+        self.bypass_header = Some((name, blake3::hash(secret.as_ref())));
+        self
+    }
+
+    /// Whether idempotency is skipped for `req`, through a [`BypassIdempotency`] extension or
+    /// the [`Self::bypass_header`], which is removed from the request.
+    pub(crate) fn bypasses(&self, req: &mut Request) -> bool {
+        let header = self.bypass_header.as_ref().and_then(|(name, secret)| {
+            let value = req.headers_mut().remove(name)?;
+            // Hashes are compared in constant time
+            Some(blake3::hash(value.as_bytes()) == *secret)
+        });
+        if header == Some(false) {
+            tracing::warn!("Ignoring a bypass header with an invalid secret");
+        }
+        header == Some(true) || req.extensions().get::<BypassIdempotency>().is_some()
+    }
+
+    /// Retries failed cache writes up to `retries` times, with exponential backoff starting
+    /// at `initial_backoff`.
+    ///
+    /// By default a failed write is logged and dropped, so a single transient store error
+    /// loses the entry for that key and a retry of the request re-executes the handler.
+    /// Retries delay the response by the time spent backing off.
+    pub fn retry_cache_writes(mut self, retries: u32, initial_backoff: Duration) -> Self {
+        self.write_retries = retries;
+        self.write_retry_backoff = initial_backoff;
+        self
+    }
+
+    /// Whether the handler should run to completion, and its response be cached, even if the
+    /// client disconnects mid-request.
+    ///
+    /// By default the request future is dropped when the client goes away, so nothing is
+    /// cached and the inevitable retry re-executes a possibly committed operation. When
+    /// enabled, the handler and the cache write run in a detached `tokio` task instead.
+    ///
+    /// **NOTE:** With session-backed storage, the retry only replays if the client already
+    /// holds the session cookie, since the response carrying a new cookie never reaches it.
+    pub fn complete_on_disconnect(mut self, enabled: bool) -> Self {
+        self.complete_on_disconnect = enabled;
+        self
+    }
+
+    /// Whether responses are returned to the client before being stored, and stored by a
+    /// background task.
+    ///
+    /// By default the response waits for the store write, which adds a store round trip to
+    /// the latency of every request that is not replayed. With write-behind, a retry arriving
+    /// before the write completes is executed again, unless
+    /// [`lock_in_flight`](Self::lock_in_flight) is enabled too: the lock is held until the
+    /// write completes. Await [`ShutdownHandle::shutdown`](crate::ShutdownHandle::shutdown)
+    /// before the process exits, so pending writes are not lost.
+    ///
+    /// At most 1024 writes are queued; see [`Self::max_pending_writes`].
+    pub fn write_behind(mut self, enabled: bool) -> Self {
+        self.write_behind = match (enabled, self.write_behind.take()) {
+            (true, write_behind) => {
+                write_behind.or_else(|| Some(WriteBehind::new(DEFAULT_MAX_PENDING_WRITES)))
+            }
+            (false, _) => None,
+        };
+        self
+    }
+
+    /// Enables [`write_behind`](Self::write_behind) with at most `max` writes queued.
+    ///
+    /// When the queue is full, responses are returned without being stored, which is logged
+    /// and counted in `idempotency_write_behind_dropped_total` (with the `metrics` feature), so
+    /// a slow store cannot accumulate unbounded memory.
+    pub fn max_pending_writes(mut self, max: usize) -> Self {
+        self.write_behind = Some(WriteBehind::new(max));
+        self
+    }
+
+    /// Marks keys as in flight while their request is being processed, so that concurrent
+    /// identical requests do not execute the handler twice.
+    ///
+    /// Before calling the handler, a pending marker expiring after `lock_ttl_secs` is stored
+    /// under the key. It is replaced by the response once cached, or removed if the response
+    /// is not cached. Identical requests arriving in the meantime are handled according to
+    /// [`Self::on_conflict`], by default with a `409 Conflict`.
+    ///
+    /// `lock_ttl_secs` should exceed the longest expected handler duration: if the request
+    /// future is dropped mid-handler, the marker is only released once it expires.
+    ///
+    /// **NOTE:** The marker is written with
+    /// [`IdempotencyStore::set_if_absent`](crate::IdempotencyStore::set_if_absent). Unless the
+    /// store implements it atomically (as `RedisStore` does), two requests arriving within the
+    /// same store round trip may still both execute.
+    pub fn lock_in_flight(mut self, lock_ttl_secs: i64) -> Self {
+        self.in_flight_lock_ttl_secs = Some(lock_ttl_secs);
+        self
+    }
+
+    /// Whether concurrent identical requests handled by this process share one handler
+    /// execution.
+    ///
+    /// Requests arriving while another request with the same key is being processed by this
+    /// process wait for it, then replay its response without reading the store, so a burst of
+    /// 100 identical retries results in a single handler call. If the response is not cached
+    /// (see [`Self::ignore_response_status_code`]), the waiting requests are handled as if
+    /// they had just arrived.
+    ///
+    /// This works without a store round trip and independently of [`Self::lock_in_flight`],
+    /// which is still needed to deduplicate requests handled by different processes.
+    pub fn single_flight(mut self, enabled: bool) -> Self {
+        self.single_flight = enabled.then(SingleFlight::default);
+        self
+    }
+
+    /// Whether the replays of each cached response are counted.
+    ///
+    /// The count is stored alongside the response, and sent in an `idempotency-replay-count`
+    /// header on replays, to help clients debug retry loops. Counting takes a store write per
+    /// replay, made with
+    /// [`IdempotencyStore::compare_and_set`](crate::IdempotencyStore::compare_and_set), so
+    /// concurrent replays of a key are all counted with stores implementing it atomically, as
+    /// the built-in ones do.
+    pub fn count_replays(mut self, enabled: bool) -> Self {
+        self.count_replays = enabled;
+        self
+    }
+
+    /// Whether every replay of a cached response extends its expiration time by the TTL the
+    /// response was stored with (see [`Self::expire_after`]), whatever the TTL of the replaying
+    /// request.
+    ///
+    /// This keeps entries alive for as long as clients keep retrying, e.g. mobile apps offline
+    /// for hours, at the cost of a store write per replay.
+    pub fn sliding_expiration(mut self, enabled: bool) -> Self {
+        self.sliding_expiration = enabled;
+        self
+    }
+
+    /// Limits how many times a cached response is replayed, handling further requests with its
+    /// key according to `behavior`.
+    ///
+    /// Replays are counted as with [`Self::count_replays`], even if it is disabled.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::http::StatusCode;
+    /// use axum_idempotent::{IdempotentOptions, ReplayLimit};
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .max_replays(10, ReplayLimit::Reject(StatusCode::TOO_MANY_REQUESTS));
+    /// ```
+    pub fn max_replays(mut self, max: u32, behavior: ReplayLimit) -> Self {
+        self.max_replays = Some((max, behavior));
+        self
+    }
+
+    /// Whether the replays of each cached response are counted, as they must be to be limited.
+    pub(crate) fn counts_replays(&self) -> bool {
+        self.count_replays || self.max_replays.is_some()
+    }
+
+    /// Sets how requests hitting an in-flight key are handled.
+    ///
+    /// Only relevant with [`Self::lock_in_flight`]. Defaults to
+    /// `ConflictBehavior::Reject(StatusCode::CONFLICT)`.
+    ///
+    /// # Example
+    /// ```rust
+    /// use std::time::Duration;
+    /// use axum_idempotent::{ConflictBehavior, IdempotentOptions};
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .lock_in_flight(30)
+    ///     .on_conflict(ConflictBehavior::Wait(Duration::from_secs(10)));
+    ///
+    /// // Ask clients to retry once the original request should be done
+    /// let options = IdempotentOptions::default()
+    ///     .lock_in_flight(30)
+    ///     .on_conflict(ConflictBehavior::TooEarly(Duration::from_secs(5)));
+    /// ```
+    pub fn on_conflict(mut self, behavior: ConflictBehavior) -> Self {
+        self.on_conflict = behavior;
+        self
+    }
+
+    /// Remembers the keys of requests rejected because of an in-flight duplicate for `ttl`,
+    /// so that clients retrying in a tight loop are rejected again without hammering the store.
+    ///
+    /// Rejections are kept in memory, per layer. They are forgotten early once the original
+    /// request completes on the same instance. Rejected responses carry a `Retry-After` header
+    /// estimating when the original request completes, from the time taken by previous
+    /// requests.
+    ///
+    /// Only relevant with [`Self::lock_in_flight`], when requests are rejected (see
+    /// [`Self::on_conflict`]).
+    ///
+    /// # Example
+    /// ```rust
+    /// use std::time::Duration;
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .lock_in_flight(30)
+    ///     .cache_rejections(Duration::from_millis(500));
+    /// ```
+    pub fn cache_rejections(mut self, ttl: Duration) -> Self {
+        self.rejection_cache = Some(RejectionCache::new(ttl));
+        self
+    }
+
+    /// Limits each idempotency key to `requests` requests per `window`, so that a client
+    /// retrying the same key in a tight loop gets a `429 Too Many Requests` with a
+    /// `Retry-After` header, before its request reaches the store.
+    ///
+    /// Keys are limited with a token bucket: up to `requests` requests can be sent at once,
+    /// after which they are allowed at an even rate. Buckets are kept in memory, per layer, so
+    /// with several instances a key may be sent up to `requests` times per window to each.
+    ///
+    /// # Example
+    /// ```rust
+    /// use std::time::Duration;
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// // At most 5 requests per key and second
+    /// let options = IdempotentOptions::default().rate_limit_per_key(5, Duration::from_secs(1));
+    /// ```
+    pub fn rate_limit_per_key(mut self, requests: u32, window: Duration) -> Self {
+        self.key_rate_limiter = Some(KeyRateLimiter::new(requests, window));
+        self
+    }
+
+    /// Sets how requests are handled when looking up their key or acquiring their in-flight
+    /// lock fails, e.g. because the store is unreachable (default:
+    /// [`StoreErrorPolicy::FailOpen`]).
+    ///
+    /// Failing open keeps the service available but may execute a retried request twice,
+    /// which is worse than an error for endpoints such as payments. Failing closed rejects
+    /// such requests with a JSON body instead. Failures to cache a response after the request
+    /// was executed are only logged either way, since the operation already took place.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::http::StatusCode;
+    /// use axum_idempotent::{IdempotentOptions, StoreErrorPolicy};
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .on_store_error(StoreErrorPolicy::FailClosed(StatusCode::SERVICE_UNAVAILABLE));
+    /// ```
+    pub fn on_store_error(mut self, policy: StoreErrorPolicy) -> Self {
+        self.on_store_error = policy;
+        self
+    }
+
+    /// Sets a hook called with every error encountered while handling a request, deciding how
+    /// the request is handled.
+    ///
+    /// This allows alerting on store failures, or answering errors with custom responses.
+    /// Returning [`ErrorAction::Default`] keeps the configured behavior. Errors are logged
+    /// whether a hook is set or not.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::http::StatusCode;
+    /// use axum::response::IntoResponse;
+    /// use axum_idempotent::{ErrorAction, IdempotencyError, IdempotentOptions};
+    ///
+    /// let options = IdempotentOptions::default().on_error(|err| match err {
+    ///     IdempotencyError::Store { .. } => {
+    ///         // Page the on-call engineer here
+    ///         ErrorAction::Respond(StatusCode::SERVICE_UNAVAILABLE.into_response())
+    ///     }
+    ///     _ => ErrorAction::Default,
+    /// });
+    /// ```
+    pub fn on_error<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&IdempotencyError) -> ErrorAction + Send + Sync + 'static,
+    {
+        self.on_error = Some(Hook(Arc::new(hook)));
+        self
+    }
+
+    /// Reports `err` to the [`Self::on_error`] hook, returning how the request is handled.
+    pub(crate) fn report(&self, err: &IdempotencyError) -> ErrorAction {
+        self.on_error
+            .as_ref()
+            .map_or(ErrorAction::Default, |hook| (hook.0)(err))
+    }
+
+    /// Returns the response for requests rejected because of a store error, or `None` if they
+    /// should be forwarded.
+    pub(crate) fn store_error_response(&self) -> Option<Response> {
+        match self.on_store_error {
+            StoreErrorPolicy::FailOpen => None,
+            StoreErrorPolicy::FailClosed(status) => Some(self.error_response(
+                status,
+                "store_unavailable",
+                "Idempotency store unavailable",
+                "The idempotency store is unavailable",
+            )),
+        }
+    }
+
+    /// The response to requests whose cached response cannot be replayed because its body
+    /// cannot be fetched from the blob store. The request was already executed, so it is
+    /// rejected with `503 Service Unavailable` even under [`StoreErrorPolicy::FailOpen`].
+    pub(crate) fn blob_error_response(&self) -> Response {
+        self.store_error_response().unwrap_or_else(|| {
+            self.error_response(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "store_unavailable",
+                "Idempotency store unavailable",
+                "The cached response is unavailable",
+            )
+        })
+    }
+
+    /// Whether the request body should be ignored when calculating the idempotency key.
+    ///
+    /// By default, the request body is included in the key. If you set this to `true`,
+    /// only the request method, path, and headers will be used, and the original request
+    /// body is forwarded to the handler as-is, without being collected.
+    ///
+    /// **NOTE:** Setting this to `true` can significantly improve performance as it avoids
+    /// reading the entire request body into memory. However, it also means that two requests
+    /// with different bodies will be treated as identical if their method, path, and headers
+    /// are the same, which may not be the desired behavior.
+    pub fn ignore_body(mut self, ignore: bool) -> Self {
+        self.ignore_body = ignore;
+        self
+    }
+
+    /// Hashes the body digest sent by the client in a `Content-Digest`
+    /// ([RFC 9530](https://www.rfc-editor.org/rfc/rfc9530)) or legacy `Digest` header in place
+    /// of the body, so that large uploads (e.g. multipart files) are forwarded as they stream
+    /// in rather than buffered to compute the key. Requests without a digest header are hashed
+    /// as usual.
+    ///
+    /// With `verify`, the body is checked against its `sha-256` or `sha-512` digest as the
+    /// handler reads it, and fails at its end if it does not match, so the handler rejects it
+    /// (usually with a `400 Bad Request`). Requests whose digest header has neither algorithm
+    /// are then hashed as usual.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default().hash_content_digest(true);
+    /// ```
+    pub fn hash_content_digest(mut self, verify: bool) -> Self {
+        self.hash_content_digest = true;
+        self.verify_content_digest = verify;
+        self
+    }
+
+    /// Whether the query string should be ignored when calculating the request hash.
+    ///
+    /// By default, the query string is part of the hash, so `/orders?page=1` and
+    /// `/orders?page=2` are different requests.
+    pub fn ignore_query(mut self, ignore: bool) -> Self {
+        self.ignore_query = ignore;
+        self
+    }
+
+    /// Whether the route template matched by axum (see [`MatchedPath`](axum::extract::MatchedPath))
+    /// and the decoded path parameters are hashed instead of the raw path.
+    ///
+    /// Percent-encoding differences, such as `/users/%61/orders` and `/users/a/orders`, then no
+    /// longer break idempotency. The raw path is still hashed for requests handled before
+    /// routing, e.g. when the layer wraps the whole router with `ServiceBuilder`, or by a
+    /// fallback.
+    pub fn hash_matched_path(mut self, enabled: bool) -> Self {
+        self.hash_matched_path = enabled;
+        self
+    }
+
+    /// Includes the value of type `T` in the request extensions in the request hash, e.g. an
+    /// API version negotiated, or a tenant or locale resolved, by an earlier middleware.
+    ///
+    /// Requests that are otherwise identical but carry different values, or only one of which
+    /// carries a value, then get different keys. Call it once per type to include, with a `tag`
+    /// naming the value in the hash, which must be unique among the included types and stay the
+    /// same across releases. Values are hashed with their [`Hash`] implementation, with
+    /// integers written in little-endian order, so it must produce the same writes on every
+    /// instance sharing the store: derived implementations do, but not those of types such as
+    /// `HashMap`, whose iteration order varies. This has no effect in direct key mode.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// #[derive(Clone, Hash)]
+    /// struct ApiVersion(u16);
+    ///
+    /// let options = IdempotentOptions::default().hash_extension::<ApiVersion>("api-version");
+    /// ```
+    pub fn hash_extension<T>(mut self, tag: impl Into<String>) -> Self
+    where
+        T: Hash + Send + Sync + 'static,
+    {
+        let tag = tag.into();
+        self.hashed_extensions.push(Hook(Arc::new(
+            move |extensions: &Extensions, mut hasher: &mut dyn Hasher| {
+                hasher.write(tag.as_bytes());
+                match extensions.get::<T>() {
+                    Some(value) => {
+                        hasher.write_u8(1);
+                        value.hash(&mut hasher);
+                    }
+                    None => hasher.write_u8(0),
+                }
+            },
+        )));
+        self
+    }
+
+    /// Whether the query parameters should be sorted before calculating the request hash, so
+    /// `/orders?a=1&b=2` and `/orders?b=2&a=1` are the same request.
+    ///
+    /// Parameters are sorted by name, keeping the order of repeated parameters (e.g.
+    /// `?id=2&id=1`), since it may be significant.
+    pub fn sort_query_params(mut self, sort: bool) -> Self {
+        self.sort_query = sort;
+        self
+    }
+
+    /// Ignores the query parameters named after `pattern` when calculating the request hash,
+    /// e.g. cache busters or tracking parameters.
+    ///
+    /// `*` in `pattern` matches any sequence of characters.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .ignore_query_param("_ts")
+    ///     .ignore_query_param("utm_*");
+    /// ```
+    pub fn ignore_query_param(mut self, pattern: impl Into<String>) -> Self {
+        self.ignored_query_params.push(pattern.into());
+        self
+    }
+
+    /// Normalizes request bodies of `content_type` with `normalizer` before hashing them, so
+    /// that equivalent bodies (e.g. forms with fields in another order) are the same request.
+    ///
+    /// `content_type` is matched against the media type of the `Content-Type` header, ignoring
+    /// case and parameters such as `charset`. Bodies with a normalizer are hashed once fully
+    /// read rather than as they stream in. See [`BodyNormalizer`] for the built-in normalizers.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::{FormNormalizer, IdempotentOptions, MultipartNormalizer};
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .body_normalizer("application/x-www-form-urlencoded", FormNormalizer)
+    ///     .body_normalizer("multipart/form-data", MultipartNormalizer);
+    /// ```
+    pub fn body_normalizer(
+        mut self,
+        content_type: impl Into<String>,
+        normalizer: impl BodyNormalizer,
+    ) -> Self {
+        let content_type = content_type.into().to_ascii_lowercase();
+        self.body_normalizers
+            .retain(|(registered, _)| *registered != content_type);
+        self.body_normalizers
+            .push((content_type, Hook(Arc::new(normalizer))));
+        self
+    }
+
+    /// Returns the `Content-Type` of a request with `headers`, and the normalizer registered for
+    /// it, if any.
+    pub(crate) fn body_normalizer_for(
+        &self,
+        headers: &HeaderMap,
+    ) -> Option<(String, Arc<dyn BodyNormalizer>)> {
+        if self.body_normalizers.is_empty() {
+            return None;
+        }
+        let content_type = headers.get(header::CONTENT_TYPE)?.to_str().ok()?;
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        self.body_normalizers
+            .iter()
+            .find(|(registered, _)| registered.eq_ignore_ascii_case(media_type))
+            .map(|(_, normalizer)| (content_type.to_owned(), normalizer.0.clone()))
+    }
+
+    /// Limits the size of the request and response bodies buffered by the middleware.
+    ///
+    /// Bodies are read incrementally, and reading stops as soon as `limit` bytes are exceeded,
+    /// so large uploads cannot exhaust memory. Requests whose body is too large to hash are
+    /// handled according to [`Self::on_oversized_body`]; responses whose body is too large
+    /// are forwarded without being cached. In both cases, the body is passed on unaltered.
+    ///
+    /// By default, bodies are not limited.
+    pub fn max_body_bytes(mut self, limit: usize) -> Self {
+        self.max_body_bytes = Some(limit);
+        self
+    }
+
+    /// Sets how requests whose body exceeds [`Self::max_body_bytes`] are handled.
+    ///
+    /// Defaults to [`OversizedBody::Bypass`].
+    pub fn on_oversized_body(mut self, behavior: OversizedBody) -> Self {
+        self.oversized_body = behavior;
+        self
+    }
+
+    /// Limits the size of the response bodies cached, e.g. to keep file exports or reports out
+    /// of the store.
+    ///
+    /// This overrides [`Self::max_body_bytes`] for responses. Responses whose body is too large
+    /// are forwarded unaltered, and handled according to [`Self::on_oversized_response`].
+    pub fn max_response_bytes(mut self, limit: usize) -> Self {
+        self.max_response_bytes = Some(limit);
+        self
+    }
+
+    /// Sets how responses whose body exceeds [`Self::max_response_bytes`] (or
+    /// [`Self::max_body_bytes`]) are handled.
+    ///
+    /// Defaults to [`OversizedResponse::Skip`].
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::http::StatusCode;
+    /// use axum_idempotent::{IdempotentOptions, OversizedResponse};
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .max_response_bytes(1024 * 1024)
+    ///     .on_oversized_response(OversizedResponse::Tombstone(StatusCode::GONE));
+    /// ```
+    pub fn on_oversized_response(mut self, behavior: OversizedResponse) -> Self {
+        self.oversized_response = behavior;
+        self
+    }
+
+    /// Keeps the bodies of responses larger than `over_bytes` in an external [`BlobStore`],
+    /// e.g. S3 or GCS, instead of the idempotency store.
+    ///
+    /// The entry then only holds the status code, the headers and a
+    /// [`BlobPointer`](crate::BlobPointer) to the body, which is streamed back from the blob
+    /// store on replay. Responses whose body cannot be uploaded are not cached. Replays whose blob
+    /// cannot be fetched are rejected as store errors, with `503 Service Unavailable` under
+    /// [`StoreErrorPolicy::FailOpen`], since the request must not be executed again. The bodies
+    /// are still buffered in memory, and subject to [`Self::max_response_bytes`]. Responses with
+    /// trailers are kept in the idempotency store.
+    ///
+    /// Entries pointing to a blob are replayed with an empty body by releases without blob
+    /// support, so only enable this once every instance runs a release with it.
+    ///
+    /// See [`BlobStore`] for an example.
+    pub fn blob_store(mut self, store: impl BlobStore, over_bytes: usize) -> Self {
+        self.blob_store = Some((Hook(Arc::new(store)), over_bytes));
+        self
+    }
+
+    /// Sets which parts of responses are stored (default: [`StoreMode::Full`]).
+    ///
+    /// When clients only need to know that a request was processed (e.g. deduplicated
+    /// webhooks or events), storing just the status code keeps entries to a few bytes.
+    /// Response bodies are then forwarded without being buffered, and not subject to
+    /// [`Self::max_response_bytes`].
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::http::header;
+    /// use axum_idempotent::{IdempotentOptions, StoreMode};
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .store_mode(StoreMode::StatusWithHeaders(vec![header::LOCATION]));
+    /// ```
+    pub fn store_mode(mut self, mode: StoreMode) -> Self {
+        self.store_mode = mode;
+        self
+    }
+
+    /// Compresses cached responses whose serialized form is larger than `threshold` bytes.
+    ///
+    /// This reduces the memory used by large responses in the store, at the cost of some CPU
+    /// time when caching and replaying them. Compressed entries are marked, so entries written
+    /// with different settings can still be replayed. Requires the `gzip` or `zstd` feature.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default().compress_over_bytes(16 * 1024);
+    /// ```
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    pub fn compress_over_bytes(mut self, threshold: usize) -> Self {
+        self.compress_over_bytes = Some(threshold);
+        self
+    }
+
+    /// Sets the algorithm used by [`Self::compress_over_bytes`] (default:
+    /// [`Compression::Zstd`] with the `zstd` feature, and [`Compression::Gzip`] otherwise).
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Adds a header to the list of headers that should be ignored when calculating the request hash.
+    pub fn ignore_header(mut self, name: HeaderName) -> Self {
+        self.ignored_req_headers.insert(name);
+        self
+    }
+
+    /// Removes a header from the list of headers ignored when calculating the request hash, e.g.
+    /// one of the headers ignored by default, so that it is hashed.
+    pub fn unignore_header(mut self, name: &HeaderName) -> Self {
+        self.ignored_req_headers.remove(name);
+        self
+    }
+
+    /// Clears the list of headers ignored when calculating the request hash, including the
+    /// headers ignored by default.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::http::header;
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// // Hash every header except `Authorization`
+    /// let options = IdempotentOptions::default()
+    ///     .clear_ignored_headers()
+    ///     .ignore_header(header::AUTHORIZATION);
+    /// ```
+    pub fn clear_ignored_headers(mut self) -> Self {
+        self.ignored_req_headers.clear();
+        self
+    }
+
+    /// Ignores the headers whose name matches `pattern` when calculating the request hash, e.g.
+    /// the headers added by a proxy or CDN.
+    ///
+    /// `*` in `pattern` matches any sequence of characters, and names are matched ignoring
+    /// case.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .ignore_headers_matching("x-forwarded-*")
+    ///     .ignore_headers_matching("x-amzn-*")
+    ///     .ignore_headers_matching("cf-*");
+    /// ```
+    pub fn ignore_headers_matching(mut self, pattern: impl Into<String>) -> Self {
+        self.ignored_req_header_patterns
+            .push(pattern.into().to_ascii_lowercase());
+        self
+    }
+
+    /// Only includes `names` in the request hash, instead of every header not ignored with
+    /// [`Self::ignore_header`].
+    ///
+    /// This makes it explicit which headers influence the key, e.g. `content-type` and an API
+    /// version header, so new headers added by clients or proxies cannot cause cache misses.
+    /// Once an allow-list is set, ignored headers are no longer consulted. Can be called
+    /// multiple times to add headers. It has no effect when all headers are ignored, e.g. in
+    /// direct key mode.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::http::{HeaderName, header};
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default().hash_only_headers([
+    ///     header::CONTENT_TYPE,
+    ///     HeaderName::from_static("x-api-version"),
+    /// ]);
+    /// ```
+    pub fn hash_only_headers(mut self, names: impl IntoIterator<Item = HeaderName>) -> Self {
+        self.hashed_req_headers
+            .get_or_insert_with(HashSet::new)
+            .extend(names);
+        self
+    }
+
+    /// Adds headers to the list of response headers that are not cached, and so not replayed.
     ///
-    /// By default, the request body is included in the key. If you set this to `true`,
-    /// only the request method, path, and headers will be used.
+    /// The response sent for the original request keeps them. By default, only `Set-Cookie`
+    /// is stripped, so a replay cannot hand out the session of another request.
     ///
-    /// **NOTE:** Setting this to `true` can significantly improve performance as it avoids
-    /// reading the entire request body into memory. However, it also means that two requests
-    /// with different bodies will be treated as identical if their method, path, and headers
-    /// are the same, which may not be the desired behavior.
-    pub fn ignore_body(mut self, ignore: bool) -> Self {
-        self.ignore_body = ignore;
+    /// # Example
+    /// ```rust
+    /// use axum::http::HeaderName;
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .strip_response_headers([HeaderName::from_static("x-request-id")]);
+    /// ```
+    pub fn strip_response_headers<I>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = HeaderName>,
+    {
+        self.stripped_res_headers.extend(names);
         self
     }
 
-    /// Adds a header to the list of headers that should be ignored when calculating the request hash.
-    pub fn ignore_header(mut self, name: HeaderName) -> Self {
-        self.ignored_req_headers.insert(name);
+    /// Removes headers from the list of response headers that are not cached (see
+    /// [`Self::strip_response_headers`]), so they are replayed, e.g. `Set-Cookie`.
+    pub fn preserve_response_headers<I>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = HeaderName>,
+    {
+        for name in names {
+            self.stripped_res_headers.remove(&name);
+        }
         self
     }
 
@@ -77,6 +1630,33 @@ impl IdempotentOptions {
         self
     }
 
+    /// Seeds the request hash with a fixed 32-byte key.
+    ///
+    /// In hashing mode, keys are then computed as a keyed hash of the method, path, sorted
+    /// headers and body (see [`Self::hash_algorithm`]), and are reproducible across runs for a
+    /// given seed, e.g. for tests of downstream applications that snapshot cache keys in golden
+    /// files.
+    ///
+    /// Layers backed by a session store also derive the session id of scopes not tied to a
+    /// session from it ([`KeyScope::Global`], [`KeyScope::Custom`] and session fallbacks), and
+    /// require it for them: keep it secret, and identical across the instances sharing a store.
+    /// Keys in direct key mode are not hashed with it.
+    pub fn hash_seed(mut self, seed: [u8; 32]) -> Self {
+        self.hash_seed = Some(seed);
+        self
+    }
+
+    /// Sets the hash function used to compute idempotency keys in hashing mode (default:
+    /// [`HashAlgorithm::Blake3`]).
+    ///
+    /// Keys computed with different algorithms differ, so changing it makes previously cached
+    /// responses unreachable. With [`hash_seed`](Self::hash_seed), BLAKE3 runs in keyed mode,
+    /// SHA-256 as HMAC-SHA256, and XXH3 with a secret derived from the seed.
+    pub fn hash_algorithm(mut self, algorithm: HashAlgorithm) -> Self {
+        self.hash_algorithm = algorithm;
+        self
+    }
+
     /// Configures the layer to ignore all headers when calculating the request hash.
     ///
     /// When enabled, only the method, path, and body will be used to determine idempotency.
@@ -92,6 +1672,144 @@ impl IdempotentOptions {
         self
     }
 
+    /// Removes a status code from the list of ignored status codes, e.g. one of the status codes
+    /// ignored by default, so that responses with it are cached.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::http::StatusCode;
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// // Validation errors are deterministic, and can be replayed
+    /// let options = IdempotentOptions::default()
+    ///     .unignore_response_status_code(StatusCode::BAD_REQUEST);
+    /// ```
+    pub fn unignore_response_status_code(mut self, status_code: StatusCode) -> Self {
+        self.ignored_res_status_codes.remove(&status_code);
+        self
+    }
+
+    /// Clears the list of ignored status codes, including the status codes ignored by default,
+    /// so that responses with any status code are cached.
+    pub fn clear_ignored_status_codes(mut self) -> Self {
+        self.ignored_res_status_codes.clear();
+        self
+    }
+
+    /// Only caches responses with one of `status_codes`, instead of every status code not
+    /// ignored with [`Self::ignore_response_status_code`].
+    ///
+    /// This declares exactly which outcomes are safe to replay, so that newly introduced error
+    /// responses are not cached by accident. Once an allow-list is set, ignored status codes are
+    /// no longer consulted. Can be combined with [`Self::cache_status_range`], and called
+    /// multiple times to add status codes.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::http::StatusCode;
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .cache_only_status_codes([StatusCode::OK, StatusCode::CREATED]);
+    /// ```
+    pub fn cache_only_status_codes(
+        mut self,
+        status_codes: impl IntoIterator<Item = StatusCode>,
+    ) -> Self {
+        self.cached_res_status_codes
+            .get_or_insert_with(HashSet::new)
+            .extend(status_codes);
+        self
+    }
+
+    /// Only caches responses whose status code is within `range`, e.g. `200..300` for
+    /// successful responses.
+    ///
+    /// See [`Self::cache_only_status_codes`].
+    pub fn cache_status_range(mut self, range: impl RangeBounds<u16>) -> Self {
+        let status_codes = (100..1000)
+            .filter(|code| range.contains(code))
+            .filter_map(|code| StatusCode::from_u16(code).ok());
+        self.cached_res_status_codes
+            .get_or_insert_with(HashSet::new)
+            .extend(status_codes);
+        self
+    }
+
+    /// Always caches responses with `status_code`, even if it is ignored (by default or with
+    /// [`Self::ignore_response_status_code`]) or missing from the allow-list of
+    /// [`Self::cache_only_status_codes`]. See [`StatusCaching`] for the precedence rules.
+    ///
+    /// This is meant for error outcomes that must not be re-triggered by retries, e.g. a
+    /// `402 Payment Required` after a declined charge, so the fraud checks leading to it do not
+    /// run again.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::http::StatusCode;
+    /// use axum_idempotent::{IdempotentOptions, StatusCaching};
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .ignore_response_status_code(StatusCode::CONFLICT)
+    ///     .always_cache_status(StatusCode::PAYMENT_REQUIRED)
+    ///     .always_cache_status(StatusCode::CONFLICT);
+    /// assert_eq!(options.status_caching(StatusCode::CONFLICT), StatusCaching::Always);
+    /// ```
+    pub fn always_cache_status(mut self, status_code: StatusCode) -> Self {
+        self.always_cached_res_status_codes.insert(status_code);
+        self
+    }
+
+    /// Returns whether responses with `status_code` are cached, and why.
+    pub fn status_caching(&self, status_code: StatusCode) -> StatusCaching {
+        if self.always_cached_res_status_codes.contains(&status_code) {
+            return StatusCaching::Always;
+        }
+        let cached = match &self.cached_res_status_codes {
+            Some(status_codes) => status_codes.contains(&status_code),
+            None => !self.ignored_res_status_codes.contains(&status_code),
+        };
+        if cached {
+            StatusCaching::Cached
+        } else {
+            StatusCaching::Ignored
+        }
+    }
+
+    /// Sets a predicate deciding whether a response is cached, from its status, headers and
+    /// extensions.
+    ///
+    /// The predicate is only evaluated for responses whose status code is cached (see
+    /// [`StatusCaching`]). Responses
+    /// for which it returns `false` are sent without being cached, so a retried request is
+    /// executed again.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .cache_if(|res| res.headers().get("x-retryable").is_none_or(|value| value != "true"));
+    /// ```
+    pub fn cache_if<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&Response) -> bool + Send + Sync + 'static,
+    {
+        self.cache_if = Some(Hook(Arc::new(predicate)));
+        self
+    }
+
+    /// Whether `res` is cached, according to its status code and [`Self::cache_if`].
+    pub(crate) fn caches(&self, res: &Response) -> bool {
+        let cached_status = self.status_caching(res.status()) != StatusCaching::Ignored;
+
+        cached_status
+            && self
+                .cache_if
+                .as_ref()
+                .is_none_or(|predicate| (predicate.0)(res))
+    }
+
     /// Configures the middleware to use a request header's value directly as the idempotency key.
     ///
     /// When this option is enabled, the middleware will **not** hash any part of the request.
@@ -107,12 +1825,534 @@ impl IdempotentOptions {
         self.ignore_all_headers = true;
         self.ignore_body = true;
         self.use_idempotency_key = true;
-        header_name.map(|n| {
+        if let Some(n) = header_name {
             self.idempotency_key_header = n.to_string();
-        });
+        }
+        self
+    }
+
+    /// Sets a prefix prepended to every key entries are stored under, e.g.
+    /// `"payments-svc:prod:"`.
+    ///
+    /// This keeps the entries of different services or environments sharing a store apart. The
+    /// prefix is not part of the [`IdempotencyKey`](crate::IdempotencyKey) seen by handlers.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .use_idempotency_key_header(None)
+    ///     .key_prefix("payments-svc:prod:");
+    /// ```
+    pub fn key_prefix(mut self, prefix: impl Into<String>) -> Self {
+        self.key_prefix = prefix.into();
+        self
+    }
+
+    /// Sets the namespace of the layer, keeping its entries apart from those of other layers
+    /// sharing the store.
+    ///
+    /// Entries are stored under `{key_prefix}{namespace}:{key}`. Without namespaces, two
+    /// layers mounted on different routes (e.g. `/payments` and `/webhooks`) read each other's
+    /// entries whenever clients send the same idempotency key to both.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default().use_idempotency_key_header(None);
+    /// let payments = options.clone().namespace("payments");
+    /// let webhooks = options.namespace("webhooks");
+    /// ```
+    pub fn namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    /// Sets how idempotency keys are isolated between clients (default: [`KeyScope::Store`]).
+    ///
+    /// Without a scope, two users sending the same key (or, in hashing mode, identical
+    /// requests) share an entry whenever they share a store, and one may receive the other's
+    /// response. Scoping keys by the authenticated principal keeps their entries apart.
+    /// Conversely, [`KeyScope::Global`] lets clients without a stable session share a key
+    /// space with session stores.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::{IdempotentOptions, KeyScope};
+    ///
+    /// #[derive(Clone)]
+    /// struct UserId(u64);
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .use_idempotency_key_header(None)
+    ///     .key_scope(KeyScope::custom(|extensions| {
+    ///         extensions.get::<UserId>().map(|user| user.0.to_string())
+    ///     }));
+    /// ```
+    pub fn key_scope(mut self, scope: KeyScope) -> Self {
+        self.key_scope = scope;
+        self
+    }
+
+    /// Scopes idempotency keys by the value of type `T` in the request extensions, inserted by
+    /// an earlier middleware. Shorthand for `key_scope(KeyScope::extension::<T>())`.
+    ///
+    /// In multi-tenant applications, this keeps tenants apart even when their clients reuse
+    /// generic keys like `1`. Requests without a `T` extension are forwarded to the inner
+    /// service without idempotency.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    /// use std::fmt;
+    ///
+    /// #[derive(Clone)]
+    /// struct TenantId(u64);
+    ///
+    /// impl fmt::Display for TenantId {
+    ///     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    ///         write!(f, "tenant-{}", self.0)
+    ///     }
+    /// }
+    ///
+    /// let options = IdempotentOptions::default().scope_by_extension::<TenantId>();
+    /// ```
+    pub fn scope_by_extension<T>(self) -> Self
+    where
+        T: fmt::Display + Send + Sync + 'static,
+    {
+        self.key_scope(KeyScope::extension::<T>())
+    }
+
+    /// Sets how requests with an idempotency key but no scope are handled, with a
+    /// [`KeyScope::Custom`] scope. Such requests are logged, and counted in
+    /// `idempotency_scope_missing_total` (with the `metrics` feature).
+    ///
+    /// Defaults to [`MissingScope::Reject`], so requests are not executed without idempotency
+    /// when the principal is missing. Use [`MissingScope::Forward`] for routes that also serve
+    /// anonymous clients.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::{IdempotentOptions, MissingScope};
+    ///
+    /// #[derive(Clone)]
+    /// struct TenantId(u64);
+    ///
+    /// # impl std::fmt::Display for TenantId {
+    /// #     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    /// #         write!(f, "{}", self.0)
+    /// #     }
+    /// # }
+    /// let options = IdempotentOptions::default()
+    ///     .scope_by_extension::<TenantId>()
+    ///     .on_missing_scope(MissingScope::Forward);
+    /// ```
+    pub fn on_missing_scope(mut self, behavior: MissingScope) -> Self {
+        self.on_missing_scope = behavior;
+        self
+    }
+
+    /// Returns the key the entries of a request with idempotency key `key` are stored under,
+    /// or `None` if the request has no scope.
+    pub(crate) fn storage_key(&self, extensions: &Extensions, key: &str) -> Option<String> {
+        let prefix = self.entry_prefix();
+        match &self.key_scope {
+            KeyScope::Store | KeyScope::Session | KeyScope::Global => {
+                Some(format!("{prefix}{key}"))
+            }
+            KeyScope::Custom(scope) => {
+                // The length of the scope keeps a scope ending in `:{part}` from meeting a key
+                // starting with `{part}:`
+                let scope = scope(extensions)?;
+                Some(format!("{prefix}{}:{scope}:{key}", scope.len()))
+            }
+        }
+    }
+
+    /// The number of hex digits of the request hashes ending the keys of entries, in hashing
+    /// mode.
+    pub(crate) fn hash_len(&self) -> Option<usize> {
+        if !self.key_mode().is_hashed() {
+            return None;
+        }
+        match self.hash_algorithm {
+            HashAlgorithm::Blake3 | HashAlgorithm::Sha256 => Some(64),
+            HashAlgorithm::Xxh3 => Some(32),
+        }
+    }
+
+    /// Returns the storage key `key` as a key of the store `S`.
+    pub(crate) fn store_key<'a, S: IdempotencyStore>(&self, key: &'a str) -> Cow<'a, S::Key> {
+        store_key(key, self.hash_len())
+    }
+
+    /// Returns the prefix of the keys of all entries stored by the layer: the
+    /// [`Self::key_prefix`] followed by the [`Self::namespace`], if any.
+    pub(crate) fn entry_prefix(&self) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("{}{namespace}:", self.key_prefix),
+            None => self.key_prefix.clone(),
+        }
+    }
+
+    /// Whether requests without the idempotency key header are rejected.
+    ///
+    /// By default, in direct key mode (see [`Self::use_idempotency_key_header`]), requests
+    /// lacking the header are forwarded to the inner service without idempotency. When
+    /// required, they are rejected instead, by default with a `400 Bad Request` and a JSON
+    /// body (see [`Self::missing_key_response`]). This has no effect in hashing mode.
+    pub fn require_key(mut self, required: bool) -> Self {
+        self.require_key = required;
+        self
+    }
+
+    /// Sets the response sent for requests rejected by [`Self::require_key`].
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::http::StatusCode;
+    /// use axum::response::IntoResponse;
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .use_idempotency_key_header(None)
+    ///     .require_key(true)
+    ///     .missing_key_response(|| {
+    ///         (StatusCode::UNPROCESSABLE_ENTITY, "Idempotency-Key is required").into_response()
+    ///     });
+    /// ```
+    pub fn missing_key_response<F>(mut self, response: F) -> Self
+    where
+        F: Fn() -> Response + Send + Sync + 'static,
+    {
+        self.missing_key_response = Some(Hook(Arc::new(response)));
+        self
+    }
+
+    /// Whether reusing an idempotency key with a different request is rejected.
+    ///
+    /// In direct key mode, the method, path, query and body of the request are hashed into a
+    /// fingerprint stored with its response. A later request with the same key but a different
+    /// fingerprint is rejected with a `422 Unprocessable Entity` instead of being answered with
+    /// the response to another request. This has no effect in hashing mode, where requests
+    /// differing in these parts get different keys.
+    pub fn validate_fingerprint(mut self, enabled: bool) -> Self {
+        self.validate_fingerprint = enabled;
+        self
+    }
+
+    /// Configures the semantics of the IETF
+    /// [`Idempotency-Key` header draft](https://datatracker.ietf.org/doc/draft-ietf-httpapi-idempotency-key-header/):
+    ///
+    /// - Keys are read from the `Idempotency-Key` header (see [`Self::use_idempotency_key_header`])
+    ///   and required (see [`Self::require_key`]): requests without one get a
+    ///   `400 Bad Request`. Use [`Self::include_paths`] to designate the resources requiring
+    ///   a key.
+    /// - Reusing a key with a different request gets a `422 Unprocessable Entity` (see
+    ///   [`Self::validate_fingerprint`]).
+    /// - Requests arriving while a request with the same key is being processed get a
+    ///   `409 Conflict` (see [`Self::lock_in_flight`], here with a lock of 60 seconds).
+    /// - These errors are answered with the `application/problem+json` bodies of the draft
+    ///   (see [`Self::problem_details`]).
+    ///
+    /// Settings can still be adjusted afterwards.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .rfc_mode()
+    ///     .include_paths(["/payments/*"]);
+    /// ```
+    pub fn rfc_mode(mut self) -> Self {
+        self.problem_details = true;
+        self.use_idempotency_key_header(None)
+            .require_key(true)
+            .validate_fingerprint(true)
+            .lock_in_flight(60)
+            .on_conflict(ConflictBehavior::Reject(StatusCode::CONFLICT))
+    }
+
+    /// Answers the requests rejected by the middleware itself (e.g. for a missing or invalid
+    /// key, a conflict, or a payload too large) with `application/problem+json` bodies
+    /// ([RFC 9457](https://www.rfc-editor.org/rfc/rfc9457)) instead of the default JSON
+    /// bodies.
+    ///
+    /// Problem details carry a `title`, the `status` code and a `detail` message. If
+    /// `type_base_uri` is set, they also carry a `type` URI made of it followed by the
+    /// kebab-case error code, e.g. `https://example.com/problems/missing-idempotency-key`.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .problem_details(Some("https://example.com/problems/"));
+    /// ```
+    pub fn problem_details(mut self, type_base_uri: Option<&str>) -> Self {
+        self.problem_details = true;
+        self.problem_type_base_uri = type_base_uri.map(str::to_owned);
+        self
+    }
+
+    /// Sets the maximum length in bytes of client-supplied idempotency keys, or `None` for no
+    /// limit.
+    ///
+    /// In direct key mode, requests with longer keys are rejected with a `400 Bad Request`
+    /// rather than used verbatim as store keys. Defaults to 255.
+    pub fn max_key_length(mut self, max_len: Option<usize>) -> Self {
+        self.max_key_len = max_len;
+        self
+    }
+
+    /// Sets the characters allowed in client-supplied idempotency keys.
+    ///
+    /// In direct key mode, requests with keys not matching `format` are rejected with a
+    /// `400 Bad Request`. Keys must always consist of visible ASCII characters. Defaults to
+    /// [`KeyFormat::Any`].
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::{IdempotentOptions, KeyFormat};
+    ///
+    /// let options = IdempotentOptions::default()
+    ///     .use_idempotency_key_header(None)
+    ///     .key_format(KeyFormat::Chars(|c| c.is_ascii_alphanumeric() || c == '-'));
+    /// ```
+    pub fn key_format(mut self, format: KeyFormat) -> Self {
+        self.key_format = format;
         self
     }
 
+    /// Checks the client-supplied idempotency key of `req`, returning why it is invalid, if it
+    /// is.
+    pub(crate) fn key_error(&self, req: &Request) -> Option<IdempotencyError> {
+        if !self.use_idempotency_key {
+            return None;
+        }
+        let value = req.headers().get(&self.idempotency_key_header)?;
+
+        let key = match value.to_str() {
+            Ok(key) => key,
+            Err(_) => {
+                return Some(IdempotencyError::KeyInvalid {
+                    reason: "must only contain visible ASCII characters",
+                });
+            }
+        };
+        if let Some(max_len) = self.max_key_len.filter(|max_len| key.len() > *max_len) {
+            return Some(IdempotencyError::KeyTooLong {
+                len: key.len(),
+                max_len,
+            });
+        }
+        self.key_format
+            .check(key)
+            .err()
+            .map(|reason| IdempotencyError::KeyInvalid { reason })
+    }
+
+    /// Returns the response sent for requests rejected because of `err`, an error returned by
+    /// [`Self::key_error`].
+    pub(crate) fn key_error_response(&self, err: &IdempotencyError) -> Response {
+        let problem = match err {
+            IdempotencyError::KeyTooLong { .. } => "is too long",
+            IdempotencyError::KeyInvalid { reason } => reason,
+            _ => "is invalid",
+        };
+
+        self.error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_idempotency_key",
+            "Idempotency-Key is invalid",
+            &format!("The {} header {problem}", self.idempotency_key_header),
+        )
+    }
+
+    /// How the keys of requests are derived.
+    pub(crate) fn key_mode(&self) -> KeyMode {
+        #[cfg(feature = "jwt")]
+        if let Some(jwt) = &self.jwt_claim_key {
+            // Scoped keys are hashed unless the idempotency key header is used as is
+            let direct = self.use_idempotency_key && self.ignore_body && self.ignore_all_headers;
+            let hashed = jwt.mode == JwtClaimMode::Scope && !direct;
+            return KeyMode::Jwt { hashed };
+        }
+        #[cfg(feature = "webhook")]
+        if self.webhook_dedup.is_some() {
+            return KeyMode::Webhook;
+        }
+
+        if self.use_idempotency_key {
+            KeyMode::Direct
+        } else {
+            KeyMode::Hash
+        }
+    }
+
+    /// Whether `req` must be rejected because it lacks a required idempotency key.
+    pub(crate) fn is_missing_key(&self, req: &Request) -> bool {
+        self.require_key
+            && self.use_idempotency_key
+            && !req.headers().contains_key(&self.idempotency_key_header)
+    }
+
+    /// Returns an error response with the machine-readable `code`: a JSON object with the
+    /// `error` code and the `message`, or with [`Self::problem_details`], a problem details
+    /// object with the `title` and the `message` as `detail`.
+    fn error_response(
+        &self,
+        status: StatusCode,
+        code: &str,
+        title: &str,
+        message: &str,
+    ) -> Response {
+        if !self.problem_details {
+            return json_error(status, code, message);
+        }
+
+        let mut problem = serde_json::json!({
+            "title": title,
+            "status": status.as_u16(),
+            "detail": message,
+        });
+        if let Some(base) = &self.problem_type_base_uri {
+            problem["type"] = format!("{base}{}", code.replace('_', "-")).into();
+        }
+        (
+            status,
+            [(header::CONTENT_TYPE, "application/problem+json")],
+            problem.to_string(),
+        )
+            .into_response()
+    }
+
+    /// Returns the response sent for requests rejected by [`MissingScope::Reject`].
+    pub(crate) fn missing_scope_response(&self) -> Response {
+        self.error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "scope_missing",
+            "Idempotency key scope unavailable",
+            "No idempotency key scope could be derived from the request. \
+             Is the layer providing it applied before `IdempotentLayer`?",
+        )
+    }
+
+    /// Returns the response sent for requests rejected by
+    /// [`MissingSession::Reject`](crate::MissingSession::Reject).
+    #[cfg(feature = "session")]
+    pub(crate) fn missing_session_response(&self) -> Response {
+        self.error_response(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "session_missing",
+            "Session unavailable",
+            "No session could be extracted from the request. \
+             Is `SessionLayer` applied before `IdempotentLayer`?",
+        )
+    }
+
+    /// Returns the response sent for requests lacking a required idempotency key.
+    pub(crate) fn missing_key_response_for(&self) -> Response {
+        if let Some(response) = &self.missing_key_response {
+            return (response.0)();
+        }
+
+        self.error_response(
+            StatusCode::BAD_REQUEST,
+            "missing_idempotency_key",
+            "Idempotency-Key is missing",
+            &format!("The {} header is required", self.idempotency_key_header),
+        )
+    }
+
+    /// Returns the response sent for requests reusing the key of a different request (see
+    /// [`Self::validate_fingerprint`]).
+    pub(crate) fn key_reused_response(&self) -> Response {
+        self.error_response(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "idempotency_key_reused",
+            "Idempotency-Key is already used",
+            &format!(
+                "The {} header was already used with a different request",
+                self.idempotency_key_header
+            ),
+        )
+    }
+
+    /// Returns the response sent for requests rejected because a request with the same key is
+    /// still being processed, asking clients to retry after `retry_after`.
+    pub(crate) fn conflict_response(&self, status: StatusCode, retry_after: Duration) -> Response {
+        // Retry-After is in whole seconds, and at least one
+        let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        let message = "A request with the same idempotency key is still being processed";
+        let mut res = if self.problem_details {
+            self.error_response(
+                status,
+                "request_in_flight",
+                "A request is outstanding for this Idempotency-Key",
+                message,
+            )
+        } else {
+            (status, message).into_response()
+        };
+        res.headers_mut()
+            .insert(header::RETRY_AFTER, retry_after.into());
+        res
+    }
+
+    /// Returns the response sent for keys exceeding [`Self::rate_limit_per_key`].
+    pub(crate) fn rate_limited_response(&self, retry_after: Duration) -> Response {
+        // Retry-After is in whole seconds, and at least one
+        let retry_after = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+        let mut res = self.error_response(
+            StatusCode::TOO_MANY_REQUESTS,
+            "rate_limited",
+            "Too many requests for this Idempotency-Key",
+            "Too many requests were sent with this idempotency key, retry later",
+        );
+        res.headers_mut()
+            .insert(header::RETRY_AFTER, retry_after.into());
+        res
+    }
+
+    /// Returns the response replayed for keys whose original response was too large to cache
+    /// (see [`OversizedResponse::Tombstone`]).
+    pub(crate) fn tombstone_response(&self, status: StatusCode) -> Response {
+        self.error_response(
+            status,
+            "response_not_replayable",
+            "Response cannot be replayed",
+            "The response to the original request was too large to be stored, and cannot be replayed",
+        )
+    }
+
+    /// Returns the response sent for keys replayed more than [`Self::max_replays`] times.
+    pub(crate) fn replay_limit_response(&self, status: StatusCode) -> Response {
+        self.error_response(
+            status,
+            "replay_limit_exceeded",
+            "Replay limit exceeded",
+            "The response to the original request was replayed too many times",
+        )
+    }
+
+    /// Returns the response sent for requests rejected by [`OversizedBody::Reject`].
+    pub(crate) fn body_too_large_response(&self) -> Response {
+        self.error_response(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "payload_too_large",
+            "Payload too large",
+            &format!(
+                "The request body exceeds the limit of {} bytes",
+                self.max_body_bytes.unwrap_or_default()
+            ),
+        )
+    }
+
     /// Sets the name of the header added to a response to indicate it was served from the cache.
     ///
     /// The default header is `idempotency-replayed: true`.
@@ -121,6 +2361,138 @@ impl IdempotentOptions {
         self
     }
 
+    /// Sets the [`Clock`] used to timestamp cached responses and compute their age and
+    /// expiration time (default: [`SystemClock`](crate::SystemClock)).
+    ///
+    /// # Example
+    /// ```rust
+    /// use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// // A frozen clock, e.g. for snapshot tests
+    /// let options = IdempotentOptions::default()
+    ///     .clock(|| UNIX_EPOCH + Duration::from_secs(1_700_000_000));
+    /// ```
+    pub fn clock(mut self, clock: impl Clock) -> Self {
+        self.clock = Hook(Arc::new(clock));
+        self
+    }
+
+    /// Whether replayed responses are decoded when the client does not accept their
+    /// `Content-Encoding`.
+    ///
+    /// Responses are cached as the inner service produced them, so a body compressed by a
+    /// compression layer below this one for the original client is replayed compressed. When
+    /// enabled (the default), a replay to a client whose `Accept-Encoding` excludes that
+    /// encoding gets the identity-encoded body instead, with a weakened `ETag`. Decoding gzip
+    /// and deflate bodies requires the `gzip` feature, and Zstandard bodies the `zstd`
+    /// feature; bodies in other encodings are replayed as they are.
+    pub fn negotiate_content_encoding(mut self, enabled: bool) -> Self {
+        self.negotiate_content_encoding = enabled;
+        self
+    }
+
+    /// Whether replayed responses carry an `idempotency-original-date` header with the time the
+    /// original response was cached, as an HTTP date.
+    ///
+    /// The same information is always available to downstream layers through the
+    /// [`ReplayInfo`](crate::ReplayInfo) response extension.
+    pub fn original_date_header(mut self, enabled: bool) -> Self {
+        self.original_date_header = enabled;
+        self
+    }
+
+    /// Whether replayed responses carry an `idempotency-original-timestamp` header with the
+    /// time the original response was cached, as a Unix timestamp in seconds.
+    ///
+    /// This lets clients tell a fresh execution from the replay of an hour-old operation, e.g.
+    /// for reconciliation. Enabled by default.
+    pub fn original_timestamp_header(mut self, enabled: bool) -> Self {
+        self.original_timestamp_header = enabled;
+        self
+    }
+
+    /// Sets a hook called with every entry successfully written to the session store.
+    ///
+    /// Deployments spanning several regions can use it to ship entries asynchronously to the
+    /// other regions and write them there with [`ReplicatedEntry::apply`], so that a retry
+    /// routed to another region still replays. The hook is called on the request path and
+    /// should hand the entry off (e.g. to a channel) rather than block.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let (tx, _rx) = std::sync::mpsc::channel();
+    /// let tx = std::sync::Mutex::new(tx);
+    ///
+    /// let options = IdempotentOptions::default().replicate_with(move |entry| {
+    ///     let _ = tx.lock().unwrap().send(entry);
+    /// });
+    /// ```
+    pub fn replicate_with<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(ReplicatedEntry) + Send + Sync + 'static,
+    {
+        self.replication_hook = Some(Hook(Arc::new(hook)));
+        self
+    }
+
+    /// Sets a hook called with every replayed response before it is sent, so applications can
+    /// rewrite it instead of replaying a frozen copy, e.g. to refresh a CSRF token header or
+    /// re-sign a token in the body.
+    ///
+    /// The response carries the replay header and the [`ReplayedResponse`](crate::ReplayedResponse)
+    /// and [`ReplayInfo`](crate::ReplayInfo) extensions. Changes only affect the response sent,
+    /// not the cached entry.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::IdempotentOptions;
+    ///
+    /// let options = IdempotentOptions::default().on_replay(|res| {
+    ///     res.headers_mut()
+    ///         .insert("x-csrf-token", "fresh-token".parse().unwrap());
+    /// });
+    /// ```
+    pub fn on_replay<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&mut Response) + Send + Sync + 'static,
+    {
+        self.on_replay = Some(Hook(Arc::new(hook)));
+        self
+    }
+
+    /// Reports the cache hits and misses, store writes and errors, and conflicts of the
+    /// requests handled by the layer to `observer`, replacing any previous observer.
+    ///
+    /// See [`IdempotencyObserver`] for an example.
+    pub fn observer(mut self, observer: impl IdempotencyObserver) -> Self {
+        self.observer = Some(Hook(Arc::new(observer)));
+        self
+    }
+
+    /// Counts the cache hits and misses, conflicts and store errors of the requests handled by
+    /// the layer in `stats`, and tracks the keys being executed, replacing any previous stats.
+    ///
+    /// Share the same [`IdempotencyStats`] between layers to aggregate their counters. See
+    /// [`admin_router`](crate::admin_router) to expose them.
+    pub fn stats(mut self, stats: IdempotencyStats) -> Self {
+        self.stats = Some(stats);
+        self
+    }
+
+    /// Writes an audit record of every request with an idempotency key to `sink`: its key,
+    /// method, path, whether it was executed, replayed or rejected, and when it was received
+    /// and completed.
+    ///
+    /// See [`AuditSink`] for an example. This requires the `audit` feature.
+    #[cfg(feature = "audit")]
+    pub fn audit_sink(mut self, sink: impl AuditSink) -> Self {
+        self.audit_sink = Some(Hook(Arc::new(sink)));
+        self
+    }
+
     /// When used with `ruts`'s `LayeredStore`, this sets the desired caching
     /// strategy for the idempotent response.
     ///
@@ -130,22 +2502,146 @@ impl IdempotentOptions {
         self.layered_hot_cache_ttl_secs = Some(hot_cache_ttl_secs);
         self
     }
+
+    /// Derives or scopes the idempotency key from a claim of the request's bearer JWT.
+    ///
+    /// See [`JwtClaimKey`] for the available modes.
+    ///
+    /// This requires the `jwt` feature.
+    #[cfg(feature = "jwt")]
+    pub fn jwt_claim_key(mut self, jwt_claim_key: JwtClaimKey) -> Self {
+        self.jwt_claim_key = Some(jwt_claim_key);
+        self
+    }
+
+    /// Deduplicates incoming webhooks by the event ID of their provider, caching only the
+    /// status code of their acknowledgment (see [`StoreMode::StatusOnly`]).
+    ///
+    /// See [`WebhookDedup`] for the supported providers.
+    ///
+    /// This requires the `webhook` feature.
+    #[cfg(feature = "webhook")]
+    pub fn webhook_dedup(mut self, webhook_dedup: WebhookDedup) -> Self {
+        self.webhook_dedup = Some(webhook_dedup);
+        self.store_mode = StoreMode::StatusOnly;
+        self
+    }
+
+    /// Keeps the responses this process caches in memory as well, so replays of hot keys
+    /// (e.g. during a retry storm) are served without a round trip to the store.
+    ///
+    /// Entries are added when a response is written to the store, and expire with their copy in
+    /// the store. They are evicted, least recently used first, beyond `limit`. Evicting an entry
+    /// through an [`IdempotencyManager`](crate::IdempotencyManager) created with these options
+    /// also evicts it from this cache, but evictions by other processes are only seen once the
+    /// entry expires here.
+    ///
+    /// This requires the `front-cache` feature.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::{FrontCacheLimit, IdempotentOptions};
+    ///
+    /// let options = IdempotentOptions::default().front_cache(FrontCacheLimit::Bytes(64 << 20));
+    /// ```
+    #[cfg(feature = "front-cache")]
+    pub fn front_cache(mut self, limit: FrontCacheLimit) -> Self {
+        self.front_cache = Some(FrontCache::new(limit));
+        self
+    }
 }
 
 impl Default for IdempotentOptions {
     fn default() -> Self {
         let mut options = Self {
             use_idempotency_key: false,
+            require_key: false,
+            missing_key_response: None,
+            max_key_len: Some(255),
+            key_format: KeyFormat::Any,
             idempotency_key_header: String::from("idempotency-key"),
             replay_header_name: HeaderName::from_static("idempotency-replayed"),
+            original_date_header: false,
+            original_timestamp_header: true,
+            negotiate_content_encoding: true,
+            clock: Hook(Arc::new(SystemClock)),
             body_cache_ttl_secs: 60 * 5, // 5 mins default
+            soft_ttl_secs: None,
+            hash_algorithm: HashAlgorithm::Blake3,
+            key_prefix: String::new(),
+            namespace: None,
+            key_scope: KeyScope::Store,
+            on_missing_scope: MissingScope::Reject,
+            hash_seed: None,
+            write_retries: 0,
+            complete_on_disconnect: false,
+            background: ShutdownHandle::default(),
+            write_behind: None,
+            in_flight_lock_ttl_secs: None,
+            on_conflict: ConflictBehavior::Reject(StatusCode::CONFLICT),
+            rejection_cache: None,
+            key_rate_limiter: None,
+            single_flight: None,
+            count_replays: false,
+            sliding_expiration: false,
+            max_replays: None,
+            on_store_error: StoreErrorPolicy::FailOpen,
+            on_error: None,
+            write_retry_backoff: Duration::from_millis(50),
+            ttl_policy: None,
+            max_client_ttl_secs: None,
+            ttl_from_response_headers: false,
+            replication_hook: None,
+            validate_fingerprint: false,
+            problem_details: false,
+            problem_type_base_uri: None,
+            on_replay: None,
+            observer: None,
+            stats: None,
+            #[cfg(feature = "audit")]
+            audit_sink: None,
+            enabled_when: None,
+            bypass_header: None,
             ignore_body: false,
+            hash_content_digest: false,
+            verify_content_digest: false,
+            ignore_query: false,
+            hash_matched_path: false,
+            hashed_extensions: Vec::new(),
+            sort_query: false,
+            ignored_query_params: Vec::new(),
+            body_normalizers: Vec::new(),
+            max_body_bytes: None,
+            #[cfg(any(feature = "gzip", feature = "zstd"))]
+            compression: Compression::default(),
+            #[cfg(any(feature = "gzip", feature = "zstd"))]
+            compress_over_bytes: None,
+            oversized_body: OversizedBody::Bypass,
+            max_response_bytes: None,
+            oversized_response: OversizedResponse::Skip,
+            blob_store: None,
+            methods: HashSet::from([Method::POST, Method::PATCH, Method::DELETE]),
+            include_paths: Vec::new(),
+            exclude_paths: Vec::new(),
             ignored_req_headers: HashSet::new(),
+            ignored_req_header_patterns: Vec::new(),
+            hashed_req_headers: None,
+            stripped_res_headers: HashSet::from([header::SET_COOKIE]),
             ignored_header_values: HeaderMap::new(),
             ignored_res_status_codes: HashSet::new(),
+            cached_res_status_codes: None,
+            always_cached_res_status_codes: HashSet::new(),
+            cache_if: None,
             ignore_all_headers: false,
             #[cfg(feature = "layered-store")]
             layered_hot_cache_ttl_secs: None,
+            #[cfg(feature = "jwt")]
+            jwt_claim_key: None,
+            #[cfg(feature = "webhook")]
+            webhook_dedup: None,
+            store_mode: StoreMode::Full,
+            #[cfg(feature = "front-cache")]
+            front_cache: None,
         };
 
         let default_ignored_headers = [
@@ -192,3 +2688,14 @@ impl Default for IdempotentOptions {
         options
     }
 }
+
+/// A response with a JSON body describing the error.
+fn json_error(status: StatusCode, error: &str, message: &str) -> Response {
+    let body = serde_json::json!({ "error": error, "message": message });
+    (
+        status,
+        [(header::CONTENT_TYPE, "application/json")],
+        body.to_string(),
+    )
+        .into_response()
+}
@@ -0,0 +1,120 @@
+use axum::BoxError;
+use axum::body::{Body, Bytes, HttpBody};
+use axum::http::{HeaderMap, HeaderName, HeaderValue};
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use http_body::{Frame, SizeHint};
+use sha2::{Digest, Sha256, Sha512};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// The header of RFC 9530.
+const CONTENT_DIGEST: HeaderName = HeaderName::from_static("content-digest");
+/// The legacy header of RFC 3230.
+const DIGEST: HeaderName = HeaderName::from_static("digest");
+
+/// Returns the digest header of a request, preferring `Content-Digest` over `Digest`, along
+/// with a checker for the first digest in it computed with a supported algorithm, if any.
+pub(crate) fn content_digest(headers: &HeaderMap) -> Option<(&HeaderValue, Option<DigestCheck>)> {
+    let value = headers
+        .get(CONTENT_DIGEST)
+        .or_else(|| headers.get(DIGEST))?;
+    let check = value.to_str().ok().and_then(DigestCheck::parse);
+    Some((value, check))
+}
+
+/// An expected body digest, e.g. `sha-256=:<base64>:` (or `SHA-256=<base64>` in a `Digest`
+/// header).
+pub(crate) struct DigestCheck {
+    hasher: BodyHasher,
+    expected: Vec<u8>,
+}
+
+enum BodyHasher {
+    Sha256(Sha256),
+    Sha512(Box<Sha512>),
+}
+
+impl DigestCheck {
+    fn parse(value: &str) -> Option<Self> {
+        value.split(',').find_map(|member| {
+            let (algorithm, digest) = member.trim().split_once('=')?;
+            let hasher = match algorithm.to_ascii_lowercase().as_str() {
+                "sha-256" => BodyHasher::Sha256(Sha256::new()),
+                "sha-512" => BodyHasher::Sha512(Box::default()),
+                _ => return None,
+            };
+            // Structured field byte sequences are wrapped in colons
+            let digest = digest.trim().trim_matches(':');
+            let expected = BASE64_STANDARD.decode(digest).ok()?;
+            Some(Self { hasher, expected })
+        })
+    }
+
+    /// Wraps `body` so that it fails at its end if it does not match the digest.
+    pub(crate) fn verify(self, body: Body) -> Body {
+        Body::new(VerifiedBody {
+            inner: body,
+            check: Some(self),
+        })
+    }
+
+    fn update(&mut self, chunk: &[u8]) {
+        match &mut self.hasher {
+            BodyHasher::Sha256(hasher) => hasher.update(chunk),
+            BodyHasher::Sha512(hasher) => hasher.update(chunk),
+        }
+    }
+
+    fn matches(self) -> bool {
+        let digest = match self.hasher {
+            BodyHasher::Sha256(hasher) => hasher.finalize().to_vec(),
+            BodyHasher::Sha512(hasher) => hasher.finalize().to_vec(),
+        };
+        digest == self.expected
+    }
+}
+
+/// A body checked against its digest as it streams through, without being buffered.
+struct VerifiedBody {
+    inner: Body,
+    /// Taken once the body ends.
+    check: Option<DigestCheck>,
+}
+
+impl HttpBody for VerifiedBody {
+    type Data = Bytes;
+    type Error = BoxError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = &mut *self;
+        match Pin::new(&mut this.inner).poll_frame(cx) {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let (Some(check), Some(chunk)) = (&mut this.check, frame.data_ref()) {
+                    check.update(chunk);
+                }
+                Poll::Ready(Some(Ok(frame)))
+            }
+            Poll::Ready(Some(Err(err))) => Poll::Ready(Some(Err(err.into()))),
+            Poll::Ready(None) => {
+                if this.check.take().is_some_and(|check| !check.matches()) {
+                    return Poll::Ready(Some(Err("request body does not match its digest".into())));
+                }
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    fn is_end_stream(&self) -> bool {
+        // The end of the body is polled to check the digest
+        self.check.is_none() && self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
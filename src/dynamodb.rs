@@ -0,0 +1,355 @@
+use crate::store::IdempotencyStore;
+use aws_sdk_dynamodb::Client;
+use aws_sdk_dynamodb::client::Waiters;
+use aws_sdk_dynamodb::operation::put_item::PutItemError;
+use aws_sdk_dynamodb::primitives::Blob;
+use aws_sdk_dynamodb::types::{
+    AttributeDefinition, AttributeValue, BillingMode, KeySchemaElement, KeyType,
+    ReturnValuesOnConditionCheckFailure, ScalarAttributeType, TimeToLiveSpecification,
+    TimeToLiveStatus,
+};
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The condition of writes that must not replace a live entry.
+const ABSENT: &str = "attribute_not_exists(#key) OR #expires_at <= :now";
+
+/// The condition of writes made only if the live entry holds `:current`.
+const UNCHANGED: &str = "#value = :current AND #expires_at > :now";
+
+/// The condition of a write to a [`DynamoDbStore`].
+enum Condition {
+    /// The entry is written whatever is stored.
+    Always,
+    /// The entry is written if there is no live entry.
+    Absent,
+    /// The entry is written if the live entry holds the given value.
+    Unchanged(Vec<u8>),
+}
+
+/// A DynamoDB [`IdempotencyStore`] implementation, for serverless deployments without a cache
+/// cluster.
+///
+/// Entries are kept in a table (see [`DynamoDbStore::new`]) with a `key` string partition key,
+/// a `value` binary attribute and an `expires_at` number attribute, holding the Unix time the
+/// entry expires at. DynamoDB's Time to Live on `expires_at` deletes expired entries, within a
+/// few days, and they are ignored on reads in the meantime. In-flight locks are acquired with a
+/// conditional `PutItem`, which returns the existing entry when the condition fails, in a
+/// single round trip.
+///
+/// Removing and listing entries by prefix scans the whole table.
+///
+/// This requires the `dynamodb` feature.
+///
+/// # Example
+/// ```rust,no_run
+/// use axum::{Router, routing::post};
+/// use axum_idempotent::{DynamoDbStore, IdempotentLayer, IdempotentOptions};
+///
+/// // e.g. `aws_sdk_dynamodb::Client::new(&aws_config::load_from_env().await)`
+/// async fn app(client: aws_sdk_dynamodb::Client) -> Router {
+///     let store = DynamoDbStore::new(client, "idempotency");
+///     store.migrate().await.unwrap();
+///
+///     let options = IdempotentOptions::default().use_idempotency_key_header(None);
+///     Router::new()
+///         .route("/payments", post(|| async { "Payment processed" }))
+///         .layer(IdempotentLayer::with_store(store, options))
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct DynamoDbStore {
+    client: Client,
+    table: Arc<str>,
+}
+
+impl DynamoDbStore {
+    pub fn new(client: Client, table: impl Into<String>) -> Self {
+        Self {
+            client,
+            table: Arc::from(table.into()),
+        }
+    }
+
+    /// Creates the table with on-demand capacity, if it does not exist, and enables Time to Live
+    /// on its `expires_at` attribute.
+    ///
+    /// This waits up to two minutes for a new table to become active.
+    pub async fn migrate(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let created = self
+            .client
+            .create_table()
+            .table_name(&*self.table)
+            .attribute_definitions(
+                AttributeDefinition::builder()
+                    .attribute_name("key")
+                    .attribute_type(ScalarAttributeType::S)
+                    .build()?,
+            )
+            .key_schema(
+                KeySchemaElement::builder()
+                    .attribute_name("key")
+                    .key_type(KeyType::Hash)
+                    .build()?,
+            )
+            .billing_mode(BillingMode::PayPerRequest)
+            .send()
+            .await;
+        match created {
+            Ok(_) => {}
+            Err(err)
+                if err
+                    .as_service_error()
+                    .is_some_and(|err| err.is_resource_in_use_exception()) => {}
+            Err(err) => return Err(err.into()),
+        }
+        self.client
+            .wait_until_table_exists()
+            .table_name(&*self.table)
+            .wait(Duration::from_secs(120))
+            .await?;
+
+        let ttl = self
+            .client
+            .describe_time_to_live()
+            .table_name(&*self.table)
+            .send()
+            .await?;
+        let enabled = ttl.time_to_live_description().is_some_and(|ttl| {
+            ttl.attribute_name() == Some("expires_at")
+                && matches!(
+                    ttl.time_to_live_status(),
+                    Some(TimeToLiveStatus::Enabled | TimeToLiveStatus::Enabling)
+                )
+        });
+        if !enabled {
+            self.client
+                .update_time_to_live()
+                .table_name(&*self.table)
+                .time_to_live_specification(
+                    TimeToLiveSpecification::builder()
+                        .attribute_name("expires_at")
+                        .enabled(true)
+                        .build()?,
+                )
+                .send()
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `value` under `key` for `ttl_secs` seconds if `condition` holds, returning the
+    /// entry that prevented the write otherwise.
+    async fn put(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl_secs: i64,
+        condition: Condition,
+    ) -> Result<Option<HashMap<String, AttributeValue>>, Box<dyn Error + Send + Sync>> {
+        let now = now_secs();
+        let mut put = self
+            .client
+            .put_item()
+            .table_name(&*self.table)
+            .item("key", AttributeValue::S(key.to_owned()))
+            .item("value", AttributeValue::B(Blob::new(value)))
+            .item(
+                "expires_at",
+                AttributeValue::N((now + ttl_secs).to_string()),
+            );
+        match condition {
+            Condition::Always => {}
+            Condition::Absent => {
+                put = put
+                    .condition_expression(ABSENT)
+                    .expression_attribute_names("#key", "key")
+                    .expression_attribute_names("#expires_at", "expires_at")
+                    .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+                    .return_values_on_condition_check_failure(
+                        ReturnValuesOnConditionCheckFailure::AllOld,
+                    );
+            }
+            Condition::Unchanged(current) => {
+                put = put
+                    .condition_expression(UNCHANGED)
+                    .expression_attribute_names("#value", "value")
+                    .expression_attribute_names("#expires_at", "expires_at")
+                    .expression_attribute_values(":current", AttributeValue::B(Blob::new(current)))
+                    .expression_attribute_values(":now", AttributeValue::N(now.to_string()));
+            }
+        }
+
+        match put.send().await {
+            Ok(_) => Ok(None),
+            Err(err) => match err.into_service_error() {
+                PutItemError::ConditionalCheckFailedException(err) => {
+                    Ok(Some(err.item.unwrap_or_default()))
+                }
+                err => Err(err.into()),
+            },
+        }
+    }
+
+    /// Scans the keys of the live entries whose key starts with `prefix`, with their expiry.
+    async fn scan_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, i64)>, Box<dyn Error + Send + Sync>> {
+        let now = now_secs();
+        let mut items = self
+            .client
+            .scan()
+            .table_name(&*self.table)
+            .filter_expression("begins_with(#key, :prefix) AND #expires_at > :now")
+            .projection_expression("#key, #expires_at")
+            .expression_attribute_names("#key", "key")
+            .expression_attribute_names("#expires_at", "expires_at")
+            .expression_attribute_values(":prefix", AttributeValue::S(prefix.to_owned()))
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+            .consistent_read(true)
+            .into_paginator()
+            .items()
+            .send();
+
+        let mut entries = Vec::new();
+        while let Some(item) = items.next().await {
+            let item = item?;
+            if let (Some(AttributeValue::S(key)), Some(expires_at)) =
+                (item.get("key"), expires_at(&item))
+            {
+                entries.push((key.clone(), expires_at));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl IdempotencyStore for DynamoDbStore {
+    type Key = str;
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        let output = self
+            .client
+            .get_item()
+            .table_name(&*self.table)
+            .key("key", AttributeValue::S(key.to_owned()))
+            .consistent_read(true)
+            .send()
+            .await?;
+
+        Ok(output.item.and_then(live_value))
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.put(key, value, ttl_secs, Condition::Always).await?;
+
+        Ok(())
+    }
+
+    async fn set_if_absent(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        // Expired entries are replaced, as if they had been deleted
+        Ok(self
+            .put(key, value, ttl_secs, Condition::Absent)
+            .await?
+            .is_none())
+    }
+
+    async fn get_or_lock(
+        &self,
+        key: &str,
+        marker: Vec<u8>,
+        lock_ttl_secs: i64,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        match self
+            .put(key, marker.clone(), lock_ttl_secs, Condition::Absent)
+            .await?
+        {
+            None => Ok(None),
+            Some(item) => Ok(Some(live_value(item).unwrap_or(marker))),
+        }
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        current: Vec<u8>,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let condition = Condition::Unchanged(current);
+        Ok(self.put(key, value, ttl_secs, condition).await?.is_none())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.client
+            .delete_item()
+            .table_name(&*self.table)
+            .key("key", AttributeValue::S(key.to_owned()))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn remove_prefix(&self, prefix: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for (key, _) in self.scan_prefix(prefix).await? {
+            self.remove(&key).await?;
+        }
+
+        Ok(())
+    }
+
+    async fn list_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, i64)>, Box<dyn Error + Send + Sync>> {
+        let now = now_secs();
+        let entries = self.scan_prefix(prefix).await?;
+
+        Ok(entries
+            .into_iter()
+            .map(|(key, expires_at)| (key, expires_at - now))
+            .collect())
+    }
+}
+
+/// The current Unix time, in seconds.
+fn now_secs() -> i64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_secs() as i64
+}
+
+/// The Unix time the entry `item` expires at.
+fn expires_at(item: &HashMap<String, AttributeValue>) -> Option<i64> {
+    match item.get("expires_at") {
+        Some(AttributeValue::N(expires_at)) => expires_at.parse().ok(),
+        _ => None,
+    }
+}
+
+/// The value of the entry `item`, unless it has expired but was not deleted yet.
+fn live_value(mut item: HashMap<String, AttributeValue>) -> Option<Vec<u8>> {
+    if expires_at(&item)? <= now_secs() {
+        return None;
+    }
+    match item.remove("value") {
+        Some(AttributeValue::B(value)) => Some(value.into_inner()),
+        _ => None,
+    }
+}
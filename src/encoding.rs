@@ -0,0 +1,109 @@
+use crate::cached::CachedResponse;
+use axum::body::Bytes;
+use axum::http::{HeaderMap, HeaderValue, header};
+
+/// Decodes the body of the cached response `cached` if the replaying request, whose headers
+/// are `headers`, does not accept its `Content-Encoding`.
+///
+/// Responses are cached as the inner service produced them, e.g. compressed by a
+/// `CompressionLayer` below this layer for a client accepting gzip, while a client replaying
+/// the key may not accept it. Bodies in an encoding this build cannot decode are replayed as
+/// they are.
+pub(crate) fn negotiate(cached: &mut CachedResponse, headers: &HeaderMap) {
+    let Some(coding) = cached
+        .headers
+        .get(header::CONTENT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.trim().to_ascii_lowercase())
+    else {
+        return;
+    };
+    // Stacked codings are left alone
+    if coding.contains(',') || accepts(headers, &coding) {
+        return;
+    }
+    let Some(body) = decode(&coding, &cached.body) else {
+        tracing::debug!("Replaying a response in the {coding} encoding the client does not accept");
+        return;
+    };
+
+    cached.body = body;
+    cached.headers.remove(header::CONTENT_ENCODING);
+    cached
+        .headers
+        .insert(header::CONTENT_LENGTH, cached.body.len().into());
+    // The decoded body is another representation of the resource
+    if let Some(etag) = cached.headers.get(header::ETAG) {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(etag.as_bytes());
+            if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+                cached.headers.insert(header::ETAG, weak);
+            }
+        }
+    }
+}
+
+/// Whether a request with `headers` accepts bodies in the content `coding`, following the
+/// `Accept-Encoding` rules of RFC 9110.
+fn accepts(headers: &HeaderMap, coding: &str) -> bool {
+    let mut values = headers.get_all(header::ACCEPT_ENCODING).iter().peekable();
+    // Without the header, any coding is acceptable
+    if values.peek().is_none() || coding == "identity" {
+        return true;
+    }
+
+    let mut wildcard = None;
+    for value in values {
+        let Ok(value) = value.to_str() else { continue };
+        for item in value.split(',') {
+            let mut params = item.split(';');
+            let name = params.next().unwrap_or_default().trim();
+            let accepted = params
+                .filter_map(|param| param.trim().strip_prefix("q="))
+                .all(|q| q.trim().parse::<f32>().is_ok_and(|q| q > 0.0));
+            if name.eq_ignore_ascii_case(coding)
+                || (coding == "gzip" && name.eq_ignore_ascii_case("x-gzip"))
+            {
+                return accepted;
+            }
+            if name == "*" {
+                wildcard = Some(accepted);
+            }
+        }
+    }
+    wildcard.unwrap_or(false)
+}
+
+/// Decodes a body in the content `coding`, if this build can.
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+fn decode(coding: &str, body: &[u8]) -> Option<Bytes> {
+    use std::io::Read;
+
+    let mut decoded = Vec::new();
+    let result = match coding {
+        #[cfg(feature = "gzip")]
+        "gzip" | "x-gzip" => flate2::read::GzDecoder::new(body).read_to_end(&mut decoded),
+        #[cfg(feature = "gzip")]
+        "deflate" => flate2::read::ZlibDecoder::new(body).read_to_end(&mut decoded),
+        #[cfg(feature = "zstd")]
+        "zstd" => zstd::stream::Decoder::new(body)
+            .and_then(|mut decoder| decoder.read_to_end(&mut decoded)),
+        _ => return None,
+    };
+
+    match result {
+        Ok(_) => Some(Bytes::from(decoded)),
+        Err(err) => {
+            tracing::warn!("Failed to decode the {coding} body of a cached response: {err:?}");
+            None
+        }
+    }
+}
+
+/// Decodes a body in the content `coding`, if this build can, which requires the `gzip` or
+/// `zstd` feature.
+#[cfg(not(any(feature = "gzip", feature = "zstd")))]
+fn decode(_coding: &str, _body: &[u8]) -> Option<Bytes> {
+    None
+}
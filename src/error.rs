@@ -0,0 +1,255 @@
+use axum::response::Response;
+use std::error::Error;
+use std::fmt;
+
+/// An error encountered by the middleware while handling a request.
+///
+/// Errors are logged, and reported to the hook set with
+/// [`IdempotentOptions::on_error`](crate::IdempotentOptions::on_error), which decides how the
+/// request is handled.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum IdempotencyError {
+    /// An operation on the store failed.
+    Store {
+        /// The operation that failed.
+        operation: StoreOperation,
+        /// The error returned by the store.
+        source: Box<dyn Error + Send + Sync>,
+    },
+    /// A cached entry could not be deserialized.
+    Serialization(Box<dyn Error + Send + Sync>),
+    /// The request lacks the idempotency key header, which is required (see
+    /// [`IdempotentOptions::require_key`](crate::IdempotentOptions::require_key)).
+    KeyMissing,
+    /// The idempotency key is longer than
+    /// [`IdempotentOptions::max_key_length`](crate::IdempotentOptions::max_key_length).
+    KeyTooLong {
+        /// The length of the key in bytes.
+        len: usize,
+        /// The maximum length of keys in bytes.
+        max_len: usize,
+    },
+    /// The idempotency key contains characters that are not allowed.
+    KeyInvalid {
+        /// Why the key is invalid, e.g. "must be a UUID".
+        reason: &'static str,
+    },
+    /// A request with the same key is still being processed (see
+    /// [`IdempotentOptions::lock_in_flight`](crate::IdempotentOptions::lock_in_flight)).
+    ConcurrentRequest,
+    /// The idempotency key was already used with a different request (see
+    /// [`IdempotentOptions::validate_fingerprint`](crate::IdempotentOptions::validate_fingerprint)).
+    KeyReused,
+}
+
+impl fmt::Display for IdempotencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdempotencyError::Store { operation, source } => {
+                write!(f, "idempotency store {operation} failed: {source}")
+            }
+            IdempotencyError::Serialization(err) => {
+                write!(f, "failed to deserialize cached response: {err}")
+            }
+            IdempotencyError::KeyMissing => f.write_str("idempotency key is missing"),
+            IdempotencyError::KeyTooLong { len, max_len } => write!(
+                f,
+                "idempotency key is {len} bytes long, longer than the maximum of {max_len}"
+            ),
+            IdempotencyError::KeyInvalid { reason } => write!(f, "idempotency key {reason}"),
+            IdempotencyError::ConcurrentRequest => {
+                f.write_str("a request with the same idempotency key is still being processed")
+            }
+            IdempotencyError::KeyReused => {
+                f.write_str("the idempotency key was already used with a different request")
+            }
+        }
+    }
+}
+
+impl Error for IdempotencyError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            IdempotencyError::Store { source, .. } => Some(source.as_ref()),
+            IdempotencyError::Serialization(err) => Some(err.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+/// A contradictory or invalid setting of [`IdempotentOptions`](crate::IdempotentOptions).
+///
+/// Returned by [`IdempotentOptions::build`](crate::IdempotentOptions::build),
+/// [`IdempotentLayer::try_new`](crate::IdempotentLayer::try_new) and
+/// [`IdempotentLayer::try_with_store`](crate::IdempotentLayer::try_with_store).
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// The expiration time of cached responses is not positive.
+    InvalidTtl(i64),
+    /// The stale-while-revalidate threshold is not lower than the expiration time.
+    SoftTtlNotBelowTtl {
+        /// The threshold in seconds.
+        soft_ttl_secs: i64,
+        /// The expiration time in seconds.
+        ttl_secs: i64,
+    },
+    /// The expiration time of in-flight locks is not positive.
+    InvalidLockTtl(i64),
+    /// The name of the idempotency key header is empty or not a valid header name.
+    InvalidKeyHeader(String),
+    /// Direct key mode is enabled, but the request body or headers are hashed as well, e.g.
+    /// because `ignore_body(false)` was called after `use_idempotency_key_header()`.
+    DirectKeyHashesRequest,
+    /// The secret of the bypass header is empty, which would let any client skip idempotency.
+    EmptyBypassSecret,
+    /// A setting loaded through `serde` has an invalid value, e.g. an unknown status code.
+    InvalidSetting(&'static str, String),
+    /// Keys are scoped by session ([`KeyScope::Session`](crate::KeyScope::Session)), but the
+    /// layer keeps entries in an [`IdempotencyStore`](crate::IdempotencyStore), not in sessions.
+    SessionScopeWithoutSession,
+    /// A layer backed by a session store keeps entries in a scope not tied to a session, with
+    /// [`KeyScope::Global`](crate::KeyScope::Global), [`KeyScope::Custom`](crate::KeyScope::Custom)
+    /// or a `SessionFallback`, but no [`hash_seed`](crate::IdempotentOptions::hash_seed), from
+    /// which the id of these scopes is derived, is set.
+    MissingHashSeed,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidTtl(ttl_secs) => {
+                write!(f, "expiration time must be positive, got {ttl_secs}s")
+            }
+            ConfigError::SoftTtlNotBelowTtl {
+                soft_ttl_secs,
+                ttl_secs,
+            } => write!(
+                f,
+                "soft TTL of {soft_ttl_secs}s must be lower than the expiration time of {ttl_secs}s"
+            ),
+            ConfigError::InvalidLockTtl(ttl_secs) => {
+                write!(f, "in-flight lock TTL must be positive, got {ttl_secs}s")
+            }
+            ConfigError::InvalidKeyHeader(name) => {
+                write!(f, "{name:?} is not a valid idempotency key header name")
+            }
+            ConfigError::DirectKeyHashesRequest => f.write_str(
+                "direct key mode is enabled, but the request body or headers are hashed as well",
+            ),
+            ConfigError::EmptyBypassSecret => f.write_str("the bypass header secret is empty"),
+            ConfigError::InvalidSetting(setting, value) => {
+                write!(f, "{value:?} is not a valid value for {setting}")
+            }
+            ConfigError::SessionScopeWithoutSession => {
+                f.write_str("keys are scoped by session, but the layer does not use sessions")
+            }
+            ConfigError::MissingHashSeed => {
+                f.write_str("scopes not tied to a session require a hash seed")
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+/// An error returned by [`migrate::upgrade_entries`](crate::migrate::upgrade_entries).
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum MigrationError {
+    /// Entries cannot be upgraded between these versions: `to` is not the version written by
+    /// this release, or `from` is not an older version it reads.
+    UnsupportedVersions {
+        /// The version entries are upgraded from.
+        from: u8,
+        /// The version entries are upgraded to.
+        to: u8,
+    },
+    /// An operation on the store failed.
+    Store {
+        /// The operation that failed.
+        operation: StoreOperation,
+        /// The error returned by the store.
+        source: Box<dyn Error + Send + Sync>,
+    },
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrationError::UnsupportedVersions { from, to } => write!(
+                f,
+                "cannot upgrade cached responses from format version {from} to {to}"
+            ),
+            MigrationError::Store { operation, source } => {
+                write!(f, "idempotency store {operation} failed: {source}")
+            }
+        }
+    }
+}
+
+impl Error for MigrationError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MigrationError::Store { source, .. } => Some(source.as_ref()),
+            MigrationError::UnsupportedVersions { .. } => None,
+        }
+    }
+}
+
+/// A store operation performed by the middleware.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum StoreOperation {
+    /// Looking up the entry of a key.
+    Get,
+    /// Looking up the entry of a key while acquiring its in-flight lock.
+    Lock,
+    /// Caching a response, after the inner service responded.
+    Set,
+    /// Releasing the in-flight lock of a key, after the inner service responded.
+    Release,
+    /// Removing entries through an [`IdempotencyManager`](crate::IdempotencyManager), or
+    /// once they were replayed [`IdempotentOptions::max_replays`](crate::IdempotentOptions::max_replays)
+    /// times.
+    Invalidate,
+    /// Checking that the store is reachable, through
+    /// [`IdempotentLayer::health_check`](crate::IdempotentLayer::health_check).
+    Ping,
+    /// Listing entries, to upgrade them with
+    /// [`migrate::upgrade_entries`](crate::migrate::upgrade_entries).
+    List,
+}
+
+impl fmt::Display for StoreOperation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            StoreOperation::Get => "get",
+            StoreOperation::Lock => "lock",
+            StoreOperation::Set => "set",
+            StoreOperation::Release => "release",
+            StoreOperation::Invalidate => "invalidate",
+            StoreOperation::Ping => "ping",
+            StoreOperation::List => "list",
+        })
+    }
+}
+
+/// How a request is handled after an [`IdempotencyError`].
+///
+/// Errors raised after the inner service responded ([`StoreOperation::Set`] and
+/// [`StoreOperation::Release`]) are only reported: the response of the inner service is sent
+/// whatever the action. Requests whose cached response cannot be replayed because its body
+/// cannot be fetched from a [`BlobStore`](crate::BlobStore) were already executed, so they are
+/// not forwarded either: [`ErrorAction::Forward`] is handled as [`ErrorAction::Default`].
+#[derive(Debug)]
+pub enum ErrorAction {
+    /// Handle the error as configured, e.g. by [`StoreErrorPolicy`](crate::StoreErrorPolicy)
+    /// or [`ConflictBehavior`](crate::ConflictBehavior).
+    Default,
+    /// Forward the request to the inner service without idempotency.
+    Forward,
+    /// Send the given response instead.
+    Respond(Response),
+}
@@ -0,0 +1,297 @@
+use axum::extract::{FromRequestParts, OptionalFromRequestParts};
+use axum::http::StatusCode;
+use axum::http::request::Parts;
+use axum::response::{IntoResponse, Response};
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// Marker inserted into the response extensions when a response is served from the cache.
+///
+/// Layers sitting outside of [`IdempotentLayer`](crate::IdempotentLayer) (rate limiters,
+/// billing meters, audit layers, ...) can check for it to avoid counting replays as new
+/// operations.
+///
+/// # Example
+/// ```rust
+/// use axum::response::Response;
+/// use axum_idempotent::ReplayedResponse;
+///
+/// fn is_replay(res: &Response) -> bool {
+///     res.extensions().get::<ReplayedResponse>().is_some()
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReplayedResponse;
+
+/// Details about a replayed response, inserted into its extensions next to
+/// [`ReplayedResponse`].
+///
+/// Downstream layers (logging, tracing, ...) can use it to enrich replays.
+///
+/// # Example
+/// ```rust
+/// use axum::response::Response;
+/// use axum_idempotent::ReplayInfo;
+///
+/// fn log_replay(res: &Response) {
+///     if let Some(info) = res.extensions().get::<ReplayInfo>() {
+///         println!("Replayed {} cached {}s ago", info.key, info.age.as_secs());
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ReplayInfo {
+    /// When the original response was cached, with a precision of one second.
+    pub original_timestamp: SystemTime,
+    /// How long ago the original response was cached.
+    pub age: Duration,
+    /// The idempotency key the response was cached under, without the
+    /// [`key_prefix`](crate::IdempotentOptions::key_prefix) and
+    /// [`namespace`](crate::IdempotentOptions::namespace).
+    pub key: String,
+}
+
+/// Makes the middleware skip a request entirely: no key is computed, no response is looked up
+/// or stored, and the request reaches the inner service untouched.
+///
+/// It must be inserted into the request extensions by a layer running before the
+/// [`IdempotentLayer`](crate::IdempotentLayer), e.g. after authenticating an operator replaying
+/// a request or a load test that must hit the handlers. For clients that cannot run code on
+/// the server, see [`IdempotentOptions::bypass_header`](crate::IdempotentOptions::bypass_header).
+///
+/// # Example
+/// ```rust
+/// use axum::extract::Request;
+/// use axum_idempotent::BypassIdempotency;
+///
+/// async fn bypass_for_load_tests(mut req: Request) -> Request {
+///     if req.headers().contains_key("x-load-test") {
+///         req.extensions_mut().insert(BypassIdempotency);
+///     }
+///     req
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BypassIdempotency;
+
+/// Overrides the expiration time of the cached response for a route or a single request.
+///
+/// It is read from the response extensions, where the handler can set it, and from the
+/// request extensions, where it must be inserted by a layer running before the
+/// [`IdempotentLayer`](crate::IdempotentLayer) (e.g. axum's `Extension` layer applied on the
+/// outside). The response extension takes precedence over everything else, and the request
+/// extension over [`IdempotentOptions::ttl_policy`](crate::IdempotentOptions::ttl_policy) and
+/// [`IdempotentOptions::expire_after`](crate::IdempotentOptions::expire_after). An
+/// `Idempotency-TTL` request header (see
+/// [`IdempotentOptions::allow_client_ttl`](crate::IdempotentOptions::allow_client_ttl)) can
+/// only shorten the request extension.
+///
+/// # Example
+/// ```rust
+/// use std::time::Duration;
+/// use axum::Extension;
+/// use axum::response::IntoResponse;
+/// use axum_idempotent::IdempotencyTtl;
+///
+/// // Bulk imports only need to be deduplicated for a minute
+/// async fn bulk_import() -> impl IntoResponse {
+///     (Extension(IdempotencyTtl(Duration::from_secs(60))), "Imported")
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdempotencyTtl(pub Duration);
+
+impl IdempotencyTtl {
+    /// The expiration time in seconds.
+    pub(crate) fn as_secs(&self) -> i64 {
+        i64::try_from(self.0.as_secs()).unwrap_or(i64::MAX)
+    }
+}
+
+/// Controls how the response it is inserted into is cached.
+///
+/// Handlers can insert it into the response extensions to make business-level decisions that
+/// status code filtering cannot express, e.g. not caching a `200 OK` that only reports a
+/// pending operation. It takes precedence over an [`IdempotencyTtl`] response extension.
+///
+/// # Example
+/// ```rust
+/// use axum::Extension;
+/// use axum::response::IntoResponse;
+/// use axum_idempotent::IdempotencyDirective;
+///
+/// async fn charge() -> impl IntoResponse {
+///     let settled = false;
+///     if settled {
+///         (Extension(IdempotencyDirective::ExpireAfter(60 * 60 * 24)), "Charged")
+///     } else {
+///         (Extension(IdempotencyDirective::NoStore), "Pending, retry later")
+///     }
+/// }
+/// ```
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IdempotencyDirective {
+    /// Do not cache the response, so a retry of the request executes the handler again.
+    NoStore,
+    /// Cache the response for the given number of seconds.
+    ExpireAfter(i64),
+}
+
+/// The idempotency key of a request, as used in the store.
+///
+/// In direct key mode this is the client-supplied key, and otherwise the hash of the request.
+/// It is inserted into the request extensions before the request reaches the handler, which
+/// can extract it, e.g. to persist it alongside the operation it triggered. Extracting it
+/// fails with a `500 Internal Server Error` for requests the middleware did not handle; use
+/// `Option<IdempotencyKey>` for routes where it is optional.
+///
+/// # Example
+/// ```rust
+/// use axum_idempotent::IdempotencyKey;
+///
+/// async fn create_payment(IdempotencyKey(key): IdempotencyKey) -> String {
+///     format!("Processing payment for key {key}")
+/// }
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct IdempotencyKey(pub String);
+
+impl<S: Send + Sync> FromRequestParts<S> for IdempotencyKey {
+    type Rejection = MissingIdempotencyKey;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<IdempotencyKey>()
+            .cloned()
+            .ok_or(MissingIdempotencyKey)
+    }
+}
+
+impl<S: Send + Sync> OptionalFromRequestParts<S> for IdempotencyKey {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        Ok(parts.extensions.get::<IdempotencyKey>().cloned())
+    }
+}
+
+/// Handler-level control over the idempotency of the current request.
+///
+/// It gives the handler the idempotency key of the request, tells whether this is its first
+/// execution, and lets it decide how its response is cached, as an [`IdempotencyDirective`]
+/// response extension would. A directive inserted into the response extensions takes
+/// precedence. Extracting it fails like extracting an [`IdempotencyKey`].
+///
+/// # Example
+/// ```rust
+/// use axum_idempotent::Idempotency;
+///
+/// async fn charge(idempotency: Idempotency) -> &'static str {
+///     if !idempotency.is_fresh() {
+///         // A refresh of a stale response, or a concurrent duplicate
+///     }
+///
+///     let settled = false;
+///     if settled {
+///         idempotency.set_ttl(60 * 60 * 24);
+///         "Charged"
+///     } else {
+///         idempotency.mark_no_store();
+///         "Pending, retry later"
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct Idempotency {
+    key: String,
+    fresh: bool,
+    directive: Arc<Mutex<Option<IdempotencyDirective>>>,
+}
+
+impl Idempotency {
+    pub(crate) fn new(key: String) -> Self {
+        Self {
+            key,
+            fresh: true,
+            directive: Arc::default(),
+        }
+    }
+
+    /// The idempotency key of the request, as in [`IdempotencyKey`].
+    pub fn key(&self) -> &str {
+        &self.key
+    }
+
+    /// Whether this is the first execution of the request.
+    ///
+    /// This is `false` when refreshing a stale response in the background (see
+    /// [`IdempotentOptions::soft_ttl`](crate::IdempotentOptions::soft_ttl)), and for duplicates
+    /// forwarded while the original request is in flight (see
+    /// [`ConflictBehavior::Passthrough`](crate::ConflictBehavior::Passthrough)).
+    pub fn is_fresh(&self) -> bool {
+        self.fresh
+    }
+
+    /// Do not cache the response, so a retry of the request executes the handler again.
+    pub fn mark_no_store(&self) {
+        *self.directive.lock().unwrap() = Some(IdempotencyDirective::NoStore);
+    }
+
+    /// Caches the response for the given number of seconds.
+    pub fn set_ttl(&self, secs: i64) {
+        *self.directive.lock().unwrap() = Some(IdempotencyDirective::ExpireAfter(secs));
+    }
+
+    pub(crate) fn set_fresh(&mut self, fresh: bool) {
+        self.fresh = fresh;
+    }
+
+    /// The directive set by the handler, if any.
+    pub(crate) fn directive(&self) -> Option<IdempotencyDirective> {
+        *self.directive.lock().unwrap()
+    }
+}
+
+impl<S: Send + Sync> FromRequestParts<S> for Idempotency {
+    type Rejection = MissingIdempotencyKey;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<Idempotency>()
+            .cloned()
+            .ok_or(MissingIdempotencyKey)
+    }
+}
+
+impl<S: Send + Sync> OptionalFromRequestParts<S> for Idempotency {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        parts: &mut Parts,
+        _state: &S,
+    ) -> Result<Option<Self>, Self::Rejection> {
+        Ok(parts.extensions.get::<Idempotency>().cloned())
+    }
+}
+
+/// Rejection used for [`IdempotencyKey`] and [`Idempotency`] when the request has no
+/// idempotency key.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MissingIdempotencyKey;
+
+impl IntoResponse for MissingIdempotencyKey {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Missing idempotency key. Is `IdempotentLayer` applied to this route?",
+        )
+            .into_response()
+    }
+}
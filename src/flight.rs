@@ -0,0 +1,85 @@
+use axum::body::Bytes;
+use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
+use futures_util::FutureExt;
+use futures_util::future::{BoxFuture, Shared};
+use std::fmt;
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+/// The key of an execution: the scope (e.g. session id) of the store, and the key in the store.
+type Key = (Option<String>, String);
+
+/// The cached entry produced by an execution, or `None` if it cached nothing.
+type Execution = Shared<BoxFuture<'static, Option<Bytes>>>;
+
+/// Lets concurrent identical requests handled by this process share one handler execution.
+///
+/// The first request with a key becomes the leader and executes the handler, while the
+/// requests arriving before it completes await the entry it caches. The executions are shared
+/// by the clones of the options they were created with.
+#[derive(Clone, Default)]
+pub(crate) struct SingleFlight {
+    executions: Arc<DashMap<Key, Execution>>,
+}
+
+/// The role of a request in the execution of its key.
+pub(crate) enum Join {
+    /// No other request is executing the key: this one does, and shares its entry through the
+    /// guard.
+    Leader(FlightGuard),
+    /// Another request is executing the key, and this one awaits its entry.
+    Follower(Execution),
+}
+
+impl SingleFlight {
+    /// Joins the execution of `key` in the store of `scope`, starting it if there is none.
+    pub(crate) fn join(&self, scope: Option<String>, key: &str) -> Join {
+        match self.executions.entry((scope, key.to_owned())) {
+            Entry::Occupied(entry) => Join::Follower(entry.get().clone()),
+            Entry::Vacant(entry) => {
+                let (sender, receiver) = oneshot::channel();
+                let execution = receiver.map(|entry| entry.ok().flatten()).boxed().shared();
+                let key = entry.key().clone();
+                entry.insert(execution);
+                Join::Leader(FlightGuard {
+                    executions: self.executions.clone(),
+                    key,
+                    sender: Some(sender),
+                })
+            }
+        }
+    }
+}
+
+impl fmt::Debug for SingleFlight {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SingleFlight")
+            .field("executions", &self.executions.len())
+            .finish()
+    }
+}
+
+/// Held by the leader of an execution, which ends when the guard is dropped.
+///
+/// Followers of an execution that ends without [`FlightGuard::complete`] get no entry.
+pub(crate) struct FlightGuard {
+    executions: Arc<DashMap<Key, Execution>>,
+    key: Key,
+    sender: Option<oneshot::Sender<Option<Bytes>>>,
+}
+
+impl FlightGuard {
+    /// Hands the entry cached by the execution to its followers.
+    pub(crate) fn complete(mut self, entry: Bytes) {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(Some(entry));
+        }
+    }
+}
+
+impl Drop for FlightGuard {
+    fn drop(&mut self) {
+        self.executions.remove(&self.key);
+    }
+}
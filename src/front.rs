@@ -0,0 +1,109 @@
+use axum::body::Bytes;
+use moka::Expiry;
+use moka::sync::Cache;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// The bound on the size of the in-memory front cache, see
+/// [`IdempotentOptions::front_cache`](crate::IdempotentOptions::front_cache).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrontCacheLimit {
+    /// At most this many entries are kept.
+    Entries(u64),
+    /// At most this many bytes of serialized responses are kept.
+    Bytes(u64),
+}
+
+/// The key of an entry: the scope (e.g. session id) of the store, and the key in the store.
+type Key = (Option<String>, String);
+
+#[derive(Clone)]
+struct Entry {
+    bytes: Bytes,
+    ttl: Duration,
+}
+
+/// Expires each entry together with its copy in the backing store.
+struct EntryExpiry;
+
+impl Expiry<Key, Entry> for EntryExpiry {
+    fn expire_after_create(
+        &self,
+        _key: &Key,
+        entry: &Entry,
+        _created_at: Instant,
+    ) -> Option<Duration> {
+        Some(entry.ttl)
+    }
+
+    fn expire_after_update(
+        &self,
+        _key: &Key,
+        entry: &Entry,
+        _updated_at: Instant,
+        _duration_until_expiry: Option<Duration>,
+    ) -> Option<Duration> {
+        Some(entry.ttl)
+    }
+}
+
+/// A process-local copy of the entries this process wrote to the store, consulted before the
+/// store so replays of hot keys skip the network.
+///
+/// The cache is shared by the clones of the options it was created with.
+#[derive(Clone)]
+pub(crate) struct FrontCache {
+    limit: FrontCacheLimit,
+    entries: Cache<Key, Entry>,
+}
+
+impl FrontCache {
+    pub(crate) fn new(limit: FrontCacheLimit) -> Self {
+        let builder = Cache::builder().expire_after(EntryExpiry);
+        let entries = match limit {
+            FrontCacheLimit::Entries(max) => builder.max_capacity(max).build(),
+            FrontCacheLimit::Bytes(max) => builder
+                .weigher(|_, entry: &Entry| u32::try_from(entry.bytes.len()).unwrap_or(u32::MAX))
+                .max_capacity(max)
+                .build(),
+        };
+        Self { limit, entries }
+    }
+
+    /// Returns the entry stored under `key` in the store of `scope`, if this process wrote it
+    /// and it has not expired.
+    pub(crate) fn get(&self, scope: Option<String>, key: &str) -> Option<Bytes> {
+        self.entries
+            .get(&(scope, key.to_owned()))
+            .map(|entry| entry.bytes)
+    }
+
+    /// Records that `bytes` were written under `key` in the store of `scope` for `ttl_secs`.
+    pub(crate) fn insert(&self, scope: Option<String>, key: &str, bytes: Bytes, ttl_secs: i64) {
+        let ttl = Duration::from_secs(ttl_secs.max(0) as u64);
+        self.entries
+            .insert((scope, key.to_owned()), Entry { bytes, ttl });
+    }
+
+    /// Forgets the entry stored under `key` in the store of `scope`.
+    pub(crate) fn remove(&self, scope: Option<String>, key: &str) {
+        self.entries.invalidate(&(scope, key.to_owned()));
+    }
+
+    /// Forgets the entries whose key starts with `prefix` in the store of `scope`.
+    pub(crate) fn remove_prefix(&self, scope: Option<String>, prefix: &str) {
+        for (key, _) in &self.entries {
+            if key.0 == scope && key.1.starts_with(prefix) {
+                self.entries.invalidate(&*key);
+            }
+        }
+    }
+}
+
+impl fmt::Debug for FrontCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FrontCache")
+            .field("limit", &self.limit)
+            .finish_non_exhaustive()
+    }
+}
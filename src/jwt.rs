@@ -0,0 +1,113 @@
+use axum::http::{HeaderMap, header};
+use jsonwebtoken::{DecodingKey, Validation};
+use serde_json::{Map, Value};
+use std::fmt;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum JwtClaimMode {
+    Derive,
+    Scope,
+}
+
+/// Derives or scopes the idempotency key from a claim of the request's bearer JWT.
+///
+/// The token is read from the `Authorization: Bearer <token>` header and validated against
+/// the configured [`DecodingKey`] and [`Validation`] before the claim is used. Requests
+/// without a valid token, or whose token lacks the claim, are forwarded without idempotency.
+///
+/// This requires the `jwt` feature.
+///
+/// # Example
+/// ```rust
+/// use axum_idempotent::{IdempotentOptions, JwtClaimKey};
+/// use jsonwebtoken::{DecodingKey, Validation};
+///
+/// // Use the token's `jti` as the idempotency key.
+/// let options = IdempotentOptions::default().jwt_claim_key(JwtClaimKey::derive(
+///     "jti",
+///     DecodingKey::from_secret(b"secret"),
+///     Validation::default(),
+/// ));
+///
+/// // Keep the `Idempotency-Key` header, but isolate keys per `sub`.
+/// let options = IdempotentOptions::default()
+///     .use_idempotency_key_header(Some("Idempotency-Key"))
+///     .jwt_claim_key(JwtClaimKey::scope(
+///         "sub",
+///         DecodingKey::from_secret(b"secret"),
+///         Validation::default(),
+///     ));
+/// ```
+#[derive(Clone)]
+pub struct JwtClaimKey {
+    claim: String,
+    pub(crate) mode: JwtClaimMode,
+    decoding_key: DecodingKey,
+    validation: Validation,
+}
+
+impl JwtClaimKey {
+    /// Uses the value of `claim` (e.g. `jti`) directly as the idempotency key.
+    ///
+    /// The rest of the request is not considered when computing the key.
+    pub fn derive(
+        claim: impl Into<String>,
+        decoding_key: DecodingKey,
+        validation: Validation,
+    ) -> Self {
+        Self {
+            claim: claim.into(),
+            mode: JwtClaimMode::Derive,
+            decoding_key,
+            validation,
+        }
+    }
+
+    /// Prefixes the otherwise computed idempotency key with the value of `claim` (e.g. `sub`),
+    /// so that identical keys sent by different principals never share a cache entry.
+    pub fn scope(
+        claim: impl Into<String>,
+        decoding_key: DecodingKey,
+        validation: Validation,
+    ) -> Self {
+        Self {
+            claim: claim.into(),
+            mode: JwtClaimMode::Scope,
+            decoding_key,
+            validation,
+        }
+    }
+
+    /// Returns the claim value of a valid bearer token found in `headers`.
+    pub(crate) fn claim_value(&self, headers: &HeaderMap) -> Option<String> {
+        let token = headers
+            .get(header::AUTHORIZATION)?
+            .to_str()
+            .ok()?
+            .strip_prefix("Bearer ")?;
+
+        let data = jsonwebtoken::decode::<Map<String, Value>>(
+            token.trim(),
+            &self.decoding_key,
+            &self.validation,
+        )
+        .map_err(|err| tracing::debug!("Failed to validate bearer token: {err:?}"))
+        .ok()?;
+
+        match data.claims.get(&self.claim)? {
+            Value::String(value) => Some(value.clone()),
+            Value::Number(value) => Some(value.to_string()),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Debug for JwtClaimKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("JwtClaimKey")
+            .field("claim", &self.claim)
+            .field("mode", &self.mode)
+            .field("validation", &self.validation)
+            .finish_non_exhaustive()
+    }
+}
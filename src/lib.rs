@@ -14,8 +14,8 @@
 //!     server-side hashing and uses an identifier known to both the client and server.
 //!
 //! 2.  **Hashing Mode:** If not using a direct key, a unique hash is generated
-//!     from the request's method, path, headers (configurable), and body. This hash is
-//!     then used as the cache key.
+//!     from the request's method, path, query string, headers (configurable), and body.
+//!     This hash is then used as the cache key.
 //!
 //! If a key is found in the session store, the cached response is returned immediately.
 //! If not, the request is processed by the handler, and the response is cached before
@@ -24,10 +24,73 @@
 //! ## Features
 //!
 //! - Request deduplication using either a direct client-provided key or automatic request hashing.
-//! - Configurable response caching duration.
-//! - Fine-grained controls for hashing, including ignoring the request body or specific headers.
+//! - Standard semantics of the IETF `Idempotency-Key` header draft with `rfc_mode()`: required keys, `422 Unprocessable Entity` for keys reused with a different payload (`validate_fingerprint()`), `409 Conflict` for in-flight requests, and `application/problem+json` error bodies.
+//! - Presets for common use cases: `IdempotentOptions::payments()` (required keys, fingerprint validation, 24-hour TTL, fail-closed), `webhooks()` (provider event IDs, status-only entries) and `api_default()` (hashing mode, 5-minute TTL).
+//! - Optional in-flight locking, so concurrent identical requests get a `409 Conflict` (or a `425 Too Early` with `ConflictBehavior::TooEarly`) or wait for the original response instead of executing twice.
+//! - Single-flight: with `single_flight(true)`, concurrent identical requests handled by the same process share one handler execution, without a store round trip.
+//! - A fail-open or fail-closed policy ([`StoreErrorPolicy`]) for requests whose key cannot be checked because the store is unreachable.
+//! - Problem Details (RFC 9457): `problem_details()` answers requests rejected by the middleware with `application/problem+json` bodies, with `type` URIs under a configurable base URI.
+//! - Structured errors ([`IdempotencyError`]) reported to an `on_error()` hook, for custom alerting or responses.
+//! - Validated configuration: `IdempotentOptions::build()`, `IdempotentLayer::try_new()` and `IdempotentLayer::try_with_store()` reject contradictory settings with a descriptive `ConfigError`.
+//! - Configuration files: `IdempotentOptions` can be deserialized with `serde` from YAML, TOML or the environment, to tune TTLs per environment without recompiling.
+//! - Configurable response caching duration, optionally per authenticated principal, per route, or per response through the [`IdempotencyTtl`] extension.
+//! - Fine-grained controls for hashing, including ignoring the request body or specific headers (or header families, with `ignore_headers_matching("x-forwarded-*")`, or hashing only an allow-list of headers with `hash_only_headers()`), hashing the matched route template instead of the raw path (`hash_matched_path()`), hashing request extensions such as a negotiated API version (`hash_extension::<T>(tag)`), normalizing the query string (`sort_query_params()`, `ignore_query_param("utm_*")`, `ignore_query()`), and content-type-aware body normalizers (`body_normalizer()`, with built-in form and multipart normalizers).
+//! - Large uploads keyed on their `Content-Digest` (RFC 9530) or `Digest` header instead of a buffered body hash (`hash_content_digest()`), optionally verifying the digest as the body streams to the handler.
+//! - A choice of hash function ([`HashAlgorithm`]): BLAKE3 (default), SHA-256 for compliance requirements, or XXH3 for speed with trusted clients.
+//! - Bounded memory use: bodies are read incrementally up to `max_body_bytes()`, bypassing idempotency or rejecting with `413 Payload Too Large` beyond it.
+//! - Status-only storage: `store_mode(StoreMode::StatusOnly)` persists only the status code (and optionally selected headers) when clients just need to know a request was processed.
+//! - Response size limit: `max_response_bytes()` keeps large responses out of the store, optionally storing a tombstone so retries are rejected instead of re-executed.
+//! - External blob storage: `blob_store()` uploads the bodies of large responses to a [`BlobStore`] (e.g. S3 or GCS), storing only a pointer in the entry and streaming the body back on replay.
+//! - Status code allow-list: `cache_only_status_codes()` and `cache_status_range(200..300)` declare exactly which outcomes are replayed. `always_cache_status()` deliberately caches error outcomes such as `402 Payment Required`, overriding the ignored status codes.
+//! - Manual invalidation: `IdempotencyManager` evicts or inspects cached entries by key or prefix, e.g. after a refund reverses a cached payment.
+//! - Negative caching: `cache_rejections()` briefly remembers rejected in-flight duplicates, answering retries with a `Retry-After` estimated from previous requests.
+//! - Retry storm shedding: `rate_limit_per_key()` answers clients sending the same key too often with `429 Too Many Requests`, before their requests reach the store.
+//! - Conditional replay: `GET` and `HEAD` replays answer a matching `If-None-Match` with `304 Not Modified` and no body, for clients polling for a result.
+//! - Trailers: response trailers, as used by gRPC-web and streaming APIs, are cached and replayed along with the body.
+//! - Sliding expiration: with `sliding_expiration(true)`, every replay extends the TTL of the entry, so long-running retry loops keep their response.
+//! - Stale-while-revalidate: with `soft_ttl()`, older responses are replayed immediately while being refreshed in the background.
+//! - Compression of large cached responses with gzip or Zstandard (`compress_over_bytes()`, requires the `gzip` or `zstd` feature).
+//! - Content-encoding negotiation: replays of bodies compressed by a compression layer are decoded for clients whose `Accept-Encoding` excludes their encoding (`negotiate_content_encoding()`, with the `gzip` or `zstd` feature).
+//! - Response-driven cache control: handlers can skip caching or adjust the TTL of a response with the [`IdempotencyDirective`] extension, or, with `ttl_from_response_headers()`, an `x-idempotency-ttl` or `Cache-Control: max-age` header.
+//! - Handler-level control: the [`Idempotency`] extractor exposes the key and whether this is a fresh execution, and lets handlers skip caching or set the TTL.
+//! - Restricting idempotency to some methods (`only_methods()`) or paths (`include_paths()`, `exclude_paths()`), so the layer can be applied at the router root.
+//! - Conditional engagement via `enabled_when()`, e.g. to skip internal service-to-service calls.
+//! - A per-request escape hatch for admin replays and load tests: a `bypass_header()` holding a shared secret, or a [`BypassIdempotency`] request extension, skips both lookup and store.
+//! - Replay limits: `count_replays()` counts the replays of each key in the entry and an `idempotency-replay-count` header, and `max_replays()` rejects or re-executes requests for keys replayed too often ([`ReplayLimit`]).
 //! - Observability through a replay header (default: `idempotency-replayed`) on cached responses.
-//! - Seamless integration with session-based storage via the `ruts` crate.
+//! - An `idempotency.check` tracing span around each handled request, recording `key.mode` (`direct`, `hash`, `jwt` or `webhook`), `key.len`, `cache.hit` and `store.latency_ms`.
+//! - Rewriting replayed responses with an `on_replay()` hook, e.g. to refresh a CSRF token header instead of replaying a frozen copy.
+//! - A [`ReplayedResponse`] marker in the extensions of cached responses for downstream layers.
+//! - Replay metadata ([`ReplayInfo`]: original timestamp, age and key) for logging and tracing, and as an `idempotency-original-timestamp` header (optionally also `idempotency-original-date`).
+//! - An [`IdempotencyKey`] extractor giving handlers access to the key of the request, e.g. to store it on the payment record.
+//! - Seamless integration with session-based storage via the `ruts` crate (`session` feature, enabled by default).
+//! - In-memory front cache: `front_cache()` keeps the responses a process cached in memory, expiring with the store's copy, so replays of hot keys skip the network (requires the `front-cache` feature).
+//! - Usable outside `axum`: the service accepts `http::Request<B>` with any body, so it fits `hyper`, `tonic-web` or other `tower` stacks, used with `IdempotentLayer::with_store()`.
+//! - Strict handling of a missing `SessionLayer` (`on_missing_session()`): rejected with a `500 Internal Server Error` explaining the misconfiguration by default in debug builds, or a panic, instead of silently disabling idempotency.
+//! - Pluggable storage through the [`IdempotencyStore`] trait, for servers without sessions.
+//! - Binary store keys: stores whose `Key` is `[u8]`, such as `RedisStore::binary_keys()` and `SledStore::binary_keys()`, receive the request hashes of hashing mode as raw bytes rather than hex, halving their size.
+//! - A standalone in-process store, `MemoryIdempotencyStore`, with per-entry TTLs, max-entries/max-bytes LRU eviction that accounts for entry sizes and an eviction callback, for tests, CLIs and single-instance services (no `ruts` or cookies needed).
+//! - A two-tier store, `TieredStore`, layering any two stores (e.g. memory in front of Redis, or Redis in front of PostgreSQL) with write-through, read repair and a separate TTL for the hot tier.
+//! - Store health checks (`IdempotentLayer::health_check()`) for readiness endpoints, so fail-closed deployments stop taking traffic when the store is unreachable.
+//! - Write-behind caching: with `write_behind(true)`, responses are returned immediately and stored by a background task, through a bounded queue (`max_pending_writes()`).
+//! - Graceful shutdown: `IdempotentLayer::shutdown_handle()` waits for cache writes running in the background, so a deploy does not drop the entry of an operation that already executed.
+//! - A pluggable time source ([`Clock`], set with `clock()`) for timestamps, `Age` and TTL calculations, so tests need not sleep and targets without a system clock can supply their own.
+//! - An admin `Router` ([`admin_router()`]) serving hit, miss and conflict counters, per-route stats and in-flight keys as JSON, recorded by an [`IdempotencyStats`] set with `stats()`, to mount behind your own auth middleware.
+//! - Key inspection for support engineers: [`inspect_router()`] serves `GET /idempotency/{key}` with the state, status code, creation time, remaining TTL, fingerprint and replay count of an entry, without its body unless `?body=true` is passed.
+//! - Test doubles for downstream integration tests: `test_util::MockStore` with injectable latency, failures and partial writes, expiring entries by a manually advanced `MockClock`, and `test_util::conformance`, checking a store implementation against the `IdempotencyStore` contract (requires the `test-util` feature).
+//! - A built-in Redis store, `RedisStore` (requires the `redis-store` feature).
+//! - A built-in PostgreSQL store, `PostgresStore`, for durable entries that survive cache flushes and restarts, with a background cleanup task (requires the `postgres` feature).
+//! - A built-in DynamoDB store, `DynamoDbStore`, for serverless deployments, with entries expired by DynamoDB's Time to Live and in-flight locks acquired by conditional writes (requires the `dynamodb` feature).
+//! - An embedded store, `SledStore`, keeping entries in a `sled` tree on local disk, for single-node services whose entries must survive restarts (requires the `sled-store` feature).
+//! - A built-in memcached store, `MemcachedStore`, with atomic in-flight locks acquired by memcached's `add` (requires the `memcached` feature).
+//! - Key prefixes (`key_prefix()`) to keep the entries of services or environments sharing a store apart, and per-layer namespaces (`namespace()`) for stacked layers.
+//! - Per-principal key isolation ([`KeyScope`]), e.g. by a user ID inserted by an authentication layer, or by any request extension with [`IdempotentOptions::scope_by_extension`] for multi-tenant apps. `KeyScope::Session` and `KeyScope::Global` choose whether keys are per session or shared by all clients, e.g. server-to-server clients without a stable session.
+//! - Replication hooks to copy cached entries to other regions.
+//! - Deriving or scoping keys from a bearer JWT claim (requires the `jwt` feature).
+//! - Webhook deduplication (`WebhookDedup`) keyed on the provider's event ID, from a header such as `X-GitHub-Delivery` or a JSON field such as Stripe's `id`, caching only the acknowledgment status (requires the `webhook` feature).
+//! - Metrics for cache hits and misses, store errors, and store and hashing latency, labelled by method and route (requires the `metrics` feature).
+//! - Lifecycle callbacks for custom logging, metrics or audit trails ([`IdempotencyObserver`]).
+//! - An append-only audit record of every request with an idempotency key (key, method, path, outcome and timestamps), written through a pluggable [`AuditSink`] (requires the `audit` feature).
 //!
 //! ## Example
 //!
@@ -52,7 +115,7 @@
 //! // Create the router
 //! let app = Router::new()
 //!     .route("/payments", post(process_payment))
-//!     .layer(IdempotentLayer::<MemoryStore>::new(idempotent_options))
+//!     .layer(IdempotentLayer::<MemoryStore>::try_new(idempotent_options).unwrap())
 //!     .layer(SessionLayer::new(store)
 //!         .with_cookie_options(CookieOptions::build().name("session")))
 //!     .layer(CookieManagerLayer::new());
@@ -70,6 +133,18 @@
 //!
 //! `axum-idempotent` is configured with safe defaults to prevent common issues.
 //!
+//! ### Methods
+//!
+//! Only `POST`, `PATCH` and `DELETE` requests are handled by default; requests with other methods,
+//! which are idempotent by definition, are forwarded without hashing or store access. Use
+//! `only_methods()` to change this.
+//!
+//! ### Idempotency Keys
+//!
+//! In direct key mode, keys longer than 255 bytes or containing characters other than
+//! visible ASCII are rejected with a `400 Bad Request`. Use `max_key_length()` and
+//! `key_format()` to change this.
+//!
 //! ### Ignored Status Codes
 //!
 //! To avoid caching transient server errors or certain client errors, responses with
@@ -107,133 +182,1071 @@
 //! - sec-ch-ua,
 //! - sec-ch-ua-mobile,
 //! - sec-ch-ua-platform
+//!
+//! ### Stripped Response Headers
+//!
+//! `Set-Cookie` headers are not cached, so a replayed response cannot hand out the session of
+//! another request. Use `strip_response_headers()` and `preserve_response_headers()` to change
+//! this.
 
-use axum::RequestExt;
-use axum::extract::Request;
+use axum::BoxError;
+use axum::body::{Bytes, HttpBody};
+use axum::extract::{MatchedPath, Request};
+use axum::http::{self, Method, StatusCode, header};
 use axum::response::Response;
-use ruts::Session;
+#[cfg(feature = "session")]
 use ruts::store::SessionStore;
-use std::error::Error;
+use std::fmt;
 use std::future::Future;
-use std::marker::PhantomData;
 use std::pin::Pin;
+#[cfg(feature = "session")]
+use std::sync::Arc;
 use std::task::{Context, Poll};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tower_layer::Layer;
 use tower_service::Service;
+use tracing::field::Empty;
+use tracing::{Instrument, Span};
 
 mod utils;
 
+#[cfg(feature = "audit")]
+mod audit;
+#[cfg(feature = "audit")]
+use crate::audit::Audit;
+#[cfg(feature = "audit")]
+pub use crate::audit::{AuditOutcome, AuditRecord, AuditSink};
+
+mod admin;
+pub use crate::admin::{
+    IdempotencyStats, InFlightKey, KeyInfo, KeyState, RouteStats, StatsSnapshot, admin_router,
+    inspect_router,
+};
+
+mod blob;
+use crate::blob::MissingBlob;
+pub use crate::blob::{BlobPointer, BlobStore};
+
+mod body;
+use crate::body::{AxumService, BodyLimitExceeded};
+/// The body of the requests passed to the inner service and of the responses of
+/// [`IdempotentService`], re-exported for stacks that do not otherwise depend on `axum`.
+pub use axum::body::Body;
+
+mod cached;
+pub use crate::cached::CachedResponse;
+use crate::cached::{
+    UnsupportedVersion, is_pending, pending_marker, pending_since, tombstone_status,
+};
+
+mod clock;
+pub use crate::clock::{Clock, SystemClock};
+
 mod config;
-pub use crate::config::IdempotentOptions;
-use crate::utils::{bytes_to_response, hash_request, response_to_bytes};
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+pub use crate::config::Compression;
+pub use crate::config::{
+    ConflictBehavior, HashAlgorithm, IdempotentOptions, KeyFormat, KeyScope, MissingScope,
+    OversizedBody, OversizedResponse, ReplayLimit, StatusCaching, StoreErrorPolicy, StoreMode,
+};
+
+mod digest;
+
+mod encoding;
+
+mod error;
+pub use crate::error::{
+    ConfigError, ErrorAction, IdempotencyError, MigrationError, StoreOperation,
+};
+
+mod extension;
+pub use crate::extension::{
+    BypassIdempotency, Idempotency, IdempotencyDirective, IdempotencyKey, IdempotencyTtl,
+    MissingIdempotencyKey, ReplayInfo, ReplayedResponse,
+};
+
+mod flight;
+use crate::flight::{FlightGuard, Join};
+
+mod manager;
+pub use crate::manager::IdempotencyManager;
+
+mod memory;
+pub use crate::memory::{EvictionCause, MemoryIdempotencyStore};
+
+mod metrics;
+use crate::metrics::Metrics;
+
+pub mod migrate;
+
+mod normalize;
+pub use crate::normalize::{BodyNormalizer, FormNormalizer, MultipartNormalizer};
+
+mod observer;
+pub use crate::observer::{IdempotencyEvent, IdempotencyObserver};
+
+mod rejection;
+
+mod replication;
+pub use crate::replication::ReplicatedEntry;
+
+mod settings;
+
+#[cfg(feature = "test-util")]
+pub mod test_util;
+
+mod throttle;
+
+mod tiered;
+pub use crate::tiered::TieredStore;
+
+mod shutdown;
+pub use crate::shutdown::ShutdownHandle;
+
+mod store;
+use crate::store::Backend;
+pub use crate::store::{IdempotencyStore, StoreBackend, StoreKey};
+
+#[cfg(feature = "redis-store")]
+mod redis;
+#[cfg(feature = "redis-store")]
+pub use crate::redis::RedisStore;
+
+#[cfg(feature = "postgres")]
+mod postgres;
+#[cfg(feature = "postgres")]
+pub use crate::postgres::PostgresStore;
+
+#[cfg(feature = "dynamodb")]
+mod dynamodb;
+#[cfg(feature = "dynamodb")]
+pub use crate::dynamodb::DynamoDbStore;
+
+#[cfg(feature = "sled-store")]
+mod sled;
+#[cfg(feature = "sled-store")]
+pub use crate::sled::SledStore;
+
+#[cfg(feature = "memcached")]
+mod memcached;
+#[cfg(feature = "memcached")]
+pub use crate::memcached::MemcachedStore;
+
+#[cfg(feature = "session")]
+mod session;
+#[cfg(feature = "session")]
+pub use crate::session::{MissingSession, SessionFallback};
+#[cfg(feature = "session")]
+use crate::session::{SessionMissing, SessionState};
+
+#[cfg(feature = "front-cache")]
+mod front;
+#[cfg(feature = "front-cache")]
+pub use crate::front::FrontCacheLimit;
+
+#[cfg(feature = "jwt")]
+mod jwt;
+#[cfg(feature = "jwt")]
+pub use crate::jwt::JwtClaimKey;
+#[cfg(feature = "webhook")]
+mod webhook;
+use crate::utils::{
+    RequestFingerprint, encode_response, etag_matches, hash_request, serialize_response,
+};
+#[cfg(feature = "webhook")]
+pub use crate::webhook::WebhookDedup;
+
+/// How often a request waiting for an in-flight key checks the store.
+const IN_FLIGHT_POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How many times the replay of a response is recorded before giving up, when its entry keeps
+/// changing in the meantime. Each failed attempt means another replay was recorded, so this
+/// bounds the concurrent replays of a key that are all counted.
+const REPLAY_RECORD_ATTEMPTS: usize = 16;
 
 /// Service that handles idempotent request processing.
-#[derive(Clone, Debug)]
-pub struct IdempotentService<S, T> {
+pub struct IdempotentService<S, T: Backend> {
     inner: S,
     config: IdempotentOptions,
-    phantom: PhantomData<T>,
+    state: T::State,
 }
 
-impl<S, T> IdempotentService<S, T> {
+#[cfg(feature = "session")]
+impl<S, T: SessionStore> IdempotentService<S, T> {
     pub const fn new(inner: S, config: IdempotentOptions) -> Self {
         IdempotentService::<S, T> {
             inner,
             config,
-            phantom: PhantomData,
+            state: SessionState::new(),
+        }
+    }
+}
+
+impl<S: Clone, T: Backend> Clone for IdempotentService<S, T> {
+    fn clone(&self) -> Self {
+        Self {
+            inner: self.inner.clone(),
+            config: self.config.clone(),
+            state: self.state.clone(),
         }
     }
 }
 
-impl<S, T> Service<Request> for IdempotentService<S, T>
+impl<S: fmt::Debug, T: Backend> fmt::Debug for IdempotentService<S, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdempotentService")
+            .field("inner", &self.inner)
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
+}
+
+/// The service accepts requests with any body, so it can be used in `hyper`, `tonic`, or other
+/// `tower` stacks, not only in `axum`. Request bodies are converted to a [`Body`] before being
+/// passed to the inner service, whose responses may have any body, and are converted to a
+/// [`Body`] as well.
+impl<S, T, ReqBody, ResBody> Service<http::Request<ReqBody>> for IdempotentService<S, T>
 where
-    S: Service<Request, Response = Response> + Clone + Send + 'static,
-    S::Error: Send,
+    S: Service<Request, Response = http::Response<ResBody>> + Clone + Send + 'static,
+    S::Error: Send + 'static,
     S::Future: Send + 'static,
-    T: SessionStore,
+    ReqBody: HttpBody<Data = Bytes> + Send + 'static,
+    ReqBody::Error: Into<BoxError>,
+    ResBody: HttpBody<Data = Bytes> + Send + 'static,
+    ResBody::Error: Into<BoxError>,
+    T: Backend,
 {
-    type Response = S::Response;
+    type Response = Response;
     type Error = S::Error;
     type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
 
     fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx).map_err(Into::into)
+        self.inner.poll_ready(cx)
     }
 
-    fn call(&mut self, mut req: Request) -> Self::Future {
+    fn call(&mut self, req: http::Request<ReqBody>) -> Self::Future {
         let clone = self.inner.clone();
-        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let mut inner = AxumService(std::mem::replace(&mut self.inner, clone));
+        let mut req = req.map(Body::new);
+
+        // The bypass header is removed from every request, so it is checked first
+        if self.config.bypasses(&mut req)
+            || !self.config.methods.contains(req.method())
+            || !self.config.applies_to_path(req.uri().path())
+        {
+            return Box::pin(inner.call(req));
+        }
+
+        if let Some(predicate) = &self.config.enabled_when {
+            let (parts, body) = req.into_parts();
+            let enabled = (predicate.0)(&parts);
+            req = Request::from_parts(parts, body);
+            if !enabled {
+                return Box::pin(inner.call(req));
+            }
+        }
+
         let config = self.config.clone();
+        let state = self.state.clone();
+        let span = tracing::info_span!(
+            "idempotency.check",
+            key.mode = config.key_mode().as_str(),
+            key.len = Empty,
+            cache.hit = Empty,
+            store.latency_ms = Empty,
+        );
+
+        let future = async move {
+            #[cfg(feature = "audit")]
+            let received_at = config.now();
+            // The route template, when the layer runs after routing.
+            let route = req
+                .extensions()
+                .get::<MatchedPath>()
+                .map(|path| path.as_str().to_owned());
+            let mut metrics = Metrics::new(
+                req.method(),
+                route.as_deref(),
+                config.observer.clone(),
+                config.stats.clone(),
+            );
+
+            if config.is_missing_key(&req) {
+                tracing::debug!(
+                    route = route.as_deref(),
+                    "Request lacks a required idempotency key"
+                );
+                return match config.report(&IdempotencyError::KeyMissing) {
+                    ErrorAction::Default => Ok(config.missing_key_response_for()),
+                    ErrorAction::Forward => inner.call(req).await,
+                    ErrorAction::Respond(res) => Ok(res),
+                };
+            }
+            if let Some(err) = config.key_error(&req) {
+                tracing::debug!(route = route.as_deref(), "Invalid idempotency key: {err}");
+                return match config.report(&err) {
+                    ErrorAction::Default => Ok(config.key_error_response(&err)),
+                    ErrorAction::Forward => inner.call(req).await,
+                    ErrorAction::Respond(res) => Ok(res),
+                };
+            }
+
+            let Some(storage) = T::resolve(&state, &mut req, &config, route.as_deref()).await
+            else {
+                #[cfg(feature = "session")]
+                if req.extensions_mut().remove::<SessionMissing>().is_some() {
+                    return Ok(config.missing_session_response());
+                }
+                // Forward the request to the inner service without idempotency
+                return inner.call(req).await;
+            };
 
-        Box::pin(async move {
-            let session = match req.extract_parts::<Session<T>>().await {
-                Ok(session) => session,
-                Err(err) => {
-                    tracing::error!("Failed to extract Session from request: {err:?}");
-                    // Forward the request to the inner service without idempotency
-                    return inner.call(req).await;
+            let started = Instant::now();
+            let (mut req, key) = hash_request(req, &config).await;
+            metrics.hash_latency(started.elapsed());
+            if req.extensions_mut().remove::<BodyLimitExceeded>().is_some() {
+                tracing::debug!(
+                    route = route.as_deref(),
+                    "Request body exceeds the idempotency body limit"
+                );
+                if config.oversized_body == OversizedBody::Reject {
+                    return Ok(config.body_too_large_response());
+                }
+                // Forward the request to the inner service without idempotency
+                return inner.call(req).await;
+            }
+            let hash = key
+                .as_deref()
+                .and_then(|key| config.storage_key(req.extensions(), key));
+            if key.is_some() && hash.is_none() {
+                tracing::warn!(
+                    route = route.as_deref(),
+                    "Request has an idempotency key but no key scope"
+                );
+                metrics.scope_missing();
+                if config.on_missing_scope == MissingScope::Reject {
+                    return Ok(config.missing_scope_response());
                 }
+                // Forward the request to the inner service without idempotency
+                return inner.call(req).await;
+            }
+            if let Some(key) = &key {
+                Span::current().record("key.len", key.len());
+                req.extensions_mut().insert(IdempotencyKey(key.clone()));
+                req.extensions_mut().insert(Idempotency::new(key.clone()));
+                metrics.set_key(key);
+            }
+            // Identifies the request producing the cached response
+            let fingerprint = match req.extensions().get::<RequestFingerprint>() {
+                Some(RequestFingerprint(fingerprint)) => Some(fingerprint.clone()),
+                None => key.clone().filter(|_| config.key_mode().is_hashed()),
             };
+            #[cfg(feature = "audit")]
+            let audit = Audit::new(
+                config.audit_sink.as_ref(),
+                key.as_deref(),
+                &req,
+                received_at,
+                &config.clock,
+            );
+            let ttl_secs = config.ttl_for(&req);
+            let complete_on_disconnect = config.complete_on_disconnect;
+            let mut locked = false;
+            let mut flight = None;
 
-            let (req, hash) = hash_request(req, &config).await;
+            if let (Some(key), Some(hash)) = (&key, &hash) {
+                let throttled = config
+                    .key_rate_limiter
+                    .as_ref()
+                    .and_then(|limiter| limiter.acquire(hash).err());
+                if let Some(retry_after) = throttled {
+                    tracing::debug!(
+                        route = route.as_deref(),
+                        "Rejecting request exceeding the rate limit of its idempotency key"
+                    );
+                    let res = config.rate_limited_response(retry_after);
+                    #[cfg(feature = "audit")]
+                    audit.record(AuditOutcome::Rejected(res.status()));
+                    return Ok(res);
+                }
+                let rejection = config
+                    .rejection_cache
+                    .as_ref()
+                    .and_then(|cache| cache.get(hash));
+                if let Some((status, retry_after)) = rejection {
+                    tracing::debug!(
+                        route = route.as_deref(),
+                        "Rejecting request with a recently rejected idempotency key"
+                    );
+                    metrics.conflict(Duration::ZERO);
+                    #[cfg(feature = "audit")]
+                    audit.record(AuditOutcome::Rejected(status));
+                    return Ok(config.conflict_response(status, retry_after));
+                }
+
+                let started = Instant::now();
+                let mut shared = None;
+                if let Some(single_flight) = &config.single_flight {
+                    match single_flight.join(T::scope(&storage), hash) {
+                        Join::Leader(guard) => flight = Some(guard),
+                        Join::Follower(execution) => {
+                            tracing::debug!(
+                                route = route.as_deref(),
+                                "Waiting for the execution of an identical request"
+                            );
+                            shared = execution.await;
+                        }
+                    }
+                }
+                let mut lookup = match shared {
+                    Some(entry) => Ok(decode_lookup(entry, config.now()).unwrap_or(Lookup::Miss)),
+                    None => {
+                        let lock_ttl_secs = config.in_flight_lock_ttl_secs;
+                        let lookup = check_cached_response::<T>(
+                            hash,
+                            &storage,
+                            &config,
+                            &metrics,
+                            lock_ttl_secs,
+                        );
+                        lookup.await
+                    }
+                };
+                if let Ok(Lookup::Locked) = lookup {
+                    locked = true;
+                    lookup = Ok(Lookup::Miss);
+                }
+                lookup = match lookup {
+                    Ok(Lookup::Hit(cached, _)) if !fingerprint_matches(&cached, &fingerprint) => {
+                        Ok(Lookup::KeyReused)
+                    }
+                    Ok(Lookup::Hit(cached, entry))
+                        if config.counts_replays() || config.sliding_expiration =>
+                    {
+                        let recorded = record_replay::<T>(
+                            cached, entry, hash, &storage, &config, &metrics, ttl_secs,
+                        );
+                        Ok(recorded.await)
+                    }
+                    lookup => lookup,
+                };
+                // Locks keys whose entry was found but discarded, e.g. to be re-executed
+                if let (Ok(Lookup::Miss), false, Some(lock_ttl_secs)) =
+                    (&lookup, locked, config.in_flight_lock_ttl_secs)
+                {
+                    let started = Instant::now();
+                    let acquired = storage
+                        .set_if_absent(
+                            &config.store_key::<T::Store>(hash),
+                            pending_marker(config.now()),
+                            lock_ttl_secs,
+                        )
+                        .await;
+                    metrics.store_latency(StoreOperation::Lock, started.elapsed());
+                    match acquired {
+                        Ok(true) => locked = true,
+                        // A concurrent request with the same key got there first
+                        Ok(false) => lookup = Ok(Lookup::InFlight(None)),
+                        Err(source) => {
+                            tracing::error!(
+                                route = route.as_deref(),
+                                "Failed to mark idempotency key as in flight: {source:?}"
+                            );
+                            metrics.store_error(StoreOperation::Lock, started.elapsed());
+                            let err = IdempotencyError::Store {
+                                operation: StoreOperation::Lock,
+                                source,
+                            };
+                            match config.report(&err) {
+                                ErrorAction::Default => {
+                                    if let Some(res) = config.store_error_response() {
+                                        return Ok(res);
+                                    }
+                                }
+                                ErrorAction::Forward => return inner.call(req).await,
+                                ErrorAction::Respond(res) => return Ok(res),
+                            }
+                        }
+                    }
+                }
+                if let (Ok(Lookup::InFlight(_)), ConflictBehavior::Wait(timeout)) =
+                    (&lookup, config.on_conflict)
+                {
+                    let waited =
+                        wait_for_in_flight::<T>(hash, &storage, &config, &metrics, timeout);
+                    lookup = match waited.await {
+                        Ok(Lookup::Locked) => {
+                            locked = true;
+                            Ok(Lookup::Miss)
+                        }
+                        Ok(Lookup::Hit(cached, _))
+                            if !fingerprint_matches(&cached, &fingerprint) =>
+                        {
+                            Ok(Lookup::KeyReused)
+                        }
+                        lookup => lookup,
+                    };
+                }
+                let mut blob = None;
+                if let Ok(Lookup::Hit(cached, _)) = &lookup {
+                    match fetch_blob(cached, &config, &metrics).await {
+                        Ok(body) => blob = body,
+                        // The request was executed, so it is not executed again
+                        Err(err) => {
+                            tracing::error!(
+                                route = route.as_deref(),
+                                "Failed to fetch the body of the cached response: {err:?}"
+                            );
+                            return Ok(match config.report(&err) {
+                                ErrorAction::Respond(res) => res,
+                                ErrorAction::Default | ErrorAction::Forward => {
+                                    config.blob_error_response()
+                                }
+                            });
+                        }
+                    }
+                }
+                let latency = started.elapsed();
+                let span = Span::current();
+                span.record("store.latency_ms", latency.as_secs_f64() * 1000.0);
+                if let Ok(lookup) = &lookup {
+                    span.record("cache.hit", matches!(lookup, Lookup::Hit(..)));
+                }
 
-            if let Some(hash) = &hash {
-                match check_cached_response(hash, &session).await {
-                    Ok(Some(mut res)) => {
-                        res.headers_mut()
-                            .insert(config.replay_header_name, "true".parse().unwrap());
+                match lookup {
+                    Ok(Lookup::Hit(mut cached, _)) => {
+                        metrics.hit(latency);
+                        tracing::debug!(
+                            route = route.as_deref(),
+                            key_prefix = config.key_prefix,
+                            "Replaying cached idempotent response"
+                        );
+                        let now = config.now();
+                        let info = ReplayInfo {
+                            original_timestamp: cached.stored_at,
+                            age: now.duration_since(cached.stored_at).unwrap_or_default(),
+                            key: key.clone(),
+                        };
+                        let replays = cached.replays;
+                        // Clients polling for the result may already have it
+                        let not_modified = matches!(*req.method(), Method::GET | Method::HEAD)
+                            && req
+                                .headers()
+                                .get(header::IF_NONE_MATCH)
+                                .zip(cached.headers.get(header::ETAG))
+                                .is_some_and(|(tags, etag)| etag_matches(tags, etag));
+                        // The body of responses kept in a blob store is not at hand
+                        if config.negotiate_content_encoding && blob.is_none() {
+                            encoding::negotiate(&mut cached, req.headers());
+                        }
+                        let mut res = cached.into_response();
+                        if let Some((body, len)) = blob {
+                            *res.body_mut() = body;
+                            res.headers_mut().insert(header::CONTENT_LENGTH, len.into());
+                        }
+                        let headers = res.headers_mut();
+                        headers.insert(config.replay_header_name.clone(), "true".parse().unwrap());
+                        if config.counts_replays() {
+                            headers.insert("idempotency-replay-count", replays.into());
+                        }
+                        // The response is served now, from a cache
+                        let date = httpdate::fmt_http_date(now);
+                        headers.insert(header::DATE, date.parse().unwrap());
+                        headers.insert(header::AGE, info.age.as_secs().into());
+                        if config.original_date_header {
+                            let date = httpdate::fmt_http_date(info.original_timestamp);
+                            headers.insert("idempotency-original-date", date.parse().unwrap());
+                        }
+                        if config.original_timestamp_header {
+                            let timestamp = info
+                                .original_timestamp
+                                .duration_since(UNIX_EPOCH)
+                                .unwrap_or_default();
+                            headers.insert(
+                                "idempotency-original-timestamp",
+                                timestamp.as_secs().into(),
+                            );
+                        }
+                        let stale = config.is_stale(info.age);
+                        if not_modified {
+                            *res.status_mut() = StatusCode::NOT_MODIFIED;
+                            *res.body_mut() = Body::empty();
+                            let headers = res.headers_mut();
+                            headers.remove(header::CONTENT_LENGTH);
+                            headers.remove(header::CONTENT_TYPE);
+                            headers.remove(header::CONTENT_ENCODING);
+                        }
+                        res.extensions_mut().insert(ReplayedResponse);
+                        res.extensions_mut().insert(info);
+                        if let Some(hook) = &config.on_replay {
+                            (hook.0)(&mut res);
+                        }
+                        #[cfg(feature = "audit")]
+                        audit.record(AuditOutcome::Replayed(res.status()));
+                        if stale {
+                            tracing::debug!(
+                                route = route.as_deref(),
+                                "Refreshing stale idempotent response in the background"
+                            );
+                            let context = CacheContext {
+                                storage,
+                                hash: Some(hash.clone()),
+                                ttl_secs,
+                                route,
+                                locked: false,
+                                fingerprint: fingerprint.clone(),
+                                flight: None,
+                                metrics,
+                                #[cfg(feature = "audit")]
+                                audit: Audit::default(),
+                            };
+                            mark_not_fresh(&mut req);
+                            let background = config.background.clone();
+                            let refresh = execute_and_cache::<_, T>(inner, req, context, config);
+                            background.spawn(refresh.in_current_span());
+                        }
+                        return Ok(res);
+                    }
+                    Ok(Lookup::KeyReused) => {
+                        tracing::debug!(
+                            route = route.as_deref(),
+                            "Rejecting request reusing the idempotency key of another request"
+                        );
+                        return match config.report(&IdempotencyError::KeyReused) {
+                            ErrorAction::Default => {
+                                let res = config.key_reused_response();
+                                #[cfg(feature = "audit")]
+                                audit.record(AuditOutcome::Rejected(res.status()));
+                                Ok(res)
+                            }
+                            ErrorAction::Forward => inner.call(req).await,
+                            ErrorAction::Respond(res) => Ok(res),
+                        };
+                    }
+                    Ok(Lookup::Exhausted(status)) => {
+                        tracing::debug!(
+                            route = route.as_deref(),
+                            "Rejecting request whose response was replayed too many times"
+                        );
+                        let res = config.replay_limit_response(status);
+                        #[cfg(feature = "audit")]
+                        audit.record(AuditOutcome::Rejected(res.status()));
                         return Ok(res);
                     }
-                    Ok(None) => {} // No cached response, continue
+                    Ok(Lookup::Tombstone(status)) => {
+                        tracing::debug!(
+                            route = route.as_deref(),
+                            "Rejecting request whose original response was not cached"
+                        );
+                        let res = config.tombstone_response(status);
+                        #[cfg(feature = "audit")]
+                        audit.record(AuditOutcome::Rejected(res.status()));
+                        return Ok(res);
+                    }
+                    Ok(Lookup::InFlight(started_at)) => {
+                        metrics.conflict(latency);
+                        match config.report(&IdempotencyError::ConcurrentRequest) {
+                            ErrorAction::Default => {}
+                            ErrorAction::Forward => return inner.call(req).await,
+                            ErrorAction::Respond(res) => return Ok(res),
+                        }
+                        let mut expected = Duration::ZERO;
+                        let status = match config.on_conflict {
+                            ConflictBehavior::Passthrough => {
+                                tracing::debug!(
+                                    route = route.as_deref(),
+                                    "Forwarding request with an in-flight idempotency key"
+                                );
+                                mark_not_fresh(&mut req);
+                                let res = inner.call(req).await;
+                                #[cfg(feature = "audit")]
+                                if let Ok(res) = &res {
+                                    let status = res.status();
+                                    audit.record(AuditOutcome::Executed {
+                                        status,
+                                        cached: false,
+                                    });
+                                }
+                                return res;
+                            }
+                            ConflictBehavior::Reject(status) => {
+                                tracing::debug!(
+                                    route = route.as_deref(),
+                                    "Rejecting request with an in-flight idempotency key"
+                                );
+                                status
+                            }
+                            ConflictBehavior::TooEarly(duration) => {
+                                tracing::debug!(
+                                    route = route.as_deref(),
+                                    "Rejecting request with an in-flight idempotency key as too early"
+                                );
+                                expected = duration;
+                                StatusCode::TOO_EARLY
+                            }
+                            ConflictBehavior::Wait(_) => {
+                                tracing::debug!(
+                                    route = route.as_deref(),
+                                    "Timed out waiting for an in-flight idempotency key"
+                                );
+                                StatusCode::CONFLICT
+                            }
+                        };
+                        let now = config.now();
+                        let retry_after = match &config.rejection_cache {
+                            Some(cache) => cache.insert(hash, status, started_at, now),
+                            None => Duration::ZERO,
+                        };
+                        // The time left until the original request is expected to complete
+                        let elapsed = started_at
+                            .and_then(|started_at| now.duration_since(started_at).ok())
+                            .unwrap_or_default();
+                        let retry_after = retry_after.max(expected.saturating_sub(elapsed));
+                        #[cfg(feature = "audit")]
+                        audit.record(AuditOutcome::Rejected(status));
+                        return Ok(config.conflict_response(status, retry_after));
+                    }
+                    Ok(Lookup::Miss | Lookup::Locked) => {
+                        // No cached response, continue
+                        metrics.miss(latency);
+                        tracing::debug!(route = route.as_deref(), "No cached idempotent response");
+                    }
                     Err(err) => {
-                        tracing::error!("Failed to check idempotent cached response: {err:?}");
-                        // Continue without cache
+                        tracing::error!(
+                            route = route.as_deref(),
+                            "Failed to check idempotent cached response: {err:?}"
+                        );
+                        match config.report(&err) {
+                            ErrorAction::Default => {
+                                let store_error = matches!(err, IdempotencyError::Store { .. });
+                                if let (true, Some(res)) =
+                                    (store_error, config.store_error_response())
+                                {
+                                    return Ok(res);
+                                }
+                                // Continue without cache
+                            }
+                            ErrorAction::Forward => return inner.call(req).await,
+                            ErrorAction::Respond(res) => return Ok(res),
+                        }
                     }
                 }
             }
 
-            let res = inner.call(req).await?;
-            let status_code = res.status();
-            if !config.ignored_res_status_codes.contains(&status_code) {
-                if let Some(hash) = &hash {
-                    let (res, response_bytes) = response_to_bytes(res).await;
-
-                    #[cfg(feature = "layered-store")]
-                    let result = session
-                        .set(
-                            &hash,
-                            &response_bytes,
-                            Some(config.body_cache_ttl_secs),
-                            config.layered_hot_cache_ttl_secs,
-                        )
-                        .await;
-                    #[cfg(not(feature = "layered-store"))]
-                    let result = session
-                        .set(
-                            &hash,
-                            &response_bytes,
-                            Some(config.body_cache_ttl_secs),
-                            None,
-                        )
-                        .await;
+            let context = CacheContext {
+                storage,
+                hash,
+                ttl_secs,
+                route,
+                locked,
+                fingerprint,
+                flight,
+                metrics,
+                #[cfg(feature = "audit")]
+                audit,
+            };
+            let background = config.background.clone();
+            let execution = execute_and_cache::<_, T>(inner, req, context, config);
+            if complete_on_disconnect {
+                // The spawned task keeps running, and caches the response, even if this future
+                // is dropped because the client went away.
+                match background.spawn(execution).await {
+                    Ok(result) => result,
+                    Err(err) => std::panic::resume_unwind(err.into_panic()),
+                }
+            } else {
+                execution.await
+            }
+        };
 
-                    if let Err(err) = result {
-                        tracing::error!("Failed to cache idempotent response: {err:?}");
-                    }
+        Box::pin(future.instrument(span))
+    }
+}
 
-                    return Ok(res);
+/// Tells the handler, through the [`Idempotency`] extractor, that `req` is not the first
+/// execution of the request.
+fn mark_not_fresh(req: &mut Request) {
+    if let Some(control) = req.extensions_mut().get_mut::<Idempotency>() {
+        control.set_fresh(false);
+    }
+}
+
+/// Everything needed to cache the response of a request once the handler completes.
+struct CacheContext<T: IdempotencyStore> {
+    storage: T,
+    hash: Option<String>,
+    ttl_secs: i64,
+    route: Option<String>,
+    /// Whether an in-flight marker was stored under `hash`.
+    locked: bool,
+    /// The request hash, in hashing mode.
+    fingerprint: Option<String>,
+    /// Set when requests with the same key wait for this execution.
+    flight: Option<FlightGuard>,
+    metrics: Metrics,
+    /// Records how the request was handled.
+    #[cfg(feature = "audit")]
+    audit: Audit,
+}
+
+/// Calls the inner service and caches its response.
+async fn execute_and_cache<S, T>(
+    mut inner: S,
+    req: Request,
+    context: CacheContext<T::Store>,
+    config: IdempotentOptions,
+) -> Result<Response, S::Error>
+where
+    S: Service<Request, Response = Response>,
+    T: Backend,
+{
+    let CacheContext {
+        storage,
+        hash,
+        ttl_secs,
+        route,
+        locked,
+        fingerprint,
+        flight,
+        metrics,
+        #[cfg(feature = "audit")]
+        audit,
+    } = context;
+
+    let control = req.extensions().get::<Idempotency>().cloned();
+    let started = Instant::now();
+    let in_flight = match (metrics.stats(), &hash) {
+        (Some(stats), Some(hash)) => Some(stats.start(hash, metrics.route(), config.now())),
+        _ => None,
+    };
+    let res = inner.call(req).await;
+    drop(in_flight);
+    if let (true, Some(hash), Some(cache)) = (locked, &hash, &config.rejection_cache) {
+        cache.complete(hash, started.elapsed());
+    }
+    let mut res = match res {
+        Ok(res) => res,
+        Err(err) => {
+            if let (true, Some(hash)) = (locked, &hash) {
+                release_lock(hash, &storage, &config, &metrics, route.as_deref()).await;
+            }
+            return Err(err);
+        }
+    };
+    let response_ttl = config.response_ttl(res.headers_mut());
+
+    let directive = res
+        .extensions()
+        .get::<IdempotencyDirective>()
+        .copied()
+        .or_else(|| control.and_then(|control| control.directive()));
+    if config.caches(&res) && directive != Some(IdempotencyDirective::NoStore) {
+        if let Some(hash) = &hash {
+            let ttl_secs = match directive {
+                Some(IdempotencyDirective::ExpireAfter(secs)) => secs,
+                _ => res
+                    .extensions()
+                    .get::<IdempotencyTtl>()
+                    .map(IdempotencyTtl::as_secs)
+                    .or(response_ttl)
+                    .unwrap_or(ttl_secs),
+            };
+            let (res, response_bytes) =
+                serialize_response(res, &config, fingerprint.as_deref(), ttl_secs).await;
+            let Some(response_bytes) = response_bytes else {
+                tracing::debug!(
+                    route = route.as_deref(),
+                    "Not caching idempotent response exceeding the body limit"
+                );
+                if locked {
+                    release_lock(hash, &storage, &config, &metrics, route.as_deref()).await;
                 }
+                #[cfg(feature = "audit")]
+                audit.record(AuditOutcome::Executed {
+                    status: res.status(),
+                    cached: false,
+                });
+                return Ok(res);
+            };
+
+            // With write-behind, the response is returned before being stored
+            let permit = match &config.write_behind {
+                Some(write_behind) => match write_behind.try_reserve() {
+                    Some(permit) => Some(permit),
+                    None => {
+                        tracing::warn!(
+                            route = route.as_deref(),
+                            "Dropping idempotent response write: the write-behind queue is full"
+                        );
+                        metrics.write_dropped();
+                        if locked {
+                            release_lock(hash, &storage, &config, &metrics, route.as_deref()).await;
+                        }
+                        #[cfg(feature = "audit")]
+                        audit.record(AuditOutcome::Executed {
+                            status: res.status(),
+                            cached: false,
+                        });
+                        return Ok(res);
+                    }
+                },
+                None => None,
+            };
+            let context = CacheContext {
+                storage,
+                hash: Some(hash.clone()),
+                ttl_secs,
+                route,
+                locked,
+                fingerprint,
+                flight,
+                metrics,
+                #[cfg(feature = "audit")]
+                audit,
+            };
+            let background = config.background.clone();
+            let write = write_response::<T>(context, response_bytes, res.status(), config);
+            match permit {
+                Some(permit) => {
+                    let write = async move {
+                        write.await;
+                        drop(permit);
+                    };
+                    background.spawn(write.in_current_span());
+                }
+                None => write.await,
             }
 
-            Ok(res)
-        })
+            return Ok(res);
+        }
+    }
+
+    if let (true, Some(hash)) = (locked, &hash) {
+        release_lock(hash, &storage, &config, &metrics, route.as_deref()).await;
+    }
+    #[cfg(feature = "audit")]
+    audit.record(AuditOutcome::Executed {
+        status: res.status(),
+        cached: false,
+    });
+
+    Ok(res)
+}
+
+/// Stores the serialized response `response_bytes` under the key of `context`, retrying
+/// failed writes as configured.
+async fn write_response<T: Backend>(
+    context: CacheContext<T::Store>,
+    response_bytes: Vec<u8>,
+    status: StatusCode,
+    config: IdempotentOptions,
+) {
+    let CacheContext {
+        storage,
+        hash,
+        ttl_secs,
+        route,
+        locked,
+        flight,
+        metrics,
+        #[cfg(feature = "audit")]
+        audit,
+        ..
+    } = context;
+    let Some(hash) = &hash else { return };
+    #[cfg(not(feature = "audit"))]
+    let _ = status;
+
+    let key = config.store_key::<T::Store>(hash);
+    let write_started = Instant::now();
+    let started = Instant::now();
+    let mut result = storage.set(&key, response_bytes.clone(), ttl_secs).await;
+    metrics.store_latency(StoreOperation::Set, started.elapsed());
+    let mut backoff = config.write_retry_backoff;
+    for attempt in 1..=config.write_retries {
+        let Err(err) = &result else { break };
+        tracing::warn!(
+            route = route.as_deref(),
+            "Failed to cache idempotent response, retrying ({attempt}/{}): {err:?}",
+            config.write_retries
+        );
+        tokio::time::sleep(backoff).await;
+        backoff = backoff.saturating_mul(2);
+        let started = Instant::now();
+        result = storage.set(&key, response_bytes.clone(), ttl_secs).await;
+        metrics.store_latency(StoreOperation::Set, started.elapsed());
+    }
+
+    #[cfg(feature = "audit")]
+    audit.record(AuditOutcome::Executed {
+        status,
+        cached: result.is_ok(),
+    });
+    match result {
+        Ok(()) => {
+            metrics.store_write(write_started.elapsed());
+            if let Some(flight) = flight {
+                flight.complete(Bytes::from(response_bytes.clone()));
+            }
+            #[cfg(feature = "front-cache")]
+            if let Some(cache) = &config.front_cache {
+                let bytes = Bytes::from(response_bytes.clone());
+                cache.insert(T::scope(&storage), hash, bytes, ttl_secs);
+            }
+            if let Some(hook) = &config.replication_hook {
+                (hook.0)(ReplicatedEntry {
+                    session_id: T::scope(&storage),
+                    key: hash.clone(),
+                    hash_len: config.hash_len(),
+                    value: response_bytes,
+                    ttl_secs,
+                });
+            }
+        }
+        Err(source) => {
+            tracing::error!(
+                route = route.as_deref(),
+                "Failed to cache idempotent response: {source:?}"
+            );
+            metrics.store_error(StoreOperation::Set, write_started.elapsed());
+            config.report(&IdempotencyError::Store {
+                operation: StoreOperation::Set,
+                source,
+            });
+            if locked {
+                release_lock(hash, &storage, &config, &metrics, route.as_deref()).await;
+            }
+        }
+    }
+}
+
+/// Removes the in-flight marker stored under `hash`.
+async fn release_lock<T: IdempotencyStore>(
+    hash: &str,
+    storage: &T,
+    config: &IdempotentOptions,
+    metrics: &Metrics,
+    route: Option<&str>,
+) {
+    let started = Instant::now();
+    if let Err(source) = storage.remove(&config.store_key::<T>(hash)).await {
+        tracing::error!(
+            route,
+            "Failed to release in-flight idempotency key: {source:?}"
+        );
+        metrics.store_error(StoreOperation::Release, started.elapsed());
+        config.report(&IdempotencyError::Store {
+            operation: StoreOperation::Release,
+            source,
+        });
     }
 }
 
 /// Layer to apply [`IdempotentService`] middleware in `axum`.
 ///
-/// This layer caches responses in a session store and returns the cached response
-/// for identical requests within the configured expiration time.
+/// This layer caches responses in a session store (or any [`IdempotencyStore`], see
+/// [`IdempotentLayer::with_store`]) and returns the cached response for identical requests
+/// within the configured expiration time.
 ///
 /// # Example
 /// ```rust,no_run
@@ -250,7 +1263,7 @@ where
 /// let store = Arc::new(MemoryStore::new());
 ///
 /// let idempotent_options = IdempotentOptions::default().expire_after(3);
-/// let idempotent_layer = IdempotentLayer::<MemoryStore>::new(idempotent_options);
+/// let idempotent_layer = IdempotentLayer::<MemoryStore>::try_new(idempotent_options).unwrap();
 ///
 /// let app = Router::new()
 ///     .route("/test", get(|| async { "Hello, World!"}))
@@ -262,42 +1275,531 @@ where
 /// axum::serve(listener, app).await.unwrap();
 /// }
 /// ```
-#[derive(Clone, Debug)]
-pub struct IdempotentLayer<T> {
+pub struct IdempotentLayer<T: Backend> {
     config: IdempotentOptions,
-    phantom_data: PhantomData<T>,
+    state: T::State,
 }
 
-impl<T> IdempotentLayer<T> {
-    pub const fn new(config: IdempotentOptions) -> Self {
-        IdempotentLayer {
-            config,
-            phantom_data: PhantomData,
+#[cfg(feature = "session")]
+impl<T: SessionStore> IdempotentLayer<T> {
+    /// Creates a layer keeping entries in the session of each request.
+    ///
+    /// # Panics
+    /// Panics if the options are invalid, as reported by [`IdempotentLayer::try_new`].
+    #[deprecated(note = "use `IdempotentLayer::try_new`, which returns invalid options as errors")]
+    pub fn new(config: IdempotentOptions) -> Self {
+        match Self::try_new(config) {
+            Ok(layer) => layer,
+            Err(err) => panic!("invalid idempotency options: {err}"),
         }
     }
+
+    /// Creates a layer keeping entries in the session of each request, after validating
+    /// `config` with [`IdempotentOptions::build`].
+    ///
+    /// Keys shared by all sessions ([`KeyScope::Global`] and [`KeyScope::Custom`]) are kept
+    /// under a session id derived from the [`hash_seed`](IdempotentOptions::hash_seed), so that
+    /// clients cannot send it as their session cookie, which must then be set.
+    pub fn try_new(config: IdempotentOptions) -> Result<Self, ConfigError> {
+        Ok(IdempotentLayer {
+            config: config.build_for_sessions()?,
+            state: SessionState::new(),
+        })
+    }
+
+    /// Keeps idempotency enabled when no [`Session`](ruts::Session) can be extracted from a
+    /// request.
+    ///
+    /// By default, such requests (e.g. from clients that dropped the session cookie) are
+    /// forwarded without idempotency. With a fallback, requests carrying a direct idempotency
+    /// key (see [`IdempotentOptions::use_idempotency_key_header`]) instead store their entries
+    /// in `store`, scoped according to `scope`, so a missing cookie does not silently
+    /// reintroduce duplicate operations.
+    ///
+    /// Entries of the fallback scopes are kept under a session id derived from the
+    /// [`hash_seed`](IdempotentOptions::hash_seed), which clients cannot send as their session
+    /// cookie.
+    ///
+    /// # Panics
+    /// Panics if the options have no [`hash_seed`](IdempotentOptions::hash_seed).
+    ///
+    /// # Example
+    /// ```rust
+    /// use std::sync::Arc;
+    /// use axum_idempotent::{IdempotentLayer, IdempotentOptions, SessionFallback};
+    /// use ruts::store::memory::MemoryStore;
+    ///
+    /// # let secret = [7; 32];
+    /// let store = Arc::new(MemoryStore::new());
+    /// let options = IdempotentOptions::default()
+    ///     .use_idempotency_key_header(None)
+    ///     .hash_seed(secret);
+    /// let layer = IdempotentLayer::<MemoryStore>::try_new(options).unwrap()
+    ///     .with_session_fallback(store, SessionFallback::ClientIp);
+    /// ```
+    pub fn with_session_fallback(mut self, store: Arc<T>, scope: SessionFallback) -> Self {
+        if self.config.hash_seed.is_none() {
+            panic!(
+                "session fallbacks require a hash seed: {}",
+                ConfigError::MissingHashSeed
+            );
+        }
+        self.state.fallback = Some((store, scope));
+        self
+    }
+
+    /// Sets how requests are handled when no [`Session`](ruts::Session) can be extracted from
+    /// them, which means the `SessionLayer` is missing or misordered.
+    ///
+    /// Defaults to [`MissingSession::Reject`] in debug builds, so the misconfiguration is
+    /// caught in development, and to [`MissingSession::Forward`] in release builds.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::{IdempotentLayer, IdempotentOptions, MissingSession};
+    /// use ruts::store::memory::MemoryStore;
+    ///
+    /// let layer = IdempotentLayer::<MemoryStore>::try_new(IdempotentOptions::default())
+    ///     .unwrap()
+    ///     .on_missing_session(MissingSession::Panic);
+    /// ```
+    pub fn on_missing_session(mut self, behavior: MissingSession) -> Self {
+        self.state.on_missing = behavior;
+        self
+    }
+}
+
+impl<S: IdempotencyStore> IdempotentLayer<StoreBackend<S>> {
+    /// Creates a layer that keeps entries in `store` instead of the request's session.
+    ///
+    /// No session layer is needed, which suits API servers that authenticate with bearer
+    /// tokens rather than cookies. See [`IdempotencyStore`] for how entries are scoped.
+    ///
+    /// # Panics
+    /// Panics if `config` is invalid, see [`Self::try_with_store`], which returns the error
+    /// instead.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::{Router, routing::post};
+    /// use axum_idempotent::{IdempotentLayer, IdempotentOptions};
+    /// # use axum_idempotent::IdempotencyStore;
+    /// # use std::error::Error;
+    /// # #[derive(Clone)]
+    /// # struct RedisStore;
+    /// # impl IdempotencyStore for RedisStore {
+    /// #     type Key = str;
+    /// #     async fn get(&self, _: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+    /// #         Ok(None)
+    /// #     }
+    /// #     async fn set(&self, _: &str, _: Vec<u8>, _: i64) -> Result<(), Box<dyn Error + Send + Sync>> {
+    /// #         Ok(())
+    /// #     }
+    /// #     async fn remove(&self, _: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    /// #         Ok(())
+    /// #     }
+    /// # }
+    /// # let store = RedisStore;
+    ///
+    /// let options = IdempotentOptions::default().use_idempotency_key_header(None);
+    /// let app: Router = Router::new()
+    ///     .route("/payments", post(|| async { "Payment processed" }))
+    ///     .layer(IdempotentLayer::with_store(store, options));
+    /// ```
+    pub fn with_store(store: S, config: IdempotentOptions) -> Self {
+        match Self::try_with_store(store, config) {
+            Ok(layer) => layer,
+            Err(err) => panic!("invalid idempotency options: {err}"),
+        }
+    }
+
+    /// Creates a layer that keeps entries in `store`, after validating `config` with
+    /// [`IdempotentOptions::build`], and checking that keys are not scoped by session
+    /// ([`KeyScope::Session`]), which requires a session store.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum_idempotent::{ConfigError, IdempotentLayer, IdempotentOptions};
+    /// use axum_idempotent::MemoryIdempotencyStore;
+    ///
+    /// let options = IdempotentOptions::default().expire_after(0);
+    /// let result = IdempotentLayer::try_with_store(MemoryIdempotencyStore::new(), options);
+    /// assert_eq!(result.err(), Some(ConfigError::InvalidTtl(0)));
+    /// ```
+    pub fn try_with_store(store: S, config: IdempotentOptions) -> Result<Self, ConfigError> {
+        if let KeyScope::Session = config.key_scope {
+            return Err(ConfigError::SessionScopeWithoutSession);
+        }
+        Ok(IdempotentLayer {
+            config: config.build()?,
+            state: store,
+        })
+    }
+
+    /// Checks that the store of this layer is reachable.
+    ///
+    /// Wire this into a readiness endpoint (e.g. `/healthz`) to stop routing traffic to an
+    /// instance whose requests cannot be deduplicated, which matters most with
+    /// [`StoreErrorPolicy::FailClosed`], where such requests are rejected.
+    ///
+    /// # Example
+    /// ```rust
+    /// use axum::{Router, extract::State, http::StatusCode, routing::get};
+    /// use axum_idempotent::{IdempotentLayer, IdempotentOptions, MemoryIdempotencyStore};
+    ///
+    /// let options = IdempotentOptions::default().use_idempotency_key_header(None);
+    /// let layer = IdempotentLayer::with_store(MemoryIdempotencyStore::new(), options);
+    ///
+    /// let app: Router = Router::new()
+    ///     .route(
+    ///         "/healthz",
+    ///         get(|State(layer): State<IdempotentLayer<_>>| async move {
+    ///             match layer.health_check().await {
+    ///                 Ok(()) => StatusCode::OK,
+    ///                 Err(_) => StatusCode::SERVICE_UNAVAILABLE,
+    ///             }
+    ///         }),
+    ///     )
+    ///     .with_state(layer.clone());
+    /// ```
+    pub async fn health_check(&self) -> Result<(), IdempotencyError> {
+        self.manager().health_check().await
+    }
+
+    /// Returns an [`IdempotencyManager`] for the entries stored by this layer.
+    pub fn manager(&self) -> IdempotencyManager<S> {
+        IdempotencyManager::new(self.state.clone(), &self.config)
+    }
+}
+
+impl<T: Backend> IdempotentLayer<T> {
+    /// Returns a [`ShutdownHandle`] to wait for the cache writes this layer performs in the
+    /// background before the process exits.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.config.background.clone()
+    }
+}
+
+impl<T: Backend> Clone for IdempotentLayer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            state: self.state.clone(),
+        }
+    }
+}
+
+impl<T: Backend> fmt::Debug for IdempotentLayer<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IdempotentLayer")
+            .field("config", &self.config)
+            .finish_non_exhaustive()
+    }
 }
 
-impl<S, T> Layer<S> for IdempotentLayer<T> {
+impl<S, T: Backend> Layer<S> for IdempotentLayer<T> {
     type Service = IdempotentService<S, T>;
 
     fn layer(&self, service: S) -> Self::Service {
-        IdempotentService::new(service, self.config.clone())
+        IdempotentService {
+            inner: service,
+            config: self.config.clone(),
+            state: self.state.clone(),
+        }
     }
 }
 
-async fn check_cached_response<T: SessionStore>(
+/// The outcome of looking up a key in the store.
+enum Lookup {
+    /// A response was cached under the key, decoded from the given entry.
+    Hit(Box<CachedResponse>, Bytes),
+    /// The request that first used the key, started at the given time if known, is still
+    /// being processed.
+    InFlight(Option<SystemTime>),
+    /// The response to the request that first used the key was too large to be cached, and
+    /// replays are rejected with the status code.
+    Tombstone(StatusCode),
+    /// The response cached under the key was produced by a request with a different
+    /// fingerprint.
+    KeyReused,
+    /// The response cached under the key was replayed too many times, and further requests
+    /// are rejected with the status code.
+    Exhausted(StatusCode),
+    /// Nothing is stored under the key.
+    Miss,
+    /// Nothing was stored under the key, and its in-flight lock was acquired.
+    Locked,
+}
+
+async fn check_cached_response<T: Backend>(
     hash: impl AsRef<str>,
-    session: &Session<T>,
-) -> Result<Option<Response>, Box<dyn Error + Send + Sync>> {
-    let response_bytes = session.get::<Vec<u8>>(hash.as_ref()).await?;
+    storage: &T::Store,
+    config: &IdempotentOptions,
+    metrics: &Metrics,
+    lock_ttl_secs: Option<i64>,
+) -> Result<Lookup, IdempotencyError> {
+    #[cfg(feature = "front-cache")]
+    if let Some(lookup) = config
+        .front_cache
+        .as_ref()
+        .and_then(|cache| cache.get(T::scope(storage), hash.as_ref()))
+        .and_then(|bytes| decode_lookup(bytes, config.now()))
+    {
+        return Ok(lookup);
+    }
+    let key = config.store_key::<T::Store>(hash.as_ref());
+    let (operation, missing) = match lock_ttl_secs {
+        Some(_) => (StoreOperation::Lock, Lookup::Locked),
+        None => (StoreOperation::Get, Lookup::Miss),
+    };
+    let started = Instant::now();
+    let response_bytes = match lock_ttl_secs {
+        Some(lock_ttl_secs) => {
+            let marker = pending_marker(config.now());
+            let lock = storage.get_or_lock(&key, marker, lock_ttl_secs);
+            lock.await
+        }
+        None => storage.get(&key).await,
+    };
+    metrics.store_latency(operation, started.elapsed());
+    let response_bytes = response_bytes.map_err(|source| {
+        metrics.store_error(operation, started.elapsed());
+        IdempotencyError::Store { operation, source }
+    })?;
 
-    let res = if let Some(bytes) = response_bytes {
-        let response = bytes_to_response(bytes)?;
+    let Some(bytes) = response_bytes else {
+        return Ok(missing);
+    };
+    if let Some(lookup) = decode_lookup(Bytes::from(bytes), config.now()) {
+        return Ok(lookup);
+    }
 
-        Some(response)
-    } else {
-        None
+    // Entries that cannot be decoded are discarded, and the request executed as if none was
+    // found, acquiring the in-flight lock the entry stood in the way of
+    let started = Instant::now();
+    let discarded = match lock_ttl_secs {
+        Some(lock_ttl_secs) => match storage.remove(&key).await {
+            Ok(()) => {
+                let marker = pending_marker(config.now());
+                let lock = storage.set_if_absent(&key, marker, lock_ttl_secs);
+                // Unless a concurrent request got there first
+                lock.await.map(|acquired| {
+                    if acquired {
+                        Lookup::Locked
+                    } else {
+                        Lookup::InFlight(None)
+                    }
+                })
+            }
+            Err(source) => Err(source),
+        },
+        None => storage.remove(&key).await.map(|()| Lookup::Miss),
     };
+    discarded.map_err(|source| {
+        metrics.store_error(StoreOperation::Invalidate, started.elapsed());
+        IdempotencyError::Store {
+            operation: StoreOperation::Invalidate,
+            source,
+        }
+    })
+}
 
-    Ok(res)
+/// Interprets the entry stored under a key, read at `now`, or returns `None` if it cannot be
+/// decoded, e.g. because it is corrupted.
+fn decode_lookup(bytes: Bytes, now: SystemTime) -> Option<Lookup> {
+    if is_pending(&bytes) {
+        return Some(Lookup::InFlight(pending_since(&bytes)));
+    }
+    if let Some(status) = tombstone_status(&bytes) {
+        return Some(Lookup::Tombstone(status));
+    }
+
+    match CachedResponse::decode(&bytes, now) {
+        Ok(cached) => Some(Lookup::Hit(Box::new(cached), bytes)),
+        // Written by a newer release, e.g. during a rolling deploy
+        Err(err) if err.is::<UnsupportedVersion>() => {
+            tracing::debug!("Ignoring cached response: {err}");
+            Some(Lookup::Miss)
+        }
+        Err(err) => {
+            tracing::warn!("Discarding cached response that cannot be decoded: {err}");
+            None
+        }
+    }
+}
+
+/// Whether the response `cached` was produced by a request with the given `fingerprint`.
+///
+/// Responses cached without a fingerprint, or before fingerprints were validated, match any
+/// request.
+fn fingerprint_matches(cached: &CachedResponse, fingerprint: &Option<String>) -> bool {
+    match (&cached.fingerprint, fingerprint) {
+        (Some(cached), Some(fingerprint)) => cached == fingerprint,
+        _ => true,
+    }
+}
+
+/// Records a replay of the response `cached`, decoded from the `entry` stored under `hash`:
+/// counts it, handling keys replayed more than [`IdempotentOptions::max_replays`] times, and
+/// with [`IdempotentOptions::sliding_expiration`], extends the entry by the TTL it was stored
+/// with.
+///
+/// The entry is only updated if it did not change in the meantime (see
+/// [`IdempotencyStore::compare_and_set`]), so concurrent replays are all counted and an entry
+/// removed in the meantime is not stored again. If it changed, the replay is recorded on the
+/// entry read again, as long as it holds the same response.
+async fn record_replay<T: Backend>(
+    mut cached: Box<CachedResponse>,
+    mut entry: Bytes,
+    hash: &str,
+    storage: &T::Store,
+    config: &IdempotentOptions,
+    metrics: &Metrics,
+    ttl_secs: i64,
+) -> Lookup {
+    let key = config.store_key::<T::Store>(hash);
+    for _ in 0..REPLAY_RECORD_ATTEMPTS {
+        let mut replayed = cached.clone();
+        if config.counts_replays() {
+            replayed.replays = replayed.replays.saturating_add(1);
+        }
+        match config.max_replays {
+            Some((max, ReplayLimit::Reject(status))) if replayed.replays > max => {
+                return Lookup::Exhausted(status);
+            }
+            Some((max, ReplayLimit::Reexecute)) if replayed.replays > max => {
+                // Make room for the in-flight lock and the new response
+                let started = Instant::now();
+                if let Err(source) = storage.remove(&key).await {
+                    tracing::error!("Failed to remove exhausted idempotent response: {source:?}");
+                    metrics.store_error(StoreOperation::Invalidate, started.elapsed());
+                }
+                #[cfg(feature = "front-cache")]
+                if let Some(cache) = &config.front_cache {
+                    cache.remove(T::scope(storage), hash);
+                }
+                return Lookup::Miss;
+            }
+            _ => {}
+        }
+
+        let ttl_secs = if config.sliding_expiration {
+            // Extends the entry by the TTL it was stored with, not the one of this request
+            let ttl = replayed
+                .ttl
+                .unwrap_or(Duration::from_secs(ttl_secs.max(0) as u64));
+            replayed.expires_at = Some(config.now() + ttl);
+            ttl.as_secs() as i64
+        } else {
+            replayed
+                .remaining_ttl_secs(config.now())
+                .unwrap_or(ttl_secs)
+        };
+        if ttl_secs <= 0 {
+            return Lookup::Hit(replayed, entry);
+        }
+        let bytes = encode_response(&replayed, config);
+        let started = Instant::now();
+        let stored = storage.compare_and_set(&key, entry.to_vec(), bytes.clone(), ttl_secs);
+        match stored.await {
+            Ok(true) => {
+                #[cfg(feature = "front-cache")]
+                if let Some(cache) = &config.front_cache {
+                    cache.insert(
+                        T::scope(storage),
+                        hash,
+                        Bytes::from(bytes.clone()),
+                        ttl_secs,
+                    );
+                }
+                return Lookup::Hit(replayed, Bytes::from(bytes));
+            }
+            Ok(false) => {}
+            Err(source) => {
+                tracing::warn!("Failed to record the replay of a cached response: {source:?}");
+                metrics.store_error(StoreOperation::Set, started.elapsed());
+                return Lookup::Hit(replayed, entry);
+            }
+        }
+
+        // The entry changed in the meantime, e.g. by a concurrent replay
+        let current = match storage.get(&key).await {
+            Ok(current) => current.and_then(|current| decode_lookup(current.into(), config.now())),
+            Err(source) => {
+                tracing::warn!("Failed to record the replay of a cached response: {source:?}");
+                metrics.store_error(StoreOperation::Get, started.elapsed());
+                return Lookup::Hit(replayed, entry);
+            }
+        };
+        match current {
+            Some(Lookup::Hit(current, current_entry))
+                if current.stored_at == cached.stored_at
+                    && current.fingerprint == cached.fingerprint =>
+            {
+                (cached, entry) = (current, current_entry);
+            }
+            // Removed or replaced, so the response is replayed without being stored again
+            _ => return Lookup::Hit(replayed, entry),
+        }
+    }
+
+    tracing::warn!("Failed to record the replay of a cached response: its entry kept changing");
+    Lookup::Hit(cached, entry)
+}
+
+/// Fetches the body of the response `cached` and its length from the blob store, if it was
+/// uploaded to one (see [`IdempotentOptions::blob_store`]).
+async fn fetch_blob(
+    cached: &CachedResponse,
+    config: &IdempotentOptions,
+    metrics: &Metrics,
+) -> Result<Option<(Body, u64)>, IdempotencyError> {
+    let Some(pointer) = &cached.blob else {
+        return Ok(None);
+    };
+
+    let started = Instant::now();
+    let body = match &config.blob_store {
+        Some((store, _)) => store.0.get(pointer.key.clone()).await,
+        None => Err("Cached response body is in a blob store, but none is configured".into()),
+    };
+    let source = match body {
+        Ok(Some(body)) => return Ok(Some((body, pointer.len))),
+        Ok(None) => MissingBlob(pointer.key.clone()).into(),
+        Err(err) => err,
+    };
+    metrics.store_error(StoreOperation::Get, started.elapsed());
+    Err(IdempotencyError::Store {
+        operation: StoreOperation::Get,
+        source,
+    })
+}
+
+/// Polls the store until the in-flight request for `hash` completes or `timeout` elapses.
+///
+/// If the request completes without caching its response, or its lock expires, the in-flight
+/// lock is acquired in its place, and [`Lookup::Locked`] returned, so that only one of the
+/// waiting requests is executed.
+async fn wait_for_in_flight<T: Backend>(
+    hash: &str,
+    storage: &T::Store,
+    config: &IdempotentOptions,
+    metrics: &Metrics,
+    timeout: Duration,
+) -> Result<Lookup, IdempotencyError> {
+    let deadline = tokio::time::Instant::now() + timeout;
+    let mut started_at = None;
+    loop {
+        let now = tokio::time::Instant::now();
+        if now >= deadline {
+            return Ok(Lookup::InFlight(started_at));
+        }
+        tokio::time::sleep(IN_FLIGHT_POLL_INTERVAL.min(deadline - now)).await;
+
+        let lock_ttl_secs = config.in_flight_lock_ttl_secs;
+        match check_cached_response::<T>(hash, storage, config, metrics, lock_ttl_secs).await? {
+            Lookup::InFlight(since) => started_at = since,
+            lookup => return Ok(lookup),
+        }
+    }
 }
@@ -0,0 +1,221 @@
+use crate::admin::{KeyInfo, KeyState};
+use crate::cached::{
+    CachedResponse, UnsupportedVersion, is_pending, pending_since, tombstone_status,
+};
+use crate::config::IdempotentOptions;
+use crate::error::{IdempotencyError, StoreOperation};
+#[cfg(feature = "front-cache")]
+use crate::front::FrontCache;
+use crate::store::{IdempotencyStore, StoreKey, store_key};
+use std::borrow::Borrow;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A handle to inspect and evict the entries of an [`IdempotencyStore`] outside of requests.
+///
+/// This lets admin endpoints and background jobs act on cached responses, e.g. evicting the
+/// response of a payment after a refund reversed it. The manager is cheap to clone, so it can
+/// be kept in the application state.
+///
+/// Keys are the keys entries are stored under, without the
+/// [`key_prefix`](IdempotentOptions::key_prefix) and
+/// [`namespace`](IdempotentOptions::namespace) of the options, which the manager prepends:
+/// the idempotency key of requests in direct key mode, or the request hash otherwise. With a
+/// custom [`KeyScope`](crate::KeyScope), they start with the length of the scope in bytes and
+/// the scope, as in `{len}:{scope}:{key}` (e.g. `5:alice:{key}`).
+/// Request hashes are given in hex, including to stores with binary keys.
+///
+/// # Example
+/// ```rust
+/// use axum::{Router, extract::{Path, State}, routing::delete};
+/// use axum_idempotent::{IdempotencyManager, IdempotentLayer, IdempotentOptions};
+/// # use axum_idempotent::IdempotencyStore;
+/// # use std::error::Error;
+/// # #[derive(Clone)]
+/// # struct RedisStore;
+/// # impl IdempotencyStore for RedisStore {
+/// #     type Key = str;
+/// #     async fn get(&self, _: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+/// #         Ok(None)
+/// #     }
+/// #     async fn set(&self, _: &str, _: Vec<u8>, _: i64) -> Result<(), Box<dyn Error + Send + Sync>> {
+/// #         Ok(())
+/// #     }
+/// #     async fn remove(&self, _: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+/// #         Ok(())
+/// #     }
+/// # }
+/// # let store = RedisStore;
+///
+/// let options = IdempotentOptions::default().use_idempotency_key_header(None);
+/// let layer = IdempotentLayer::with_store(store, options);
+///
+/// let app: Router = Router::new()
+///     .route(
+///         "/admin/idempotency/{key}",
+///         delete(
+///             |State(manager): State<IdempotencyManager<RedisStore>>, Path(key): Path<String>| async move {
+///                 manager.invalidate(&key).await.is_ok().to_string()
+///             },
+///         ),
+///     )
+///     .with_state(layer.manager());
+/// ```
+#[derive(Clone, Debug)]
+pub struct IdempotencyManager<S> {
+    store: S,
+    key_prefix: String,
+    /// The number of hex digits of the request hashes ending keys, in hashing mode.
+    hash_len: Option<usize>,
+    #[cfg(feature = "front-cache")]
+    front_cache: Option<FrontCache>,
+}
+
+impl<S: IdempotencyStore> IdempotencyManager<S> {
+    /// Creates a manager for the entries stored in `store` by a layer configured with `options`.
+    pub fn new(store: S, options: &IdempotentOptions) -> Self {
+        Self {
+            store,
+            key_prefix: options.entry_prefix(),
+            hash_len: options.hash_len(),
+            #[cfg(feature = "front-cache")]
+            front_cache: options.front_cache.clone(),
+        }
+    }
+
+    /// Removes the entry stored under `key`, so the next request with it is executed again.
+    pub async fn invalidate(&self, key: &str) -> Result<(), IdempotencyError> {
+        let key = format!("{}{key}", self.key_prefix);
+        #[cfg(feature = "front-cache")]
+        if let Some(cache) = &self.front_cache {
+            cache.remove(None, &key);
+        }
+        let key = store_key::<S::Key>(&key, self.hash_len);
+        self.store.remove(&key).await.map_err(invalidate_error)
+    }
+
+    /// Removes every entry whose key starts with `prefix`, e.g. all the entries of a scope.
+    ///
+    /// This fails unless the store implements [`IdempotencyStore::remove_prefix`]. Request
+    /// hashes stored in binary form do not match prefixes including part of them.
+    pub async fn invalidate_prefix(&self, prefix: &str) -> Result<(), IdempotencyError> {
+        let prefix = format!("{}{prefix}", self.key_prefix);
+        #[cfg(feature = "front-cache")]
+        if let Some(cache) = &self.front_cache {
+            cache.remove_prefix(None, &prefix);
+        }
+        self.store
+            .remove_prefix(S::Key::from_text(&prefix))
+            .await
+            .map_err(invalidate_error)
+    }
+
+    /// Checks that the store is reachable, e.g. for a readiness endpoint.
+    pub async fn health_check(&self) -> Result<(), IdempotencyError> {
+        self.store
+            .ping()
+            .await
+            .map_err(|source| IdempotencyError::Store {
+                operation: StoreOperation::Ping,
+                source,
+            })
+    }
+
+    /// Returns the key the entry of `key` is stored under.
+    fn store_key(&self, key: &str) -> <S::Key as ToOwned>::Owned {
+        let key = format!("{}{key}", self.key_prefix);
+        store_key::<S::Key>(&key, self.hash_len).into_owned()
+    }
+
+    /// Returns the response cached under `key`, if any.
+    ///
+    /// Keys of requests still being processed, or whose response was too large to be cached
+    /// (see [`OversizedResponse`](crate::OversizedResponse)), have no cached response. Neither
+    /// have entries written in a format version unknown to this release.
+    pub async fn get_cached(&self, key: &str) -> Result<Option<CachedResponse>, IdempotencyError> {
+        let bytes = self
+            .store
+            .get(self.store_key(key).borrow())
+            .await
+            .map_err(|source| IdempotencyError::Store {
+                operation: StoreOperation::Get,
+                source,
+            })?;
+
+        match bytes {
+            Some(bytes) if !is_pending(&bytes) && tombstone_status(&bytes).is_none() => {
+                match CachedResponse::from_bytes(&bytes) {
+                    Ok(cached) => Ok(Some(cached)),
+                    Err(err) if err.is::<UnsupportedVersion>() => Ok(None),
+                    Err(err) => Err(IdempotencyError::Serialization(err)),
+                }
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the metadata of the entry stored under `key`, if any: whether its request
+    /// completed or is in flight, the status code, creation time, remaining TTL, fingerprint
+    /// and replay count of its response.
+    ///
+    /// The body of the response is only included with `include_body`. Entries written in a
+    /// format version unknown to this release are not returned.
+    pub async fn inspect(
+        &self,
+        key: &str,
+        include_body: bool,
+    ) -> Result<Option<KeyInfo>, IdempotencyError> {
+        let bytes = self
+            .store
+            .get(self.store_key(key).borrow())
+            .await
+            .map_err(|source| IdempotencyError::Store {
+                operation: StoreOperation::Get,
+                source,
+            })?;
+        let Some(bytes) = bytes else {
+            return Ok(None);
+        };
+        let timestamp = |time: SystemTime| {
+            time.duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        };
+
+        if is_pending(&bytes) {
+            let mut info = KeyInfo::new(key, KeyState::InFlight);
+            info.created_at = pending_since(&bytes).map(timestamp);
+            return Ok(Some(info));
+        }
+        if let Some(status) = tombstone_status(&bytes) {
+            let mut info = KeyInfo::new(key, KeyState::Oversized);
+            info.status = Some(status);
+            return Ok(Some(info));
+        }
+        let cached = match CachedResponse::from_bytes(&bytes) {
+            Ok(cached) => cached,
+            Err(err) if err.is::<UnsupportedVersion>() => return Ok(None),
+            Err(err) => return Err(IdempotencyError::Serialization(err)),
+        };
+
+        let mut info = KeyInfo::new(key, KeyState::Completed);
+        info.status = Some(cached.status);
+        info.created_at = Some(timestamp(cached.stored_at));
+        info.expires_at = cached.expires_at.map(timestamp);
+        info.ttl_remaining = cached
+            .expires_at
+            .map(|expires_at| timestamp(expires_at).saturating_sub(timestamp(SystemTime::now())));
+        info.fingerprint = cached.fingerprint;
+        info.replays = cached.replays;
+        if include_body {
+            info.body = Some(cached.body);
+        }
+        Ok(Some(info))
+    }
+}
+
+fn invalidate_error(source: Box<dyn std::error::Error + Send + Sync>) -> IdempotencyError {
+    IdempotencyError::Store {
+        operation: StoreOperation::Invalidate,
+        source,
+    }
+}
@@ -0,0 +1,383 @@
+use crate::store::IdempotencyStore;
+use base64::Engine;
+use base64::prelude::BASE64_STANDARD;
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+use vmemcached::driver::{self, RetrievalCommand};
+use vmemcached::{Client, Status};
+
+/// The longest key memcached accepts.
+const MAX_KEY_LEN: usize = 250;
+
+/// The prefix of the keys stored under their hash. Keys starting with it are hashed as well, so
+/// no key is stored under the hash of another.
+const HASHED_KEY_PREFIX: &str = "#h:";
+
+/// The longest relative expiration time memcached accepts: longer ones are taken as a Unix time.
+const MAX_RELATIVE_EXPIRATION: i64 = 30 * 24 * 60 * 60;
+
+/// How many times [`get_or_lock`](IdempotencyStore::get_or_lock) tries to add the lock, or read
+/// the entry standing in its way, before giving up.
+const MAX_LOCK_ATTEMPTS: usize = 3;
+
+/// How long the CAS token of a lock is kept after the lock expired, if the request holding it
+/// neither stored its response nor released it, e.g. because it was dropped.
+const LOCK_TOKEN_RETENTION: Duration = Duration::from_secs(60 * 60);
+
+/// A memcached [`IdempotencyStore`] implementation, on a `vmemcached` client.
+///
+/// Entries are stored Base64-encoded, since the client stores values as JSON, and expired by
+/// memcached. In-flight locks are acquired with `add`, which memcached refuses (`NOT_STORED`)
+/// when the key already holds an entry, so concurrent requests cannot both acquire one. The
+/// CAS token of an acquired lock is kept, and the response replaces the lock, or the lock is
+/// released, with `cas` against it, so a request whose lock expired cannot overwrite or delete
+/// the lock another request acquired since. Keys
+/// longer than memcached's 250-byte limit, or holding whitespace, control or non-ASCII bytes the
+/// text protocol cannot carry, are stored under their BLAKE3 hash, prefixed with `#h:`.
+///
+/// memcached cannot list its keys, so entries cannot be removed or listed by prefix.
+///
+/// This requires the `memcached` feature.
+///
+/// # Example
+/// ```rust,no_run
+/// use axum::{Router, routing::post};
+/// use axum_idempotent::{IdempotentLayer, IdempotentOptions, MemcachedStore};
+/// use vmemcached::{Client, ConnectionManager, Pool, Settings};
+///
+/// #[tokio::main]
+/// async fn main() {
+/// let manager = ConnectionManager::try_from("memcache://127.0.0.1:11211").unwrap();
+/// // `vmemcached` rejects idle connections when they are checked
+/// let pool = Pool::builder()
+///     .max_size(16)
+///     .test_on_check_out(false)
+///     .build(manager)
+///     .await
+///     .unwrap();
+/// let store = MemcachedStore::new(Client::with_pool(pool, Settings::new()));
+///
+/// let options = IdempotentOptions::default().use_idempotency_key_header(None);
+/// let app: Router = Router::new()
+///     .route("/payments", post(|| async { "Payment processed" }))
+///     .layer(IdempotentLayer::with_store(store, options));
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct MemcachedStore {
+    client: Client,
+    /// The CAS tokens of the locks acquired through this store, with when they were acquired
+    /// and their TTL, by memcached key.
+    locks: Arc<Mutex<HashMap<String, LockToken>>>,
+}
+
+impl MemcachedStore {
+    /// Creates a store keeping entries in the memcached servers of `client`.
+    ///
+    /// Entries expire after their TTL. memcached takes expiration times above 30 days as a
+    /// Unix time, so longer TTLs are sent as the Unix time the entry expires at, according to
+    /// the clock of this host. TTLs below a second are rounded up to one, as 0 would never
+    /// expire.
+    pub fn new(client: Client) -> Self {
+        Self {
+            client,
+            locks: Arc::default(),
+        }
+    }
+
+    /// Gets the entry stored under the memcached key `key`, with its CAS token.
+    async fn gets(
+        &self,
+        key: &str,
+    ) -> Result<Option<(Vec<u8>, u64)>, Box<dyn Error + Send + Sync>> {
+        let conn = self.client.get_connection().await?;
+        let settings = self.client.get_settings();
+        let values = driver::retrieve(conn, RetrievalCommand::Gets, &[key], settings).await?;
+        let Some(value) = values.and_then(|values| values.into_iter().next()) else {
+            return Ok(None);
+        };
+        let cas = value.cas.ok_or("memcached returned no CAS token")?;
+        let value: String = serde_json::from_slice(&value.data)?;
+
+        Ok(Some((BASE64_STANDARD.decode(value)?, cas)))
+    }
+
+    /// Stores `value` under the memcached key `key` with `cas`, if the entry is still the one
+    /// `cas` was read with. An `exptime` below 0 expires the entry at once.
+    async fn cas(
+        &self,
+        key: &str,
+        value: &[u8],
+        exptime: i64,
+        cas: u64,
+    ) -> Result<Status, Box<dyn Error + Send + Sync>> {
+        // `vmemcached` has no `cas` command, so it is sent on one of its connections, encoded
+        // like the values of its other commands
+        let data = serde_json::to_vec(&BASE64_STANDARD.encode(value))?;
+        let mut conn = self.client.get_connection().await?;
+        let command = format!("cas {key} 0 {exptime} {} {cas}\r\n", data.len());
+        conn.write_all(command.as_bytes()).await?;
+        conn.write_all(&data).await?;
+        conn.write_all(b"\r\n").await?;
+        conn.flush().await?;
+        let mut line = String::new();
+        conn.read_line(&mut line).await?;
+
+        match line.trim_end() {
+            "STORED" => Ok(Status::Stored),
+            "EXISTS" => Ok(Status::Exists),
+            "NOT_FOUND" => Ok(Status::NotFound),
+            line => Err(format!("unexpected response from memcached: {line}").into()),
+        }
+    }
+}
+
+/// The CAS token of a lock acquired through a [`MemcachedStore`].
+#[derive(Clone, Copy, Debug)]
+struct LockToken {
+    cas: u64,
+    acquired_at: Instant,
+    ttl: Duration,
+}
+
+impl LockToken {
+    /// Whether the token can be forgotten, its lock having expired long ago.
+    fn is_stale(&self) -> bool {
+        self.acquired_at.elapsed() > self.ttl.saturating_add(LOCK_TOKEN_RETENTION)
+    }
+}
+
+impl IdempotencyStore for MemcachedStore {
+    type Key = str;
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        let value: Option<String> = self.client.get(&*storage_key(key)).await?;
+
+        Ok(value
+            .map(|value| BASE64_STANDARD.decode(value))
+            .transpose()?)
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let key = storage_key(key);
+        // Even if the lock expired, as another request may have acquired it since
+        let token = self.locks.lock().unwrap().get(&*key).copied();
+        let Some(LockToken { cas, .. }) = token else {
+            let value = BASE64_STANDARD.encode(value);
+            return match self.client.set(&*key, value, expiration(ttl_secs)).await? {
+                Status::Stored => Ok(()),
+                status => Err(format!("memcached did not store the entry: {status}").into()),
+            };
+        };
+
+        // The lock is only replaced if no other request took it over since it was acquired
+        let exptime = expiration(ttl_secs).as_secs() as i64;
+        let status = match self.cas(&key, &value, exptime, cas).await? {
+            // Unless it expired, and nothing took its place
+            Status::NotFound => {
+                let value = BASE64_STANDARD.encode(value);
+                self.client.add(&*key, value, expiration(ttl_secs)).await?
+            }
+            status => status,
+        };
+        match status {
+            Status::Stored => {
+                self.locks.lock().unwrap().remove(&*key);
+                Ok(())
+            }
+            // The token is kept, so that releasing the lock leaves the new one in place
+            status => Err(format!("the in-flight lock was taken over: {status}").into()),
+        }
+    }
+
+    async fn set_if_absent(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        // `add` is refused if the key already holds an entry
+        let value = BASE64_STANDARD.encode(value);
+        match self
+            .client
+            .add(&*storage_key(key), value, expiration(ttl_secs))
+            .await?
+        {
+            Status::Stored => Ok(true),
+            Status::NotStored | Status::Exists => Ok(false),
+            status => Err(format!("unexpected response from memcached: {status}").into()),
+        }
+    }
+
+    async fn get_or_lock(
+        &self,
+        key: &str,
+        marker: Vec<u8>,
+        lock_ttl_secs: i64,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        // Adding first saves a round trip for new keys
+        for _ in 0..MAX_LOCK_ATTEMPTS {
+            if self
+                .set_if_absent(key, marker.clone(), lock_ttl_secs)
+                .await?
+            {
+                let key = storage_key(key);
+                if let Some((_, cas)) = self.gets(&key).await?.filter(|(lock, _)| *lock == marker) {
+                    let token = LockToken {
+                        cas,
+                        acquired_at: Instant::now(),
+                        ttl: Duration::from_secs(lock_ttl_secs.max(0) as u64),
+                    };
+                    let mut locks = self.locks.lock().unwrap();
+                    locks.retain(|_, token| !token.is_stale());
+                    locks.insert(key.into_owned(), token);
+                }
+                return Ok(None);
+            }
+            // Unless the entry expired or was removed in the meantime
+            if let Some(entry) = self.get(key).await? {
+                return Ok(Some(entry));
+            }
+        }
+
+        Err(format!("memcached refused the lock of {key:?} {MAX_LOCK_ATTEMPTS} times, without holding an entry").into())
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        current: Vec<u8>,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let key = storage_key(key);
+        let Some((_, cas)) = self
+            .gets(&key)
+            .await?
+            .filter(|(entry, _)| *entry == current)
+        else {
+            return Ok(false);
+        };
+        let exptime = expiration(ttl_secs).as_secs() as i64;
+
+        Ok(self.cas(&key, &value, exptime, cas).await? == Status::Stored)
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let key = storage_key(key);
+        let lock = self.locks.lock().unwrap().remove(&*key);
+        match lock {
+            // A lock is only released, by expiring it, if no other request took it over
+            Some(LockToken { cas, .. }) => {
+                self.cas(&key, b"", -1, cas).await?;
+            }
+            None => {
+                self.client.delete(&*key).await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.client.version().await?;
+
+        Ok(())
+    }
+}
+
+/// The memcached key `key` is stored under.
+fn storage_key(key: &str) -> Cow<'_, str> {
+    // Keys are sent as a token of the text protocol, so they must not split or end a command
+    let printable = key.bytes().all(|byte| matches!(byte, 0x21..=0x7e));
+    if !key.is_empty()
+        && key.len() <= MAX_KEY_LEN
+        && printable
+        && !key.starts_with(HASHED_KEY_PREFIX)
+    {
+        return key.into();
+    }
+    format!(
+        "{HASHED_KEY_PREFIX}{}",
+        blake3::hash(key.as_bytes()).to_hex()
+    )
+    .into()
+}
+
+/// The expiration time sent to memcached for entries expiring in `ttl_secs` seconds.
+fn expiration(ttl_secs: i64) -> Duration {
+    // 0 would never expire
+    let ttl_secs = ttl_secs.max(1);
+    if ttl_secs <= MAX_RELATIVE_EXPIRATION {
+        return Duration::from_secs(ttl_secs as u64);
+    }
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now + Duration::from_secs(ttl_secs as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_storage_key() {
+        assert_eq!(storage_key("idempotency:key"), "idempotency:key");
+        let long = "k".repeat(MAX_KEY_LEN + 1);
+        let hashed = storage_key(&long);
+        assert_eq!(
+            hashed,
+            format!("#h:{}", blake3::hash(long.as_bytes()).to_hex())
+        );
+        assert_eq!(storage_key(&long[1..]), &long[1..]);
+
+        // Keys that would split or end a command are hashed
+        for key in ["key noreply", "key\tvalue", "key\r\nflush_all", "kéy", ""] {
+            assert_eq!(
+                storage_key(key),
+                format!("#h:{}", blake3::hash(key.as_bytes()).to_hex())
+            );
+        }
+    }
+
+    #[test]
+    fn test_hashed_keys_do_not_collide() {
+        // Neither the bare hash of a key nor its stored form is stored under that key
+        let long = "k".repeat(MAX_KEY_LEN + 1);
+        let hashed = storage_key(&long).into_owned();
+        let hex = blake3::hash(long.as_bytes()).to_hex();
+        assert_eq!(storage_key(&hex), hex.as_str());
+        assert_ne!(storage_key(&hex), hashed);
+        assert_ne!(storage_key(&hashed), hashed);
+        assert!(storage_key(&hashed).starts_with(HASHED_KEY_PREFIX));
+        assert_eq!(storage_key("#key"), "#key");
+    }
+
+    #[test]
+    fn test_expiration() {
+        assert_eq!(expiration(0), Duration::from_secs(1));
+        assert_eq!(expiration(60), Duration::from_secs(60));
+        assert_eq!(
+            expiration(MAX_RELATIVE_EXPIRATION),
+            Duration::from_secs(MAX_RELATIVE_EXPIRATION as u64)
+        );
+
+        // Longer expirations are sent as a Unix time
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let ttl = MAX_RELATIVE_EXPIRATION + 1;
+        let expires_at = expiration(ttl).as_secs();
+        assert!(
+            (now.as_secs() + ttl as u64..=now.as_secs() + ttl as u64 + 1).contains(&expires_at)
+        );
+    }
+}
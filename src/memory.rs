@@ -0,0 +1,400 @@
+use crate::config::Hook;
+use crate::store::IdempotencyStore;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::mem::size_of;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How many of the least recently used entries are considered when evicting by size.
+const EVICTION_CANDIDATES: usize = 8;
+
+/// How often expired entries are purged on writes, when the store is not over capacity.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+type EvictionHook = dyn Fn(&str, &[u8], EvictionCause) + Send + Sync;
+
+/// Why an entry was evicted from a [`MemoryIdempotencyStore`].
+///
+/// See [`MemoryIdempotencyStore::on_evict`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EvictionCause {
+    /// The entry outlived its TTL.
+    Expired,
+    /// The store was over [`max_entries`](MemoryIdempotencyStore::max_entries) or
+    /// [`max_bytes`](MemoryIdempotencyStore::max_bytes).
+    Capacity,
+}
+
+/// An in-process [`IdempotencyStore`] with bounded capacity, for tests, CLIs and
+/// single-instance services.
+///
+/// Entries expire after their TTL, and expired entries are purged by writes, at most once a
+/// second unless the store is over capacity. Once the store holds more than
+/// [`max_entries`](Self::max_entries) entries or [`max_bytes`](Self::max_bytes) bytes, entries
+/// are evicted, the least recently used first. Evictions can be observed with
+/// [`on_evict`](Self::on_evict). It is unbounded by default. Clones share the same entries.
+///
+/// Unlike session stores, it does not need `ruts` or cookies, and is available without the
+/// `session` feature.
+///
+/// # Example
+/// ```rust
+/// use axum::{Router, routing::post};
+/// use axum_idempotent::{IdempotentLayer, IdempotentOptions, MemoryIdempotencyStore};
+///
+/// let store = MemoryIdempotencyStore::new()
+///     .max_entries(10_000)
+///     .max_bytes(64 * 1024 * 1024)
+///     .on_evict(|key, _, cause| tracing::debug!("Evicted {key} ({cause:?})"));
+/// let options = IdempotentOptions::default().use_idempotency_key_header(None);
+/// let app: Router = Router::new()
+///     .route("/payments", post(|| async { "Payment processed" }))
+///     .layer(IdempotentLayer::with_store(store, options));
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct MemoryIdempotencyStore {
+    max_entries: Option<usize>,
+    max_bytes: Option<usize>,
+    on_evict: Option<Hook<EvictionHook>>,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    entries: HashMap<String, Entry>,
+    /// The keys of the entries, from the least to the most recently used.
+    recency: BTreeMap<u64, String>,
+    /// Incremented on every use of an entry.
+    clock: u64,
+    /// The size of the entries, as counted against `max_bytes`.
+    bytes: usize,
+    /// The entries evicted while the state was locked, reported once it is released.
+    evicted: Vec<(String, Vec<u8>, EvictionCause)>,
+    /// When expired entries were last purged.
+    swept_at: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Vec<u8>,
+    /// `None` for entries whose TTL is too long to be represented, which never expire.
+    expires_at: Option<Instant>,
+    used_at: u64,
+    size: usize,
+}
+
+impl Entry {
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+impl MemoryIdempotencyStore {
+    /// The bytes counted for every entry on top of its key and value, for the bookkeeping of
+    /// the store.
+    pub const ENTRY_OVERHEAD: usize = size_of::<(String, Entry)>() + size_of::<(u64, String)>();
+
+    /// Creates an empty, unbounded store.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Evicts the least recently used entries once the store holds more than `max_entries`.
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.max_entries = Some(max_entries);
+        self
+    }
+
+    /// Evicts entries once they take more than `max_bytes`. Writing an entry larger than
+    /// `max_bytes` fails, and it is not stored.
+    ///
+    /// Each entry counts the memory allocated for its value, twice the length of its key, which
+    /// is also kept to track recency, and [`ENTRY_OVERHEAD`](Self::ENTRY_OVERHEAD) bytes.
+    ///
+    /// To make room, the least recently used entry freeing enough space on its own is evicted,
+    /// among the few least recently used ones, or the largest of them if none does, so that a
+    /// large response does not evict many small ones.
+    pub fn max_bytes(mut self, max_bytes: usize) -> Self {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    /// Sets a callback called with the key, value and cause of every entry evicted because it
+    /// expired or the store was over capacity, including entries too large to be stored.
+    /// Entries that are replaced or removed are not reported.
+    ///
+    /// The callback is called after the store is unlocked, so it may use the store.
+    pub fn on_evict<F>(mut self, callback: F) -> Self
+    where
+        F: Fn(&str, &[u8], EvictionCause) + Send + Sync + 'static,
+    {
+        self.on_evict = Some(Hook(Arc::new(callback)));
+        self
+    }
+
+    /// Returns the number of entries, including expired entries not evicted yet.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    /// Whether the store holds no entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the size of the entries, as counted against [`max_bytes`](Self::max_bytes).
+    pub fn bytes(&self) -> usize {
+        self.state.lock().unwrap().bytes
+    }
+
+    /// Runs `f` on the locked state, then reports the entries it evicted.
+    fn with_state<T>(&self, f: impl FnOnce(&mut State) -> T) -> T {
+        let (result, evicted) = {
+            let mut state = self.state.lock().unwrap();
+            let result = f(&mut state);
+            (result, std::mem::take(&mut state.evicted))
+        };
+        if let Some(on_evict) = &self.on_evict {
+            for (key, value, cause) in evicted {
+                (on_evict.0)(&key, &value, cause);
+            }
+        }
+        result
+    }
+
+    /// Stores `value` under `key` for `ttl_secs` seconds, then evicts entries over capacity.
+    ///
+    /// Fails if the entry is larger than [`max_bytes`](Self::max_bytes), removing the entry
+    /// previously stored under `key`.
+    fn insert(
+        &self,
+        state: &mut State,
+        key: &str,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        state.remove(key);
+        let size = 2 * key.len() + value.capacity() + Self::ENTRY_OVERHEAD;
+        if let Some(max_bytes) = self.max_bytes.filter(|max_bytes| size > *max_bytes) {
+            state
+                .evicted
+                .push((key.to_owned(), value, EvictionCause::Capacity));
+            return Err(format!(
+                "the entry takes {size} bytes, more than the {max_bytes} bytes of the store"
+            )
+            .into());
+        }
+
+        let used_at = state.tick();
+        state.recency.insert(used_at, key.to_owned());
+        state.bytes += size;
+        state.entries.insert(
+            key.to_owned(),
+            Entry {
+                value,
+                expires_at: Instant::now().checked_add(Duration::from_secs(ttl_secs.max(0) as u64)),
+                used_at,
+                size,
+            },
+        );
+
+        let over_entries = |state: &State| {
+            self.max_entries
+                .is_some_and(|max_entries| state.entries.len() > max_entries)
+        };
+        let excess_bytes = |state: &State| {
+            self.max_bytes
+                .map_or(0, |max_bytes| state.bytes.saturating_sub(max_bytes))
+        };
+        // Expired entries go first, and do not wait for the store to fill up to go
+        let now = Instant::now();
+        let sweep_due = state
+            .swept_at
+            .is_none_or(|swept_at| now.duration_since(swept_at) >= SWEEP_INTERVAL);
+        if sweep_due || over_entries(state) || excess_bytes(state) > 0 {
+            state.evict_expired(now);
+        }
+        while over_entries(state) {
+            let Some(key) = state.recency.values().next().cloned() else {
+                break;
+            };
+            state.evict(&key, EvictionCause::Capacity);
+        }
+        loop {
+            let excess = excess_bytes(state);
+            if excess == 0 {
+                break;
+            }
+            // The new entry fits on its own, so there are others to evict
+            let candidates: Vec<_> = state
+                .recency
+                .values()
+                .filter(|candidate| *candidate != key)
+                .take(EVICTION_CANDIDATES)
+                .map(|candidate| (candidate, state.entries[candidate].size))
+                .collect();
+            let victim = candidates
+                .iter()
+                .find(|(_, size)| *size >= excess)
+                .or_else(|| candidates.iter().max_by_key(|(_, size)| *size));
+            let Some((victim, _)) = victim else {
+                break;
+            };
+            let victim = (*victim).clone();
+            state.evict(&victim, EvictionCause::Capacity);
+        }
+        Ok(())
+    }
+}
+
+impl State {
+    fn tick(&mut self) -> u64 {
+        self.clock += 1;
+        self.clock
+    }
+
+    /// Returns the unexpired entry stored under `key`, marking it as used.
+    fn get(&mut self, key: &str) -> Option<&Entry> {
+        if self.entries.get(key)?.is_expired(Instant::now()) {
+            self.evict(key, EvictionCause::Expired);
+            return None;
+        }
+        let used_at = self.tick();
+        let entry = self.entries.get_mut(key)?;
+        self.recency.remove(&entry.used_at);
+        self.recency.insert(used_at, key.to_owned());
+        entry.used_at = used_at;
+        Some(entry)
+    }
+
+    fn remove(&mut self, key: &str) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        self.recency.remove(&entry.used_at);
+        self.bytes -= entry.size;
+        Some(entry)
+    }
+
+    /// Removes the entries expired at `now`.
+    fn evict_expired(&mut self, now: Instant) {
+        let expired: Vec<_> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in expired {
+            self.evict(&key, EvictionCause::Expired);
+        }
+        self.swept_at = Some(now);
+    }
+
+    /// Removes the entry stored under `key`, to be reported as evicted for `cause`.
+    fn evict(&mut self, key: &str, cause: EvictionCause) {
+        if let Some(entry) = self.remove(key) {
+            self.evicted.push((key.to_owned(), entry.value, cause));
+        }
+    }
+}
+
+impl IdempotencyStore for MemoryIdempotencyStore {
+    type Key = str;
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        Ok(self.with_state(|state| state.get(key).map(|entry| entry.value.clone())))
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.with_state(|state| self.insert(state, key, value, ttl_secs))
+    }
+
+    async fn set_if_absent(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.with_state(|state| {
+            if state.get(key).is_some() {
+                return Ok(false);
+            }
+            self.insert(state, key, value, ttl_secs)?;
+            Ok(true)
+        })
+    }
+
+    async fn get_or_lock(
+        &self,
+        key: &str,
+        marker: Vec<u8>,
+        lock_ttl_secs: i64,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        self.with_state(|state| {
+            if let Some(entry) = state.get(key) {
+                return Ok(Some(entry.value.clone()));
+            }
+            self.insert(state, key, marker, lock_ttl_secs)?;
+            Ok(None)
+        })
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        current: Vec<u8>,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.with_state(|state| {
+            if state.get(key).is_none_or(|entry| entry.value != current) {
+                return Ok(false);
+            }
+            self.insert(state, key, value, ttl_secs)?;
+            Ok(true)
+        })
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.state.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn remove_prefix(&self, prefix: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut state = self.state.lock().unwrap();
+        let keys: Vec<_> = state
+            .entries
+            .keys()
+            .filter(|key| key.starts_with(prefix))
+            .cloned()
+            .collect();
+        for key in keys {
+            state.remove(&key);
+        }
+        Ok(())
+    }
+
+    async fn list_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, i64)>, Box<dyn Error + Send + Sync>> {
+        let state = self.state.lock().unwrap();
+        let now = Instant::now();
+        Ok(state
+            .entries
+            .iter()
+            .filter(|(key, entry)| key.starts_with(prefix) && !entry.is_expired(now))
+            .map(|(key, entry)| {
+                let ttl_secs = match entry.expires_at {
+                    Some(expires_at) => (expires_at - now).as_secs_f64().ceil() as i64,
+                    None => i64::MAX,
+                };
+                (key.clone(), ttl_secs)
+            })
+            .collect())
+    }
+}
@@ -0,0 +1,171 @@
+use crate::admin::{IdempotencyStats, StatsEvent};
+use crate::config::Hook;
+use crate::error::StoreOperation;
+use crate::observer::{IdempotencyEvent, IdempotencyObserver};
+use axum::http::Method;
+use std::time::Duration;
+
+/// Records the metrics of a request handled by the middleware.
+///
+/// Metrics are emitted through the `metrics` facade, labelled by `method` and, when the layer
+/// runs after routing, `route`. Without the `metrics` feature, nothing is recorded. Events are
+/// also reported to the [`IdempotencyObserver`], if any, once the key of the request is known,
+/// and counted in the [`IdempotencyStats`], if any.
+#[derive(Clone, Debug)]
+pub(crate) struct Metrics {
+    #[cfg(feature = "metrics")]
+    labels: Vec<(&'static str, String)>,
+    observer: Option<Hook<dyn IdempotencyObserver>>,
+    stats: Option<IdempotencyStats>,
+    route: Option<String>,
+    key: Option<String>,
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+impl Metrics {
+    pub(crate) fn new(
+        method: &Method,
+        route: Option<&str>,
+        observer: Option<Hook<dyn IdempotencyObserver>>,
+        stats: Option<IdempotencyStats>,
+    ) -> Self {
+        #[cfg(feature = "metrics")]
+        {
+            let mut labels = vec![("method", method.to_string())];
+            if let Some(route) = route {
+                labels.push(("route", route.to_owned()));
+            }
+            let route = route.map(str::to_owned);
+            Self {
+                labels,
+                observer,
+                stats,
+                route,
+                key: None,
+            }
+        }
+        #[cfg(not(feature = "metrics"))]
+        Self {
+            observer,
+            stats,
+            route: route.map(str::to_owned),
+            key: None,
+        }
+    }
+
+    /// Sets the key reported to the observer.
+    pub(crate) fn set_key(&mut self, key: &str) {
+        if self.observer.is_some() {
+            self.key = Some(key.to_owned());
+        }
+    }
+
+    /// Counts a response replayed from the cache (`idempotency_cache_hit_total`).
+    pub(crate) fn hit(&self, latency: Duration) {
+        #[cfg(feature = "metrics")]
+        metrics::counter!("idempotency_cache_hit_total", &self.labels).increment(1);
+        self.count(StatsEvent::Hit);
+        self.observe(latency, |observer, event| observer.on_cache_hit(event));
+    }
+
+    /// Counts a request without a cached response (`idempotency_cache_miss_total`).
+    pub(crate) fn miss(&self, latency: Duration) {
+        #[cfg(feature = "metrics")]
+        metrics::counter!("idempotency_cache_miss_total", &self.labels).increment(1);
+        self.count(StatsEvent::Miss);
+        self.observe(latency, |observer, event| observer.on_cache_miss(event));
+    }
+
+    /// Reports a request with an in-flight key.
+    pub(crate) fn conflict(&self, latency: Duration) {
+        self.count(StatsEvent::Conflict);
+        self.observe(latency, |observer, event| observer.on_conflict(event));
+    }
+
+    /// Reports a stored response.
+    pub(crate) fn store_write(&self, latency: Duration) {
+        self.observe(latency, |observer, event| observer.on_store_write(event));
+    }
+
+    /// Counts a response write dropped because the write-behind queue was full
+    /// (`idempotency_write_behind_dropped_total`).
+    pub(crate) fn write_dropped(&self) {
+        #[cfg(feature = "metrics")]
+        metrics::counter!("idempotency_write_behind_dropped_total", &self.labels).increment(1);
+    }
+
+    /// Counts a request with an idempotency key but no key scope
+    /// (`idempotency_scope_missing_total`).
+    pub(crate) fn scope_missing(&self) {
+        #[cfg(feature = "metrics")]
+        metrics::counter!("idempotency_scope_missing_total", &self.labels).increment(1);
+    }
+
+    /// Counts a failed store operation (`idempotency_store_error_total`).
+    pub(crate) fn store_error(&self, operation: StoreOperation, latency: Duration) {
+        #[cfg(feature = "metrics")]
+        metrics::counter!(
+            "idempotency_store_error_total",
+            &self.with_operation(operation)
+        )
+        .increment(1);
+        self.count(StatsEvent::StoreError);
+        self.observe(latency, |observer, event| {
+            observer.on_store_error(event, operation)
+        });
+    }
+    /// Records the latency of a store operation (`idempotency_store_duration_seconds`).
+    pub(crate) fn store_latency(&self, operation: StoreOperation, elapsed: Duration) {
+        #[cfg(feature = "metrics")]
+        metrics::histogram!(
+            "idempotency_store_duration_seconds",
+            &self.with_operation(operation)
+        )
+        .record(elapsed);
+    }
+
+    /// Records the time spent computing the key of a request, including reading its body
+    /// (`idempotency_hash_duration_seconds`).
+    pub(crate) fn hash_latency(&self, elapsed: Duration) {
+        #[cfg(feature = "metrics")]
+        metrics::histogram!("idempotency_hash_duration_seconds", &self.labels).record(elapsed);
+    }
+
+    /// Returns the stats the request is counted in, if any.
+    pub(crate) fn stats(&self) -> Option<&IdempotencyStats> {
+        self.stats.as_ref()
+    }
+
+    /// Returns the route template of the request, when the layer runs after routing.
+    pub(crate) fn route(&self) -> Option<&str> {
+        self.route.as_deref()
+    }
+
+    fn count(&self, event: StatsEvent) {
+        if let Some(stats) = &self.stats {
+            stats.record(event, self.route.as_deref());
+        }
+    }
+
+    fn observe(
+        &self,
+        latency: Duration,
+        callback: impl FnOnce(&dyn IdempotencyObserver, &IdempotencyEvent<'_>),
+    ) {
+        if let (Some(observer), Some(key)) = (&self.observer, &self.key) {
+            let event = IdempotencyEvent {
+                key,
+                route: self.route.as_deref(),
+                latency,
+            };
+            callback(observer.0.as_ref(), &event);
+        }
+    }
+
+    #[cfg(feature = "metrics")]
+    fn with_operation(&self, operation: StoreOperation) -> Vec<(&'static str, String)> {
+        let mut labels = self.labels.clone();
+        labels.push(("operation", operation.to_string()));
+        labels
+    }
+}
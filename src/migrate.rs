@@ -0,0 +1,133 @@
+//! Upgrading stored entries to a newer serialization format.
+//!
+//! Each release reads entries written in at least the previous format version, so a rolling
+//! deploy bumping the format keeps replaying older entries. Entries outliving the next bump,
+//! such as 24-hour payment keys, would then be lost: [`upgrade_entries`] re-encodes them in
+//! the current format ([`FORMAT_VERSION`]), keeping their remaining TTL.
+//!
+//! Run it once every instance writes the current format, so no entries in the previous format
+//! are written anymore. It requires a store implementing [`IdempotencyStore::list_prefix`].
+//!
+//! # Example
+//! ```rust
+//! use axum_idempotent::MemoryIdempotencyStore;
+//! use axum_idempotent::migrate::{FORMAT_VERSION, upgrade_entries};
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let store = MemoryIdempotencyStore::new();
+//! let report = upgrade_entries(&store, 0, FORMAT_VERSION).await.unwrap();
+//! println!("{} entries upgraded", report.upgraded);
+//! # }
+//! ```
+
+use crate::cached::{CachedResponse, format_version};
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+use crate::cached::{compress, compression_of};
+use crate::error::{MigrationError, StoreOperation};
+use crate::store::{IdempotencyStore, StoreKey};
+use std::borrow::Borrow;
+use std::time::{Duration, SystemTime};
+
+/// The format version entries are written in by this release.
+pub const FORMAT_VERSION: u8 = crate::cached::FORMAT_VERSION;
+
+/// The outcome of [`upgrade_entries`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct MigrationReport {
+    /// The number of entries re-encoded.
+    pub upgraded: usize,
+    /// The number of entries left as they were: entries in another version, in-flight markers,
+    /// tombstones, entries that expired during the migration, and values that are not entries.
+    pub skipped: usize,
+}
+
+/// Re-encodes the entries of `store` written in format version `from_version` into
+/// `to_version`, keeping their remaining TTL and compression.
+///
+/// `to_version` must be [`FORMAT_VERSION`], and `from_version` an older version this release
+/// reads: 0 for the unversioned entries of releases before the format was versioned, or 1.
+/// Entries written in these versions did not record when they expire, so it is set from their
+/// remaining TTL, and entries in version 0 did not record when they were stored either, which
+/// is set to the time of the migration.
+///
+/// Entries are read and written back one by one, so an entry rewritten by a request during the
+/// migration may be overwritten with its previous response.
+pub async fn upgrade_entries<S: IdempotencyStore>(
+    store: &S,
+    from_version: u8,
+    to_version: u8,
+) -> Result<MigrationReport, MigrationError> {
+    if to_version != FORMAT_VERSION || from_version >= to_version {
+        return Err(MigrationError::UnsupportedVersions {
+            from: from_version,
+            to: to_version,
+        });
+    }
+
+    let keys = store
+        .list_prefix(S::Key::from_text(""))
+        .await
+        .map_err(|source| MigrationError::Store {
+            operation: StoreOperation::List,
+            source,
+        })?;
+
+    let mut report = MigrationReport::default();
+    for (key, ttl_secs) in keys {
+        let key = key.borrow();
+        let bytes = store
+            .get(key)
+            .await
+            .map_err(|source| MigrationError::Store {
+                operation: StoreOperation::Get,
+                source,
+            })?;
+        let Some(bytes) = bytes else {
+            report.skipped += 1;
+            continue;
+        };
+        let cached = match format_version(&bytes) {
+            Ok(Some(version)) if version == from_version => CachedResponse::from_bytes(&bytes),
+            Ok(_) => {
+                report.skipped += 1;
+                continue;
+            }
+            Err(err) => Err(err),
+        };
+        let mut cached = match cached {
+            Ok(cached) => cached,
+            Err(err) => {
+                tracing::debug!(key = %key.to_text(), "Skipping value that is not a cached response: {err}");
+                report.skipped += 1;
+                continue;
+            }
+        };
+        if cached.expires_at.is_none() {
+            cached.expires_at = Some(SystemTime::now() + Duration::from_secs(ttl_secs as u64));
+        }
+
+        let upgraded = cached.to_bytes();
+        #[cfg(any(feature = "gzip", feature = "zstd"))]
+        let upgraded = match compression_of(&bytes) {
+            Some(compression) => compress(upgraded, compression),
+            None => upgraded,
+        };
+        store
+            .set(key, upgraded, ttl_secs)
+            .await
+            .map_err(|source| MigrationError::Store {
+                operation: StoreOperation::Set,
+                source,
+            })?;
+        report.upgraded += 1;
+    }
+
+    tracing::info!(
+        upgraded = report.upgraded,
+        skipped = report.skipped,
+        "Upgraded cached responses from format version {from_version} to {to_version}"
+    );
+    Ok(report)
+}
@@ -0,0 +1,117 @@
+use std::borrow::Cow;
+
+/// Rewrites request bodies of a content type into a canonical form before they are hashed, so
+/// that equivalent bodies get the same idempotency key.
+///
+/// Normalizers are registered per content type with
+/// [`IdempotentOptions::body_normalizer`](crate::IdempotentOptions::body_normalizer), and only
+/// affect the hash: the handler receives the body as sent. Built-in normalizers are available
+/// for forms ([`FormNormalizer`]) and multipart bodies ([`MultipartNormalizer`]).
+///
+/// # Example
+/// ```rust
+/// use axum_idempotent::{BodyNormalizer, IdempotentOptions};
+///
+/// /// Ignores trailing whitespace in plain text bodies.
+/// struct TrimNormalizer;
+///
+/// impl BodyNormalizer for TrimNormalizer {
+///     fn normalize(&self, _content_type: &str, body: &[u8]) -> Option<Vec<u8>> {
+///         Some(body.trim_ascii_end().to_vec())
+///     }
+/// }
+///
+/// let options = IdempotentOptions::default().body_normalizer("text/plain", TrimNormalizer);
+/// ```
+pub trait BodyNormalizer: Send + Sync + 'static {
+    /// Returns the canonical form of `body`, sent with the `content_type` header value, or
+    /// `None` to hash the body as is, e.g. if it cannot be parsed.
+    fn normalize(&self, content_type: &str, body: &[u8]) -> Option<Vec<u8>>;
+
+    /// Returns the canonical form of the `Content-Type` header value, hashed in its place.
+    ///
+    /// By default, the header is hashed as is.
+    fn normalize_content_type<'a>(&self, content_type: &'a str) -> Cow<'a, str> {
+        Cow::Borrowed(content_type)
+    }
+}
+
+/// Normalizes `application/x-www-form-urlencoded` bodies by sorting their fields by name, so
+/// the order in which a client serializes them does not matter.
+///
+/// Repeated fields keep their order, since it may be significant.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct FormNormalizer;
+
+impl BodyNormalizer for FormNormalizer {
+    fn normalize(&self, _content_type: &str, body: &[u8]) -> Option<Vec<u8>> {
+        let body = std::str::from_utf8(body).ok()?;
+        Some(sort_params(body).into_bytes())
+    }
+}
+
+/// Normalizes `multipart/form-data` bodies by replacing the boundary, which clients usually
+/// generate randomly for every request, with a fixed one.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MultipartNormalizer;
+
+/// The boundary hashed in place of the one chosen by the client.
+const CANONICAL_BOUNDARY: &[u8] = b"axum-idempotent-boundary";
+
+impl BodyNormalizer for MultipartNormalizer {
+    fn normalize(&self, content_type: &str, body: &[u8]) -> Option<Vec<u8>> {
+        let delimiter = [b"--", boundary(content_type)?.as_bytes()].concat();
+        let mut normalized = Vec::with_capacity(body.len());
+        let mut rest = body;
+        while let Some(index) = find(rest, &delimiter) {
+            normalized.extend_from_slice(&rest[..index]);
+            normalized.extend_from_slice(b"--");
+            normalized.extend_from_slice(CANONICAL_BOUNDARY);
+            rest = &rest[index + delimiter.len()..];
+        }
+        normalized.extend_from_slice(rest);
+        Some(normalized)
+    }
+
+    fn normalize_content_type<'a>(&self, content_type: &'a str) -> Cow<'a, str> {
+        let params = content_type
+            .split(';')
+            .map(str::trim)
+            .filter(|param| !param.to_ascii_lowercase().starts_with("boundary="));
+        Cow::Owned(params.collect::<Vec<_>>().join("; "))
+    }
+}
+
+/// The `boundary` parameter of a `multipart/*` content type.
+fn boundary(content_type: &str) -> Option<&str> {
+    content_type.split(';').skip(1).find_map(|param| {
+        let (name, value) = param.trim().split_once('=')?;
+        name.eq_ignore_ascii_case("boundary")
+            .then(|| value.trim_matches('"'))
+            .filter(|value| !value.is_empty())
+    })
+}
+
+/// The position of the first occurrence of `needle` in `haystack`.
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+/// Sorts the `&`-separated parameters of a query string or form by name, keeping the order of
+/// repeated parameters, and drops empty ones.
+pub(crate) fn sort_params(params: &str) -> String {
+    let mut params: Vec<_> = params
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .collect();
+    // Stable, so repeated parameters keep their order
+    params.sort_by(|a, b| param_name(a).cmp(param_name(b)));
+    params.join("&")
+}
+
+/// The name of a `name=value` parameter.
+pub(crate) fn param_name(param: &str) -> &str {
+    param.split_once('=').map_or(param, |(name, _)| name)
+}
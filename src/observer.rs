@@ -0,0 +1,65 @@
+use crate::error::StoreOperation;
+use std::time::Duration;
+
+/// Receives the lifecycle events of the requests handled by the middleware, for custom
+/// logging, metrics or audit trails.
+///
+/// Register an observer with
+/// [`IdempotentOptions::observer`](crate::IdempotentOptions::observer). All callbacks do
+/// nothing by default. They are called inline while handling requests, so they should return
+/// quickly, e.g. by sending events to a channel.
+///
+/// # Example
+/// ```rust
+/// use axum_idempotent::{IdempotencyEvent, IdempotencyObserver, IdempotentOptions};
+///
+/// struct LogObserver;
+///
+/// impl IdempotencyObserver for LogObserver {
+///     fn on_cache_hit(&self, event: &IdempotencyEvent<'_>) {
+///         println!("replayed {} on {:?} in {:?}", event.key, event.route, event.latency);
+///     }
+/// }
+///
+/// let options = IdempotentOptions::default().observer(LogObserver);
+/// ```
+pub trait IdempotencyObserver: Send + Sync + 'static {
+    /// Called when a cached response is replayed. The latency is that of the lookup.
+    fn on_cache_hit(&self, event: &IdempotencyEvent<'_>) {
+        let _ = event;
+    }
+
+    /// Called when no response is cached for a key, before the request is forwarded to the
+    /// inner service. The latency is that of the lookup.
+    fn on_cache_miss(&self, event: &IdempotencyEvent<'_>) {
+        let _ = event;
+    }
+
+    /// Called when a response is stored. The latency is that of the write, including retries.
+    fn on_store_write(&self, event: &IdempotencyEvent<'_>) {
+        let _ = event;
+    }
+
+    /// Called when a store `operation` fails. The latency is that of the failed operation.
+    fn on_store_error(&self, event: &IdempotencyEvent<'_>, operation: StoreOperation) {
+        let _ = (event, operation);
+    }
+
+    /// Called when a request is rejected or forwarded because another request with the same
+    /// key is in flight. The latency is that of the lookup, including any wait.
+    fn on_conflict(&self, event: &IdempotencyEvent<'_>) {
+        let _ = event;
+    }
+}
+
+/// An event reported to an [`IdempotencyObserver`].
+#[derive(Clone, Copy, Debug)]
+#[non_exhaustive]
+pub struct IdempotencyEvent<'a> {
+    /// The idempotency key of the request (or its hash, in hashing mode).
+    pub key: &'a str,
+    /// The route template of the request, when the layer runs after routing.
+    pub route: Option<&'a str>,
+    /// The time taken by the operation the event reports.
+    pub latency: Duration,
+}
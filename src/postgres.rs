@@ -0,0 +1,283 @@
+use crate::store::IdempotencyStore;
+use sqlx::PgPool;
+use std::error::Error;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// A PostgreSQL [`IdempotencyStore`] implementation, for durable idempotency that survives
+/// cache flushes and restarts.
+///
+/// Entries are kept in a table (`idempotency_entries` by default, see
+/// [`PostgresStore::with_table`]) with a `key` primary key, a `value` and an `expires_at`
+/// column. Expired entries are ignored on reads, and deleted by the task started with
+/// [`PostgresStore::spawn_cleanup`]. In-flight locks are acquired atomically with
+/// `INSERT ... ON CONFLICT`, in the same round trip as the lookup of the key.
+///
+/// This requires the `postgres` feature.
+///
+/// # Example
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use axum::{Router, routing::post};
+/// use axum_idempotent::{IdempotentLayer, IdempotentOptions, PostgresStore};
+/// use sqlx::PgPool;
+///
+/// #[tokio::main]
+/// async fn main() {
+/// let pool = PgPool::connect("postgres://localhost/app").await.unwrap();
+/// let store = PostgresStore::new(pool);
+/// store.migrate().await.unwrap();
+/// store.spawn_cleanup(Duration::from_secs(60));
+///
+/// let options = IdempotentOptions::default().use_idempotency_key_header(None);
+/// let app: Router = Router::new()
+///     .route("/payments", post(|| async { "Payment processed" }))
+///     .layer(IdempotentLayer::with_store(store, options));
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct PostgresStore {
+    pool: PgPool,
+    table: Arc<str>,
+}
+
+impl PostgresStore {
+    pub fn new(pool: PgPool) -> Self {
+        Self {
+            pool,
+            table: Arc::from("idempotency_entries"),
+        }
+    }
+
+    /// Keeps entries in `table` instead of `idempotency_entries`.
+    ///
+    /// The name is inserted into queries as is, and may be schema-qualified (e.g.
+    /// `app.idempotency_entries`).
+    ///
+    /// # Panics
+    /// Panics if `table` contains characters other than ASCII letters, digits, `_` and `.`.
+    pub fn with_table(mut self, table: impl Into<String>) -> Self {
+        let table = table.into();
+        assert!(
+            !table.is_empty()
+                && table
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.'),
+            "invalid table name {table:?}"
+        );
+        self.table = Arc::from(table);
+        self
+    }
+
+    /// Creates the table of entries and its index on `expires_at`, if they do not exist.
+    pub async fn migrate(&self) -> Result<(), sqlx::Error> {
+        let table = &self.table;
+        let index = table.replace('.', "_");
+        sqlx::query(&format!(
+            "CREATE TABLE IF NOT EXISTS {table} (
+                key TEXT PRIMARY KEY,
+                value BYTEA NOT NULL,
+                expires_at TIMESTAMPTZ NOT NULL
+            )"
+        ))
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(&format!(
+            "CREATE INDEX IF NOT EXISTS {index}_expires_at ON {table} (expires_at)"
+        ))
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Deletes the expired entries, returning how many were deleted.
+    pub async fn remove_expired(&self) -> Result<u64, sqlx::Error> {
+        let table = &self.table;
+        let result = sqlx::query(&format!("DELETE FROM {table} WHERE expires_at <= now()"))
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Spawns a task deleting the expired entries every `interval`, until it is aborted.
+    pub fn spawn_cleanup(&self, interval: Duration) -> JoinHandle<()> {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                match store.remove_expired().await {
+                    Ok(removed) => tracing::debug!("Removed {removed} expired idempotency entries"),
+                    Err(err) => {
+                        tracing::warn!("Failed to remove expired idempotency entries: {err:?}")
+                    }
+                }
+            }
+        })
+    }
+}
+
+impl IdempotencyStore for PostgresStore {
+    type Key = str;
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        let table = &self.table;
+        let value = sqlx::query_scalar::<_, Vec<u8>>(&format!(
+            "SELECT value FROM {table} WHERE key = $1 AND expires_at > now()"
+        ))
+        .bind(key)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(value)
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let table = &self.table;
+        sqlx::query(&format!(
+            "INSERT INTO {table} (key, value, expires_at)
+            VALUES ($1, $2, now() + make_interval(secs => $3::float8))
+            ON CONFLICT (key) DO UPDATE
+            SET value = EXCLUDED.value, expires_at = EXCLUDED.expires_at"
+        ))
+        .bind(key)
+        .bind(value)
+        .bind(ttl_secs)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    async fn set_if_absent(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        // Expired entries are replaced, as if they had been deleted
+        let table = &self.table;
+        let result = sqlx::query(&format!(
+            "INSERT INTO {table} AS entry (key, value, expires_at)
+            VALUES ($1, $2, now() + make_interval(secs => $3::float8))
+            ON CONFLICT (key) DO UPDATE
+            SET value = EXCLUDED.value, expires_at = EXCLUDED.expires_at
+            WHERE entry.expires_at <= now()"
+        ))
+        .bind(key)
+        .bind(value)
+        .bind(ttl_secs)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn get_or_lock(
+        &self,
+        key: &str,
+        marker: Vec<u8>,
+        lock_ttl_secs: i64,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        let table = &self.table;
+        let (locked, value) = sqlx::query_as::<_, (bool, Option<Vec<u8>>)>(&format!(
+            "WITH locked AS (
+                INSERT INTO {table} AS entry (key, value, expires_at)
+                VALUES ($1, $2, now() + make_interval(secs => $3::float8))
+                ON CONFLICT (key) DO UPDATE
+                SET value = EXCLUDED.value, expires_at = EXCLUDED.expires_at
+                WHERE entry.expires_at <= now()
+                RETURNING 1
+            )
+            SELECT
+                EXISTS (SELECT 1 FROM locked),
+                (SELECT value FROM {table} WHERE key = $1 AND expires_at > now())"
+        ))
+        .bind(key)
+        .bind(&marker)
+        .bind(lock_ttl_secs)
+        .fetch_one(&self.pool)
+        .await?;
+
+        match (locked, value) {
+            (true, _) => Ok(None),
+            (false, Some(value)) => Ok(Some(value)),
+            // Inserted by a concurrent transaction, after this statement's snapshot was taken
+            (false, None) => Ok(Some(self.get(key).await?.unwrap_or(marker))),
+        }
+    }
+
+    async fn ping(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+
+        Ok(())
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        current: Vec<u8>,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let table = &self.table;
+        let result = sqlx::query(&format!(
+            "UPDATE {table}
+            SET value = $3, expires_at = now() + make_interval(secs => $4::float8)
+            WHERE key = $1 AND value = $2 AND expires_at > now()"
+        ))
+        .bind(key)
+        .bind(current)
+        .bind(value)
+        .bind(ttl_secs)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let table = &self.table;
+        sqlx::query(&format!("DELETE FROM {table} WHERE key = $1"))
+            .bind(key)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn remove_prefix(&self, prefix: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let table = &self.table;
+        sqlx::query(&format!("DELETE FROM {table} WHERE starts_with(key, $1)"))
+            .bind(prefix)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn list_prefix(
+        &self,
+        prefix: &str,
+    ) -> Result<Vec<(String, i64)>, Box<dyn Error + Send + Sync>> {
+        let table = &self.table;
+        let entries = sqlx::query_as::<_, (String, i64)>(&format!(
+            "SELECT key, CEIL(EXTRACT(EPOCH FROM expires_at - now()))::int8 FROM {table}
+            WHERE starts_with(key, $1) AND expires_at > now()"
+        ))
+        .bind(prefix)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(entries)
+    }
+}
@@ -0,0 +1,284 @@
+use crate::store::{IdempotencyStore, StoreKey};
+use fred::clients::Pool;
+use fred::interfaces::{KeysInterface, LuaInterface};
+use fred::types::{Expiration, Key, SetOptions, Value};
+use std::error::Error;
+use std::marker::PhantomData;
+use std::sync::Arc;
+
+/// A Redis [`IdempotencyStore`] implementation.
+///
+/// Each entry is kept in a Redis string under its key, expiring with `EX`. In-flight locks are
+/// acquired atomically with `SET NX`, so concurrent requests cannot both acquire one, and in
+/// the same round trip as the lookup of the key, with a Lua script. Entries
+/// are removed by prefix with `SCAN` and `DEL`, which requires a hash tag in the prefix on
+/// clustered deployments.
+///
+/// Keys are strings, unless [`binary_keys`](Self::binary_keys) is set.
+///
+/// This requires the `redis-store` feature.
+///
+/// # Example
+/// ```rust,no_run
+/// use std::sync::Arc;
+/// use axum::{Router, routing::post};
+/// use axum_idempotent::{IdempotentLayer, IdempotentOptions, RedisStore};
+/// use fred::prelude::{ClientLike, Config, Pool};
+///
+/// #[tokio::main]
+/// async fn main() {
+/// let config = Config::from_url("redis://127.0.0.1:6379").unwrap();
+/// let pool = Pool::new(config, None, None, None, 4).unwrap();
+/// pool.init().await.unwrap();
+///
+/// let options = IdempotentOptions::default().use_idempotency_key_header(None);
+/// let app: Router = Router::new()
+///     .route("/payments", post(|| async { "Payment processed" }))
+///     .layer(IdempotentLayer::with_store(RedisStore::new(Arc::new(pool)), options));
+/// }
+/// ```
+#[derive(Debug)]
+pub struct RedisStore<C: KeysInterface + Clone + Send + Sync = Pool, K: ?Sized = str> {
+    client: Arc<C>,
+    key: PhantomData<K>,
+}
+
+impl<C> RedisStore<C>
+where
+    C: KeysInterface + Clone + Send + Sync,
+{
+    pub fn new(client: Arc<C>) -> Self {
+        Self {
+            client,
+            key: PhantomData,
+        }
+    }
+
+    /// Takes keys as bytes, so that the request hashes of hashing mode are stored as raw bytes
+    /// rather than hex, halving the size of keys.
+    ///
+    /// Entries stored with string keys in hashing mode are not found anymore.
+    pub fn binary_keys(self) -> RedisStore<C, [u8]> {
+        RedisStore {
+            client: self.client,
+            key: PhantomData,
+        }
+    }
+}
+
+impl<C, K: ?Sized> Clone for RedisStore<C, K>
+where
+    C: KeysInterface + Clone + Send + Sync,
+{
+    fn clone(&self) -> Self {
+        Self {
+            client: self.client.clone(),
+            key: PhantomData,
+        }
+    }
+}
+
+/// Returns the entry stored under `KEYS[1]`, or stores `ARGV[1]` under it for `ARGV[2]`
+/// seconds and returns nil if there is none.
+const GET_OR_LOCK: &str = r#"
+local entry = redis.call('GET', KEYS[1])
+if entry then
+    return entry
+end
+redis.call('SET', KEYS[1], ARGV[1], 'EX', ARGV[2])
+return false
+"#;
+
+/// Stores `ARGV[2]` under `KEYS[1]` for `ARGV[3]` seconds if `ARGV[1]` is stored under it,
+/// returning 1, or returns 0 otherwise.
+const COMPARE_AND_SET: &str = r#"
+if redis.call('GET', KEYS[1]) ~= ARGV[1] then
+    return 0
+end
+redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+return 1
+"#;
+
+impl<C, K> IdempotencyStore for RedisStore<C, K>
+where
+    C: KeysInterface + LuaInterface + Clone + Send + Sync + 'static,
+    K: StoreKey + AsRef<[u8]> + ?Sized,
+{
+    type Key = K;
+
+    async fn get(&self, key: &K) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        let value = self.client.get::<Option<Vec<u8>>, _>(key.as_ref()).await?;
+
+        Ok(value)
+    }
+
+    async fn set(
+        &self,
+        key: &K,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.client
+            .set::<(), _, _>(
+                key.as_ref(),
+                value,
+                Some(Expiration::EX(ttl_secs)),
+                None,
+                false,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    async fn set_if_absent(
+        &self,
+        key: &K,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        // `SET NX` replies `OK` when the value was set, and nil otherwise.
+        let reply = self
+            .client
+            .set::<Option<String>, _, _>(
+                key.as_ref(),
+                value,
+                Some(Expiration::EX(ttl_secs)),
+                Some(SetOptions::NX),
+                false,
+            )
+            .await?;
+
+        Ok(reply.is_some())
+    }
+
+    async fn get_or_lock(
+        &self,
+        key: &K,
+        marker: Vec<u8>,
+        lock_ttl_secs: i64,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        let args = vec![Value::Bytes(marker.into()), Value::from(lock_ttl_secs)];
+        let entry = self
+            .client
+            .eval::<Option<Vec<u8>>, _, _, _>(GET_OR_LOCK, key.as_ref(), args)
+            .await?;
+
+        Ok(entry)
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &K,
+        current: Vec<u8>,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let args = vec![
+            Value::Bytes(current.into()),
+            Value::Bytes(value.into()),
+            Value::from(ttl_secs),
+        ];
+        let stored = self
+            .client
+            .eval::<i64, _, _, _>(COMPARE_AND_SET, key.as_ref(), args)
+            .await?;
+
+        Ok(stored == 1)
+    }
+
+    async fn remove(&self, key: &K) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.client.del::<(), _>(key.as_ref()).await?;
+
+        Ok(())
+    }
+
+    async fn ping(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.client.ping::<()>(None).await?;
+
+        Ok(())
+    }
+
+    async fn remove_prefix(&self, prefix: &K) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let pattern = key_pattern(prefix.as_ref())?;
+        let mut cursor = String::from("0");
+        loop {
+            let (next, keys) = self
+                .client
+                .scan_page::<(String, Vec<Key>), _, _>(cursor, pattern.as_str(), None, None)
+                .await?;
+            if !keys.is_empty() {
+                self.client.del::<(), _>(keys).await?;
+            }
+            if next == "0" {
+                return Ok(());
+            }
+            cursor = next;
+        }
+    }
+
+    async fn list_prefix(
+        &self,
+        prefix: &K,
+    ) -> Result<Vec<(K::Owned, i64)>, Box<dyn Error + Send + Sync>> {
+        let pattern = key_pattern(prefix.as_ref())?;
+        let mut entries = Vec::new();
+        let mut cursor = String::from("0");
+        loop {
+            let (next, keys) = self
+                .client
+                .scan_page::<(String, Vec<Key>), _, _>(cursor, pattern.as_str(), None, None)
+                .await?;
+            for key in keys {
+                // Keys of other types, that expired since, or that were stored without expiration
+                // by others
+                let Some(store_key) = K::from_raw(key.as_bytes()) else {
+                    continue;
+                };
+                let ttl_secs = self.client.ttl::<i64, _>(&key).await?;
+                if ttl_secs > 0 {
+                    entries.push((store_key.to_owned(), ttl_secs));
+                }
+            }
+            if next == "0" {
+                return Ok(entries);
+            }
+            cursor = next;
+        }
+    }
+}
+
+/// Returns the `SCAN` pattern matching the keys starting with `prefix`, which must be valid
+/// UTF-8.
+fn key_pattern(prefix: &[u8]) -> Result<String, Box<dyn Error + Send + Sync>> {
+    match std::str::from_utf8(prefix) {
+        Ok(prefix) => Ok(prefix_pattern(prefix)),
+        Err(_) => Err("Redis key prefixes must be valid UTF-8".into()),
+    }
+}
+
+/// Returns the `SCAN` pattern matching the keys starting with `prefix`.
+fn prefix_pattern(prefix: &str) -> String {
+    // Escape the glob characters of the prefix, so only the trailing `*` is a wildcard.
+    let mut pattern = String::with_capacity(prefix.len() + 1);
+    for c in prefix.chars() {
+        if matches!(c, '*' | '?' | '[' | ']' | '\\') {
+            pattern.push('\\');
+        }
+        pattern.push(c);
+    }
+    pattern.push('*');
+    pattern
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prefix_pattern() {
+        assert_eq!(prefix_pattern(""), "*");
+        assert_eq!(prefix_pattern("tenant-1:"), "tenant-1:*");
+        assert_eq!(prefix_pattern("a*b?[c]\\"), "a\\*b\\?\\[c\\]\\\\*");
+    }
+}
@@ -0,0 +1,108 @@
+use axum::http::StatusCode;
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Remembers the keys of requests recently rejected because of an in-flight duplicate, so that
+/// retries arriving in a tight loop are rejected without a store round trip.
+///
+/// The cache is kept in memory, and shared by the clones of the options it was created with.
+#[derive(Clone)]
+pub(crate) struct RejectionCache {
+    ttl: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    rejections: HashMap<String, Rejection>,
+    /// A moving average of the time taken by the requests holding an in-flight lock.
+    expected_duration: Option<Duration>,
+}
+
+#[derive(Clone, Copy)]
+struct Rejection {
+    status: StatusCode,
+    expires_at: Instant,
+    retry_at: Instant,
+}
+
+impl RejectionCache {
+    pub(crate) fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            state: Arc::default(),
+        }
+    }
+
+    /// Returns the status code `key` was rejected with, and how long the client should wait
+    /// before retrying, if it was rejected recently.
+    pub(crate) fn get(&self, key: &str) -> Option<(StatusCode, Duration)> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let rejection = *state.rejections.get(key)?;
+        if rejection.expires_at <= now {
+            state.rejections.remove(key);
+            return None;
+        }
+
+        Some((
+            rejection.status,
+            rejection.retry_at.saturating_duration_since(now),
+        ))
+    }
+
+    /// Records that `key` was rejected with `status` at `rejected_at` while the original
+    /// request, started at `started_at` if known, was in flight, and returns how long the client
+    /// should wait before retrying.
+    pub(crate) fn insert(
+        &self,
+        key: &str,
+        status: StatusCode,
+        started_at: Option<SystemTime>,
+        rejected_at: SystemTime,
+    ) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let elapsed = started_at
+            .and_then(|started_at| rejected_at.duration_since(started_at).ok())
+            .unwrap_or_default();
+        let retry_after = state
+            .expected_duration
+            .unwrap_or_default()
+            .saturating_sub(elapsed);
+
+        state
+            .rejections
+            .retain(|_, rejection| rejection.expires_at > now);
+        state.rejections.insert(
+            key.to_owned(),
+            Rejection {
+                status,
+                expires_at: now + self.ttl,
+                retry_at: now + retry_after,
+            },
+        );
+        retry_after
+    }
+
+    /// Records that the request holding the in-flight lock of `key` completed after `elapsed`,
+    /// so retries are no longer rejected.
+    pub(crate) fn complete(&self, key: &str, elapsed: Duration) {
+        let mut state = self.state.lock().unwrap();
+        state.rejections.remove(key);
+        state.expected_duration = Some(match state.expected_duration {
+            Some(expected) => (expected * 7 + elapsed) / 8,
+            None => elapsed,
+        });
+    }
+}
+
+impl fmt::Debug for RejectionCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RejectionCache")
+            .field("ttl", &self.ttl)
+            .finish_non_exhaustive()
+    }
+}
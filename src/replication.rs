@@ -0,0 +1,73 @@
+use crate::cached::CachedResponse;
+use crate::store::{IdempotencyStore, store_key};
+#[cfg(feature = "session")]
+use ruts::Id;
+#[cfg(feature = "session")]
+use ruts::store::SessionStore;
+use std::error::Error;
+
+/// An idempotency entry that was written to the store.
+///
+/// Entries are handed to the hook configured with
+/// [`IdempotentOptions::replicate_with`](crate::IdempotentOptions::replicate_with) after every
+/// successful write, so they can be shipped to other regions and written there with
+/// [`ReplicatedEntry::apply`] or [`ReplicatedEntry::apply_to_store`]. A retry routed to another
+/// region then still replays.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ReplicatedEntry {
+    /// The id of the session the entry belongs to, if it was written to a session store.
+    pub session_id: Option<String>,
+    /// The idempotency key the entry is stored under.
+    pub key: String,
+    /// The number of hex digits of the request hash ending [`Self::key`] in hashing mode,
+    /// which stores with binary keys store as raw bytes.
+    pub hash_len: Option<usize>,
+    /// The serialized response.
+    pub value: Vec<u8>,
+    /// The expiration time of the entry in seconds.
+    pub ttl_secs: i64,
+}
+
+impl ReplicatedEntry {
+    /// Deserializes the replicated response.
+    pub fn response(&self) -> Result<CachedResponse, Box<dyn Error + Send + Sync>> {
+        CachedResponse::from_bytes(&self.value)
+    }
+
+    /// Writes this entry into the session store `store`.
+    ///
+    /// The session is created if it does not exist yet in `store`, expiring together with
+    /// the entry. Fails if the entry was not written to a session store.
+    #[cfg(feature = "session")]
+    pub async fn apply<T: SessionStore>(
+        &self,
+        store: &T,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let session_id = self
+            .session_id
+            .as_deref()
+            .ok_or("Replicated entry does not belong to a session")?
+            .parse::<Id>()?;
+        store
+            .set(
+                &session_id,
+                &self.key,
+                &self.value,
+                self.ttl_secs,
+                self.ttl_secs,
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+
+    /// Writes this entry into `store`.
+    pub async fn apply_to_store<S: IdempotencyStore>(
+        &self,
+        store: &S,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let key = store_key::<S::Key>(&self.key, self.hash_len);
+        store.set(&key, self.value.clone(), self.ttl_secs).await
+    }
+}
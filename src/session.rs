@@ -0,0 +1,262 @@
+use crate::config::{IdempotentOptions, KeyScope};
+use crate::error::ConfigError;
+use crate::store::{Backend, IdempotencyStore};
+use axum::RequestExt;
+use axum::extract::{ConnectInfo, Request};
+use axum::http::Extensions;
+use base64::Engine;
+use base64::prelude::BASE64_URL_SAFE_NO_PAD;
+use ruts::store::SessionStore;
+use ruts::{Id, Inner, Session};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+/// Where idempotency entries are kept when no [`Session`] can be extracted from the request.
+///
+/// See [`IdempotentLayer::with_session_fallback`](crate::IdempotentLayer::with_session_fallback).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SessionFallback {
+    /// A single key space shared by all clients.
+    Global,
+    /// One key space per client IP address, taken from axum's [`ConnectInfo<SocketAddr>`].
+    ///
+    /// Requests without a `ConnectInfo<SocketAddr>` extension are forwarded without idempotency.
+    ClientIp,
+}
+
+impl SessionFallback {
+    /// Derives the id under which entries are stored for this scope, keyed with the server-side
+    /// `seed`, so clients cannot address it with a session cookie.
+    pub(crate) fn id(&self, extensions: &Extensions, seed: &[u8; 32]) -> Option<Id> {
+        let scope = match self {
+            SessionFallback::Global => String::from("global"),
+            SessionFallback::ClientIp => {
+                let ConnectInfo(addr) = extensions.get::<ConnectInfo<SocketAddr>>()?;
+                format!("ip:{}", addr.ip())
+            }
+        };
+
+        let hash = blake3::keyed_hash(seed, format!("axum-idempotent:{scope}").as_bytes());
+        BASE64_URL_SAFE_NO_PAD
+            .encode(&hash.as_bytes()[..16])
+            .parse()
+            .ok()
+    }
+}
+
+/// How requests are handled when no [`Session`] can be extracted from them (and no
+/// [`SessionFallback`] applies), which means the `SessionLayer` is missing or was added after
+/// the idempotency layer.
+///
+/// See [`IdempotentLayer::on_missing_session`](crate::IdempotentLayer::on_missing_session).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MissingSession {
+    /// Log an error and forward the request without idempotency. This is the default in
+    /// release builds.
+    Forward,
+    /// Log an error and reject the request with a `500 Internal Server Error` explaining the
+    /// misconfiguration. This is the default in debug builds.
+    Reject,
+    /// Panic on the first such request, so the misconfiguration cannot go unnoticed in
+    /// development.
+    Panic,
+}
+
+/// Marker inserted into the request extensions when it is rejected by
+/// [`MissingSession::Reject`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct SessionMissing;
+
+/// The state kept by layers backed by a session store.
+#[derive(Debug)]
+pub struct SessionState<T> {
+    pub(crate) fallback: Option<(Arc<T>, SessionFallback)>,
+    pub(crate) on_missing: MissingSession,
+}
+
+impl<T> SessionState<T> {
+    pub(crate) const fn new() -> Self {
+        Self {
+            fallback: None,
+            on_missing: if cfg!(debug_assertions) {
+                MissingSession::Reject
+            } else {
+                MissingSession::Forward
+            },
+        }
+    }
+}
+
+impl<T> Clone for SessionState<T> {
+    fn clone(&self) -> Self {
+        Self {
+            fallback: self.fallback.clone(),
+            on_missing: self.on_missing,
+        }
+    }
+}
+
+impl<T: SessionStore> Backend for T {
+    type State = SessionState<T>;
+    type Store = SessionStorage<T>;
+
+    async fn resolve(
+        state: &SessionState<T>,
+        req: &mut Request,
+        config: &IdempotentOptions,
+        route: Option<&str>,
+    ) -> Option<SessionStorage<T>> {
+        let shared = matches!(config.key_scope, KeyScope::Global | KeyScope::Custom(_));
+        // Keys shared by all sessions are kept under a single scope, in the store of the
+        // session layer, or else of the fallback, so requests need no session
+        let store = req
+            .extensions()
+            .get::<Arc<Inner<T>>>()
+            .map(|inner| inner.store.clone())
+            .or_else(|| state.fallback.as_ref().map(|(store, _)| store.clone()));
+        if let (true, Some(store)) = (shared, store) {
+            // Checked when the layer is created
+            let Some(seed) = config.hash_seed.as_ref() else {
+                let err = ConfigError::MissingHashSeed;
+                tracing::error!(route, "Failed to scope request, forwarding it: {err}");
+                return None;
+            };
+            let id = SessionFallback::Global.id(req.extensions(), seed)?;
+            return Some(SessionStorage {
+                scope: Scope::Id { store, id },
+                #[cfg(feature = "layered-store")]
+                hot_cache_ttl_secs: config.layered_hot_cache_ttl_secs,
+            });
+        }
+
+        let scope = match req.extract_parts::<Session<T>>().await {
+            Ok(session) => Scope::Session(session),
+            Err(err) => {
+                let has_direct_key = config.use_idempotency_key
+                    && req.headers().contains_key(&config.idempotency_key_header);
+                let fallback = state
+                    .fallback
+                    .clone()
+                    .filter(|_| has_direct_key)
+                    .zip(config.hash_seed.as_ref())
+                    .and_then(|((store, scope), seed)| {
+                        Some((store, scope.id(req.extensions(), seed)?))
+                    });
+
+                match fallback {
+                    Some((store, id)) => {
+                        tracing::warn!(
+                            route,
+                            "Failed to extract Session from request, using fallback scope: {err:?}"
+                        );
+                        Scope::Id { store, id }
+                    }
+                    None => {
+                        match state.on_missing {
+                            MissingSession::Forward => {}
+                            MissingSession::Reject => {
+                                req.extensions_mut().insert(SessionMissing);
+                            }
+                            MissingSession::Panic => panic!(
+                                "Failed to extract Session from request: {err:?}. \
+                                 Is `SessionLayer` applied before `IdempotentLayer`?"
+                            ),
+                        }
+                        tracing::error!(route, "Failed to extract Session from request: {err:?}");
+                        return None;
+                    }
+                }
+            }
+        };
+
+        Some(SessionStorage {
+            scope,
+            #[cfg(feature = "layered-store")]
+            hot_cache_ttl_secs: config.layered_hot_cache_ttl_secs,
+        })
+    }
+
+    fn scope(store: &SessionStorage<T>) -> Option<String> {
+        store.id().map(|id| id.to_string())
+    }
+}
+
+/// The storage used to look up and cache responses for a single request.
+#[derive(Clone)]
+pub struct SessionStorage<T: SessionStore> {
+    scope: Scope<T>,
+    #[cfg(feature = "layered-store")]
+    hot_cache_ttl_secs: Option<i64>,
+}
+
+#[derive(Clone)]
+enum Scope<T: SessionStore> {
+    /// The session of the request.
+    Session(Session<T>),
+    /// A scope not tied to a session, e.g. a fallback or the key space shared by all sessions.
+    Id { store: Arc<T>, id: Id },
+}
+
+impl<T: SessionStore> SessionStorage<T> {
+    /// The id of the session (or fallback scope) entries are stored under.
+    fn id(&self) -> Option<Id> {
+        match &self.scope {
+            Scope::Session(session) => session.id(),
+            Scope::Id { id, .. } => Some(*id),
+        }
+    }
+}
+
+impl<T: SessionStore> IdempotencyStore for SessionStorage<T> {
+    type Key = str;
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        let value = match &self.scope {
+            Scope::Session(session) => session.get::<Vec<u8>>(key).await?,
+            Scope::Id { store, id } => store.get::<Vec<u8>>(id, key).await?,
+        };
+
+        Ok(value)
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        #[cfg(feature = "layered-store")]
+        let hot_cache_ttl_secs = self.hot_cache_ttl_secs;
+        #[cfg(not(feature = "layered-store"))]
+        let hot_cache_ttl_secs = None;
+
+        match &self.scope {
+            Scope::Session(session) => {
+                session
+                    .set(key, &value, Some(ttl_secs), hot_cache_ttl_secs)
+                    .await?;
+            }
+            Scope::Id { store, id } => {
+                store
+                    .set(id, key, &value, ttl_secs, ttl_secs, hot_cache_ttl_secs)
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        match &self.scope {
+            Scope::Session(session) => {
+                session.remove(key).await?;
+            }
+            Scope::Id { store, id } => {
+                store.remove(id, key).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
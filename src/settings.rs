@@ -0,0 +1,98 @@
+use crate::config::IdempotentOptions;
+use crate::error::ConfigError;
+use axum::http::{HeaderName, Method, StatusCode};
+use serde::Deserialize;
+
+/// The settings of [`IdempotentOptions`] that can be loaded from a configuration file or the
+/// environment. Settings left out keep their default.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Settings {
+    ttl_secs: Option<i64>,
+    soft_ttl_secs: Option<i64>,
+    sliding_expiration: Option<bool>,
+    /// Enables direct key mode with this header.
+    idempotency_key_header: Option<String>,
+    require_key: Option<bool>,
+    max_key_length: Option<usize>,
+    key_prefix: Option<String>,
+    namespace: Option<String>,
+    lock_in_flight_secs: Option<i64>,
+    max_body_bytes: Option<usize>,
+    ignore_body: Option<bool>,
+    ignored_headers: Vec<String>,
+    ignored_status_codes: Vec<u16>,
+    methods: Option<Vec<String>>,
+    include_paths: Vec<String>,
+    exclude_paths: Vec<String>,
+}
+
+impl TryFrom<Settings> for IdempotentOptions {
+    type Error = ConfigError;
+
+    fn try_from(settings: Settings) -> Result<Self, ConfigError> {
+        let mut options = IdempotentOptions::default();
+        if let Some(ttl_secs) = settings.ttl_secs {
+            options = options.expire_after(ttl_secs);
+        }
+        if let Some(soft_ttl_secs) = settings.soft_ttl_secs {
+            options = options.soft_ttl(soft_ttl_secs);
+        }
+        if let Some(sliding) = settings.sliding_expiration {
+            options = options.sliding_expiration(sliding);
+        }
+        if let Some(header) = &settings.idempotency_key_header {
+            options = options.use_idempotency_key_header(Some(header));
+        }
+        if let Some(require_key) = settings.require_key {
+            options = options.require_key(require_key);
+        }
+        if let Some(max_len) = settings.max_key_length {
+            options = options.max_key_length(Some(max_len));
+        }
+        if let Some(prefix) = settings.key_prefix {
+            options = options.key_prefix(prefix);
+        }
+        if let Some(namespace) = settings.namespace {
+            options = options.namespace(namespace);
+        }
+        if let Some(lock_ttl_secs) = settings.lock_in_flight_secs {
+            options = options.lock_in_flight(lock_ttl_secs);
+        }
+        if let Some(limit) = settings.max_body_bytes {
+            options = options.max_body_bytes(limit);
+        }
+        if let Some(ignore) = settings.ignore_body {
+            options = options.ignore_body(ignore);
+        }
+        for name in settings.ignored_headers {
+            let header = HeaderName::try_from(&name)
+                .map_err(|_| ConfigError::InvalidSetting("ignored_headers", name))?;
+            options = options.ignore_header(header);
+        }
+        for code in settings.ignored_status_codes {
+            let status_code = StatusCode::from_u16(code).map_err(|_| {
+                ConfigError::InvalidSetting("ignored_status_codes", code.to_string())
+            })?;
+            options = options.ignore_response_status_code(status_code);
+        }
+        if let Some(methods) = settings.methods {
+            let methods = methods
+                .into_iter()
+                .map(|method| {
+                    Method::from_bytes(method.to_ascii_uppercase().as_bytes())
+                        .map_err(|_| ConfigError::InvalidSetting("methods", method))
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            options = options.only_methods(methods);
+        }
+        if !settings.include_paths.is_empty() {
+            options = options.include_paths(settings.include_paths);
+        }
+        if !settings.exclude_paths.is_empty() {
+            options = options.exclude_paths(settings.exclude_paths);
+        }
+
+        options.build()
+    }
+}
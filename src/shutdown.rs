@@ -0,0 +1,156 @@
+use std::fmt;
+use std::future::Future;
+use std::pin::pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tokio::sync::{Notify, OwnedSemaphorePermit, Semaphore};
+use tokio::task::JoinHandle;
+
+/// A handle to wait for the cache writes the middleware performs in the background.
+///
+/// Some responses are cached by detached tasks, which outlive the request that started them:
+/// with [`IdempotentOptions::complete_on_disconnect`](crate::IdempotentOptions::complete_on_disconnect)
+/// or [`IdempotentOptions::write_behind`](crate::IdempotentOptions::write_behind), and when refreshing stale responses (see
+/// [`IdempotentOptions::soft_ttl`](crate::IdempotentOptions::soft_ttl)). A process exiting
+/// before they complete drops the cache entry of an operation that already executed, so its
+/// retry executes it again. Awaiting [`ShutdownHandle::shutdown`] once the server stopped
+/// accepting requests lets them complete.
+///
+/// The tasks are shared by the clones of the options, so every layer built from the same
+/// options shares one handle.
+///
+/// # Example
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use axum::{Router, routing::post};
+/// use axum_idempotent::{IdempotentLayer, IdempotentOptions, MemoryIdempotencyStore};
+///
+/// #[tokio::main]
+/// async fn main() {
+/// let options = IdempotentOptions::default()
+///     .use_idempotency_key_header(None)
+///     .complete_on_disconnect(true);
+/// let layer = IdempotentLayer::with_store(MemoryIdempotencyStore::new(), options);
+/// let shutdown = layer.shutdown_handle();
+/// let app = Router::new()
+///     .route("/payments", post(|| async { "Payment processed" }))
+///     .layer(layer);
+///
+/// let listener = tokio::net::TcpListener::bind("0.0.0.0:3000").await.unwrap();
+/// axum::serve(listener, app)
+///     .with_graceful_shutdown(async { tokio::signal::ctrl_c().await.unwrap() })
+///     .await
+///     .unwrap();
+/// // Give pending cache writes up to 10 seconds to complete
+/// let _ = tokio::time::timeout(Duration::from_secs(10), shutdown.shutdown()).await;
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct ShutdownHandle {
+    tasks: Arc<Tasks>,
+}
+
+#[derive(Default)]
+struct Tasks {
+    /// The number of running tasks.
+    pending: AtomicUsize,
+    /// Notified when the last running task completes.
+    idle: Notify,
+}
+
+impl ShutdownHandle {
+    /// Waits until every background task started by the middleware completes.
+    ///
+    /// Tasks started while waiting are waited for as well. Wrap this in
+    /// [`tokio::time::timeout`] to bound the time a deploy waits for a slow store.
+    pub async fn shutdown(&self) {
+        loop {
+            let mut idle = pin!(self.tasks.idle.notified());
+            // Registers for notifications before checking, so a completion cannot be missed
+            idle.as_mut().enable();
+            if self.pending() == 0 {
+                return;
+            }
+            idle.await;
+        }
+    }
+
+    /// Returns the number of background tasks still running.
+    pub fn pending(&self) -> usize {
+        self.tasks.pending.load(Ordering::Acquire)
+    }
+
+    /// Spawns `task` on the `tokio` runtime, tracking it until it completes.
+    pub(crate) fn spawn<F>(&self, task: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        self.tasks.pending.fetch_add(1, Ordering::AcqRel);
+        let guard = TaskGuard(self.tasks.clone());
+        tokio::spawn(async move {
+            let _guard = guard;
+            task.await
+        })
+    }
+}
+
+impl fmt::Debug for ShutdownHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShutdownHandle")
+            .field("pending", &self.pending())
+            .finish()
+    }
+}
+
+/// Marks a tracked task as completed when dropped, even if it panicked.
+struct TaskGuard(Arc<Tasks>);
+
+impl Drop for TaskGuard {
+    fn drop(&mut self) {
+        if self.0.pending.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// The number of writes queued by default with
+/// [`IdempotentOptions::write_behind`](crate::IdempotentOptions::write_behind).
+pub(crate) const DEFAULT_MAX_PENDING_WRITES: usize = 1024;
+
+/// Bounds the number of responses waiting to be stored with
+/// [`IdempotentOptions::write_behind`](crate::IdempotentOptions::write_behind).
+///
+/// The permits are shared by the clones of the options they were created with.
+#[derive(Clone)]
+pub(crate) struct WriteBehind {
+    capacity: usize,
+    permits: Arc<Semaphore>,
+}
+
+impl WriteBehind {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            permits: Arc::new(Semaphore::new(capacity)),
+        }
+    }
+
+    /// Reserves a place in the queue for a write, held until the permit is dropped, or returns
+    /// `None` if the queue is full.
+    pub(crate) fn try_reserve(&self) -> Option<OwnedSemaphorePermit> {
+        self.permits.clone().try_acquire_owned().ok()
+    }
+}
+
+impl fmt::Debug for WriteBehind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteBehind")
+            .field("capacity", &self.capacity)
+            .field(
+                "queued",
+                &(self.capacity - self.permits.available_permits()),
+            )
+            .finish()
+    }
+}
@@ -0,0 +1,263 @@
+use crate::store::{IdempotencyStore, StoreKey};
+use sled::{IVec, Tree};
+use std::error::Error;
+use std::marker::PhantomData;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::task::JoinHandle;
+
+/// An embedded [`IdempotencyStore`] implementation on a `sled` tree, for single-node services
+/// whose entries must survive restarts without running a database.
+///
+/// Each entry is stored as the Unix time in milliseconds it expires at (8 bytes, big-endian)
+/// followed by its value. Expired entries are ignored on reads, and deleted when read or by the
+/// task started with [`SledStore::spawn_cleanup`]. In-flight locks are acquired with
+/// `compare_and_swap`, so concurrent requests cannot both acquire one.
+///
+/// Keys are strings, unless [`binary_keys`](Self::binary_keys) is set.
+///
+/// This requires the `sled-store` feature.
+///
+/// # Example
+/// ```rust,no_run
+/// use std::time::Duration;
+/// use axum::{Router, routing::post};
+/// use axum_idempotent::{IdempotentLayer, IdempotentOptions, SledStore};
+///
+/// #[tokio::main]
+/// async fn main() {
+/// let db = sled::open("/var/lib/app/idempotency").unwrap();
+/// let store = SledStore::new(db.open_tree("idempotency").unwrap());
+/// store.spawn_cleanup(Duration::from_secs(60));
+///
+/// let options = IdempotentOptions::default().use_idempotency_key_header(None);
+/// let app: Router = Router::new()
+///     .route("/payments", post(|| async { "Payment processed" }))
+///     .layer(IdempotentLayer::with_store(store, options));
+/// }
+/// ```
+#[derive(Debug)]
+pub struct SledStore<K: ?Sized = str> {
+    tree: Tree,
+    key: PhantomData<K>,
+}
+
+impl SledStore {
+    pub fn new(tree: Tree) -> Self {
+        Self {
+            tree,
+            key: PhantomData,
+        }
+    }
+
+    /// Takes keys as bytes, so that the request hashes of hashing mode are stored as raw bytes
+    /// rather than hex, halving the size of keys.
+    ///
+    /// Entries stored with string keys in hashing mode are not found anymore.
+    pub fn binary_keys(self) -> SledStore<[u8]> {
+        SledStore {
+            tree: self.tree,
+            key: PhantomData,
+        }
+    }
+}
+
+impl<K: ?Sized> Clone for SledStore<K> {
+    fn clone(&self) -> Self {
+        Self {
+            tree: self.tree.clone(),
+            key: PhantomData,
+        }
+    }
+}
+
+impl<K: StoreKey + AsRef<[u8]> + ?Sized> SledStore<K> {
+    /// Deletes the expired entries, returning how many were deleted.
+    pub fn remove_expired(&self) -> Result<u64, sled::Error> {
+        let now = now_millis();
+        let mut removed = 0;
+        for entry in self.tree.iter() {
+            let (key, raw) = entry?;
+            if expires_at(&raw) <= now
+                && self
+                    .tree
+                    .compare_and_swap(key, Some(raw), None as Option<IVec>)?
+                    .is_ok()
+            {
+                removed += 1;
+            }
+        }
+
+        Ok(removed)
+    }
+
+    /// Spawns a task deleting the expired entries every `interval`, until it is aborted.
+    pub fn spawn_cleanup(&self, interval: Duration) -> JoinHandle<()> {
+        let store = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            ticks.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticks.tick().await;
+                match store.remove_expired() {
+                    Ok(removed) => tracing::debug!("Removed {removed} expired idempotency entries"),
+                    Err(err) => {
+                        tracing::warn!("Failed to remove expired idempotency entries: {err:?}")
+                    }
+                }
+            }
+        })
+    }
+
+    /// Gets the raw form of the live entry stored under `key`, deleting it if it expired.
+    fn live(&self, key: &K) -> Result<Option<IVec>, sled::Error> {
+        let Some(raw) = self.tree.get(key.as_ref())? else {
+            return Ok(None);
+        };
+        if expires_at(&raw) > now_millis() {
+            return Ok(Some(raw));
+        }
+        // Unless it was replaced in the meantime
+        let _ = self
+            .tree
+            .compare_and_swap(key.as_ref(), Some(raw), None as Option<IVec>)?;
+        Ok(None)
+    }
+
+    /// Stores `value` under `key` for `ttl_secs` seconds unless there is a live entry,
+    /// returning its value otherwise.
+    fn insert_if_absent(
+        &self,
+        key: &K,
+        value: &[u8],
+        ttl_secs: i64,
+    ) -> Result<Option<Vec<u8>>, sled::Error> {
+        let entry = encode(value, ttl_secs);
+        let mut current = self.tree.get(key.as_ref())?;
+        loop {
+            if let Some(raw) = current
+                .as_ref()
+                .filter(|raw| expires_at(raw) > now_millis())
+            {
+                return Ok(Some(raw[8..].to_vec()));
+            }
+            // Expired entries are replaced, as if they had been deleted
+            match self
+                .tree
+                .compare_and_swap(key.as_ref(), current, Some(entry.as_slice()))?
+            {
+                Ok(()) => return Ok(None),
+                Err(err) => current = err.current,
+            }
+        }
+    }
+}
+
+impl<K: StoreKey + AsRef<[u8]> + ?Sized> IdempotencyStore for SledStore<K> {
+    type Key = K;
+
+    async fn get(&self, key: &K) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        Ok(self.live(key)?.map(|raw| raw[8..].to_vec()))
+    }
+
+    async fn set(
+        &self,
+        key: &K,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.tree.insert(key.as_ref(), encode(&value, ttl_secs))?;
+
+        Ok(())
+    }
+
+    async fn set_if_absent(
+        &self,
+        key: &K,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        Ok(self.insert_if_absent(key, &value, ttl_secs)?.is_none())
+    }
+
+    async fn get_or_lock(
+        &self,
+        key: &K,
+        marker: Vec<u8>,
+        lock_ttl_secs: i64,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        Ok(self.insert_if_absent(key, &marker, lock_ttl_secs)?)
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &K,
+        current: Vec<u8>,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let Some(raw) = self.live(key)?.filter(|raw| raw[8..] == current[..]) else {
+            return Ok(false);
+        };
+        let swapped =
+            self.tree
+                .compare_and_swap(key.as_ref(), Some(raw), Some(encode(&value, ttl_secs)))?;
+
+        Ok(swapped.is_ok())
+    }
+
+    async fn remove(&self, key: &K) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.tree.remove(key.as_ref())?;
+
+        Ok(())
+    }
+
+    async fn remove_prefix(&self, prefix: &K) -> Result<(), Box<dyn Error + Send + Sync>> {
+        for key in self.tree.scan_prefix(prefix.as_ref()).keys() {
+            self.tree.remove(key?)?;
+        }
+
+        Ok(())
+    }
+
+    async fn list_prefix(
+        &self,
+        prefix: &K,
+    ) -> Result<Vec<(K::Owned, i64)>, Box<dyn Error + Send + Sync>> {
+        let now = now_millis();
+        let mut entries = Vec::new();
+        for entry in self.tree.scan_prefix(prefix.as_ref()) {
+            let (key, raw) = entry?;
+            let expires_at = expires_at(&raw);
+            // Keys of the other type are skipped
+            if let (true, Some(key)) = (expires_at > now, K::from_raw(&key)) {
+                entries.push((key.to_owned(), (expires_at - now).div_ceil(1000) as i64));
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+/// The current Unix time, in milliseconds.
+fn now_millis() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    now.as_millis() as u64
+}
+
+/// Prefixes `value` with the time it expires at, `ttl_secs` seconds from now.
+fn encode(value: &[u8], ttl_secs: i64) -> Vec<u8> {
+    let expires_at = now_millis().saturating_add(ttl_secs.max(0) as u64 * 1000);
+    let mut entry = Vec::with_capacity(8 + value.len());
+    entry.extend_from_slice(&expires_at.to_be_bytes());
+    entry.extend_from_slice(value);
+    entry
+}
+
+/// The Unix time in milliseconds the stored entry `raw` expires at, or 0 if it is malformed.
+fn expires_at(raw: &[u8]) -> u64 {
+    raw.get(..8)
+        .and_then(|bytes| bytes.try_into().ok())
+        .map_or(0, u64::from_be_bytes)
+}
@@ -0,0 +1,326 @@
+use crate::config::IdempotentOptions;
+use axum::extract::Request;
+use std::borrow::Cow;
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+
+/// The key looked up by the default [`IdempotencyStore::ping`].
+const HEALTH_CHECK_KEY: &str = "axum-idempotent:health-check";
+
+/// The keys of entries listed by [`IdempotencyStore::list_prefix`], with the number of seconds
+/// until they expire.
+type Listing<K> = Vec<(<K as ToOwned>::Owned, i64)>;
+
+/// The type of the keys of an [`IdempotencyStore`]: `str`, or `[u8]` for stores accepting
+/// binary keys.
+///
+/// Keys are made of text, except in hashing mode, where they end with the hash of the request:
+/// stores with `[u8]` keys receive it as raw bytes rather than as hex, halving its size (e.g.
+/// 32 bytes instead of 64 for BLAKE3).
+pub trait StoreKey:
+    ToOwned<Owned: Send + Sync + fmt::Debug> + fmt::Debug + Send + Sync + 'static
+{
+    /// Returns the text key `key` as a key of this type.
+    fn from_text(key: &str) -> &Self;
+
+    /// Returns `bytes` as a key of this type, or `None` if they are not one, e.g. for keys
+    /// listed by a store.
+    fn from_raw(bytes: &[u8]) -> Option<&Self>;
+
+    /// Returns the text key `key`, whose hex request hash starts at byte `hash_start`, as a key
+    /// of this type.
+    fn from_hashed(key: &str, hash_start: usize) -> Cow<'_, Self>;
+
+    /// Returns the key as text, e.g. for logs, with invalid UTF-8 replaced.
+    fn to_text(&self) -> Cow<'_, str>;
+}
+
+impl StoreKey for str {
+    fn from_text(key: &str) -> &Self {
+        key
+    }
+
+    fn from_raw(bytes: &[u8]) -> Option<&Self> {
+        std::str::from_utf8(bytes).ok()
+    }
+
+    fn from_hashed(key: &str, _hash_start: usize) -> Cow<'_, Self> {
+        Cow::Borrowed(key)
+    }
+
+    fn to_text(&self) -> Cow<'_, str> {
+        Cow::Borrowed(self)
+    }
+}
+
+impl StoreKey for [u8] {
+    fn from_text(key: &str) -> &Self {
+        key.as_bytes()
+    }
+
+    fn from_raw(bytes: &[u8]) -> Option<&Self> {
+        Some(bytes)
+    }
+
+    fn from_hashed(key: &str, hash_start: usize) -> Cow<'_, Self> {
+        let (text, hash) = key.as_bytes().split_at(hash_start);
+        let nibble = |c: u8| (c as char).to_digit(16).map(|digit| digit as u8);
+        let digest: Option<Vec<u8>> = hash
+            .chunks(2)
+            .map(|pair| match pair {
+                [high, low] => Some(nibble(*high)? << 4 | nibble(*low)?),
+                _ => None,
+            })
+            .collect();
+        match digest {
+            Some(digest) => Cow::Owned([text, &digest].concat()),
+            None => Cow::Borrowed(key.as_bytes()),
+        }
+    }
+
+    fn to_text(&self) -> Cow<'_, str> {
+        String::from_utf8_lossy(self)
+    }
+}
+
+/// Returns the text key `key` as a key of type `K`, where request hashes of `hash_len` hex
+/// digits end the keys in hashing mode.
+pub(crate) fn store_key<K: StoreKey + ?Sized>(key: &str, hash_len: Option<usize>) -> Cow<'_, K> {
+    match hash_len.and_then(|hash_len| key.len().checked_sub(hash_len)) {
+        Some(hash_start) if key.is_char_boundary(hash_start) => K::from_hashed(key, hash_start),
+        _ => Cow::Borrowed(K::from_text(key)),
+    }
+}
+
+/// A storage backend for idempotency entries.
+///
+/// Entries are opaque byte strings written by the middleware: serialized responses, and the
+/// markers of in-flight requests. Implementations only need to keep them under their key until
+/// the given TTL elapses. Keys are strings, or byte strings for stores whose
+/// [`Key`](Self::Key) is `[u8]`, which get the request hashes of hashing mode in binary form.
+///
+/// Use a store with [`IdempotentLayer::with_store`](crate::IdempotentLayer::with_store). The
+/// store is shared by all requests, so in hashing mode identical requests from different
+/// clients replay each other's responses unless the hashed headers (e.g. `Authorization`) tell
+/// them apart.
+///
+/// # Example
+/// ```rust
+/// use std::collections::HashMap;
+/// use std::error::Error;
+/// use std::sync::{Arc, Mutex};
+/// use axum_idempotent::IdempotencyStore;
+///
+/// #[derive(Clone, Default)]
+/// struct HashMapStore(Arc<Mutex<HashMap<String, Vec<u8>>>>);
+///
+/// impl IdempotencyStore for HashMapStore {
+///     type Key = str;
+///
+///     async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+///         Ok(self.0.lock().unwrap().get(key).cloned())
+///     }
+///
+///     async fn set(
+///         &self,
+///         key: &str,
+///         value: Vec<u8>,
+///         _ttl_secs: i64,
+///     ) -> Result<(), Box<dyn Error + Send + Sync>> {
+///         // A real store would expire the entry after `_ttl_secs`.
+///         self.0.lock().unwrap().insert(key.to_owned(), value);
+///         Ok(())
+///     }
+///
+///     async fn remove(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+///         self.0.lock().unwrap().remove(key);
+///         Ok(())
+///     }
+/// }
+/// ```
+pub trait IdempotencyStore: Clone + Send + Sync + 'static {
+    /// The type of the keys entries are stored under: `str`, or `[u8]` to store the request
+    /// hashes of hashing mode as raw bytes.
+    type Key: StoreKey + ?Sized;
+
+    /// Gets the entry stored under `key`, if it has not expired.
+    fn get(
+        &self,
+        key: &Self::Key,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>>> + Send;
+
+    /// Stores `value` under `key`, replacing any existing entry, for `ttl_secs` seconds.
+    fn set(
+        &self,
+        key: &Self::Key,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> impl Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send;
+
+    /// Stores `value` under `key` for `ttl_secs` seconds unless an entry already exists,
+    /// returning whether it was stored.
+    ///
+    /// This is used to acquire in-flight locks (see
+    /// [`IdempotentOptions::lock_in_flight`](crate::IdempotentOptions::lock_in_flight)). The
+    /// default implementation checks and writes in two steps, so two concurrent requests may
+    /// both acquire the lock; stores should override it with an atomic operation where
+    /// available, such as Redis's `SET NX`.
+    fn set_if_absent(
+        &self,
+        key: &Self::Key,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> impl Future<Output = Result<bool, Box<dyn Error + Send + Sync>>> + Send {
+        async move {
+            if self.get(key).await?.is_some() {
+                return Ok(false);
+            }
+            self.set(key, value, ttl_secs).await?;
+            Ok(true)
+        }
+    }
+
+    /// Gets the entry stored under `key`, or if there is none, stores `marker` under it for
+    /// `lock_ttl_secs` seconds and returns `None`.
+    ///
+    /// This looks up keys and acquires their in-flight lock at once when
+    /// [`IdempotentOptions::lock_in_flight`](crate::IdempotentOptions::lock_in_flight) is
+    /// enabled. The default implementation calls [`Self::get`] then [`Self::set_if_absent`],
+    /// taking two round trips; stores should override it with a single pipelined or scripted
+    /// operation where available, such as a Redis Lua script.
+    fn get_or_lock(
+        &self,
+        key: &Self::Key,
+        marker: Vec<u8>,
+        lock_ttl_secs: i64,
+    ) -> impl Future<Output = Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>>> + Send {
+        async move {
+            if let Some(entry) = self.get(key).await? {
+                return Ok(Some(entry));
+            }
+            if self
+                .set_if_absent(key, marker.clone(), lock_ttl_secs)
+                .await?
+            {
+                return Ok(None);
+            }
+            // A concurrent request locked the key in the meantime
+            Ok(Some(self.get(key).await?.unwrap_or(marker)))
+        }
+    }
+
+    /// Stores `value` under `key` for `ttl_secs` seconds if the entry stored under it is still
+    /// `current`, returning whether it was stored.
+    ///
+    /// This is used to record replays (see
+    /// [`IdempotentOptions::count_replays`](crate::IdempotentOptions::count_replays) and
+    /// [`IdempotentOptions::sliding_expiration`](crate::IdempotentOptions::sliding_expiration)),
+    /// so that concurrent replays are all counted, and an entry removed in the meantime is not
+    /// stored again. The default implementation compares and writes in two steps, so
+    /// concurrent replays may overwrite each other; stores should override it with an atomic
+    /// operation where available, such as a Redis Lua script.
+    fn compare_and_set(
+        &self,
+        key: &Self::Key,
+        current: Vec<u8>,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> impl Future<Output = Result<bool, Box<dyn Error + Send + Sync>>> + Send {
+        async move {
+            if self.get(key).await?.as_ref() != Some(&current) {
+                return Ok(false);
+            }
+            self.set(key, value, ttl_secs).await?;
+            Ok(true)
+        }
+    }
+
+    /// Removes the entry stored under `key`.
+    fn remove(
+        &self,
+        key: &Self::Key,
+    ) -> impl Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send;
+
+    /// Checks that the store is reachable.
+    ///
+    /// This is used by [`IdempotentLayer::health_check`](crate::IdempotentLayer::health_check).
+    /// The default implementation looks up a key no entry is stored under; stores should
+    /// override it with a cheaper operation where available, such as Redis's `PING`.
+    fn ping(&self) -> impl Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send {
+        async {
+            self.get(Self::Key::from_text(HEALTH_CHECK_KEY)).await?;
+            Ok(())
+        }
+    }
+
+    /// Removes every entry whose key starts with `prefix`.
+    ///
+    /// This is used by [`IdempotencyManager::invalidate_prefix`](crate::IdempotencyManager::invalidate_prefix).
+    /// The default implementation fails, since entries cannot be listed through this trait.
+    fn remove_prefix(
+        &self,
+        prefix: &Self::Key,
+    ) -> impl Future<Output = Result<(), Box<dyn Error + Send + Sync>>> + Send {
+        let _ = prefix;
+        async { Err("this store does not support removing entries by prefix".into()) }
+    }
+
+    /// Lists the keys of the entries whose key starts with `prefix`, with the number of
+    /// seconds until they expire.
+    ///
+    /// This is used by [`migrate::upgrade_entries`](crate::migrate::upgrade_entries). The
+    /// default implementation fails, since entries cannot be listed through this trait.
+    fn list_prefix(
+        &self,
+        prefix: &Self::Key,
+    ) -> impl Future<Output = Result<Listing<Self::Key>, Box<dyn Error + Send + Sync>>> + Send {
+        let _ = prefix;
+        async { Err("this store does not support listing entries by prefix".into()) }
+    }
+}
+
+/// Resolves the [`IdempotencyStore`] used for each request.
+///
+/// This is implemented for every `ruts` `SessionStore` (with the
+/// `session` feature), and for [`StoreBackend`]. It cannot be implemented outside this crate.
+pub trait Backend: Send + Sync + 'static {
+    /// The state kept by the layer.
+    type State: Clone + Send + Sync + 'static;
+    /// The store entries are kept in.
+    type Store: IdempotencyStore;
+
+    /// Returns the store for `req`, or `None` if the request should be forwarded without
+    /// idempotency.
+    fn resolve(
+        state: &Self::State,
+        req: &mut Request,
+        config: &IdempotentOptions,
+        route: Option<&str>,
+    ) -> impl Future<Output = Option<Self::Store>> + Send;
+
+    /// The scope (e.g. session id) entries in `store` belong to.
+    fn scope(_store: &Self::Store) -> Option<String> {
+        None
+    }
+}
+
+/// The backend of layers created with
+/// [`IdempotentLayer::with_store`](crate::IdempotentLayer::with_store).
+#[derive(Debug)]
+pub struct StoreBackend<S>(PhantomData<S>);
+
+impl<S: IdempotencyStore> Backend for StoreBackend<S> {
+    type State = S;
+    type Store = S;
+
+    async fn resolve(
+        state: &S,
+        _req: &mut Request,
+        _config: &IdempotentOptions,
+        _route: Option<&str>,
+    ) -> Option<S> {
+        Some(state.clone())
+    }
+}
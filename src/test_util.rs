@@ -0,0 +1,632 @@
+//! Test doubles for exercising idempotency behavior deterministically.
+//!
+//! [`MockStore`] is an in-memory [`IdempotencyStore`] whose failures can be scripted: added
+//! latency, failing operations, and partial writes. Its entries expire according to a
+//! [`MockClock`], which only moves when advanced, so TTL tests do not sleep.
+//!
+//! [`conformance`] checks that a store implementation honors the contract of
+//! [`IdempotencyStore`], against the real backend.
+//!
+//! This requires the `test-util` feature, which is meant for `dev-dependencies`.
+//!
+//! # Example
+//! ```rust
+//! use std::time::Duration;
+//! use axum_idempotent::IdempotencyStore;
+//! use axum_idempotent::test_util::{MockClock, MockStore};
+//!
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let clock = MockClock::new();
+//! let store = MockStore::with_clock(clock.clone());
+//! store.set("key", b"entry".to_vec(), 60).await.unwrap();
+//!
+//! clock.advance(Duration::from_secs(61));
+//! assert!(store.get("key").await.unwrap().is_none());
+//!
+//! store.fail_next(1);
+//! assert!(store.get("key").await.is_err());
+//! # }
+//! ```
+
+use crate::clock::Clock;
+use crate::store::IdempotencyStore;
+use crate::{ConflictBehavior, IdempotentLayer, IdempotentOptions};
+use axum::Router;
+use axum::body::{Body, to_bytes};
+use axum::extract::Request;
+use axum::http::StatusCode;
+use axum::response::Response;
+use axum::routing::post;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tower_service::Service;
+
+/// A clock that only moves when [advanced](Self::advance).
+///
+/// Clones share the same time. It implements [`Clock`], so the middleware can be set to the
+/// same time as a [`MockStore`] with
+/// [`IdempotentOptions::clock`](crate::IdempotentOptions::clock).
+#[derive(Clone, Debug)]
+pub struct MockClock {
+    now: Arc<Mutex<SystemTime>>,
+}
+
+impl MockClock {
+    /// Creates a clock set to the current time.
+    pub fn new() -> Self {
+        Self::at(SystemTime::now())
+    }
+
+    /// Creates a clock set to `time`.
+    pub fn at(time: SystemTime) -> Self {
+        Self {
+            now: Arc::new(Mutex::new(time)),
+        }
+    }
+
+    /// Returns the current time of the clock.
+    pub fn now(&self) -> SystemTime {
+        *self.now.lock().unwrap()
+    }
+
+    /// Moves the clock forward by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.now.lock().unwrap() += duration;
+    }
+
+    /// Sets the clock to `time`.
+    pub fn set(&self, time: SystemTime) {
+        *self.now.lock().unwrap() = time;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        MockClock::now(self)
+    }
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The entries of a [`MockStore`], with their expiration time.
+type Entries = HashMap<String, (Vec<u8>, SystemTime)>;
+
+/// An in-memory [`IdempotencyStore`] with scripted failures, for tests.
+///
+/// Entries expire after their TTL according to the store's [`MockClock`]. Failures are
+/// injected with [`latency`](Self::latency), [`fail_next`](Self::fail_next),
+/// [`fail_writes`](Self::fail_writes) and [`partial_writes`](Self::partial_writes), and can be
+/// changed while requests are handled. Clones share the same entries and settings.
+#[derive(Clone, Default)]
+pub struct MockStore {
+    clock: MockClock,
+    entries: Arc<Mutex<Entries>>,
+    latency: Arc<Mutex<Duration>>,
+    failures: Arc<AtomicUsize>,
+    fail_writes: Arc<AtomicBool>,
+    partial_writes: Arc<AtomicBool>,
+    operations: Arc<AtomicUsize>,
+}
+
+impl MockStore {
+    /// Creates an empty store, with its own clock.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates an empty store expiring entries according to `clock`.
+    pub fn with_clock(clock: MockClock) -> Self {
+        Self {
+            clock,
+            ..Self::default()
+        }
+    }
+
+    /// Returns the clock entries expire according to.
+    pub fn clock(&self) -> &MockClock {
+        &self.clock
+    }
+
+    /// Delays every operation by `latency`.
+    pub fn latency(&self, latency: Duration) {
+        *self.latency.lock().unwrap() = latency;
+    }
+
+    /// Fails the next `count` operations, whatever they are.
+    pub fn fail_next(&self, count: usize) {
+        self.failures.store(count, Ordering::SeqCst);
+    }
+
+    /// Whether operations writing entries (`set`, `set_if_absent`, `compare_and_set` and
+    /// `get_or_lock` when it would lock) fail.
+    pub fn fail_writes(&self, enabled: bool) {
+        self.fail_writes.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Whether `set` only stores the first half of values while reporting success, like a
+    /// write interrupted by a crash, so the entry read back is corrupted.
+    pub fn partial_writes(&self, enabled: bool) {
+        self.partial_writes.store(enabled, Ordering::SeqCst);
+    }
+
+    /// Returns the number of operations performed, including failed ones.
+    pub fn operations(&self) -> usize {
+        self.operations.load(Ordering::SeqCst)
+    }
+
+    /// Returns the unexpired entry stored under `key`, without counting an operation.
+    pub fn entry(&self, key: &str) -> Option<Vec<u8>> {
+        let now = self.clock.now();
+        let entries = self.entries.lock().unwrap();
+        entries
+            .get(key)
+            .filter(|(_, expires_at)| *expires_at > now)
+            .map(|(value, _)| value.clone())
+    }
+
+    /// Returns the time left before the entry stored under `key` expires.
+    pub fn ttl(&self, key: &str) -> Option<Duration> {
+        let now = self.clock.now();
+        let entries = self.entries.lock().unwrap();
+        let (_, expires_at) = entries.get(key)?;
+        expires_at.duration_since(now).ok()
+    }
+
+    /// Returns the number of unexpired entries.
+    pub fn len(&self) -> usize {
+        let now = self.clock.now();
+        let entries = self.entries.lock().unwrap();
+        entries
+            .values()
+            .filter(|(_, expires_at)| *expires_at > now)
+            .count()
+    }
+
+    /// Whether the store holds no unexpired entries.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Counts an operation, applying the injected latency and failures.
+    async fn operation(&self, write: bool) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.operations.fetch_add(1, Ordering::SeqCst);
+        let latency = *self.latency.lock().unwrap();
+        if !latency.is_zero() {
+            tokio::time::sleep(latency).await;
+        }
+        let failing = self
+            .failures
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                count.checked_sub(1)
+            })
+            .is_ok();
+        if failing || (write && self.fail_writes.load(Ordering::SeqCst)) {
+            return Err(InjectedFailure.into());
+        }
+        Ok(())
+    }
+
+    fn insert(&self, key: &str, value: Vec<u8>, ttl_secs: i64) {
+        let expires_at = self.clock.now() + Duration::from_secs(ttl_secs.max(0) as u64);
+        let mut entries = self.entries.lock().unwrap();
+        entries.insert(key.to_owned(), (value, expires_at));
+    }
+}
+
+impl fmt::Debug for MockStore {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockStore")
+            .field("clock", &self.clock)
+            .field("entries", &self.entries.lock().unwrap().len())
+            .field("operations", &self.operations())
+            .finish_non_exhaustive()
+    }
+}
+
+impl IdempotencyStore for MockStore {
+    type Key = str;
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        self.operation(false).await?;
+        Ok(self.entry(key))
+    }
+
+    async fn set(
+        &self,
+        key: &str,
+        mut value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.operation(true).await?;
+        if self.partial_writes.load(Ordering::SeqCst) {
+            value.truncate(value.len() / 2);
+        }
+        self.insert(key, value, ttl_secs);
+        Ok(())
+    }
+
+    async fn set_if_absent(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.operation(true).await?;
+        if self.entry(key).is_some() {
+            return Ok(false);
+        }
+        self.insert(key, value, ttl_secs);
+        Ok(true)
+    }
+
+    async fn get_or_lock(
+        &self,
+        key: &str,
+        marker: Vec<u8>,
+        lock_ttl_secs: i64,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        let locking = self.entry(key).is_none();
+        self.operation(locking).await?;
+        if let Some(entry) = self.entry(key) {
+            return Ok(Some(entry));
+        }
+        self.insert(key, marker, lock_ttl_secs);
+        Ok(None)
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &str,
+        current: Vec<u8>,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.operation(true).await?;
+        if self.entry(key) != Some(current) {
+            return Ok(false);
+        }
+        self.insert(key, value, ttl_secs);
+        Ok(true)
+    }
+
+    async fn remove(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.operation(true).await?;
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    async fn remove_prefix(&self, prefix: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.operation(true).await?;
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|key, _| !key.starts_with(prefix));
+        Ok(())
+    }
+}
+
+/// The error returned by the operations of a [`MockStore`] made to fail.
+#[derive(Debug)]
+pub struct InjectedFailure;
+
+impl fmt::Display for InjectedFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("injected mock store failure")
+    }
+}
+
+impl Error for InjectedFailure {}
+
+/// Checks that `store` honors the contract of [`IdempotencyStore`], panicking otherwise.
+///
+/// This is the suite shared by the tests of every store: reads, writes and removals,
+/// expiration, [`set_if_absent`](IdempotencyStore::set_if_absent),
+/// [`compare_and_set`](IdempotencyStore::compare_and_set) and
+/// [`get_or_lock`](IdempotencyStore::get_or_lock) under contention, the prefix operations, and
+/// an [`IdempotentLayer`] making concurrent duplicates wait for the first response.
+///
+/// Entries are stored under `prefix`, and the keys checked are removed first, so the suite can
+/// run repeatedly against a shared server. Stores that do not support prefix operations must
+/// fail both [`list_prefix`](IdempotencyStore::list_prefix) and
+/// [`remove_prefix`](IdempotencyStore::remove_prefix). It sleeps for entries to expire, two
+/// seconds at most, as stores counting TTLs in whole seconds need.
+///
+/// # Example
+/// ```rust,no_run
+/// use axum_idempotent::MemoryIdempotencyStore;
+/// use axum_idempotent::test_util::conformance;
+///
+/// #[tokio::test]
+/// async fn test_conformance() {
+///     conformance(MemoryIdempotencyStore::new(), "test:").await;
+/// }
+/// ```
+pub async fn conformance<S: IdempotencyStore<Key = str>>(store: S, prefix: &str) {
+    let key = |name: &str| format!("{prefix}{name}");
+    for name in [
+        "key",
+        "expiration:short",
+        "expiration:long",
+        "absent",
+        "swapped",
+        "lock",
+        "expiring",
+        "contended",
+        "tenant-1:a",
+        "tenant-1:b",
+        "tenant-10:a",
+        "tenant_1:a",
+        "tenant*:a",
+    ] {
+        store.remove(&key(name)).await.unwrap();
+    }
+
+    // Entries are replaced by `set` and deleted by `remove`, which succeeds for missing keys
+    assert_eq!(store.get(&key("key")).await.unwrap(), None);
+    store.set(&key("key"), b"first".to_vec(), 60).await.unwrap();
+    assert_eq!(
+        store.get(&key("key")).await.unwrap().as_deref(),
+        Some(&b"first"[..])
+    );
+    store
+        .set(&key("key"), vec![0, 255, 13, 10], 60)
+        .await
+        .unwrap();
+    assert_eq!(
+        store.get(&key("key")).await.unwrap().as_deref(),
+        Some(&[0, 255, 13, 10][..])
+    );
+    store.remove(&key("key")).await.unwrap();
+    assert_eq!(store.get(&key("key")).await.unwrap(), None);
+    store.remove(&key("key")).await.unwrap();
+    store.ping().await.unwrap();
+
+    // `set_if_absent` and `get_or_lock` only write missing entries
+    assert!(
+        store
+            .set_if_absent(&key("absent"), b"first".to_vec(), 1)
+            .await
+            .unwrap()
+    );
+    assert!(
+        !store
+            .set_if_absent(&key("absent"), b"second".to_vec(), 60)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        store.get(&key("absent")).await.unwrap().as_deref(),
+        Some(&b"first"[..])
+    );
+    // `compare_and_set` only replaces entries holding the expected value
+    assert!(
+        !store
+            .compare_and_set(&key("swapped"), b"first".to_vec(), b"second".to_vec(), 60)
+            .await
+            .unwrap()
+    );
+    assert_eq!(store.get(&key("swapped")).await.unwrap(), None);
+    store
+        .set(&key("swapped"), b"first".to_vec(), 60)
+        .await
+        .unwrap();
+    assert!(
+        store
+            .compare_and_set(&key("swapped"), b"first".to_vec(), b"second".to_vec(), 60)
+            .await
+            .unwrap()
+    );
+    assert!(
+        !store
+            .compare_and_set(&key("swapped"), b"first".to_vec(), b"third".to_vec(), 60)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        store.get(&key("swapped")).await.unwrap().as_deref(),
+        Some(&b"second"[..])
+    );
+    assert_eq!(
+        store
+            .get_or_lock(&key("lock"), b"lock".to_vec(), 60)
+            .await
+            .unwrap(),
+        None
+    );
+    let entry = store
+        .get_or_lock(&key("lock"), b"other".to_vec(), 60)
+        .await
+        .unwrap();
+    assert_eq!(entry.as_deref(), Some(&b"lock"[..]));
+    store
+        .set(&key("lock"), b"response".to_vec(), 60)
+        .await
+        .unwrap();
+    let entry = store
+        .get_or_lock(&key("lock"), b"other".to_vec(), 60)
+        .await
+        .unwrap();
+    assert_eq!(entry.as_deref(), Some(&b"response"[..]));
+    assert_eq!(
+        store
+            .get_or_lock(&key("expiring"), b"lock".to_vec(), 1)
+            .await
+            .unwrap(),
+        None
+    );
+
+    // Only one of concurrent requests acquires the lock
+    let locks: Vec<_> = (0..16)
+        .map(|i| {
+            let store = store.clone();
+            let key = key("contended");
+            tokio::spawn(async move { store.get_or_lock(&key, vec![i], 60).await.unwrap() })
+        })
+        .collect();
+    let mut acquired = 0;
+    for lock in locks {
+        if lock.await.unwrap().is_none() {
+            acquired += 1;
+        }
+    }
+    assert_eq!(acquired, 1);
+
+    // Only one of concurrent swaps from the same value succeeds
+    let swaps: Vec<_> = (0..16)
+        .map(|i| {
+            let store = store.clone();
+            let key = key("swapped");
+            tokio::spawn(async move {
+                store
+                    .compare_and_set(&key, b"second".to_vec(), vec![i], 60)
+                    .await
+                    .unwrap()
+            })
+        })
+        .collect();
+    let mut swapped = 0;
+    for swap in swaps {
+        if swap.await.unwrap() {
+            swapped += 1;
+        }
+    }
+    assert_eq!(swapped, 1);
+
+    // Listing reports the remaining TTL of entries
+    store
+        .set(&key("expiration:short"), b"value".to_vec(), 1)
+        .await
+        .unwrap();
+    store
+        .set(&key("expiration:long"), b"value".to_vec(), 60)
+        .await
+        .unwrap();
+    let prefixes = match store.list_prefix(&key("expiration:")).await {
+        Ok(mut entries) => {
+            entries.sort();
+            assert_eq!(entries.len(), 2, "{entries:?}");
+            assert_eq!(entries[0].0, key("expiration:long"));
+            assert!((59..=60).contains(&entries[0].1), "{entries:?}");
+            assert_eq!(entries[1].0, key("expiration:short"));
+            assert!((0..=1).contains(&entries[1].1), "{entries:?}");
+            true
+        }
+        Err(_) => {
+            assert!(store.remove_prefix(&key("expiration:")).await.is_err());
+            false
+        }
+    };
+
+    // Expired entries are ignored on reads, replaced by `set_if_absent`, and locked again
+    tokio::time::sleep(Duration::from_millis(2100)).await;
+    assert_eq!(store.get(&key("expiration:short")).await.unwrap(), None);
+    assert!(store.get(&key("expiration:long")).await.unwrap().is_some());
+    if prefixes {
+        let entries = store.list_prefix(&key("expiration:")).await.unwrap();
+        assert_eq!(entries.len(), 1, "{entries:?}");
+    }
+    assert!(
+        store
+            .set_if_absent(&key("absent"), b"third".to_vec(), 60)
+            .await
+            .unwrap()
+    );
+    assert_eq!(
+        store.get(&key("absent")).await.unwrap().as_deref(),
+        Some(&b"third"[..])
+    );
+    assert_eq!(
+        store
+            .get_or_lock(&key("expiring"), b"new".to_vec(), 60)
+            .await
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        store.get(&key("expiring")).await.unwrap().as_deref(),
+        Some(&b"new"[..])
+    );
+
+    // Prefixes are matched literally, not as glob or LIKE patterns
+    if prefixes {
+        for name in [
+            "tenant-1:a",
+            "tenant-1:b",
+            "tenant-10:a",
+            "tenant_1:a",
+            "tenant*:a",
+        ] {
+            store.set(&key(name), b"value".to_vec(), 60).await.unwrap();
+        }
+        let mut entries = store.list_prefix(&key("tenant-1:")).await.unwrap();
+        entries.sort();
+        assert_eq!(entries, [(key("tenant-1:a"), 60), (key("tenant-1:b"), 60)]);
+        store.remove_prefix(&key("tenant_1:")).await.unwrap();
+        store.remove_prefix(&key("tenant*:")).await.unwrap();
+        assert!(store.get(&key("tenant-1:a")).await.unwrap().is_some());
+        assert_eq!(store.get(&key("tenant*:a")).await.unwrap(), None);
+        store.remove_prefix(&key("tenant-1:")).await.unwrap();
+        assert_eq!(store.get(&key("tenant-1:a")).await.unwrap(), None);
+        assert_eq!(store.get(&key("tenant-1:b")).await.unwrap(), None);
+        assert!(store.get(&key("tenant-10:a")).await.unwrap().is_some());
+    }
+
+    // Concurrent duplicates wait for the response of the first request
+    let calls = Arc::new(AtomicUsize::new(0));
+    let app = {
+        let calls = calls.clone();
+        Router::new()
+            .route(
+                "/charge",
+                post(move || async move {
+                    let call = calls.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    format!("charge #{call}")
+                }),
+            )
+            .layer(IdempotentLayer::with_store(
+                store,
+                IdempotentOptions::default()
+                    .use_idempotency_key_header(None)
+                    .key_prefix(prefix)
+                    .lock_in_flight(5)
+                    .on_conflict(ConflictBehavior::Wait(Duration::from_secs(2))),
+            ))
+    };
+    // Entries may not be removable by prefix, so each run uses a new key
+    let idempotency_key = format!("charge-{}", UNIX_EPOCH.elapsed().unwrap().as_nanos());
+    let request = || {
+        Request::builder()
+            .uri("/charge")
+            .method("POST")
+            .header("idempotency-key", &idempotency_key)
+            .body(Body::empty())
+            .unwrap()
+    };
+    let (first, second) = tokio::join!(
+        oneshot(app.clone(), request()),
+        oneshot(app.clone(), request())
+    );
+    assert_eq!(first.status(), StatusCode::OK);
+    assert_eq!(second.status(), StatusCode::OK);
+    let first = to_bytes(first.into_body(), usize::MAX).await.unwrap();
+    let second = to_bytes(second.into_body(), usize::MAX).await.unwrap();
+    assert_eq!(first, second);
+    let response = oneshot(app, request()).await;
+    assert_eq!(response.headers()["idempotency-replayed"], "true");
+    assert_eq!(calls.load(Ordering::SeqCst), 1);
+}
+
+/// Sends `request` to `app`, like `tower::ServiceExt::oneshot`.
+async fn oneshot(mut app: Router, request: Request) -> Response {
+    let Ok(()) = std::future::poll_fn(|cx| Service::<Request>::poll_ready(&mut app, cx)).await;
+    let Ok(response) = app.call(request).await;
+    response
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// The number of buckets kept before full ones are pruned.
+const MIN_PRUNE_LEN: usize = 1024;
+
+/// Limits the rate of requests per idempotency key with a token bucket per key, so that a
+/// client retrying the same key in a tight loop is rejected before reaching the store.
+///
+/// Buckets are kept in memory, and shared by the clones of the options they were created with.
+#[derive(Clone)]
+pub(crate) struct KeyRateLimiter {
+    capacity: f64,
+    window: Duration,
+    state: Arc<Mutex<State>>,
+}
+
+struct State {
+    buckets: HashMap<String, Bucket>,
+    /// The number of buckets at which full ones are pruned next.
+    prune_at: usize,
+}
+
+#[derive(Clone, Copy)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl KeyRateLimiter {
+    pub(crate) fn new(requests: u32, window: Duration) -> Self {
+        Self {
+            capacity: f64::from(requests.max(1)),
+            window,
+            state: Arc::new(Mutex::new(State {
+                buckets: HashMap::new(),
+                prune_at: MIN_PRUNE_LEN,
+            })),
+        }
+    }
+
+    /// Takes a token from the bucket of `key`, or returns how long the client should wait
+    /// before retrying if it is empty.
+    pub(crate) fn acquire(&self, key: &str) -> Result<(), Duration> {
+        let window = self.window.as_secs_f64();
+        if window <= 0.0 {
+            return Ok(());
+        }
+        // Tokens refilled per second
+        let rate = self.capacity / window;
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+
+        if state.buckets.len() >= state.prune_at {
+            // Buckets refilled since are the same as no bucket
+            state
+                .buckets
+                .retain(|_, bucket| now.duration_since(bucket.updated_at) < self.window);
+            state.prune_at = (state.buckets.len() * 2).max(MIN_PRUNE_LEN);
+        }
+
+        let bucket = state.buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: self.capacity,
+            updated_at: now,
+        });
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(self.capacity);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+impl fmt::Debug for KeyRateLimiter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyRateLimiter")
+            .field("requests", &self.capacity)
+            .field("window", &self.window)
+            .finish_non_exhaustive()
+    }
+}
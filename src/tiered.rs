@@ -0,0 +1,223 @@
+use crate::cached::{CachedResponse, is_pending};
+use crate::store::IdempotencyStore;
+use std::error::Error;
+use std::time::SystemTime;
+
+/// An [`IdempotencyStore`] layering a fast `Hot` store in front of a durable `Cold` store.
+///
+/// Writes go through to both tiers, the cold one first. Reads are served by the hot tier when
+/// it holds the entry, and otherwise by the cold one, whose entry is then copied back into the
+/// hot tier (read repair), so replays of a key only reach the cold tier once. The cold tier is
+/// authoritative: in-flight locks are only acquired there, so they hold across every process
+/// sharing it.
+///
+/// Entries live in the hot tier for at most [`hot_ttl`](Self::hot_ttl) seconds, while the
+/// cold tier keeps them for their full TTL. Any two stores with the same
+/// [`Key`](IdempotencyStore::Key) type can be layered, e.g. a
+/// [`MemoryIdempotencyStore`](crate::MemoryIdempotencyStore) in front of a Redis store, or a
+/// Redis store in front of a PostgreSQL one. Failing writes and removals in the hot tier are
+/// logged, and the entry is evicted from it so it cannot serve a stale copy.
+///
+/// # Example
+/// ```rust
+/// use axum::{Router, routing::post};
+/// use axum_idempotent::{IdempotentLayer, IdempotentOptions, MemoryIdempotencyStore, TieredStore};
+///
+/// let hot = MemoryIdempotencyStore::new().max_entries(10_000);
+/// # let cold = MemoryIdempotencyStore::new();
+/// let store = TieredStore::new(hot, cold).hot_ttl(60);
+/// let options = IdempotentOptions::default().use_idempotency_key_header(None);
+/// let app: Router = Router::new()
+///     .route("/payments", post(|| async { "Payment processed" }))
+///     .layer(IdempotentLayer::with_store(store, options));
+/// ```
+#[derive(Clone, Debug)]
+pub struct TieredStore<Hot, Cold> {
+    hot: Hot,
+    cold: Cold,
+    hot_ttl_secs: Option<i64>,
+}
+
+impl<Hot, Cold> TieredStore<Hot, Cold>
+where
+    Hot: IdempotencyStore,
+    Cold: IdempotencyStore<Key = Hot::Key>,
+{
+    /// Creates a store serving entries from `hot` when it can, and keeping them in `cold`.
+    pub fn new(hot: Hot, cold: Cold) -> Self {
+        Self {
+            hot,
+            cold,
+            hot_ttl_secs: None,
+        }
+    }
+
+    /// Keeps entries in the hot tier for at most `secs` seconds.
+    ///
+    /// Entries copied back from the cold tier whose expiration time is unknown, such as
+    /// tombstones, are kept in the hot tier for `secs` seconds, and are not copied back when
+    /// this is not set.
+    pub fn hot_ttl(mut self, secs: i64) -> Self {
+        self.hot_ttl_secs = Some(secs);
+        self
+    }
+
+    /// Returns the hot tier.
+    pub fn hot(&self) -> &Hot {
+        &self.hot
+    }
+
+    /// Returns the cold tier.
+    pub fn cold(&self) -> &Cold {
+        &self.cold
+    }
+
+    /// The TTL of an entry written to the hot tier for `ttl_secs` seconds.
+    fn hot_ttl_for(&self, ttl_secs: i64) -> i64 {
+        self.hot_ttl_secs.map_or(ttl_secs, |max| ttl_secs.min(max))
+    }
+
+    /// Copies `value`, read from the cold tier, into the hot tier.
+    async fn repair(&self, key: &Hot::Key, value: &[u8]) {
+        // In-flight markers are only kept in the cold tier, which arbitrates locks
+        if is_pending(value) {
+            return;
+        }
+        let remaining = CachedResponse::from_bytes(value)
+            .ok()
+            .and_then(|cached| cached.remaining_ttl_secs(SystemTime::now()));
+        let ttl_secs = match (remaining, self.hot_ttl_secs) {
+            (Some(remaining), _) => self.hot_ttl_for(remaining),
+            (None, Some(hot_ttl_secs)) => hot_ttl_secs,
+            (None, None) => return,
+        };
+        if ttl_secs <= 0 {
+            return;
+        }
+        if let Err(err) = self.hot.set(key, value.to_vec(), ttl_secs).await {
+            tracing::warn!("Failed to copy idempotency entry into the hot tier: {err:?}");
+        }
+    }
+
+    /// Evicts `key` from the hot tier after a failed write.
+    async fn evict(&self, key: &Hot::Key) {
+        if let Err(err) = self.hot.remove(key).await {
+            tracing::warn!("Failed to evict idempotency entry from the hot tier: {err:?}");
+        }
+    }
+}
+
+impl<Hot, Cold> IdempotencyStore for TieredStore<Hot, Cold>
+where
+    Hot: IdempotencyStore,
+    Cold: IdempotencyStore<Key = Hot::Key>,
+{
+    type Key = Hot::Key;
+
+    async fn get(&self, key: &Hot::Key) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        match self.hot.get(key).await {
+            Ok(Some(value)) => return Ok(Some(value)),
+            Ok(None) => {}
+            Err(err) => {
+                tracing::warn!("Failed to read idempotency entry from the hot tier: {err:?}");
+            }
+        }
+        let value = self.cold.get(key).await?;
+        if let Some(value) = &value {
+            self.repair(key, value).await;
+        }
+        Ok(value)
+    }
+
+    async fn set(
+        &self,
+        key: &Hot::Key,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let pending = is_pending(&value);
+        self.cold.set(key, value.clone(), ttl_secs).await?;
+        if pending {
+            // A stale response in the hot tier must not shadow the marker
+            self.evict(key).await;
+            return Ok(());
+        }
+        if let Err(err) = self.hot.set(key, value, self.hot_ttl_for(ttl_secs)).await {
+            tracing::warn!("Failed to write idempotency entry to the hot tier: {err:?}");
+            self.evict(key).await;
+        }
+        Ok(())
+    }
+
+    async fn set_if_absent(
+        &self,
+        key: &Hot::Key,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        self.cold.set_if_absent(key, value, ttl_secs).await
+    }
+
+    async fn get_or_lock(
+        &self,
+        key: &Hot::Key,
+        marker: Vec<u8>,
+        lock_ttl_secs: i64,
+    ) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+        if let Ok(Some(value)) = self.hot.get(key).await {
+            return Ok(Some(value));
+        }
+        let value = self.cold.get_or_lock(key, marker, lock_ttl_secs).await?;
+        if let Some(value) = &value {
+            self.repair(key, value).await;
+        }
+        Ok(value)
+    }
+
+    async fn compare_and_set(
+        &self,
+        key: &Hot::Key,
+        current: Vec<u8>,
+        value: Vec<u8>,
+        ttl_secs: i64,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        // The cold tier holds every entry, so it decides
+        let stored = self
+            .cold
+            .compare_and_set(key, current, value.clone(), ttl_secs)
+            .await?;
+        if !stored {
+            // The hot tier may hold the entry the comparison failed on
+            self.evict(key).await;
+            return Ok(false);
+        }
+        if let Err(err) = self.hot.set(key, value, self.hot_ttl_for(ttl_secs)).await {
+            tracing::warn!("Failed to write idempotency entry to the hot tier: {err:?}");
+            self.evict(key).await;
+        }
+        Ok(true)
+    }
+
+    async fn ping(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        // Requests are still deduplicated while the hot tier is down
+        self.cold.ping().await
+    }
+
+    async fn remove(&self, key: &Hot::Key) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.cold.remove(key).await?;
+        self.hot.remove(key).await
+    }
+
+    async fn remove_prefix(&self, prefix: &Hot::Key) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.cold.remove_prefix(prefix).await?;
+        self.hot.remove_prefix(prefix).await
+    }
+
+    async fn list_prefix(
+        &self,
+        prefix: &Hot::Key,
+    ) -> Result<Vec<(<Hot::Key as ToOwned>::Owned, i64)>, Box<dyn Error + Send + Sync>> {
+        // The cold tier holds every entry
+        self.cold.list_prefix(prefix).await
+    }
+}
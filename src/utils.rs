@@ -1,35 +1,141 @@
-use crate::config::IdempotentOptions;
-use axum::body::{Body, to_bytes};
-use axum::extract::Request;
-use axum::http::{HeaderMap, HeaderName, StatusCode};
+use crate::blob::{BlobPointer, blob_key};
+use crate::body::{BodyLimitExceeded, Uncollected, collect_body, with_trailers};
+#[cfg(any(feature = "gzip", feature = "zstd"))]
+use crate::cached::compress;
+use crate::cached::{CachedResponse, tombstone};
+use crate::config::{HashAlgorithm, IdempotentOptions, OversizedResponse, StoreMode};
+use crate::digest::content_digest;
+use crate::normalize::{param_name, sort_params};
+use axum::RequestExt;
+use axum::body::{Body, Bytes};
+use axum::extract::{MatchedPath, RawPathParams, Request};
+use axum::http::{HeaderMap, HeaderValue, header};
 use axum::response::Response;
-use blake3::Hasher;
-use std::error::Error;
-use std::str::FromStr;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::fmt::Write;
+use std::hash::Hasher;
+use xxhash_rust::xxh3::Xxh3;
 
+/// Computes the idempotency key for `req` and returns the request to be forwarded.
+///
+/// The returned request keeps the original `Parts` untouched, so headers such as
+/// `content-length`/`transfer-encoding` and all extensions reach the handler as sent.
+/// The body is only collected when it is part of the hash; otherwise the original
+/// `Body` is passed through without being polled. If it exceeds
+/// [`IdempotentOptions::max_body_bytes`], no key is returned and a [`BodyLimitExceeded`]
+/// marker is inserted into the request extensions.
+///
+/// With [`IdempotentOptions::validate_fingerprint`] in direct key mode, the fingerprint of the
+/// request is inserted into its extensions as a [`RequestFingerprint`].
 pub(crate) async fn hash_request(
     mut req: Request,
     options: &IdempotentOptions,
 ) -> (Request, Option<String>) {
-    if options.use_idempotency_key && options.ignore_body && options.ignore_all_headers {
+    #[cfg(feature = "jwt")]
+    if let Some(jwt) = &options.jwt_claim_key {
+        use crate::jwt::JwtClaimMode;
+
+        let claim = jwt.claim_value(req.headers());
+        return match jwt.mode {
+            JwtClaimMode::Derive => (req, claim),
+            JwtClaimMode::Scope => {
+                let (req, key) = compute_hash(req, options, false).await;
+                (
+                    req,
+                    claim.zip(key).map(|(claim, key)| format!("{claim}:{key}")),
+                )
+            }
+        };
+    }
+
+    #[cfg(feature = "webhook")]
+    if let Some(webhook) = &options.webhook_dedup {
+        return webhook.event_id(req, options.max_body_bytes).await;
+    }
+
+    if options.use_idempotency_key && options.validate_fingerprint {
+        let (fingerprinted, fingerprint) = compute_hash(req, options, true).await;
+        req = fingerprinted;
+        if let Some(fingerprint) = fingerprint {
+            req.extensions_mut().insert(RequestFingerprint(fingerprint));
+        }
+    }
+
+    compute_hash(req, options, false).await
+}
+
+/// The fingerprint of a request in direct key mode: the hash of its method, path, query and
+/// body, compared with the fingerprint of the cached response to detect reused keys.
+#[derive(Clone, Debug)]
+pub(crate) struct RequestFingerprint(pub(crate) String);
+
+/// Hashes `req` into its key, or into its fingerprint if `fingerprint` is set, which ignores
+/// the headers and always includes the body.
+async fn compute_hash(
+    mut req: Request,
+    options: &IdempotentOptions,
+    fingerprint: bool,
+) -> (Request, Option<String>) {
+    let ignore_body = options.ignore_body && !fingerprint;
+    if !fingerprint && options.use_idempotency_key && ignore_body && options.ignore_all_headers {
         let value = req.headers().get(&options.idempotency_key_header);
         let value = value.and_then(|v| v.to_str().ok().map(|v| v.to_string()));
         return (req, value);
     }
 
-    let mut hasher = Hasher::new();
-    hasher.update(req.method().as_str().as_bytes());
-    hasher.update(req.uri().path().as_bytes());
+    let mut hasher = KeyHasher::new(options.hash_algorithm, options.hash_seed.as_ref());
+    hasher.field(Field::Method, req.method().as_str().as_bytes());
+    match req.extensions().get::<MatchedPath>().cloned() {
+        Some(route) if options.hash_matched_path => {
+            hasher.field(Field::Route, route.as_str().as_bytes());
+            if let Ok(params) = req.extract_parts::<RawPathParams>().await {
+                for (name, value) in &params {
+                    hasher.field(Field::ParamName, name.as_bytes());
+                    hasher.field(Field::ParamValue, value.as_bytes());
+                }
+            }
+        }
+        _ => hasher.field(Field::Path, req.uri().path().as_bytes()),
+    }
+    if let (false, Some(query)) = (options.ignore_query, req.uri().query()) {
+        let query = normalize_query(query, options);
+        hasher.field(Field::Query, query.as_bytes());
+    }
+    if !fingerprint {
+        for hash_extension in &options.hashed_extensions {
+            (hash_extension.0)(req.extensions(), &mut hasher);
+        }
+    }
+
+    // Bodies with a normalizer are hashed once fully read
+    let normalizer = if ignore_body {
+        None
+    } else {
+        options.body_normalizer_for(req.headers())
+    };
 
-    if !options.ignore_all_headers {
+    if !options.ignore_all_headers && !fingerprint {
         // Collect and sort headers for consistent ordering
         let mut headers: Vec<_> = req
             .headers()
             .iter()
             .filter(|(name, value)| {
+                if let Some(hashed) = &options.hashed_req_headers {
+                    return hashed.contains(*name);
+                }
                 if options.ignored_req_headers.contains(*name) {
                     return false;
                 }
+                // Header names are lowercase
+                let patterns = &options.ignored_req_header_patterns;
+                if patterns
+                    .iter()
+                    .any(|pattern| glob_matches(pattern, name.as_str()))
+                {
+                    return false;
+                }
                 if let Some(ignored_value) = options.ignored_header_values.get(name.to_owned()) {
                     return value != ignored_value;
                 }
@@ -40,106 +146,476 @@ pub(crate) async fn hash_request(
         headers.sort_by(|(a_name, _), (b_name, _)| a_name.as_str().cmp(b_name.as_str()));
 
         for (name, value) in headers {
-            hasher.update(name.as_str().as_bytes());
-            hasher.update(value.as_bytes());
+            hasher.field(Field::HeaderName, name.as_str().as_bytes());
+            match (&normalizer, value.to_str()) {
+                (Some((_, normalizer)), Ok(value)) if name == header::CONTENT_TYPE => {
+                    let value = normalizer.normalize_content_type(value);
+                    hasher.field(Field::HeaderValue, value.as_bytes());
+                }
+                _ => hasher.field(Field::HeaderValue, value.as_bytes()),
+            }
         }
     }
 
-    if !options.ignore_body {
-        let (parts, body) = req.into_parts();
-        let body_bytes = to_bytes(body, usize::MAX).await.unwrap();
-        hasher.update(&body_bytes);
+    // A digest sent by the client stands in for the body, which is then not buffered
+    let digest = match content_digest(req.headers()) {
+        Some((value, check)) if options.hash_content_digest && !ignore_body => {
+            match (options.verify_content_digest, check) {
+                (false, _) => Some((value.clone(), None)),
+                (true, Some(check)) => Some((value.clone(), Some(check))),
+                // The digest cannot be verified
+                (true, None) => None,
+            }
+        }
+        _ => None,
+    };
+    if let Some((value, check)) = digest {
+        hasher.field(Field::ContentDigest, value.as_bytes());
+        if let Some(check) = check {
+            req = req.map(|body| check.verify(body));
+        }
+    } else if !ignore_body {
+        // The body is hashed chunk by chunk as it streams in
+        let (mut parts, body) = req.into_parts();
+        hasher.begin_body();
+        let chunks = collect_body(body, options.max_body_bytes, |chunk| {
+            if normalizer.is_none() {
+                hasher.body(chunk);
+            }
+        });
+        let buffered = match chunks.await {
+            Ok(buffered) => buffered,
+            Err(uncollected) => {
+                if let Uncollected::TooLarge(_) = &uncollected {
+                    parts.extensions.insert(BodyLimitExceeded);
+                }
+                return (Request::from_parts(parts, uncollected.into_body()), None);
+            }
+        };
+
+        if let Some((content_type, normalizer)) = normalizer {
+            let body = buffered.to_bytes();
+            match normalizer.normalize(&content_type, &body) {
+                Some(normalized) => hasher.body(&normalized),
+                None => hasher.body(&body),
+            }
+        }
 
-        req = Request::from_parts(parts, Body::from(body_bytes));
+        req = Request::from_parts(parts, buffered.into_body());
     }
 
-    (req, Some(hasher.finalize().to_string()))
+    (req, Some(hasher.finalize()))
 }
 
-/// Serialize
-pub(crate) async fn response_to_bytes(res: Response<Body>) -> (Response, Vec<u8>) {
-    let (parts, body) = res.into_parts();
+/// Applies the query normalization of `options` to `query`.
+fn normalize_query<'a>(query: &'a str, options: &IdempotentOptions) -> Cow<'a, str> {
+    if !options.sort_query && options.ignored_query_params.is_empty() {
+        return Cow::Borrowed(query);
+    }
 
-    let body_bytes = to_bytes(body, usize::MAX).await.unwrap();
+    let params: Vec<_> = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .filter(|param| {
+            !options
+                .ignored_query_params
+                .iter()
+                .any(|pattern| glob_matches(pattern, param_name(param)))
+        })
+        .collect();
+    let query = params.join("&");
 
-    let mut result = Vec::new();
-    // Serialize status code
-    result.extend_from_slice(&parts.status.as_u16().to_be_bytes());
+    Cow::Owned(if options.sort_query {
+        sort_params(&query)
+    } else {
+        query
+    })
+}
 
-    let headers = parts.headers.clone();
-    let len = headers.len();
-    for (i, (name, value)) in headers.iter().enumerate() {
-        result.extend_from_slice(name.as_str().as_bytes());
-        result.extend_from_slice(b": ");
-        result.extend_from_slice(value.as_bytes());
+/// The version of the canonical input hashed into keys, hashed before the fields of the
+/// request, so that keys computed from a different input never collide with these.
+const KEY_INPUT_VERSION: &[u8] = b"axum-idempotent:key:v1";
 
-        if i < len - 1 {
-            result.extend_from_slice(b"\r\n");
-        }
+/// The fields of a request hashed into its key, each tagged with its kind and prefixed with
+/// its length, so that distinct requests cannot produce the same input, e.g. by moving bytes
+/// from the path into the query.
+#[derive(Clone, Copy)]
+#[repr(u8)]
+enum Field {
+    Method = 1,
+    Path = 2,
+    Route = 3,
+    ParamName = 4,
+    ParamValue = 5,
+    Query = 6,
+    Extension = 7,
+    HeaderName = 8,
+    HeaderValue = 9,
+    ContentDigest = 10,
+    Body = 11,
+}
+
+/// An incremental hasher for the configured [`HashAlgorithm`], hashing the canonical input of
+/// version [`KEY_INPUT_VERSION`].
+///
+/// With a seed, BLAKE3 runs in keyed mode, SHA-256 as HMAC-SHA256, and XXH3 with a secret
+/// derived from the seed.
+struct KeyHasher {
+    state: HasherState,
+    /// The length of the body hashed so far, appended once it is complete, as the body is
+    /// the last field and streamed in.
+    body_len: Option<u64>,
+}
+
+enum HasherState {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(Sha256),
+    HmacSha256(Box<Hmac<Sha256>>),
+    Xxh3(Box<Xxh3>),
+}
+
+impl KeyHasher {
+    fn new(algorithm: HashAlgorithm, seed: Option<&[u8; 32]>) -> Self {
+        let state = match (algorithm, seed) {
+            (HashAlgorithm::Blake3, Some(seed)) => {
+                HasherState::Blake3(Box::new(blake3::Hasher::new_keyed(seed)))
+            }
+            (HashAlgorithm::Blake3, None) => HasherState::Blake3(Box::default()),
+            (HashAlgorithm::Sha256, Some(seed)) => {
+                // HMAC accepts keys of any length
+                let mac = Hmac::<Sha256>::new_from_slice(seed).expect("HMAC key length");
+                HasherState::HmacSha256(Box::new(mac))
+            }
+            (HashAlgorithm::Sha256, None) => HasherState::Sha256(Sha256::new()),
+            (HashAlgorithm::Xxh3, Some(seed)) => {
+                // The size of the default secret of XXH3
+                let mut secret = [0; 192];
+                let mut derive = blake3::Hasher::new_derive_key("axum-idempotent xxh3 secret");
+                derive.update(seed).finalize_xof().fill(&mut secret);
+                HasherState::Xxh3(Box::new(Xxh3::with_secret(secret)))
+            }
+            (HashAlgorithm::Xxh3, None) => HasherState::Xxh3(Box::default()),
+        };
+        let mut hasher = KeyHasher {
+            state,
+            body_len: None,
+        };
+        hasher.update(KEY_INPUT_VERSION);
+        hasher
+    }
+
+    /// Hashes a field of the request, which must come before the body.
+    fn field(&mut self, field: Field, data: &[u8]) {
+        self.update(&[field as u8]);
+        self.update(&(data.len() as u64).to_le_bytes());
+        self.update(data);
+    }
+
+    /// Starts hashing the body, whose chunks are then passed to [`Self::body`].
+    fn begin_body(&mut self) {
+        self.update(&[Field::Body as u8]);
+        self.body_len = Some(0);
     }
 
-    // headers/body separator (double CRLF)
-    result.extend_from_slice(b"\r\n\r\n");
-    result.extend_from_slice(&body_bytes);
+    /// Hashes a chunk of the body.
+    fn body(&mut self, chunk: &[u8]) {
+        self.body_len = Some(self.body_len.unwrap_or_default() + chunk.len() as u64);
+        self.update(chunk);
+    }
 
-    (Response::from_parts(parts, Body::from(body_bytes)), result)
+    fn update(&mut self, data: &[u8]) {
+        match &mut self.state {
+            HasherState::Blake3(hasher) => {
+                hasher.update(data);
+            }
+            HasherState::Sha256(hasher) => hasher.update(data),
+            HasherState::HmacSha256(mac) => mac.update(data),
+            HasherState::Xxh3(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Returns the hash as a lowercase hex string.
+    fn finalize(mut self) -> String {
+        if let Some(len) = self.body_len {
+            self.update(&len.to_le_bytes());
+        }
+        let hex = |bytes: &[u8]| {
+            bytes
+                .iter()
+                .fold(String::with_capacity(64), |mut hex, byte| {
+                    let _ = write!(hex, "{byte:02x}");
+                    hex
+                })
+        };
+        match self.state {
+            HasherState::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+            HasherState::Sha256(hasher) => hex(&hasher.finalize()),
+            HasherState::HmacSha256(mac) => hex(&mac.finalize().into_bytes()),
+            HasherState::Xxh3(hasher) => format!("{:032x}", hasher.digest128()),
+        }
+    }
 }
 
-/// Deserialize bytes back into a `axum::response::Response`.
-pub(crate) fn bytes_to_response(bytes: Vec<u8>) -> Result<Response, Box<dyn Error + Send + Sync>> {
-    // Split the bytes into status code, headers, and body
-    let status_code_bytes = &bytes[0..2];
-    let status_code = u16::from_be_bytes([status_code_bytes[0], status_code_bytes[1]]);
-    let status_code = StatusCode::from_u16(status_code)?;
+/// Feeds values hashed with [`Hash`](std::hash::Hash), e.g. by
+/// [`IdempotentOptions::hash_extension`], into the key, each write as a field.
+///
+/// Integers are written in little-endian order, and `usize`/`isize` as 64-bit integers, so keys
+/// do not depend on the platform.
+impl Hasher for KeyHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.field(Field::Extension, bytes);
+    }
 
-    // End of headers (double CRLF: \r\n\r\n)
-    let header_end = bytes
-        .windows(4)
-        .position(|window| window == b"\r\n\r\n")
-        .ok_or("Invalid header format: missing double CRLF")?;
+    fn write_u16(&mut self, i: u16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u32(&mut self, i: u32) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_u64(&mut self, i: u64) {
+        self.write(&i.to_le_bytes());
+    }
 
-    let header_bytes = &bytes[2..header_end];
-    let headers = parse_headers(header_bytes)?;
+    fn write_u128(&mut self, i: u128) {
+        self.write(&i.to_le_bytes());
+    }
 
-    // Skip both CRLFs after the header section (skip header_end + 4)
-    let body_bytes = &bytes[(header_end + 4)..];
-    let body = Body::from(body_bytes.to_vec());
+    fn write_usize(&mut self, i: usize) {
+        self.write_u64(i as u64);
+    }
 
-    let mut response = Response::new(body);
-    *response.status_mut() = status_code;
-    *response.headers_mut() = headers;
+    fn write_i16(&mut self, i: i16) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_i32(&mut self, i: i32) {
+        self.write(&i.to_le_bytes());
+    }
 
-    Ok(response)
+    fn write_i64(&mut self, i: i64) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_i128(&mut self, i: i128) {
+        self.write(&i.to_le_bytes());
+    }
+
+    fn write_isize(&mut self, i: isize) {
+        self.write_i64(i as i64);
+    }
+
+    /// Unused: the key is computed by [`KeyHasher::finalize`].
+    fn finish(&self) -> u64 {
+        0
+    }
 }
 
-/// Parse headers from bytes.
-fn parse_headers(header_bytes: &[u8]) -> Result<HeaderMap, Box<dyn Error + Send + Sync>> {
-    let mut headers = HeaderMap::new();
-    let header_str = std::str::from_utf8(header_bytes)?;
+/// Serialize a response, returning the response to forward along with its cached form.
+///
+/// The cached form lacks the headers stripped by the options, and records the `fingerprint` of
+/// the request, if any. If the body exceeds
+/// [`IdempotentOptions::max_response_bytes`], it is a tombstone with
+/// [`OversizedResponse::Tombstone`], and `None` otherwise. `None` is also returned if the
+/// body cannot be read.
+pub(crate) async fn serialize_response(
+    res: Response<Body>,
+    options: &IdempotentOptions,
+    fingerprint: Option<&str>,
+    ttl_secs: i64,
+) -> (Response, Option<Vec<u8>>) {
+    let (parts, body) = res.into_parts();
 
-    for line in header_str.split("\r\n") {
-        if line.is_empty() {
-            continue;
+    let stored_headers = match &options.store_mode {
+        StoreMode::Full => None,
+        StoreMode::StatusOnly => Some(HeaderMap::new()),
+        StoreMode::StatusWithHeaders(names) => Some(
+            names
+                .iter()
+                .flat_map(|name| {
+                    let values = parts.headers.get_all(name).iter().cloned();
+                    values.map(|value| (name.clone(), value))
+                })
+                .collect(),
+        ),
+    };
+    if let Some(headers) = stored_headers {
+        // The body is forwarded without being read
+        let mut cached = CachedResponse::new(parts.status, headers, Bytes::new())
+            .stored_at(options.now())
+            .with_ttl(ttl_secs);
+        if let Some(fingerprint) = fingerprint {
+            cached = cached.with_fingerprint(fingerprint);
         }
+        let cached = encode_response(&cached, options);
+        return (Response::from_parts(parts, body), Some(cached));
+    }
 
-        let parts: Vec<&str> = line.splitn(2, ": ").collect();
-        if parts.len() != 2 {
-            return Err("Invalid header format".into());
+    let limit = options.max_response_bytes.or(options.max_body_bytes);
+    let (body_bytes, trailers) = match collect_body(body, limit, |_| {}).await {
+        Ok(buffered) => (buffered.to_bytes(), buffered.trailers().cloned()),
+        Err(uncollected) => {
+            let cached = match (&uncollected, options.oversized_response) {
+                (Uncollected::TooLarge(_), OversizedResponse::Tombstone(status)) => {
+                    Some(tombstone(status))
+                }
+                _ => None,
+            };
+            return (Response::from_parts(parts, uncollected.into_body()), cached);
+        }
+    };
+    let mut headers = parts.headers.clone();
+    for name in &options.stripped_res_headers {
+        headers.remove(name);
+    }
+    let mut cached = CachedResponse::new(parts.status, headers, body_bytes.clone())
+        .stored_at(options.now())
+        .with_ttl(ttl_secs);
+    if let Some(trailers) = &trailers {
+        cached = cached.with_trailers(trailers.clone());
+    }
+    if let Some(fingerprint) = fingerprint {
+        cached = cached.with_fingerprint(fingerprint);
+    }
+    match &options.blob_store {
+        Some((store, threshold)) if body_bytes.len() > *threshold && trailers.is_none() => {
+            let key = blob_key(&options.entry_prefix(), &body_bytes);
+            let pointer = BlobPointer::new(key.clone(), body_bytes.len() as u64);
+            let response = Response::from_parts(parts, with_trailers(body_bytes.clone(), trailers));
+            if let Err(err) = store.0.put(key, body_bytes, ttl_secs).await {
+                tracing::error!("Failed to upload response body to the blob store: {err:?}");
+                return (response, None);
+            }
+            let cached = cached.with_blob(pointer);
+            return (response, Some(encode_response(&cached, options)));
         }
+        _ => {}
+    }
+
+    (
+        Response::from_parts(parts, with_trailers(body_bytes, trailers)),
+        Some(encode_response(&cached, options)),
+    )
+}
+
+/// Serializes `cached`, compressing it if configured.
+#[cfg_attr(not(any(feature = "gzip", feature = "zstd")), allow(unused_variables))]
+pub(crate) fn encode_response(cached: &CachedResponse, options: &IdempotentOptions) -> Vec<u8> {
+    let bytes = cached.to_bytes();
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    let bytes = match options.compress_over_bytes {
+        Some(threshold) if bytes.len() > threshold => compress(bytes, options.compression),
+        _ => bytes,
+    };
+
+    bytes
+}
+
+/// Whether an `If-None-Match` request header matches the `ETag` of a response, using the weak
+/// comparison of RFC 9110: `W/` prefixes are ignored, and `*` matches any tag.
+pub(crate) fn etag_matches(if_none_match: &HeaderValue, etag: &HeaderValue) -> bool {
+    let (Ok(if_none_match), Ok(etag)) = (if_none_match.to_str(), etag.to_str()) else {
+        return false;
+    };
+    let opaque = |tag: &str| tag.trim().trim_start_matches("W/").to_owned();
+    let etag = opaque(etag);
+    if_none_match
+        .split(',')
+        .any(|tag| tag.trim() == "*" || opaque(tag) == etag)
+}
 
-        let name = parts[0];
-        let value = parts[1];
-        headers.insert(HeaderName::from_str(name)?, value.parse()?);
+/// Whether `path` matches `pattern`.
+///
+/// Patterns are matched segment by segment: `*` and axum-style parameters (`{id}`) match any
+/// single segment, and `**` or a wildcard parameter (`{*rest}`) matches any number of remaining
+/// segments, including none. Trailing slashes are ignored.
+pub(crate) fn path_matches(pattern: &str, path: &str) -> bool {
+    let mut pattern = pattern.trim_end_matches('/').split('/');
+    let mut path = path.trim_end_matches('/').split('/');
+
+    loop {
+        match (pattern.next(), path.next()) {
+            (Some(expected), _) if expected == "**" || expected.starts_with("{*") => return true,
+            (Some(expected), Some(segment)) => {
+                let wildcard =
+                    expected == "*" || (expected.starts_with('{') && expected.ends_with('}'));
+                if !wildcard && expected != segment {
+                    return false;
+                }
+            }
+            (None, None) => return true,
+            _ => return false,
+        }
     }
+}
+
+/// Whether `value` matches `pattern`, in which `*` matches any sequence of characters.
+pub(crate) fn glob_matches(pattern: &str, value: &str) -> bool {
+    let Some((prefix, rest)) = pattern.split_once('*') else {
+        return pattern == value;
+    };
+    let Some(mut value) = value.strip_prefix(prefix) else {
+        return false;
+    };
 
-    Ok(headers)
+    let mut parts: Vec<_> = rest.split('*').collect();
+    let suffix = parts.pop().unwrap_or_default();
+    for part in parts {
+        match value.find(part) {
+            Some(index) => value = &value[index + part.len()..],
+            None => return false,
+        }
+    }
+    value.ends_with(suffix)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use axum::http::{Method, StatusCode};
+    use crate::normalize::{FormNormalizer, MultipartNormalizer};
+    use axum::body::{HttpBody, to_bytes};
+    use axum::http::{HeaderName, Method, StatusCode};
+    use http_body::{Frame, SizeHint};
     use std::default::Default;
+    use std::error::Error;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::{Duration, UNIX_EPOCH};
+
+    /// Serialize a response without a body limit or stripped headers.
+    async fn response_to_bytes(res: Response<Body>) -> (Response, Vec<u8>) {
+        let options = IdempotentOptions::default().preserve_response_headers([header::SET_COOKIE]);
+        let (res, bytes) = serialize_response(res, &options, None, 60).await;
+        (res, bytes.unwrap())
+    }
+
+    /// Deserialize bytes back into a `axum::response::Response`.
+    fn bytes_to_response(bytes: Vec<u8>) -> Result<Response, Box<dyn Error + Send + Sync>> {
+        Ok(CachedResponse::from_bytes(&bytes)?.into_response())
+    }
+
+    /// A body that never yields, so any attempt to collect it would hang.
+    struct PendingBody;
+
+    impl HttpBody for PendingBody {
+        type Data = Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            Poll::Pending
+        }
+
+        fn size_hint(&self) -> SizeHint {
+            SizeHint::default()
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct RequestMarker(u32);
 
     #[tokio::test]
     async fn test_hash_request() {
@@ -184,6 +660,350 @@ mod tests {
         assert_ne!(hash, hash3, "Different body should produce different hash");
     }
 
+    #[tokio::test]
+    async fn test_hash_request_with_seed() {
+        let request = || {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/test/endpoint")
+                .header("x-api-version", "1")
+                .body(Body::from("test body"))
+                .unwrap()
+        };
+        let seeded = IdempotentOptions::default().hash_seed([7; 32]);
+
+        let (_, hash) = hash_request(request(), &seeded).await;
+        assert_eq!(
+            hash.as_deref(),
+            Some("b56f8601b8d8bcc79e799587c1a81fc451bbc7f889971a9e2bc9fcbcc12e88ed"),
+            "Seeded hashes must be reproducible across runs"
+        );
+
+        let (_, unseeded) = hash_request(request(), &IdempotentOptions::default()).await;
+        assert_ne!(hash, unseeded);
+
+        let other_seed = IdempotentOptions::default().hash_seed([8; 32]);
+        let (_, other) = hash_request(request(), &other_seed).await;
+        assert_ne!(hash, other);
+    }
+
+    #[tokio::test]
+    async fn test_hash_request_golden() {
+        let request = || {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/test/endpoint")
+                .header("x-api-version", "1")
+                .body(Body::from("test body"))
+                .unwrap()
+        };
+
+        // Changing these values changes the key of every request: bump KEY_INPUT_VERSION
+        for (algorithm, seed, expected) in [
+            (
+                HashAlgorithm::Blake3,
+                None,
+                "727e92b7da2cb9c994190a48714193a3d6a8b12b70759ab9578d91816f157b4d",
+            ),
+            (
+                HashAlgorithm::Blake3,
+                Some([7; 32]),
+                "b56f8601b8d8bcc79e799587c1a81fc451bbc7f889971a9e2bc9fcbcc12e88ed",
+            ),
+            (
+                HashAlgorithm::Sha256,
+                None,
+                "0b832b98dac658f28e97ab4e3e6fa1eedb3019cd20416086b7a6fb42052d169f",
+            ),
+            (
+                HashAlgorithm::Sha256,
+                Some([7; 32]),
+                "5140f98378b6facdfa2b46c97a7826de508260cfab2b76824c1b8f4ff509164e",
+            ),
+            (
+                HashAlgorithm::Xxh3,
+                None,
+                "249b04ddb6991783d12fbea9773748d6",
+            ),
+            (
+                HashAlgorithm::Xxh3,
+                Some([7; 32]),
+                "a918033bc3a28f1f4e4e05e2c2563025",
+            ),
+        ] {
+            let mut options = IdempotentOptions::default().hash_algorithm(algorithm);
+            if let Some(seed) = seed {
+                options = options.hash_seed(seed);
+            }
+            let (_, hash) = hash_request(request(), &options).await;
+            assert_eq!(
+                hash.as_deref(),
+                Some(expected),
+                "{algorithm:?}, seeded: {}",
+                seed.is_some()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hash_request_fields_are_delimited() {
+        async fn hash(uri: &str, header: (&str, &str), body: &'static str) -> String {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .header(header.0, header.1)
+                .body(Body::from(body))
+                .unwrap();
+            hash_request(req, &IdempotentOptions::default())
+                .await
+                .1
+                .unwrap()
+        }
+
+        // Moving bytes between adjacent fields changes the key
+        let reference = hash("/orders", ("x-a", "bc"), "").await;
+        assert_ne!(reference, hash("/orders", ("x-ab", "c"), "").await);
+        assert_ne!(
+            hash("/orders", ("x-a", "b"), "").await,
+            hash("/orders", ("x-a", ""), "b").await
+        );
+        assert_ne!(
+            hash("/orders?a", ("x-a", "b"), "").await,
+            hash("/orders%3Fa", ("x-a", "b"), "").await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hash_only_headers() {
+        async fn hash(headers: &[(&str, &str)], options: &IdempotentOptions) -> String {
+            let mut req = Request::builder().method(Method::POST).uri("/orders");
+            for (name, value) in headers {
+                req = req.header(*name, *value);
+            }
+            let req = req.body(Body::empty()).unwrap();
+            hash_request(req, options).await.1.unwrap()
+        }
+
+        let options = IdempotentOptions::default()
+            .hash_only_headers([header::CONTENT_TYPE])
+            .hash_only_headers([HeaderName::from_static("x-api-version")]);
+
+        let reference = hash(&[("content-type", "application/json")], &options).await;
+        // Headers outside the allow-list do not influence the key
+        let with_trace = [("content-type", "application/json"), ("x-trace-id", "1234")];
+        assert_eq!(reference, hash(&with_trace, &options).await);
+        let other_type = [("content-type", "text/plain")];
+        assert_ne!(reference, hash(&other_type, &options).await);
+        let versioned = [("content-type", "application/json"), ("x-api-version", "2")];
+        assert_ne!(reference, hash(&versioned, &options).await);
+
+        // Without an allow-list, every header not ignored does
+        let options = IdempotentOptions::default();
+        assert_ne!(
+            hash(&[("content-type", "application/json")], &options).await,
+            hash(&with_trace, &options).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hash_extension() {
+        #[derive(Clone, Hash)]
+        struct ApiVersion(u16);
+
+        #[derive(Clone, Hash)]
+        struct Locale(&'static str);
+
+        async fn hash(version: Option<u16>, options: &IdempotentOptions) -> String {
+            let mut req = Request::builder()
+                .method(Method::POST)
+                .uri("/orders")
+                .body(Body::from("order"))
+                .unwrap();
+            if let Some(version) = version {
+                req.extensions_mut().insert(ApiVersion(version));
+            }
+            req.extensions_mut().insert(Locale("en"));
+            hash_request(req, options).await.1.unwrap()
+        }
+
+        let options = IdempotentOptions::default().hash_extension::<ApiVersion>("api-version");
+        let reference = hash(Some(1), &options).await;
+        assert_eq!(reference, hash(Some(1), &options).await);
+        assert_ne!(reference, hash(Some(2), &options).await);
+        assert_ne!(reference, hash(None, &options).await);
+
+        // Extensions are not hashed unless configured
+        let unhashed = IdempotentOptions::default();
+        assert_eq!(
+            hash(Some(1), &unhashed).await,
+            hash(Some(2), &unhashed).await
+        );
+        assert_ne!(
+            reference,
+            hash(Some(1), &options.clone().hash_extension::<Locale>("locale")).await
+        );
+
+        // Values are identified by their tag rather than their type
+        #[derive(Clone, Hash)]
+        struct RenamedApiVersion(u16);
+        let renamed =
+            IdempotentOptions::default().hash_extension::<RenamedApiVersion>("api-version");
+        let mut req = Request::builder()
+            .method(Method::POST)
+            .uri("/orders")
+            .body(Body::from("order"))
+            .unwrap();
+        req.extensions_mut().insert(RenamedApiVersion(1));
+        assert_eq!(reference, hash_request(req, &renamed).await.1.unwrap());
+    }
+
+    #[test]
+    fn test_key_hasher_writes_little_endian_integers() {
+        let key = |write: fn(&mut KeyHasher)| {
+            let mut hasher = KeyHasher::new(HashAlgorithm::Blake3, None);
+            write(&mut hasher);
+            hasher.finalize()
+        };
+
+        let bytes = key(|hasher| hasher.write(&[1, 0, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(key(|hasher| hasher.write_u64(1)), bytes);
+        assert_eq!(key(|hasher| hasher.write_usize(1)), bytes);
+        assert_eq!(key(|hasher| hasher.write_isize(1)), bytes);
+        assert_eq!(
+            key(|hasher| hasher.write_u32(0x0403_0201)),
+            key(|hasher| hasher.write(&[1, 2, 3, 4]))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_ignore_headers_matching() {
+        let hash = |forwarded_for: &'static str| async move {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("/orders")
+                .header("x-forwarded-for", forwarded_for)
+                .header("x-request-id", forwarded_for)
+                .body(Body::empty())
+                .unwrap();
+            let options = IdempotentOptions::default()
+                .ignore_headers_matching("X-Forwarded-*")
+                .ignore_headers_matching("x-*-id");
+            hash_request(req, &options).await.1.unwrap()
+        };
+
+        assert_eq!(hash("10.0.0.1").await, hash("10.0.0.2").await);
+    }
+
+    #[tokio::test]
+    async fn test_hash_algorithm() {
+        let request = || {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/test/endpoint")
+                .body(Body::from("test body"))
+                .unwrap()
+        };
+
+        let mut hashes = Vec::new();
+        for (algorithm, len) in [
+            (HashAlgorithm::Blake3, 64),
+            (HashAlgorithm::Sha256, 64),
+            (HashAlgorithm::Xxh3, 32),
+        ] {
+            let options = IdempotentOptions::default().hash_algorithm(algorithm);
+            let (_, hash) = hash_request(request(), &options).await;
+            let (_, again) = hash_request(request(), &options).await;
+            let hash = hash.unwrap();
+            assert_eq!(
+                Some(&hash),
+                again.as_ref(),
+                "{algorithm:?} is not deterministic"
+            );
+            assert_eq!(hash.len(), len);
+            assert!(hash.chars().all(|c| matches!(c, '0'..='9' | 'a'..='f')));
+
+            let seeded = options.hash_seed([7; 32]);
+            let (_, seeded) = hash_request(request(), &seeded).await;
+            assert_ne!(Some(&hash), seeded.as_ref());
+
+            hashes.push(hash);
+        }
+
+        let (_, default) = hash_request(request(), &IdempotentOptions::default()).await;
+        assert_eq!(default.as_ref(), Some(&hashes[0]), "BLAKE3 is the default");
+        assert_ne!(hashes[0], hashes[1]);
+        assert_ne!(hashes[1], hashes[2]);
+    }
+
+    #[tokio::test]
+    async fn test_hash_request_preserves_parts() {
+        let mut req = Request::builder()
+            .method(Method::POST)
+            .uri("/test/endpoint")
+            .header(header::CONTENT_LENGTH, "9")
+            .header(header::CONTENT_TYPE, "text/plain")
+            .body(Body::from("test body"))
+            .unwrap();
+        req.extensions_mut().insert(RequestMarker(7));
+
+        let (new_req, hash) = hash_request(req, &IdempotentOptions::default()).await;
+        assert!(hash.is_some());
+
+        assert_eq!(new_req.headers().get(header::CONTENT_LENGTH).unwrap(), "9");
+        assert_eq!(
+            new_req.headers().get(header::CONTENT_TYPE).unwrap(),
+            "text/plain"
+        );
+        assert!(new_req.headers().get(header::TRANSFER_ENCODING).is_none());
+        assert_eq!(
+            new_req.extensions().get::<RequestMarker>(),
+            Some(&RequestMarker(7))
+        );
+        assert_eq!(new_req.body().size_hint().exact(), Some(9));
+
+        let body_bytes = to_bytes(new_req.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body_bytes[..], b"test body");
+    }
+
+    #[tokio::test]
+    async fn test_hash_request_does_not_collect_ignored_body() {
+        let mut req = Request::builder()
+            .method(Method::POST)
+            .uri("/test/endpoint")
+            .body(Body::new(PendingBody))
+            .unwrap();
+        req.extensions_mut().insert(RequestMarker(1));
+
+        let options = IdempotentOptions::default().ignore_body(true);
+        let (new_req, hash) =
+            tokio::time::timeout(Duration::from_secs(1), hash_request(req, &options))
+                .await
+                .expect("the body must not be collected when it is ignored");
+
+        assert!(hash.is_some());
+        assert_eq!(
+            new_req.extensions().get::<RequestMarker>(),
+            Some(&RequestMarker(1))
+        );
+        // The original body is forwarded as-is, including its unknown length.
+        assert_eq!(new_req.body().size_hint().exact(), None);
+
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+        let req = Request::builder()
+            .method(Method::POST)
+            .uri("/test/endpoint")
+            .header("idempotency-key", "key")
+            .body(Body::new(PendingBody))
+            .unwrap();
+        let (new_req, hash) =
+            tokio::time::timeout(Duration::from_secs(1), hash_request(req, &options))
+                .await
+                .expect("the body must not be collected in direct key mode");
+
+        assert_eq!(hash.as_deref(), Some("key"));
+        assert_eq!(new_req.body().size_hint().exact(), None);
+    }
+
     #[tokio::test]
     async fn test_response_to_bytes() {
         // Create a response with known values
@@ -219,6 +1039,318 @@ mod tests {
         assert_eq!(&body_bytes[..], b"test response body");
     }
 
+    #[tokio::test]
+    async fn test_cached_response_stored_at() {
+        let response = Response::builder()
+            .status(StatusCode::CREATED)
+            .header("Set-Cookie", "a=1")
+            .header("Set-Cookie", "b=2")
+            .body(Body::from("created"))
+            .unwrap();
+
+        let before = std::time::SystemTime::now() - Duration::from_secs(1);
+        let (_, bytes) = response_to_bytes(response).await;
+        let cached = CachedResponse::from_bytes(&bytes).unwrap();
+
+        assert_eq!(cached.status, StatusCode::CREATED);
+        assert_eq!(cached.headers.get_all("set-cookie").iter().count(), 2);
+        assert_eq!(&cached.body[..], b"created");
+        assert!(cached.stored_at >= before);
+        assert!(cached.stored_at <= std::time::SystemTime::now());
+        assert_eq!(cached.to_bytes(), bytes);
+    }
+
+    #[tokio::test]
+    async fn test_serialize_response_store_mode() {
+        let response = || {
+            Response::builder()
+                .status(StatusCode::CREATED)
+                .header(header::LOCATION, "/orders/1")
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(r#"{"id":1}"#))
+                .unwrap()
+        };
+
+        let options = IdempotentOptions::default().store_mode(StoreMode::StatusOnly);
+        let (res, bytes) = serialize_response(response(), &options, None, 60).await;
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(
+            &body[..],
+            br#"{"id":1}"#,
+            "the original response is untouched"
+        );
+        let cached = CachedResponse::from_bytes(&bytes.unwrap()).unwrap();
+        assert_eq!(cached.status, StatusCode::CREATED);
+        assert!(cached.headers.is_empty());
+        assert!(cached.body.is_empty());
+
+        let options = options.store_mode(StoreMode::StatusWithHeaders(vec![header::LOCATION]));
+        let (_, bytes) = serialize_response(response(), &options, None, 60).await;
+        let cached = CachedResponse::from_bytes(&bytes.unwrap()).unwrap();
+        assert_eq!(cached.headers.len(), 1);
+        assert_eq!(cached.headers[header::LOCATION], "/orders/1");
+        assert!(cached.body.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_serialize_response_strips_headers() {
+        let response = || {
+            Response::builder()
+                .header(header::SET_COOKIE, "session=abc")
+                .header("x-request-id", "42")
+                .header(header::CONTENT_TYPE, "text/plain")
+                .body(Body::from("body"))
+                .unwrap()
+        };
+
+        let options = IdempotentOptions::default()
+            .strip_response_headers([HeaderName::from_static("x-request-id")]);
+        let (res, bytes) = serialize_response(response(), &options, None, 60).await;
+        assert_eq!(res.headers().len(), 3, "the original response is untouched");
+        let cached = CachedResponse::from_bytes(&bytes.unwrap()).unwrap();
+        assert!(cached.headers.get(header::SET_COOKIE).is_none());
+        assert!(cached.headers.get("x-request-id").is_none());
+        assert_eq!(
+            cached.headers.get(header::CONTENT_TYPE).unwrap(),
+            "text/plain"
+        );
+
+        let options = options.preserve_response_headers([header::SET_COOKIE]);
+        let (_, bytes) = serialize_response(response(), &options, None, 60).await;
+        let cached = CachedResponse::from_bytes(&bytes.unwrap()).unwrap();
+        assert_eq!(
+            cached.headers.get(header::SET_COOKIE).unwrap(),
+            "session=abc"
+        );
+        assert!(cached.headers.get("x-request-id").is_none());
+    }
+
+    #[test]
+    fn test_versioned_format() {
+        let cached = CachedResponse::new(StatusCode::CREATED, HeaderMap::new(), "created")
+            .with_fingerprint("abc");
+        let bytes = cached.to_bytes();
+        assert_eq!(bytes[..3], [0xfe, 0xed, 2]);
+        let decoded = CachedResponse::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded.fingerprint.as_deref(), Some("abc"));
+
+        // Version 1
+        let mut v1 = vec![0xfe, 0xed, 1];
+        v1.extend_from_slice(&201u16.to_be_bytes());
+        v1.extend_from_slice(&1_000u64.to_be_bytes());
+        v1.extend_from_slice(b"x-v: 1\r\n:grpc-status: 0\r\n\r\ncreated");
+        let legacy = CachedResponse::from_bytes(&v1).unwrap();
+        assert_eq!(legacy.status, StatusCode::CREATED);
+        assert_eq!(legacy.stored_at, UNIX_EPOCH + Duration::from_secs(1_000));
+        assert_eq!(legacy.headers.get("x-v").unwrap(), "1");
+        assert_eq!(legacy.trailers.unwrap().get("grpc-status").unwrap(), "0");
+        assert_eq!(&legacy.body[..], b"created");
+
+        // Entries written before the format was versioned, as encoded by earlier releases
+        let now = UNIX_EPOCH + Duration::from_secs(2_000);
+        let mut v0 = 201u16.to_be_bytes().to_vec();
+        v0.extend_from_slice(b"content-type: text/plain\r\nx-v: 1\r\n\r\ncreated");
+        let legacy = CachedResponse::decode(&v0, now).unwrap();
+        assert_eq!(legacy.status, StatusCode::CREATED);
+        assert_eq!(legacy.stored_at, now);
+        assert_eq!(
+            legacy.headers.get(header::CONTENT_TYPE).unwrap(),
+            "text/plain"
+        );
+        assert_eq!(legacy.headers.get("x-v").unwrap(), "1");
+        assert!(legacy.trailers.is_none());
+        assert_eq!(&legacy.body[..], b"created");
+        let mut bare = 204u16.to_be_bytes().to_vec();
+        bare.extend_from_slice(b"\r\n\r\n");
+        let legacy = CachedResponse::decode(&bare, now).unwrap();
+        assert_eq!(legacy.status, StatusCode::NO_CONTENT);
+        assert!(legacy.headers.is_empty() && legacy.body.is_empty());
+
+        let mut unknown = bytes.clone();
+        unknown[2] = 0x7f;
+        let err = CachedResponse::from_bytes(&unknown).unwrap_err();
+        assert!(err.is::<crate::cached::UnsupportedVersion>());
+    }
+
+    /// A response with every field of the format set but its blob, stored at a fixed time.
+    fn full_response() -> CachedResponse {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONTENT_TYPE,
+            HeaderValue::from_static("application/octet-stream"),
+        );
+        headers.append(header::VARY, HeaderValue::from_static("accept"));
+        headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", HeaderValue::from_static("0"));
+        // Binary bodies, including the separator of the previous format
+        let body = vec![0, 1, 0xff, b'\r', b'\n', b'\r', b'\n', 2];
+        let mut cached = CachedResponse::new(StatusCode::ACCEPTED, headers, body)
+            .stored_at(UNIX_EPOCH + Duration::from_secs(1_760_000_000))
+            .with_trailers(trailers)
+            .with_fingerprint("abc");
+        cached.expires_at = Some(UNIX_EPOCH + Duration::from_secs(1_760_000_060));
+        cached.replays = 3;
+        cached
+    }
+
+    /// Asserts that `decoded` holds the fields of `expected`.
+    fn assert_same_response(decoded: &CachedResponse, expected: &CachedResponse) {
+        assert_eq!(decoded.status, expected.status);
+        assert_eq!(decoded.headers, expected.headers);
+        assert_eq!(decoded.trailers, expected.trailers);
+        assert_eq!(decoded.body, expected.body);
+        assert_eq!(decoded.stored_at, expected.stored_at);
+        assert_eq!(decoded.fingerprint, expected.fingerprint);
+        assert_eq!(decoded.expires_at, expected.expires_at);
+        assert_eq!(decoded.ttl, expected.ttl);
+        assert_eq!(decoded.replays, expected.replays);
+        assert_eq!(decoded.blob, expected.blob);
+    }
+
+    #[test]
+    fn test_format_round_trip() {
+        let cached = full_response();
+        let decoded = CachedResponse::from_bytes(&cached.to_bytes()).unwrap();
+        assert_same_response(&decoded, &cached);
+        let uploaded = full_response().with_blob(BlobPointer::new("blob:123", 42));
+        let decoded = CachedResponse::from_bytes(&uploaded.to_bytes()).unwrap();
+        assert_same_response(&decoded, &uploaded);
+        let expiring = full_response().with_ttl(600);
+        let decoded = CachedResponse::from_bytes(&expiring.to_bytes()).unwrap();
+        assert_same_response(&decoded, &expiring);
+
+        // Fields left unset stay unset
+        let bare =
+            CachedResponse::new(StatusCode::NO_CONTENT, HeaderMap::new(), "").stored_at(UNIX_EPOCH);
+        let decoded = CachedResponse::from_bytes(&bare.to_bytes()).unwrap();
+        assert_same_response(&decoded, &bare);
+
+        #[cfg(feature = "gzip")]
+        {
+            let compressed = compress(cached.to_bytes(), crate::config::Compression::Gzip);
+            let decoded = CachedResponse::from_bytes(&compressed).unwrap();
+            assert_same_response(&decoded, &cached);
+        }
+        #[cfg(feature = "zstd")]
+        {
+            let compressed = compress(cached.to_bytes(), crate::config::Compression::Zstd);
+            let decoded = CachedResponse::from_bytes(&compressed).unwrap();
+            assert_same_response(&decoded, &cached);
+        }
+    }
+
+    #[test]
+    fn test_format_compatibility() {
+        // Version 2, as encoded by this release: changing it requires a new format version
+        let v2 = full_response().to_bytes();
+        let expected = [
+            254, 237, 2, 137, 166, 115, 116, 97, 116, 117, 115, 204, 202, 167, 104, 101, 97, 100,
+            101, 114, 115, 147, 130, 164, 110, 97, 109, 101, 172, 99, 111, 110, 116, 101, 110, 116,
+            45, 116, 121, 112, 101, 165, 118, 97, 108, 117, 101, 196, 24, 97, 112, 112, 108, 105,
+            99, 97, 116, 105, 111, 110, 47, 111, 99, 116, 101, 116, 45, 115, 116, 114, 101, 97,
+            109, 130, 164, 110, 97, 109, 101, 164, 118, 97, 114, 121, 165, 118, 97, 108, 117, 101,
+            196, 6, 97, 99, 99, 101, 112, 116, 130, 164, 110, 97, 109, 101, 164, 118, 97, 114, 121,
+            165, 118, 97, 108, 117, 101, 196, 15, 97, 99, 99, 101, 112, 116, 45, 101, 110, 99, 111,
+            100, 105, 110, 103, 168, 116, 114, 97, 105, 108, 101, 114, 115, 145, 130, 164, 110, 97,
+            109, 101, 171, 103, 114, 112, 99, 45, 115, 116, 97, 116, 117, 115, 165, 118, 97, 108,
+            117, 101, 196, 1, 48, 164, 98, 111, 100, 121, 196, 8, 0, 1, 255, 13, 10, 13, 10, 2,
+            169, 115, 116, 111, 114, 101, 100, 95, 97, 116, 206, 104, 231, 120, 0, 171, 102, 105,
+            110, 103, 101, 114, 112, 114, 105, 110, 116, 163, 97, 98, 99, 170, 101, 120, 112, 105,
+            114, 101, 115, 95, 97, 116, 206, 104, 231, 120, 60, 167, 114, 101, 112, 108, 97, 121,
+            115, 3, 164, 98, 108, 111, 98, 192,
+        ];
+        assert_eq!(v2, expected);
+
+        // Version 1, as encoded by the release before version 2, which had no fingerprint,
+        // expiration time or replay count
+        let v1 = [
+            254, 237, 1, 0, 202, 0, 0, 0, 0, 104, 231, 120, 0, 99, 111, 110, 116, 101, 110, 116,
+            45, 116, 121, 112, 101, 58, 32, 97, 112, 112, 108, 105, 99, 97, 116, 105, 111, 110, 47,
+            111, 99, 116, 101, 116, 45, 115, 116, 114, 101, 97, 109, 13, 10, 118, 97, 114, 121, 58,
+            32, 97, 99, 99, 101, 112, 116, 13, 10, 118, 97, 114, 121, 58, 32, 97, 99, 99, 101, 112,
+            116, 45, 101, 110, 99, 111, 100, 105, 110, 103, 13, 10, 58, 103, 114, 112, 99, 45, 115,
+            116, 97, 116, 117, 115, 58, 32, 48, 13, 10, 13, 10, 0, 1, 255, 13, 10, 13, 10, 2,
+        ];
+        let mut expected = full_response();
+        expected.fingerprint = None;
+        expected.expires_at = None;
+        expected.replays = 0;
+        let decoded = CachedResponse::from_bytes(&v1).unwrap();
+        assert_same_response(&decoded, &expected);
+
+        // Entries re-encoded from version 1 keep their fields
+        let decoded = CachedResponse::from_bytes(&decoded.to_bytes()).unwrap();
+        assert_same_response(&decoded, &expected);
+    }
+
+    #[tokio::test]
+    async fn test_response_trailers() {
+        /// Reads the data and trailers of `body`.
+        async fn read_frames(mut body: Body) -> (Vec<u8>, Option<HeaderMap>) {
+            let (mut data, mut trailers) = (Vec::new(), None);
+            while let Some(frame) =
+                std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await
+            {
+                match frame.unwrap().into_data() {
+                    Ok(chunk) => data.extend_from_slice(&chunk),
+                    Err(frame) => trailers = frame.into_trailers().ok(),
+                }
+            }
+            (data, trailers)
+        }
+
+        let mut trailers = HeaderMap::new();
+        trailers.insert("grpc-status", "0".parse().unwrap());
+        trailers.insert("grpc-message", "OK".parse().unwrap());
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, "application/grpc-web")
+            .body(with_trailers(
+                Bytes::from("message"),
+                Some(trailers.clone()),
+            ))
+            .unwrap();
+
+        let (res, bytes) = response_to_bytes(response).await;
+        assert_eq!(
+            read_frames(res.into_body()).await,
+            (b"message".to_vec(), Some(trailers.clone())),
+            "the forwarded response keeps its trailers"
+        );
+
+        let cached = CachedResponse::from_bytes(&bytes).unwrap();
+        assert_eq!(cached.trailers.as_ref(), Some(&trailers));
+        assert_eq!(cached.headers.len(), 1);
+        assert_eq!(cached.to_bytes(), bytes);
+
+        let replayed = bytes_to_response(bytes).unwrap();
+        assert_eq!(
+            read_frames(replayed.into_body()).await,
+            (b"message".to_vec(), Some(trailers))
+        );
+    }
+
+    #[cfg(any(feature = "gzip", feature = "zstd"))]
+    #[tokio::test]
+    async fn test_compress_over_bytes() {
+        let body = "{\"status\":\"ok\"}".repeat(512);
+        let response = || Response::new(Body::from(body.clone()));
+
+        let options = IdempotentOptions::default().compress_over_bytes(1024);
+        let (res, bytes) = serialize_response(response(), &options, None, 60).await;
+        let bytes = bytes.unwrap();
+        assert_eq!(bytes[0], 0xff);
+        assert!(bytes.len() < body.len());
+        let cached = CachedResponse::from_bytes(&bytes).unwrap();
+        assert_eq!(&cached.body[..], body.as_bytes());
+        let forwarded = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&forwarded[..], body.as_bytes());
+
+        let options = IdempotentOptions::default().compress_over_bytes(body.len() * 2);
+        let (_, bytes) = serialize_response(response(), &options, None, 60).await;
+        assert_ne!(bytes.unwrap()[0], 0xff);
+    }
+
     #[tokio::test]
     async fn test_response_to_bytes_with_empty_body() {
         let response = Response::builder()
@@ -286,18 +1418,245 @@ mod tests {
 
         let (_, bytes) = response_to_bytes(response).await;
 
-        // Skip status code (2 bytes)
-        let headers_and_body = &bytes[2..];
-        let headers_str = std::str::from_utf8(headers_and_body).unwrap();
+        // The envelope (3 bytes) is followed by a MessagePack map, which other services can
+        // deserialize, ignoring the fields they do not need
+        #[derive(serde::Deserialize)]
+        struct Field {
+            name: String,
+            #[serde(with = "serde_bytes")]
+            value: Vec<u8>,
+        }
+        #[derive(serde::Deserialize)]
+        struct Entry {
+            status: u16,
+            headers: Vec<Field>,
+            fingerprint: Option<String>,
+        }
+
+        assert_eq!(bytes[..3], [0xfe, 0xed, 2]);
+        let entry: Entry = rmp_serde::from_slice(&bytes[3..]).unwrap();
+        assert_eq!(entry.status, 200);
+        assert!(entry.fingerprint.is_none());
 
         // The header names are being normalized to lowercase by the http crate
-        // Headers should be:
-        // first: 1\r\n
-        // second: 2\r\n
-        // \r\n
+        let headers: Vec<_> = entry
+            .headers
+            .iter()
+            .map(|field| (field.name.as_str(), &field.value[..]))
+            .collect();
+        assert_eq!(headers, [("first", &b"1"[..]), ("second", &b"2"[..])]);
+    }
+
+    #[test]
+    fn test_path_matches() {
+        assert!(path_matches("/payments", "/payments"));
+        assert!(path_matches("/payments/", "/payments"));
+        assert!(!path_matches("/payments", "/payments/1"));
+
+        assert!(path_matches("/payments/*", "/payments/1"));
+        assert!(path_matches("/payments/{id}", "/payments/1/"));
+        assert!(!path_matches("/payments/*", "/payments/1/refunds"));
+        assert!(!path_matches("/payments/*", "/orders/1"));
+
+        assert!(path_matches("/payments/**", "/payments"));
+        assert!(path_matches("/payments/**", "/payments/1/refunds"));
+        assert!(path_matches("/payments/{*rest}", "/payments/1/refunds"));
+        assert!(path_matches("/*/refunds", "/payments/refunds"));
+        assert!(!path_matches("/payments/**", "/orders/1"));
+    }
+
+    #[test]
+    fn test_etag_matches() {
+        let matches = |if_none_match: &'static str, etag: &'static str| {
+            etag_matches(
+                &HeaderValue::from_static(if_none_match),
+                &HeaderValue::from_static(etag),
+            )
+        };
+
+        assert!(matches(r#""abc""#, r#""abc""#));
+        assert!(matches(r#"W/"abc""#, r#""abc""#));
+        assert!(matches(r#""abc""#, r#"W/"abc""#));
+        assert!(matches(r#""xyz", "abc""#, r#""abc""#));
+        assert!(matches("*", r#""abc""#));
+        assert!(!matches(r#""xyz""#, r#""abc""#));
+        assert!(!matches(r#""abc""#, r#""abcd""#));
+    }
+
+    #[test]
+    fn test_glob_matches() {
+        assert!(glob_matches("_ts", "_ts"));
+        assert!(!glob_matches("_ts", "_ts2"));
+        assert!(glob_matches("utm_*", "utm_source"));
+        assert!(glob_matches("utm_*", "utm_"));
+        assert!(!glob_matches("utm_*", "xutm_source"));
+        assert!(glob_matches("*_id", "session_id"));
+        assert!(glob_matches("x-*-id", "x-request-id"));
+        assert!(!glob_matches("x-*-id", "x-id"));
+        assert!(glob_matches("*", ""));
+    }
+
+    #[tokio::test]
+    async fn test_query_normalization() {
+        let hash = |uri: &'static str, options: IdempotentOptions| async move {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri(uri)
+                .body(Body::empty())
+                .unwrap();
+            hash_request(req, &options).await.1.unwrap()
+        };
+        let default = IdempotentOptions::default;
+
+        assert_ne!(
+            hash("/orders?a=1", default()).await,
+            hash("/orders?a=2", default()).await
+        );
+        assert_ne!(
+            hash("/orders?a=1&b=2", default()).await,
+            hash("/orders?b=2&a=1", default()).await
+        );
+        assert_eq!(
+            hash("/orders?a=1", default().ignore_query(true)).await,
+            hash("/orders?a=2", default().ignore_query(true)).await
+        );
+
+        let sorted = || default().sort_query_params(true);
         assert_eq!(
-            headers_str, "first: 1\r\nsecond: 2\r\n\r\n",
-            "Headers should be properly formatted with correct CRLF sequences"
+            hash("/orders?a=1&b=2", sorted()).await,
+            hash("/orders?b=2&a=1", sorted()).await
+        );
+        // Repeated parameters keep their order
+        assert_ne!(
+            hash("/orders?id=1&id=2", sorted()).await,
+            hash("/orders?id=2&id=1", sorted()).await
+        );
+
+        let ignoring = || {
+            default()
+                .ignore_query_param("_ts")
+                .ignore_query_param("utm_*")
+        };
+        assert_eq!(
+            hash("/orders?a=1&_ts=1&utm_source=mail", ignoring()).await,
+            hash("/orders?a=1&_ts=2&utm_medium=web", ignoring()).await
+        );
+        assert_ne!(
+            hash("/orders?a=1&_ts=1", ignoring()).await,
+            hash("/orders?a=2&_ts=1", ignoring()).await
+        );
+    }
+
+    #[tokio::test]
+    async fn test_body_normalizers() {
+        let normalized = || {
+            IdempotentOptions::default()
+                .body_normalizer("application/x-www-form-urlencoded", FormNormalizer)
+                .body_normalizer("Multipart/Form-Data", MultipartNormalizer)
+        };
+        let hash = |content_type: String, body: String, options: IdempotentOptions| async move {
+            let req = Request::builder()
+                .method(Method::POST)
+                .uri("/upload")
+                .header(header::CONTENT_TYPE, content_type)
+                .body(Body::from(body.clone()))
+                .unwrap();
+            let (req, hash) = hash_request(req, &options).await;
+            // The handler receives the body as sent
+            let received = to_bytes(req.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(received, body);
+            hash.unwrap()
+        };
+
+        let form_hash = |body: &str, options| {
+            let content_type = String::from("application/x-www-form-urlencoded");
+            hash(content_type, body.to_owned(), options)
+        };
+        let reference = form_hash("a=1&b=2", normalized()).await;
+        assert_eq!(reference, form_hash("b=2&a=1", normalized()).await);
+        assert_ne!(reference, form_hash("b=2&a=2", normalized()).await);
+        assert_ne!(
+            reference,
+            form_hash("b=2&a=1", IdempotentOptions::default()).await
+        );
+
+        let multipart_hash = |boundary: &str, value: &str, options| {
+            let content_type = format!("multipart/form-data; boundary={boundary}");
+            let body = format!(
+                "--{boundary}\r\nContent-Disposition: form-data; name=\"a\"\r\n\r\n{value}\r\n--{boundary}--\r\n"
+            );
+            hash(content_type, body, options)
+        };
+        let reference = multipart_hash("X1", "1", normalized()).await;
+        assert_eq!(reference, multipart_hash("Y2", "1", normalized()).await);
+        assert_ne!(reference, multipart_hash("Y2", "2", normalized()).await);
+        assert_ne!(
+            reference,
+            multipart_hash("Y2", "1", IdempotentOptions::default()).await
         );
     }
+
+    #[tokio::test]
+    async fn test_hash_request_body_limit() {
+        let request = |body: &'static str| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/test")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let options = IdempotentOptions::default().max_body_bytes(8);
+
+        let (new_req, hash) = hash_request(request("12345678"), &options).await;
+        assert!(hash.is_some());
+        assert!(new_req.extensions().get::<BodyLimitExceeded>().is_none());
+
+        let (new_req, hash) = hash_request(request("123456789"), &options).await;
+        assert!(hash.is_none());
+        assert!(new_req.extensions().get::<BodyLimitExceeded>().is_some());
+        let body_bytes = to_bytes(new_req.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body_bytes[..], b"123456789");
+    }
+
+    /// A body yielding each of its chunks as a separate frame.
+    struct ChunkedBody(Vec<&'static [u8]>);
+
+    impl HttpBody for ChunkedBody {
+        type Data = Bytes;
+        type Error = std::convert::Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            if self.0.is_empty() {
+                return Poll::Ready(None);
+            }
+            let chunk = self.0.remove(0);
+            Poll::Ready(Some(Ok(Frame::data(Bytes::from_static(chunk)))))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_hash_request_streamed_body() {
+        let request = |body: Body| {
+            Request::builder()
+                .method(Method::POST)
+                .uri("/test")
+                .body(body)
+                .unwrap()
+        };
+
+        let chunked = Body::new(ChunkedBody(vec![b"{\"amount\":", b" 100", b"}"]));
+        let (new_req, streamed) = hash_request(request(chunked), &Default::default()).await;
+        let (_, whole) = hash_request(
+            request(Body::from(r#"{"amount": 100}"#)),
+            &Default::default(),
+        )
+        .await;
+        assert_eq!(streamed, whole);
+
+        let body_bytes = to_bytes(new_req.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body_bytes[..], br#"{"amount": 100}"#);
+    }
 }
@@ -0,0 +1,110 @@
+use crate::body::{BodyLimitExceeded, Uncollected, collect_body};
+use axum::extract::Request;
+use axum::http::HeaderName;
+use serde_json::Value;
+
+#[derive(Clone, Debug)]
+enum EventIdSource {
+    Header(HeaderName),
+    JsonPointer(String),
+}
+
+/// Deduplicates incoming webhooks by the event ID of their provider, so that deliveries
+/// retried by the provider are acknowledged without being processed twice.
+///
+/// The event ID is read from a header or from a field of the JSON body, and used as the
+/// idempotency key. Only the status code of the acknowledgment is cached, not the response
+/// body. Requests without an event ID are forwarded without idempotency.
+///
+/// This requires the `webhook` feature.
+///
+/// # Example
+/// ```rust
+/// use axum_idempotent::{IdempotentOptions, WebhookDedup};
+///
+/// // GitHub sends the delivery ID in the `X-GitHub-Delivery` header.
+/// let options = IdempotentOptions::default().webhook_dedup(WebhookDedup::github());
+///
+/// // Stripe sends the event ID in the `id` field of the body.
+/// let options = IdempotentOptions::default().webhook_dedup(WebhookDedup::stripe());
+///
+/// // Other providers
+/// let options = IdempotentOptions::default()
+///     .webhook_dedup(WebhookDedup::json_pointer("/event/id"));
+/// ```
+#[derive(Clone, Debug)]
+pub struct WebhookDedup {
+    source: EventIdSource,
+}
+
+impl WebhookDedup {
+    /// Reads the event ID from the header `name`.
+    pub fn header(name: HeaderName) -> Self {
+        Self {
+            source: EventIdSource::Header(name),
+        }
+    }
+
+    /// Reads the event ID from the field of the JSON body at `pointer` (RFC 6901), e.g.
+    /// `/id`. String and number fields are supported.
+    ///
+    /// The body is buffered up to
+    /// [`IdempotentOptions::max_body_bytes`](crate::IdempotentOptions::max_body_bytes).
+    pub fn json_pointer(pointer: impl Into<String>) -> Self {
+        Self {
+            source: EventIdSource::JsonPointer(pointer.into()),
+        }
+    }
+
+    /// Deduplicates GitHub webhooks by their `X-GitHub-Delivery` header, which is kept when
+    /// a delivery is redelivered.
+    pub fn github() -> Self {
+        Self::header(HeaderName::from_static("x-github-delivery"))
+    }
+
+    /// Deduplicates Stripe webhooks by the `id` of their event.
+    ///
+    /// The `Stripe-Signature` header is not used, since it is signed anew on every retry.
+    pub fn stripe() -> Self {
+        Self::json_pointer("/id")
+    }
+
+    /// Returns the event ID of `req`, and the request to be forwarded.
+    ///
+    /// If the body exceeds `max_body_bytes`, no ID is returned and a [`BodyLimitExceeded`]
+    /// marker is inserted into the request extensions.
+    pub(crate) async fn event_id(
+        &self,
+        req: Request,
+        max_body_bytes: Option<usize>,
+    ) -> (Request, Option<String>) {
+        let pointer = match &self.source {
+            EventIdSource::Header(name) => {
+                let id = req.headers().get(name).and_then(|v| v.to_str().ok());
+                let id = id.map(str::to_owned);
+                return (req, id);
+            }
+            EventIdSource::JsonPointer(pointer) => pointer,
+        };
+
+        let (mut parts, body) = req.into_parts();
+        let buffered = match collect_body(body, max_body_bytes, |_| {}).await {
+            Ok(buffered) => buffered,
+            Err(uncollected) => {
+                if let Uncollected::TooLarge(_) = &uncollected {
+                    parts.extensions.insert(BodyLimitExceeded);
+                }
+                return (Request::from_parts(parts, uncollected.into_body()), None);
+            }
+        };
+        let id = serde_json::from_slice::<Value>(&buffered.to_bytes())
+            .ok()
+            .and_then(|event| match event.pointer(pointer)? {
+                Value::String(id) => Some(id.clone()),
+                Value::Number(id) => Some(id.to_string()),
+                _ => None,
+            });
+
+        (Request::from_parts(parts, buffered.into_body()), id)
+    }
+}
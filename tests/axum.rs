@@ -1,17 +1,34 @@
 #[cfg(test)]
 mod tests {
-    use axum::Router;
-    use axum::body::{Body, to_bytes};
-    use axum::extract::Request;
-    use axum::http::{HeaderName, StatusCode};
+    use axum::body::{Body, Bytes, to_bytes};
+    use axum::extract::{Path, Request};
+    use axum::http::{self, HeaderName, Method, StatusCode, header};
     use axum::response::IntoResponse;
     use axum::routing::{get, post};
-    use axum_idempotent::{IdempotentLayer, IdempotentOptions};
+    use axum::{Extension, Router};
+    #[cfg(feature = "front-cache")]
+    use axum_idempotent::FrontCacheLimit;
+    use axum_idempotent::migrate::{FORMAT_VERSION, upgrade_entries};
+    use axum_idempotent::{
+        BlobStore, BypassIdempotency, CachedResponse, Clock, ConfigError, ConflictBehavior,
+        ErrorAction, EvictionCause, Idempotency, IdempotencyDirective, IdempotencyError,
+        IdempotencyEvent, IdempotencyKey, IdempotencyObserver, IdempotencyStats, IdempotencyStore,
+        IdempotencyTtl, IdempotentLayer, IdempotentOptions, KeyFormat, KeyScope, KeyState,
+        MemoryIdempotencyStore, MigrationError, MissingScope, MissingSession, OversizedBody,
+        OversizedResponse, ReplayInfo, ReplayLimit, ReplayedResponse, SessionFallback,
+        StatusCaching, StoreErrorPolicy, StoreOperation, TieredStore, admin_router, inspect_router,
+    };
     use ruts::store::memory::MemoryStore;
-    use ruts::{CookieOptions, SessionLayer};
-    use std::sync::Arc;
-    use std::sync::atomic::{AtomicU64, Ordering};
-    use std::time::Duration;
+    use ruts::store::{Error as StoreError, SessionMap, SessionStore};
+    use ruts::{CookieOptions, Id, SessionLayer};
+    use serde::Serialize;
+    use serde::de::DeserializeOwned;
+    use std::collections::HashMap;
+    use std::convert::Infallible;
+    use std::error::Error;
+    use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::{Duration, SystemTime};
     use tower::ServiceExt;
     use tower_cookies::CookieManagerLayer;
 
@@ -35,16 +52,261 @@ mod tests {
         let store = Arc::new(MemoryStore::new());
         let cookie_options = CookieOptions::build().name("session").max_age(10).path("/");
         let session_layer = SessionLayer::new(store.clone()).with_cookie_options(cookie_options);
-        let idempotent_layer = IdempotentLayer::<MemoryStore>::new(idempotent_options);
+        let idempotent_layer = IdempotentLayer::<MemoryStore>::try_new(idempotent_options).unwrap();
 
         Router::new()
             .route("/test", post(increment_counter))
-            .route("/error", get(return_error))
+            .route("/error", post(return_error))
+            .route(
+                "/plain",
+                post(|| async { "plain" }).put(|| async { "plain" }),
+            )
             .layer(idempotent_layer)
             .layer(session_layer)
             .layer(CookieManagerLayer::new())
     }
 
+    /// A `MemoryStore` whose writes fail while `failures` is non-zero.
+    #[derive(Clone, Default)]
+    struct FlakyStore {
+        inner: MemoryStore,
+        failures: Arc<AtomicU64>,
+    }
+
+    impl FlakyStore {
+        fn fail_next_writes(&self, count: u64) {
+            self.failures.store(count, Ordering::SeqCst);
+        }
+
+        fn should_fail(&self) -> bool {
+            self.failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok()
+        }
+    }
+
+    impl SessionStore for FlakyStore {
+        async fn get<T>(&self, session_id: &Id, field: &str) -> Result<Option<T>, StoreError>
+        where
+            T: Send + Sync + DeserializeOwned,
+        {
+            self.inner.get(session_id, field).await
+        }
+
+        async fn get_all(&self, session_id: &Id) -> Result<Option<SessionMap>, StoreError> {
+            self.inner.get_all(session_id).await
+        }
+
+        async fn set<T>(
+            &self,
+            session_id: &Id,
+            field: &str,
+            value: &T,
+            key_ttl_secs: i64,
+            field_ttl_secs: i64,
+            #[cfg(feature = "layered-store")] hot_cache_ttl_secs: Option<i64>,
+            #[cfg(not(feature = "layered-store"))] hot_cache_ttl_secs: Option<
+                std::marker::PhantomData<()>,
+            >,
+        ) -> Result<i64, StoreError>
+        where
+            T: Send + Sync + Serialize + 'static,
+        {
+            if self.should_fail() {
+                return Err(StoreError::Backend("injected failure".to_string()));
+            }
+            self.inner
+                .set(
+                    session_id,
+                    field,
+                    value,
+                    key_ttl_secs,
+                    field_ttl_secs,
+                    hot_cache_ttl_secs,
+                )
+                .await
+        }
+
+        async fn set_and_rename<T>(
+            &self,
+            old_session_id: &Id,
+            new_session_id: &Id,
+            field: &str,
+            value: &T,
+            key_ttl_secs: i64,
+            field_ttl_secs: i64,
+            #[cfg(feature = "layered-store")] hot_cache_ttl_secs: Option<i64>,
+            #[cfg(not(feature = "layered-store"))] hot_cache_ttl_secs: Option<
+                std::marker::PhantomData<()>,
+            >,
+        ) -> Result<i64, StoreError>
+        where
+            T: Send + Sync + Serialize + 'static,
+        {
+            if self.should_fail() {
+                return Err(StoreError::Backend("injected failure".to_string()));
+            }
+            self.inner
+                .set_and_rename(
+                    old_session_id,
+                    new_session_id,
+                    field,
+                    value,
+                    key_ttl_secs,
+                    field_ttl_secs,
+                    hot_cache_ttl_secs,
+                )
+                .await
+        }
+
+        async fn rename_session_id(
+            &self,
+            old_session_id: &Id,
+            new_session_id: &Id,
+        ) -> Result<bool, StoreError> {
+            self.inner
+                .rename_session_id(old_session_id, new_session_id)
+                .await
+        }
+
+        async fn remove(&self, session_id: &Id, field: &str) -> Result<i64, StoreError> {
+            self.inner.remove(session_id, field).await
+        }
+
+        async fn delete(&self, session_id: &Id) -> Result<bool, StoreError> {
+            self.inner.delete(session_id).await
+        }
+
+        async fn expire(&self, session_id: &Id, ttl_secs: i64) -> Result<bool, StoreError> {
+            self.inner.expire(session_id, ttl_secs).await
+        }
+    }
+
+    fn create_flaky_app(idempotent_options: IdempotentOptions, store: FlakyStore) -> Router {
+        let cookie_options = CookieOptions::build().name("session").max_age(10).path("/");
+        let session_layer = SessionLayer::new(Arc::new(store)).with_cookie_options(cookie_options);
+
+        Router::new()
+            .route("/plain", post(|| async { "plain" }))
+            .layer(IdempotentLayer::<FlakyStore>::try_new(idempotent_options).unwrap())
+            .layer(session_layer)
+            .layer(CookieManagerLayer::new())
+    }
+
+    /// Values and TTLs by key.
+    type Entries = HashMap<String, (Vec<u8>, i64)>;
+
+    /// An `IdempotencyStore` backed by a `HashMap`, recording but not enforcing TTLs.
+    #[derive(Clone, Default)]
+    struct HashMapStore(Arc<Mutex<Entries>>);
+
+    impl HashMapStore {
+        fn ttl(&self, key: &str) -> Option<i64> {
+            self.0
+                .lock()
+                .unwrap()
+                .get(key)
+                .map(|(_, ttl_secs)| *ttl_secs)
+        }
+    }
+
+    impl IdempotencyStore for HashMapStore {
+        type Key = str;
+
+        async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+            Ok(self
+                .0
+                .lock()
+                .unwrap()
+                .get(key)
+                .map(|(value, _)| value.clone()))
+        }
+
+        async fn set(
+            &self,
+            key: &str,
+            value: Vec<u8>,
+            ttl_secs: i64,
+        ) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(key.to_owned(), (value, ttl_secs));
+            Ok(())
+        }
+
+        async fn remove(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+
+        async fn remove_prefix(&self, prefix: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.0
+                .lock()
+                .unwrap()
+                .retain(|key, _| !key.starts_with(prefix));
+            Ok(())
+        }
+    }
+
+    /// An `IdempotencyStore` with binary keys, keeping entries in a `HashMap`.
+    #[derive(Clone, Default)]
+    struct BinaryStore(Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>);
+
+    impl BinaryStore {
+        fn keys(&self) -> Vec<Vec<u8>> {
+            self.0.lock().unwrap().keys().cloned().collect()
+        }
+    }
+
+    impl IdempotencyStore for BinaryStore {
+        type Key = [u8];
+
+        async fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+            Ok(self.0.lock().unwrap().get(key).cloned())
+        }
+
+        async fn set(
+            &self,
+            key: &[u8],
+            value: Vec<u8>,
+            _ttl_secs: i64,
+        ) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.0.lock().unwrap().insert(key.to_vec(), value);
+            Ok(())
+        }
+
+        async fn remove(&self, key: &[u8]) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.0.lock().unwrap().remove(key);
+            Ok(())
+        }
+    }
+
+    /// An `IdempotencyStore` whose backend is unreachable.
+    #[derive(Clone)]
+    struct UnavailableStore;
+
+    impl IdempotencyStore for UnavailableStore {
+        type Key = str;
+
+        async fn get(&self, _key: &str) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+            Err("connection refused".into())
+        }
+
+        async fn set(
+            &self,
+            _key: &str,
+            _value: Vec<u8>,
+            _ttl_secs: i64,
+        ) -> Result<(), Box<dyn Error + Send + Sync>> {
+            Err("connection refused".into())
+        }
+
+        async fn remove(&self, _key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+            Err("connection refused".into())
+        }
+    }
+
     fn get_session_cookie(response: &axum::http::Response<Body>) -> axum::http::HeaderValue {
         response
             .headers()
@@ -255,6 +517,7 @@ mod tests {
             .oneshot(
                 Request::builder()
                     .uri("/error")
+                    .method("POST")
                     .body(Body::empty())
                     .unwrap(),
             )
@@ -274,17 +537,4906 @@ mod tests {
         assert_eq!(COUNTER.load(Ordering::SeqCst), 1);
         assert_eq!(response1.status(), StatusCode::INTERNAL_SERVER_ERROR);
 
-        let response2 = app
+        // Error responses are not cached, even within a session
+        let response = app
+            .clone()
             .oneshot(
                 Request::builder()
-                    .uri("/error")
+                    .uri("/test")
+                    .method("POST")
                     .body(Body::empty())
                     .unwrap(),
             )
             .await
             .unwrap();
+        let session_cookie = get_session_cookie(&response);
+        let request = || {
+            Request::builder()
+                .uri("/error")
+                .method("POST")
+                .header("cookie", &session_cookie)
+                .body(Body::empty())
+                .unwrap()
+        };
+        app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 3);
 
-        assert_eq!(COUNTER.load(Ordering::SeqCst), 2); // Counter incremented again.
+        let response2 = app.oneshot(request()).await.unwrap();
+
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 4); // Counter incremented again.
+        assert!(response2.headers().get("idempotency-replayed").is_none());
         assert_eq!(response2.status(), StatusCode::INTERNAL_SERVER_ERROR);
     }
+
+    #[tokio::test]
+    async fn test_unignore_defaults() {
+        let store = HashMapStore::default();
+        let app = |options: IdempotentOptions| {
+            Router::new()
+                .route(
+                    "/status/{code}",
+                    post(
+                        |Path(code): Path<u16>| async move { StatusCode::from_u16(code).unwrap() },
+                    ),
+                )
+                .layer(IdempotentLayer::with_store(store.clone(), options))
+        };
+        let request = |code: u16| {
+            Request::builder()
+                .uri(format!("/status/{code}"))
+                .method("POST")
+                .header("idempotency-key", code.to_string())
+                .body(Body::empty())
+                .unwrap()
+        };
+        let is_cached = |code: u16| store.0.lock().unwrap().contains_key(&code.to_string());
+
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .unignore_response_status_code(StatusCode::BAD_REQUEST);
+        let app1 = app(options.clone());
+        for code in [400, 500] {
+            app1.clone().oneshot(request(code)).await.unwrap();
+        }
+        assert!(is_cached(400));
+        assert!(!is_cached(500));
+
+        let app2 = app(options.clear_ignored_status_codes());
+        app2.oneshot(request(500)).await.unwrap();
+        assert!(is_cached(500));
+
+        // Default ignored headers can be hashed again
+        let request = |user_agent: &str| {
+            Request::builder()
+                .uri("/status/200")
+                .method("POST")
+                .header(header::USER_AGENT, user_agent)
+                .body(Body::empty())
+                .unwrap()
+        };
+        for (options, keys) in [
+            (IdempotentOptions::default(), 1),
+            (
+                IdempotentOptions::default().unignore_header(&header::USER_AGENT),
+                2,
+            ),
+            (IdempotentOptions::default().clear_ignored_headers(), 2),
+        ] {
+            store.0.lock().unwrap().clear();
+            let app = app(options);
+            app.clone().oneshot(request("curl")).await.unwrap();
+            app.oneshot(request("wget")).await.unwrap();
+            assert_eq!(store.0.lock().unwrap().len(), keys);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_cache_only_status_codes() {
+        let store = HashMapStore::default();
+        let app = |options: IdempotentOptions| {
+            Router::new()
+                .route(
+                    "/status/{code}",
+                    post(
+                        |Path(code): Path<u16>| async move { StatusCode::from_u16(code).unwrap() },
+                    ),
+                )
+                .layer(IdempotentLayer::with_store(store.clone(), options))
+        };
+        let request = |code: u16| {
+            Request::builder()
+                .uri(format!("/status/{code}"))
+                .method("POST")
+                .header("idempotency-key", code.to_string())
+                .body(Body::empty())
+                .unwrap()
+        };
+        let is_cached = |code: u16| store.0.lock().unwrap().contains_key(&code.to_string());
+
+        // 409 is not ignored by default, but is outside of the allow-list
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .cache_status_range(200..300)
+            .cache_only_status_codes([StatusCode::NOT_FOUND]);
+        let app = app(options);
+        for code in [201, 404, 409, 500] {
+            let response = app.clone().oneshot(request(code)).await.unwrap();
+            assert_eq!(response.status().as_u16(), code);
+        }
+        assert!(is_cached(201));
+        assert!(is_cached(404));
+        assert!(!is_cached(409));
+        assert!(!is_cached(500));
+
+        let response = app.oneshot(request(201)).await.unwrap();
+        assert_eq!(
+            response.headers().get("idempotency-replayed").unwrap(),
+            "true"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_always_cache_status() {
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .cache_status_range(200..300)
+            .always_cache_status(StatusCode::PAYMENT_REQUIRED)
+            .always_cache_status(StatusCode::FORBIDDEN);
+        assert_eq!(
+            options.status_caching(StatusCode::FORBIDDEN),
+            StatusCaching::Always
+        );
+        assert_eq!(
+            options.status_caching(StatusCode::OK),
+            StatusCaching::Cached
+        );
+        assert_eq!(
+            options.status_caching(StatusCode::CONFLICT),
+            StatusCaching::Ignored
+        );
+
+        let app = Router::new()
+            .route(
+                "/status/{code}",
+                post(|Path(code): Path<u16>| async move { StatusCode::from_u16(code).unwrap() }),
+            )
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = |code: u16| {
+            Request::builder()
+                .uri(format!("/status/{code}"))
+                .method("POST")
+                .header("idempotency-key", code.to_string())
+                .body(Body::empty())
+                .unwrap()
+        };
+        for code in [402, 403, 409] {
+            app.clone().oneshot(request(code)).await.unwrap();
+        }
+        let is_cached = |code: u16| store.0.lock().unwrap().contains_key(&code.to_string());
+        // 403 is ignored by default, and neither is in the allow-list
+        assert!(is_cached(402));
+        assert!(is_cached(403));
+        assert!(!is_cached(409));
+
+        let response = app.oneshot(request(402)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYMENT_REQUIRED);
+        assert!(response.headers().get("idempotency-replayed").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_cache_if() {
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .cache_if(|res| res.headers().get("x-retryable").is_none());
+        let app = Router::new()
+            .route(
+                "/retryable",
+                post(|| async { ([("x-retryable", "true")], "retryable") }),
+            )
+            .route("/final", post(|| async { "final" }))
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .method("POST")
+                .header("idempotency-key", uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for _ in 0..2 {
+            let response = app.clone().oneshot(request("/retryable")).await.unwrap();
+            assert!(response.headers().get("idempotency-replayed").is_none());
+        }
+        assert!(!store.0.lock().unwrap().contains_key("/retryable"));
+
+        app.clone().oneshot(request("/final")).await.unwrap();
+        let response = app.oneshot(request("/final")).await.unwrap();
+        assert_eq!(
+            response.headers().get("idempotency-replayed").unwrap(),
+            "true"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replayed_response_extension() {
+        let options =
+            IdempotentOptions::default().use_idempotency_key_header(Some("idempotency-key"));
+        let app = create_test_app(options).await;
+
+        let response1 = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/plain")
+                    .method("POST")
+                    .header("idempotency-key", "replay-marker")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let session_cookie = get_session_cookie(&response1);
+        assert!(response1.extensions().get::<ReplayedResponse>().is_none());
+
+        let response2 = app
+            .oneshot(
+                Request::builder()
+                    .uri("/plain")
+                    .method("POST")
+                    .header("cookie", session_cookie)
+                    .header("idempotency-key", "replay-marker")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(response2.extensions().get::<ReplayedResponse>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_ttl_policy_from_extensions() {
+        #[derive(Clone)]
+        struct Partner;
+
+        async fn identify_partner(mut req: Request) -> Request {
+            if req.headers().contains_key("x-partner") {
+                req.extensions_mut().insert(Partner);
+            }
+            req
+        }
+
+        let options = IdempotentOptions::default()
+            .expire_after(1)
+            .ttl_policy(|extensions| extensions.get::<Partner>().map(|_| 60));
+        let store = Arc::new(MemoryStore::new());
+        let app = Router::new()
+            .route("/plain", post(|| async { "plain" }))
+            .layer(IdempotentLayer::<MemoryStore>::try_new(options).unwrap())
+            .layer(axum::middleware::map_request(identify_partner))
+            .layer(
+                SessionLayer::new(store)
+                    .with_cookie_options(CookieOptions::build().name("session").max_age(10)),
+            )
+            .layer(CookieManagerLayer::new());
+
+        let request =
+            |body: &'static str, partner: bool, cookie: Option<axum::http::HeaderValue>| {
+                let mut builder = Request::builder().uri("/plain").method("POST");
+                if partner {
+                    builder = builder.header("x-partner", "acme");
+                }
+                if let Some(cookie) = cookie {
+                    builder = builder.header("cookie", cookie);
+                }
+                builder.body(Body::from(body)).unwrap()
+            };
+
+        let response = app
+            .clone()
+            .oneshot(request("partner", true, None))
+            .await
+            .unwrap();
+        let session_cookie = get_session_cookie(&response);
+        app.clone()
+            .oneshot(request("anonymous", false, Some(session_cookie.clone())))
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        // The partner's entry outlives the default TTL, the anonymous one does not.
+        let response = app
+            .clone()
+            .oneshot(request("partner", true, Some(session_cookie.clone())))
+            .await
+            .unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+
+        let response = app
+            .oneshot(request("anonymous", false, Some(session_cookie)))
+            .await
+            .unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_replicated_entry_replays_in_other_region() {
+        let replicated = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let sink = replicated.clone();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .replicate_with(move |entry| sink.lock().unwrap().push(entry));
+
+        let region = |store: Arc<MemoryStore>, options: IdempotentOptions| {
+            Router::new()
+                .route("/plain", post(|| async { "plain" }))
+                .layer(IdempotentLayer::<MemoryStore>::try_new(options).unwrap())
+                .layer(
+                    SessionLayer::new(store)
+                        .with_cookie_options(CookieOptions::build().name("session").max_age(10)),
+                )
+                .layer(CookieManagerLayer::new())
+        };
+        let region_a = region(Arc::new(MemoryStore::new()), options);
+        let store_b = Arc::new(MemoryStore::new());
+        let region_b = region(
+            store_b.clone(),
+            IdempotentOptions::default().use_idempotency_key_header(None),
+        );
+
+        let response = region_a
+            .oneshot(
+                Request::builder()
+                    .uri("/plain")
+                    .method("POST")
+                    .header("idempotency-key", "replicated")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let session_cookie = get_session_cookie(&response);
+
+        let entries = replicated.lock().unwrap().clone();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].key, "replicated");
+        assert_eq!(&entries[0].response().unwrap().body[..], b"plain");
+        entries[0].apply(store_b.as_ref()).await.unwrap();
+
+        let response = region_b
+            .oneshot(
+                Request::builder()
+                    .uri("/plain")
+                    .method("POST")
+                    .header("cookie", session_cookie)
+                    .header("idempotency-key", "replicated")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers().get("idempotency-replayed").unwrap(),
+            "true"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_enabled_when_predicate() {
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .enabled_when(|parts| !parts.headers.contains_key("x-internal"));
+        let app = create_test_app(options).await;
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/plain")
+                    .method("POST")
+                    .header("idempotency-key", "internal")
+                    .header("x-internal", "1")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        // Nothing was stored, so no session was created.
+        assert!(response.headers().get("set-cookie").is_none());
+
+        let response1 = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/plain")
+                    .method("POST")
+                    .header("idempotency-key", "external")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let session_cookie = get_session_cookie(&response1);
+
+        let response2 = app
+            .oneshot(
+                Request::builder()
+                    .uri("/plain")
+                    .method("POST")
+                    .header("cookie", session_cookie)
+                    .header("idempotency-key", "external")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(response2.headers().get("idempotency-replayed").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_bypass() {
+        reset_counter();
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .bypass_header(HeaderName::from_static("x-idempotency-bypass"), "s3cret");
+        let app = Router::new()
+            .route(
+                "/counter",
+                post(|headers: http::HeaderMap| async move {
+                    assert!(!headers.contains_key("x-idempotency-bypass"));
+                    increment_counter().await
+                }),
+            )
+            .layer(IdempotentLayer::with_store(store.clone(), options))
+            .layer(axum::middleware::map_request(
+                |mut req: Request| async move {
+                    if req.headers().contains_key("x-load-test") {
+                        req.extensions_mut().insert(BypassIdempotency);
+                    }
+                    req
+                },
+            ));
+        let request = |header: Option<(&str, &str)>| {
+            let mut builder = Request::builder()
+                .uri("/counter")
+                .method("POST")
+                .header("idempotency-key", "bypassed");
+            if let Some((name, value)) = header {
+                builder = builder.header(name, value);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        // Bypassed requests are neither looked up nor stored
+        let bypass = Some(("x-idempotency-bypass", "s3cret"));
+        app.clone().oneshot(request(bypass)).await.unwrap();
+        app.clone().oneshot(request(bypass)).await.unwrap();
+        app.clone()
+            .oneshot(request(Some(("x-load-test", "1"))))
+            .await
+            .unwrap();
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 3);
+        assert!(store.0.lock().unwrap().is_empty());
+
+        // A wrong secret is ignored
+        let wrong = Some(("x-idempotency-bypass", "guess"));
+        app.clone().oneshot(request(wrong)).await.unwrap();
+        let response = app.clone().oneshot(request(None)).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        let response = app.oneshot(request(bypass)).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 5);
+
+        let result = IdempotentOptions::default()
+            .bypass_header(HeaderName::from_static("x-idempotency-bypass"), "")
+            .build();
+        assert_eq!(result.unwrap_err(), ConfigError::EmptyBypassSecret);
+    }
+
+    #[tokio::test]
+    async fn test_missing_session() {
+        let app = |layer: IdempotentLayer<MemoryStore>| {
+            Router::new()
+                .route("/plain", post(|| async { "plain" }))
+                .layer(layer)
+        };
+        let request = || {
+            Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let layer = || IdempotentLayer::<MemoryStore>::try_new(IdempotentOptions::default());
+
+        let rejecting = layer().unwrap().on_missing_session(MissingSession::Reject);
+        let response = app(rejecting).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("SessionLayer"));
+
+        let response = app(layer().unwrap()).oneshot(request()).await.unwrap();
+        let status = match cfg!(debug_assertions) {
+            true => StatusCode::INTERNAL_SERVER_ERROR,
+            false => StatusCode::OK,
+        };
+        assert_eq!(response.status(), status);
+
+        let forwarding = layer().unwrap().on_missing_session(MissingSession::Forward);
+        let response = app(forwarding).oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "Is `SessionLayer` applied before `IdempotentLayer`?")]
+    async fn test_missing_session_panic() {
+        let layer = IdempotentLayer::<MemoryStore>::try_new(IdempotentOptions::default())
+            .unwrap()
+            .on_missing_session(MissingSession::Panic);
+        let request = Request::builder()
+            .uri("/plain")
+            .method("POST")
+            .body(Body::empty())
+            .unwrap();
+        let _ = Router::new()
+            .route("/plain", post(|| async { "plain" }))
+            .layer(layer)
+            .oneshot(request)
+            .await;
+    }
+
+    #[tokio::test]
+    async fn test_session_fallback_scopes() {
+        use axum::extract::ConnectInfo;
+        use std::net::SocketAddr;
+
+        let request = |ip: [u8; 4]| {
+            let mut req = Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", "no-session")
+                .body(Body::empty())
+                .unwrap();
+            req.extensions_mut()
+                .insert(ConnectInfo(SocketAddr::from((ip, 4000))));
+            req
+        };
+        // No SessionLayer at all, so session extraction always fails.
+        let store = Arc::new(MemoryStore::new());
+        let app = |scope: SessionFallback, seed: [u8; 32]| {
+            let options = IdempotentOptions::default()
+                .use_idempotency_key_header(None)
+                .hash_seed(seed);
+            Router::new()
+                .route("/plain", post(|| async { "plain" }))
+                .layer(
+                    IdempotentLayer::<MemoryStore>::try_new(options)
+                        .unwrap()
+                        .with_session_fallback(store.clone(), scope),
+                )
+        };
+
+        let global = app(SessionFallback::Global, [1; 32]);
+        global
+            .clone()
+            .oneshot(request([10, 0, 0, 1]))
+            .await
+            .unwrap();
+        let response = global.oneshot(request([10, 0, 0, 2])).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+
+        // The id of the scope is derived from the seed
+        let other_seed = app(SessionFallback::Global, [2; 32]);
+        let response = other_seed.oneshot(request([10, 0, 0, 1])).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+
+        let per_ip = app(SessionFallback::ClientIp, [1; 32]);
+        per_ip
+            .clone()
+            .oneshot(request([10, 0, 0, 1]))
+            .await
+            .unwrap();
+        let response = per_ip
+            .clone()
+            .oneshot(request([10, 0, 0, 2]))
+            .await
+            .unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+        let response = per_ip.oneshot(request([10, 0, 0, 1])).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+    }
+
+    #[test]
+    #[should_panic(expected = "scopes not tied to a session require a hash seed")]
+    #[allow(deprecated)]
+    fn test_new_requires_hash_seed_for_shared_scopes() {
+        let options = IdempotentOptions::default().scope_by_extension::<String>();
+        IdempotentLayer::<MemoryStore>::new(options);
+    }
+
+    #[test]
+    #[should_panic(expected = "session fallbacks require a hash seed")]
+    fn test_session_fallback_requires_hash_seed() {
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+        let _ = IdempotentLayer::<MemoryStore>::try_new(options)
+            .unwrap()
+            .with_session_fallback(Arc::new(MemoryStore::new()), SessionFallback::Global);
+    }
+
+    #[tokio::test]
+    async fn test_retry_cache_writes() {
+        let request = |cookie: Option<axum::http::HeaderValue>| {
+            let mut builder = Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", "retried");
+            if let Some(cookie) = cookie {
+                builder = builder.header("cookie", cookie);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let store = FlakyStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .retry_cache_writes(3, Duration::from_millis(1));
+        let app = create_flaky_app(options, store.clone());
+
+        store.fail_next_writes(2);
+        let response = app.clone().oneshot(request(None)).await.unwrap();
+        let session_cookie = get_session_cookie(&response);
+
+        let response = app.oneshot(request(Some(session_cookie))).await.unwrap();
+        assert!(
+            response.headers().get("idempotency-replayed").is_some(),
+            "The entry should have been written after two failed attempts"
+        );
+
+        // Without retries, the entry is lost.
+        let store = FlakyStore::default();
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+        let app = create_flaky_app(options, store.clone());
+
+        store.fail_next_writes(1);
+        let response = app.oneshot(request(None)).await.unwrap();
+        assert!(response.headers().get("set-cookie").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_complete_on_disconnect() {
+        async fn slow() -> &'static str {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            "slow"
+        }
+
+        let app = |complete_on_disconnect: bool| {
+            let options = IdempotentOptions::default()
+                .use_idempotency_key_header(None)
+                .complete_on_disconnect(complete_on_disconnect);
+            let store = Arc::new(MemoryStore::new());
+            Router::new()
+                .route("/plain", post(|| async { "plain" }))
+                .route("/slow", post(slow))
+                .layer(IdempotentLayer::<MemoryStore>::try_new(options).unwrap())
+                .layer(
+                    SessionLayer::new(store)
+                        .with_cookie_options(CookieOptions::build().name("session").max_age(10)),
+                )
+                .layer(CookieManagerLayer::new())
+        };
+        let request = |uri: &str, key: &str, cookie: Option<axum::http::HeaderValue>| {
+            let mut builder = Request::builder()
+                .uri(uri)
+                .method("POST")
+                .header("idempotency-key", key);
+            if let Some(cookie) = cookie {
+                builder = builder.header("cookie", cookie);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        for complete_on_disconnect in [true, false] {
+            let app = app(complete_on_disconnect);
+            // Establish a session first.
+            let response = app
+                .clone()
+                .oneshot(request("/plain", "setup", None))
+                .await
+                .unwrap();
+            let session_cookie = get_session_cookie(&response);
+
+            // The client gives up before the handler completes.
+            let disconnected = tokio::time::timeout(
+                Duration::from_millis(50),
+                app.clone()
+                    .oneshot(request("/slow", "slow", Some(session_cookie.clone()))),
+            )
+            .await;
+            assert!(disconnected.is_err());
+
+            tokio::time::sleep(Duration::from_millis(400)).await;
+
+            let response = app
+                .oneshot(request("/slow", "slow", Some(session_cookie)))
+                .await
+                .unwrap();
+            assert_eq!(
+                response.headers().get("idempotency-replayed").is_some(),
+                complete_on_disconnect
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_waits_for_background_writes() {
+        let store = MemoryIdempotencyStore::new();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .complete_on_disconnect(true);
+        let layer = IdempotentLayer::with_store(store.clone(), options);
+        let shutdown = layer.shutdown_handle();
+        let app = Router::new()
+            .route(
+                "/slow",
+                post(|| async {
+                    tokio::time::sleep(Duration::from_millis(200)).await;
+                    "slow"
+                }),
+            )
+            .layer(layer);
+        let request = Request::builder()
+            .uri("/slow")
+            .method("POST")
+            .header("idempotency-key", "shutdown")
+            .body(Body::empty())
+            .unwrap();
+
+        // The client gives up before the handler completes.
+        let disconnected =
+            tokio::time::timeout(Duration::from_millis(50), app.oneshot(request)).await;
+        assert!(disconnected.is_err());
+        assert_eq!(shutdown.pending(), 1);
+
+        shutdown.shutdown().await;
+        assert_eq!(shutdown.pending(), 0);
+        assert!(store.get("shutdown").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_write_behind() {
+        let request = || {
+            Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", "behind")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let create_app = |options: IdempotentOptions| {
+            let store = MemoryIdempotencyStore::new();
+            let layer = IdempotentLayer::with_store(store.clone(), options);
+            let shutdown = layer.shutdown_handle();
+            let app = Router::new()
+                .route("/plain", post(|| async { "plain" }))
+                .layer(layer);
+            (app, store, shutdown)
+        };
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+
+        // The response is stored in the background
+        let (app, store, shutdown) = create_app(options.clone().write_behind(true));
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        shutdown.shutdown().await;
+        assert!(store.get("behind").await.unwrap().is_some());
+        let response = app.oneshot(request()).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+
+        // Writes are dropped when the queue is full
+        let (app, store, shutdown) = create_app(options.max_pending_writes(0));
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        shutdown.shutdown().await;
+        assert!(store.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_client_requested_ttl() {
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .expire_after(1)
+            .allow_client_ttl(60);
+        let app = create_test_app(options).await;
+
+        let request = |key: &str, ttl: &str, cookie: Option<axum::http::HeaderValue>| {
+            let mut builder = Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", key)
+                .header("idempotency-ttl", ttl);
+            if let Some(cookie) = cookie {
+                builder = builder.header("cookie", cookie);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("long", "3600", None))
+            .await
+            .unwrap();
+        let session_cookie = get_session_cookie(&response);
+        app.clone()
+            .oneshot(request("invalid", "-5", Some(session_cookie.clone())))
+            .await
+            .unwrap();
+
+        tokio::time::sleep(Duration::from_secs(2)).await;
+
+        let response = app
+            .clone()
+            .oneshot(request("long", "3600", Some(session_cookie.clone())))
+            .await
+            .unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+
+        let response = app
+            .oneshot(request("invalid", "-5", Some(session_cookie)))
+            .await
+            .unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_client_ttl_within_route_ttl() {
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .expire_after(5)
+            .allow_client_ttl(3600);
+        let app = Router::new()
+            .route("/plain", post(|| async { "plain" }))
+            .layer(IdempotentLayer::with_store(store.clone(), options))
+            .layer(Extension(IdempotencyTtl(Duration::from_secs(60))));
+        let request = |key: &str, ttl: &str| {
+            Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", key)
+                .header("idempotency-ttl", ttl)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Clients can shorten the TTL of the route, but not extend it
+        app.clone().oneshot(request("longer", "600")).await.unwrap();
+        assert_eq!(store.ttl("longer"), Some(60));
+        app.oneshot(request("shorter", "10")).await.unwrap();
+        assert_eq!(store.ttl("shorter"), Some(10));
+
+        // Nor the TTL chosen by the policy
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .ttl_policy(|_| Some(30))
+            .allow_client_ttl(3600);
+        let app = Router::new()
+            .route("/plain", post(|| async { "plain" }))
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        app.oneshot(request("policy", "600")).await.unwrap();
+        assert_eq!(store.ttl("policy"), Some(30));
+    }
+
+    #[tokio::test]
+    async fn test_ttl_from_response_headers() {
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .ttl_from_response_headers(true);
+        let app = Router::new()
+            .route(
+                "/custom",
+                post(|| async { ([("x-idempotency-ttl", "3600")], "custom") }),
+            )
+            .route(
+                "/max-age",
+                post(|| async { ([(header::CACHE_CONTROL, "private, max-age=600")], "max-age") }),
+            )
+            .route(
+                "/invalid",
+                post(|| async { ([("x-idempotency-ttl", "-5")], "invalid") }),
+            )
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .method("POST")
+                .header("idempotency-key", uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request("/custom")).await.unwrap();
+        assert!(response.headers().get("x-idempotency-ttl").is_none());
+        assert_eq!(store.ttl("/custom"), Some(3600));
+
+        app.clone().oneshot(request("/max-age")).await.unwrap();
+        assert_eq!(store.ttl("/max-age"), Some(600));
+
+        app.oneshot(request("/invalid")).await.unwrap();
+        assert_eq!(store.ttl("/invalid"), Some(300));
+    }
+
+    #[tokio::test]
+    async fn test_tower_service_without_axum() {
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+        let counter = Arc::new(AtomicUsize::new(0));
+        let service =
+            tower::ServiceBuilder::new()
+                .layer(IdempotentLayer::with_store(store, options))
+                .service_fn({
+                    let counter = counter.clone();
+                    move |_: Request| {
+                        let count = counter.fetch_add(1, Ordering::SeqCst);
+                        async move {
+                            Ok::<_, Infallible>(http::Response::new(format!("Response #{count}")))
+                        }
+                    }
+                });
+        let request = || {
+            http::Request::builder()
+                .method("POST")
+                .header("idempotency-key", "tower")
+                .body(String::from("payload"))
+                .unwrap()
+        };
+
+        let response = service.clone().oneshot(request()).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "Response #0");
+
+        let response = service.oneshot(request()).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "Response #0");
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_lock() {
+        static SLOW_CALLS: AtomicU64 = AtomicU64::new(0);
+
+        async fn slow() -> String {
+            let call = SLOW_CALLS.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            format!("slow #{call}")
+        }
+
+        let app = |options: IdempotentOptions| {
+            let store = Arc::new(MemoryStore::new());
+            Router::new()
+                .route("/plain", post(|| async { "plain" }))
+                .route("/slow", post(slow))
+                .layer(IdempotentLayer::<MemoryStore>::try_new(options).unwrap())
+                .layer(
+                    SessionLayer::new(store)
+                        .with_cookie_options(CookieOptions::build().name("session").max_age(10)),
+                )
+                .layer(CookieManagerLayer::new())
+        };
+        let request = |uri: &str, cookie: Option<axum::http::HeaderValue>| {
+            let key = if uri == "/slow" { "in-flight" } else { "setup" };
+            let mut builder = Request::builder()
+                .uri(uri)
+                .method("POST")
+                .header("idempotency-key", key);
+            if let Some(cookie) = cookie {
+                builder = builder.header("cookie", cookie);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .lock_in_flight(5);
+        let rejecting = app(options.clone());
+        let waiting = app(options.on_conflict(ConflictBehavior::Wait(Duration::from_secs(2))));
+
+        for (app, wait) in [(rejecting, false), (waiting, true)] {
+            let response = app.clone().oneshot(request("/plain", None)).await.unwrap();
+            let session_cookie = get_session_cookie(&response);
+            let first = app
+                .clone()
+                .oneshot(request("/slow", Some(session_cookie.clone())));
+            let second = async {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                app.clone()
+                    .oneshot(request("/slow", Some(session_cookie.clone())))
+                    .await
+            };
+            let (first, second) = tokio::join!(first, second);
+            let (first, second) = (first.unwrap(), second.unwrap());
+
+            assert_eq!(first.status(), StatusCode::OK);
+            if wait {
+                assert_eq!(second.status(), StatusCode::OK);
+                assert!(second.headers().get("idempotency-replayed").is_some());
+                let first = to_bytes(first.into_body(), usize::MAX).await.unwrap();
+                let second = to_bytes(second.into_body(), usize::MAX).await.unwrap();
+                assert_eq!(first, second);
+            } else {
+                assert_eq!(second.status(), StatusCode::CONFLICT);
+                assert!(second.headers().get("retry-after").is_some());
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_waiters_relock_after_failed_request() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = {
+            let calls = calls.clone();
+            Router::new()
+                .route(
+                    "/charge",
+                    post(move || async move {
+                        let call = calls.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(150)).await;
+                        // The first execution fails, so its response is not cached
+                        if call == 0 {
+                            (StatusCode::INTERNAL_SERVER_ERROR, format!("charge #{call}"))
+                        } else {
+                            (StatusCode::OK, format!("charge #{call}"))
+                        }
+                    }),
+                )
+                .layer(IdempotentLayer::with_store(
+                    MemoryIdempotencyStore::new(),
+                    IdempotentOptions::default()
+                        .use_idempotency_key_header(None)
+                        .lock_in_flight(5)
+                        .on_conflict(ConflictBehavior::Wait(Duration::from_secs(2))),
+                ))
+        };
+        let request = || {
+            Request::builder()
+                .uri("/charge")
+                .method("POST")
+                .header("idempotency-key", "charge")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let waiter = || async {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            app.clone().oneshot(request()).await.unwrap()
+        };
+
+        let (holder, first, second) =
+            tokio::join!(app.clone().oneshot(request()), waiter(), waiter());
+        assert_eq!(holder.unwrap().status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        // Only one of the waiters is executed, and the other replays its response
+        let replayed = |response: &axum::response::Response| {
+            response.headers().contains_key("idempotency-replayed")
+        };
+        assert_eq!(first.status(), StatusCode::OK);
+        assert_eq!(second.status(), StatusCode::OK);
+        assert!(replayed(&first) != replayed(&second));
+        let first = to_bytes(first.into_body(), usize::MAX).await.unwrap();
+        let second = to_bytes(second.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(first, second);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_too_early_conflict() {
+        /// A clock set by the test.
+        #[derive(Clone)]
+        struct SetClock(Arc<Mutex<SystemTime>>);
+
+        impl Clock for SetClock {
+            fn now(&self) -> SystemTime {
+                *self.0.lock().unwrap()
+            }
+        }
+
+        let store = HashMapStore::default();
+        let clock = SetClock(Arc::new(Mutex::new(
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000),
+        )));
+        let release = Arc::new(tokio::sync::Notify::new());
+        let app = {
+            let release = release.clone();
+            Router::new()
+                .route(
+                    "/transfer",
+                    post(move || async move {
+                        release.notified().await;
+                        "transferred"
+                    }),
+                )
+                .layer(IdempotentLayer::with_store(
+                    store.clone(),
+                    IdempotentOptions::default()
+                        .use_idempotency_key_header(None)
+                        .clock(clock.clone())
+                        .lock_in_flight(30)
+                        .on_conflict(ConflictBehavior::TooEarly(Duration::from_secs(5))),
+                ))
+        };
+        let request = || {
+            Request::builder()
+                .uri("/transfer")
+                .method("POST")
+                .header("idempotency-key", "transfer")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let original = tokio::spawn(app.clone().oneshot(request()));
+        while !store.0.lock().unwrap().contains_key("transfer") {
+            tokio::task::yield_now().await;
+        }
+
+        // Duplicates are told to retry once the original is expected to have completed
+        *clock.0.lock().unwrap() += Duration::from_secs(2);
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_EARLY);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "3");
+
+        // Past the expected duration, they are told to retry in a second
+        *clock.0.lock().unwrap() += Duration::from_secs(10);
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_EARLY);
+        assert_eq!(response.headers()[header::RETRY_AFTER], "1");
+
+        release.notify_one();
+        let response = original.await.unwrap().unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()["idempotency-replayed"], "true");
+    }
+
+    #[tokio::test]
+    async fn test_get_or_lock() {
+        /// A `HashMapStore` that looks up and locks keys in one operation.
+        #[derive(Clone, Default)]
+        struct LockingStore {
+            entries: HashMapStore,
+            gets: Arc<AtomicU64>,
+            locks: Arc<AtomicU64>,
+        }
+
+        impl IdempotencyStore for LockingStore {
+            type Key = str;
+
+            async fn get(
+                &self,
+                key: &str,
+            ) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+                self.gets.fetch_add(1, Ordering::SeqCst);
+                self.entries.get(key).await
+            }
+
+            async fn set(
+                &self,
+                key: &str,
+                value: Vec<u8>,
+                ttl_secs: i64,
+            ) -> Result<(), Box<dyn Error + Send + Sync>> {
+                self.entries.set(key, value, ttl_secs).await
+            }
+
+            async fn get_or_lock(
+                &self,
+                key: &str,
+                marker: Vec<u8>,
+                lock_ttl_secs: i64,
+            ) -> Result<Option<Vec<u8>>, Box<dyn Error + Send + Sync>> {
+                self.locks.fetch_add(1, Ordering::SeqCst);
+                let mut entries = self.entries.0.lock().unwrap();
+                if let Some((entry, _)) = entries.get(key) {
+                    return Ok(Some(entry.clone()));
+                }
+                entries.insert(key.to_owned(), (marker, lock_ttl_secs));
+                Ok(None)
+            }
+
+            async fn remove(&self, key: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+                self.entries.remove(key).await
+            }
+        }
+
+        let store = LockingStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .lock_in_flight(5);
+        let app = Router::new()
+            .route("/", post(|| async { "locked" }))
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = || {
+            Request::builder()
+                .uri("/")
+                .method("POST")
+                .header("idempotency-key", "get-or-lock")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let first = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+        assert!(first.headers().get("idempotency-replayed").is_none());
+        let second = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(second.status(), StatusCode::OK);
+        assert!(second.headers().get("idempotency-replayed").is_some());
+        let body = to_bytes(second.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "locked");
+
+        assert_eq!(store.locks.load(Ordering::SeqCst), 2);
+        assert_eq!(store.gets.load(Ordering::SeqCst), 0);
+
+        // The default implementation acquires the lock with two calls
+        let store = HashMapStore::default();
+        let entry = store.get_or_lock("key", b"lock".to_vec(), 5).await.unwrap();
+        assert_eq!(entry, None);
+        assert_eq!(store.ttl("key"), Some(5));
+        let entry = store
+            .get_or_lock("key", b"other".to_vec(), 5)
+            .await
+            .unwrap();
+        assert_eq!(entry.as_deref(), Some(&b"lock"[..]));
+    }
+
+    #[tokio::test]
+    async fn test_single_flight() {
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .single_flight(true);
+        let counter = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/slow",
+                post({
+                    let counter = counter.clone();
+                    move || async move {
+                        let count = counter.fetch_add(1, Ordering::SeqCst);
+                        tokio::time::sleep(Duration::from_millis(200)).await;
+                        format!("Response #{count}")
+                    }
+                }),
+            )
+            .layer(IdempotentLayer::with_store(store, options));
+        let request = || {
+            Request::builder()
+                .uri("/slow")
+                .method("POST")
+                .header("idempotency-key", "burst")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let responses = (0..100)
+            .map(|_| tokio::spawn(app.clone().oneshot(request())))
+            .collect::<Vec<_>>();
+        let mut replayed = 0;
+        for response in responses {
+            let response = response.await.unwrap().unwrap();
+            if response.headers().contains_key("idempotency-replayed") {
+                replayed += 1;
+            }
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(body, "Response #0");
+        }
+        assert_eq!(replayed, 99);
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_hash_matched_path() {
+        let app = |options: IdempotentOptions| {
+            Router::new()
+                .route(
+                    "/users/{id}/orders",
+                    post(|Path(id): Path<String>| async move { id }),
+                )
+                .layer(IdempotentLayer::with_store(
+                    HashMapStore::default(),
+                    options,
+                ))
+        };
+        let request = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .method("POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let matched = app(IdempotentOptions::default().hash_matched_path(true));
+        matched
+            .clone()
+            .oneshot(request("/users/a/orders"))
+            .await
+            .unwrap();
+        let response = matched
+            .clone()
+            .oneshot(request("/users/%61/orders"))
+            .await
+            .unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        let response = matched.oneshot(request("/users/b/orders")).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+
+        let raw = app(IdempotentOptions::default());
+        raw.clone()
+            .oneshot(request("/users/a/orders"))
+            .await
+            .unwrap();
+        let response = raw.oneshot(request("/users/%61/orders")).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_rfc_mode() {
+        let app = Router::new()
+            .route(
+                "/payments",
+                post(|body: String| async move {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    body
+                }),
+            )
+            .layer(IdempotentLayer::with_store(
+                HashMapStore::default(),
+                IdempotentOptions::default().rfc_mode(),
+            ));
+        let request = |key: Option<&str>, body: &'static str| {
+            let mut builder = Request::builder().uri("/payments").method("POST");
+            if let Some(key) = key {
+                builder = builder.header("Idempotency-Key", key);
+            }
+            builder.body(Body::from(body)).unwrap()
+        };
+        let problem = |response: axum::response::Response| async move {
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                "application/problem+json"
+            );
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            serde_json::from_slice::<serde_json::Value>(&body).unwrap()["title"].clone()
+        };
+
+        let response = app.clone().oneshot(request(None, "a")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(problem(response).await, "Idempotency-Key is missing");
+
+        let (first, second) =
+            tokio::join!(app.clone().oneshot(request(Some("key-1"), "a")), async {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                app.clone().oneshot(request(Some("key-1"), "a")).await
+            });
+        assert_eq!(first.unwrap().status(), StatusCode::OK);
+        let second = second.unwrap();
+        assert_eq!(second.status(), StatusCode::CONFLICT);
+        assert_eq!(
+            problem(second).await,
+            "A request is outstanding for this Idempotency-Key"
+        );
+
+        let response = app
+            .clone()
+            .oneshot(request(Some("key-1"), "a"))
+            .await
+            .unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        let response = app.oneshot(request(Some("key-1"), "b")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert_eq!(problem(response).await, "Idempotency-Key is already used");
+    }
+
+    #[tokio::test]
+    async fn test_presets() {
+        let layer =
+            IdempotentLayer::with_store(HashMapStore::default(), IdempotentOptions::payments());
+        let manager = layer.manager();
+        let app = Router::new()
+            .route("/payments", post(|body: String| async move { body }))
+            .layer(layer);
+        let request = |key: Option<&str>, body: &'static str| {
+            let mut builder = Request::builder().uri("/payments").method("POST");
+            if let Some(key) = key {
+                builder = builder.header("Idempotency-Key", key);
+            }
+            builder.body(Body::from(body)).unwrap()
+        };
+
+        let response = app.clone().oneshot(request(None, "a")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = app
+            .clone()
+            .oneshot(request(Some("pay-1"), "a"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let info = manager.inspect("pay-1", false).await.unwrap().unwrap();
+        assert!(info.ttl_remaining.unwrap() > 23 * 60 * 60);
+        let response = app.oneshot(request(Some("pay-1"), "b")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        // Payments are not executed while the store is unavailable
+        let app = Router::new()
+            .route("/payments", post(|body: String| async move { body }))
+            .layer(IdempotentLayer::with_store(
+                UnavailableStore,
+                IdempotentOptions::payments(),
+            ));
+        let response = app.oneshot(request(Some("pay-2"), "a")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // Identical requests are deduplicated without a key
+        reset_counter();
+        let app = Router::new()
+            .route("/counter", post(increment_counter))
+            .layer(IdempotentLayer::with_store(
+                HashMapStore::default(),
+                IdempotentOptions::api_default(),
+            ));
+        let request = || {
+            Request::builder()
+                .uri("/counter")
+                .method("POST")
+                .body(Body::from("same"))
+                .unwrap()
+        };
+        app.clone().oneshot(request()).await.unwrap();
+        let response = app.oneshot(request()).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        assert_eq!(COUNTER.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_problem_details() {
+        let app = Router::new()
+            .route("/", post(|body: String| async move { body }))
+            .layer(IdempotentLayer::with_store(
+                HashMapStore::default(),
+                IdempotentOptions::default()
+                    .use_idempotency_key_header(None)
+                    .max_key_length(Some(8))
+                    .validate_fingerprint(true)
+                    .max_body_bytes(4)
+                    .on_oversized_body(OversizedBody::Reject)
+                    .problem_details(Some("https://example.com/problems/")),
+            ));
+        let request = |key: &str, body: &'static str| {
+            Request::builder()
+                .uri("/")
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        for (request, status, problem_type) in [
+            (
+                request("much-too-long", "a"),
+                StatusCode::BAD_REQUEST,
+                "invalid-idempotency-key",
+            ),
+            (
+                request("key", "too large"),
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "payload-too-large",
+            ),
+        ] {
+            let response = app.clone().oneshot(request).await.unwrap();
+            assert_eq!(response.status(), status);
+            assert_eq!(
+                response.headers()[header::CONTENT_TYPE],
+                "application/problem+json"
+            );
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+            assert_eq!(
+                problem["type"],
+                format!("https://example.com/problems/{problem_type}")
+            );
+            assert_eq!(problem["status"], status.as_u16());
+            assert!(problem["title"].is_string());
+            assert!(problem["detail"].is_string());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_error_bodies_are_escaped() {
+        let app =
+            Router::new()
+                .route("/", post(|| async { "ok" }))
+                .layer(IdempotentLayer::with_store(
+                    HashMapStore::default(),
+                    IdempotentOptions::default()
+                        .use_idempotency_key_header(None)
+                        .require_key(true)
+                        .problem_details(Some("https://example.com/\"problems\"\\")),
+                ));
+        let request = Request::builder()
+            .uri("/")
+            .method("POST")
+            .body(Body::empty())
+            .unwrap();
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            problem["type"],
+            "https://example.com/\"problems\"\\missing-idempotency-key"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_on_replay() {
+        let app = Router::new()
+            .route(
+                "/",
+                post(|| async { ([("x-csrf-token", "original")], "ok") }),
+            )
+            .layer(IdempotentLayer::with_store(
+                HashMapStore::default(),
+                IdempotentOptions::default().on_replay(|res| {
+                    assert!(res.extensions().get::<ReplayInfo>().is_some());
+                    res.headers_mut()
+                        .insert("x-csrf-token", "refreshed".parse().unwrap());
+                }),
+            ));
+        let request = || {
+            Request::builder()
+                .uri("/")
+                .method("POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.headers()["x-csrf-token"], "original");
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.headers()["x-csrf-token"], "refreshed");
+        // The cached entry is left untouched
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.headers()["x-csrf-token"], "refreshed");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "ok");
+    }
+
+    #[tokio::test]
+    async fn test_sliding_expiration() {
+        for sliding in [true, false] {
+            let store = HashMapStore::default();
+            let app = Router::new()
+                .route("/plain", post(|| async { "plain" }))
+                .layer(IdempotentLayer::with_store(
+                    store.clone(),
+                    IdempotentOptions::default()
+                        .use_idempotency_key_header(None)
+                        .expire_after(60)
+                        .sliding_expiration(sliding),
+                ));
+            let request = || {
+                Request::builder()
+                    .uri("/plain")
+                    .method("POST")
+                    .header("idempotency-key", "retried")
+                    .body(Body::empty())
+                    .unwrap()
+            };
+
+            app.clone().oneshot(request()).await.unwrap();
+            assert_eq!(store.ttl("retried"), Some(60));
+            // The entry is about to expire when the client retries
+            store.0.lock().unwrap().get_mut("retried").unwrap().1 = 5;
+
+            let response = app.oneshot(request()).await.unwrap();
+            assert!(response.headers().get("idempotency-replayed").is_some());
+            let ttl = if sliding { 60 } else { 5 };
+            assert_eq!(store.ttl("retried"), Some(ttl));
+        }
+
+        // Replays extend the entry by the TTL it was stored with, whatever their own TTL
+        let store = HashMapStore::default();
+        let app = Router::new()
+            .route("/plain", post(|| async { "plain" }))
+            .layer(IdempotentLayer::with_store(
+                store.clone(),
+                IdempotentOptions::default()
+                    .use_idempotency_key_header(None)
+                    .expire_after(60)
+                    .allow_client_ttl(3600)
+                    .sliding_expiration(true),
+            ));
+        let request = |ttl: &str| {
+            Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", "retried")
+                .header("idempotency-ttl", ttl)
+                .body(Body::empty())
+                .unwrap()
+        };
+        app.clone().oneshot(request("600")).await.unwrap();
+        assert_eq!(store.ttl("retried"), Some(600));
+        let response = app.oneshot(request("1")).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        assert_eq!(store.ttl("retried"), Some(600));
+    }
+
+    #[tokio::test]
+    async fn test_max_replays() {
+        let app = |options: IdempotentOptions, executions: Arc<AtomicUsize>| {
+            Router::new()
+                .route(
+                    "/",
+                    post(move || async move {
+                        executions.fetch_add(1, Ordering::SeqCst);
+                        "ok"
+                    }),
+                )
+                .layer(IdempotentLayer::with_store(
+                    HashMapStore::default(),
+                    options,
+                ))
+        };
+        let request = || {
+            Request::builder()
+                .uri("/")
+                .method("POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let executions = Arc::new(AtomicUsize::new(0));
+        let rejecting = app(
+            IdempotentOptions::default()
+                .max_replays(2, ReplayLimit::Reject(StatusCode::TOO_MANY_REQUESTS)),
+            executions.clone(),
+        );
+        let response = rejecting.clone().oneshot(request()).await.unwrap();
+        assert!(response.headers().get("idempotency-replay-count").is_none());
+        for count in ["1", "2"] {
+            let response = rejecting.clone().oneshot(request()).await.unwrap();
+            assert_eq!(response.headers()["idempotency-replay-count"], count);
+        }
+        let response = rejecting.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(executions.load(Ordering::SeqCst), 1);
+
+        // Limited replays are counted even with counting disabled
+        let executions = Arc::new(AtomicUsize::new(0));
+        let uncounted = app(
+            IdempotentOptions::default()
+                .max_replays(1, ReplayLimit::Reject(StatusCode::TOO_MANY_REQUESTS))
+                .count_replays(false),
+            executions.clone(),
+        );
+        for _ in 0..2 {
+            let response = uncounted.clone().oneshot(request()).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+        }
+        let response = uncounted.oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(executions.load(Ordering::SeqCst), 1);
+
+        let executions = Arc::new(AtomicUsize::new(0));
+        let reexecuting = app(
+            IdempotentOptions::default().max_replays(1, ReplayLimit::Reexecute),
+            executions.clone(),
+        );
+        for _ in 0..3 {
+            reexecuting.clone().oneshot(request()).await.unwrap();
+        }
+        assert_eq!(executions.load(Ordering::SeqCst), 2);
+        let response = reexecuting.oneshot(request()).await.unwrap();
+        assert_eq!(response.headers()["idempotency-replay-count"], "1");
+        assert_eq!(executions.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_replays_are_counted() {
+        let app =
+            Router::new()
+                .route("/", post(|| async { "ok" }))
+                .layer(IdempotentLayer::with_store(
+                    MemoryIdempotencyStore::new(),
+                    IdempotentOptions::default()
+                        .max_replays(8, ReplayLimit::Reject(StatusCode::TOO_MANY_REQUESTS)),
+                ));
+        let request = || {
+            Request::builder()
+                .uri("/")
+                .method("POST")
+                .body(Body::empty())
+                .unwrap()
+        };
+        app.clone().oneshot(request()).await.unwrap();
+
+        // No replay overwrites the count of another, so the limit holds
+        let replays: Vec<_> = (0..12)
+            .map(|_| tokio::spawn(app.clone().oneshot(request())))
+            .collect();
+        let mut statuses = Vec::new();
+        for replay in replays {
+            statuses.push(replay.await.unwrap().unwrap().status());
+        }
+        let rejected = statuses
+            .iter()
+            .filter(|status| **status == StatusCode::TOO_MANY_REQUESTS)
+            .count();
+        assert_eq!(rejected, 4, "{statuses:?}");
+    }
+
+    #[test]
+    fn test_options_validation() {
+        let error = |options: IdempotentOptions| options.build().unwrap_err();
+
+        assert!(IdempotentOptions::default().build().is_ok());
+        assert_eq!(
+            error(IdempotentOptions::default().expire_after(0)),
+            ConfigError::InvalidTtl(0)
+        );
+        assert_eq!(
+            error(IdempotentOptions::default().expire_after(60).soft_ttl(60)),
+            ConfigError::SoftTtlNotBelowTtl {
+                soft_ttl_secs: 60,
+                ttl_secs: 60
+            }
+        );
+        assert_eq!(
+            error(IdempotentOptions::default().lock_in_flight(-1)),
+            ConfigError::InvalidLockTtl(-1)
+        );
+        assert_eq!(
+            error(IdempotentOptions::default().use_idempotency_key_header(Some(""))),
+            ConfigError::InvalidKeyHeader(String::new())
+        );
+        assert_eq!(
+            error(
+                IdempotentOptions::default()
+                    .use_idempotency_key_header(None)
+                    .ignore_body(false)
+            ),
+            ConfigError::DirectKeyHashesRequest
+        );
+        assert!(
+            IdempotentLayer::<MemoryStore>::try_new(IdempotentOptions::default().expire_after(-5))
+                .is_err()
+        );
+        assert!(
+            IdempotentLayer::try_with_store(
+                MemoryIdempotencyStore::new(),
+                IdempotentOptions::default().lock_in_flight(0)
+            )
+            .is_err()
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "invalid idempotency options")]
+    fn test_with_store_validates_options() {
+        let options = IdempotentOptions::default().expire_after(60).soft_ttl(120);
+        IdempotentLayer::with_store(MemoryIdempotencyStore::new(), options);
+    }
+
+    #[tokio::test]
+    async fn test_options_from_config() {
+        let options: IdempotentOptions = serde_json::from_str(
+            r#"{
+                "ttl_secs": 3600,
+                "idempotency_key_header": "x-idempotency-key",
+                "ignored_status_codes": [500],
+                "methods": ["post", "PUT"]
+            }"#,
+        )
+        .unwrap();
+        let store = HashMapStore::default();
+        let app = Router::new()
+            .route("/ok", post(|| async { "ok" }).put(|| async { "ok" }))
+            .route(
+                "/error",
+                post(|| async { StatusCode::INTERNAL_SERVER_ERROR }),
+            )
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = |uri: &str, method: Method, key: &str| {
+            Request::builder()
+                .uri(uri)
+                .method(method)
+                .header("x-idempotency-key", key)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        app.clone()
+            .oneshot(request("/ok", Method::PUT, "put"))
+            .await
+            .unwrap();
+        assert_eq!(store.ttl("put"), Some(3600));
+        app.oneshot(request("/error", Method::POST, "error"))
+            .await
+            .unwrap();
+        assert_eq!(store.ttl("error"), None);
+
+        let error = |json: &str| {
+            serde_json::from_str::<IdempotentOptions>(json)
+                .unwrap_err()
+                .to_string()
+        };
+        assert!(error(r#"{ "ignored_status_codes": [1000] }"#).contains("ignored_status_codes"));
+        assert!(error(r#"{ "ttl_secs": 0 }"#).contains("expiration time"));
+        assert!(error(r#"{ "ttl": 60 }"#).contains("unknown field"));
+    }
+
+    #[tokio::test]
+    async fn test_cache_rejections() {
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .lock_in_flight(5)
+            .cache_rejections(Duration::from_secs(5));
+        let app = Router::new()
+            .route(
+                "/slow",
+                post(|| async {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    "slow"
+                }),
+            )
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = |key: &str| {
+            Request::builder()
+                .uri("/slow")
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Teaches the layer how long requests take
+        app.clone().oneshot(request("first")).await.unwrap();
+
+        let original = app.clone().oneshot(request("second"));
+        let retries = async {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            let rejected = app.clone().oneshot(request("second")).await.unwrap();
+            assert_eq!(rejected.status(), StatusCode::CONFLICT);
+            assert_eq!(rejected.headers().get("retry-after").unwrap(), "1");
+
+            // Retries are rejected without looking up the store
+            let entry = store.0.lock().unwrap().remove("second").unwrap();
+            let rejected = app.clone().oneshot(request("second")).await.unwrap();
+            assert_eq!(rejected.status(), StatusCode::CONFLICT);
+            store.0.lock().unwrap().insert("second".to_owned(), entry);
+        };
+        let (original, ()) = tokio::join!(original, retries);
+        assert_eq!(original.unwrap().status(), StatusCode::OK);
+
+        // Rejections are forgotten once the original request completes
+        let response = app.oneshot(request("second")).await.unwrap();
+        assert_eq!(
+            response.headers().get("idempotency-replayed").unwrap(),
+            "true"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_per_key() {
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .rate_limit_per_key(2, Duration::from_secs(60));
+        let app = Router::new()
+            .route("/plain", post(|| async { "plain" }))
+            .layer(IdempotentLayer::with_store(
+                HashMapStore::default(),
+                options,
+            ));
+        let request = |key: &str| {
+            Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request("storm")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.clone().oneshot(request("storm")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("idempotency-replayed").is_some());
+
+        let response = app.clone().oneshot(request("storm")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        // A token is refilled every 30 seconds
+        let retry_after = response.headers().get(header::RETRY_AFTER).unwrap();
+        assert_eq!(retry_after, "30");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "rate_limited");
+
+        // Other keys have their own bucket
+        let response = app.oneshot(request("calm")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_only_methods() {
+        let request = |method: &str, cookie: Option<axum::http::HeaderValue>| {
+            let mut builder = Request::builder()
+                .uri("/plain")
+                .method(method)
+                .header("idempotency-key", method);
+            if let Some(cookie) = cookie {
+                builder = builder.header("cookie", cookie);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+        let app = create_test_app(options.clone()).await;
+        let response = app.clone().oneshot(request("POST", None)).await.unwrap();
+        let session_cookie = get_session_cookie(&response);
+        for _ in 0..2 {
+            let response = app
+                .clone()
+                .oneshot(request("PUT", Some(session_cookie.clone())))
+                .await
+                .unwrap();
+            assert!(response.headers().get("idempotency-replayed").is_none());
+        }
+
+        let app = create_test_app(options.only_methods([Method::PUT])).await;
+        let response = app.clone().oneshot(request("PUT", None)).await.unwrap();
+        let session_cookie = get_session_cookie(&response);
+        let response = app
+            .clone()
+            .oneshot(request("PUT", Some(session_cookie.clone())))
+            .await
+            .unwrap();
+        assert_eq!(
+            response.headers().get("idempotency-replayed").unwrap(),
+            "true"
+        );
+        let response = app
+            .oneshot(request("POST", Some(session_cookie)))
+            .await
+            .unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_include_exclude_paths() {
+        let request = |cookie: Option<axum::http::HeaderValue>| {
+            let mut builder = Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", "paths");
+            if let Some(cookie) = cookie {
+                builder = builder.header("cookie", cookie);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+        for (options, replayed) in [
+            (options.clone().include_paths(["/{path}"]), true),
+            (options.clone().include_paths(["/test"]), false),
+            (options.exclude_paths(["/**"]), false),
+        ] {
+            let app = create_test_app(options).await;
+            let response = app.clone().oneshot(request(None)).await.unwrap();
+            // Skipped requests never touch the session, so no cookie is set
+            let session_cookie = response.headers().get("set-cookie").cloned();
+            let response = app.oneshot(request(session_cookie)).await.unwrap();
+            assert_eq!(
+                response.headers().get("idempotency-replayed").is_some(),
+                replayed
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_require_key() {
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .require_key(true);
+        let app = create_test_app(options).await;
+
+        let response = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/plain")
+                    .method("POST")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "application/json"
+        );
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "missing_idempotency_key");
+
+        let response = app
+            .oneshot(
+                Request::builder()
+                    .uri("/plain")
+                    .method("POST")
+                    .header("idempotency-key", "present")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_key_validation() {
+        let request = |key: &str| {
+            Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let app =
+            create_test_app(IdempotentOptions::default().use_idempotency_key_header(None)).await;
+        let response = app.oneshot(request(&"k".repeat(256))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "invalid_idempotency_key");
+        assert_eq!(body["message"], "The idempotency-key header is too long");
+
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .key_format(KeyFormat::Uuid);
+        let app = create_test_app(options).await;
+        for (key, status) in [
+            ("67e55044-10b1-426f-9247-bb680e5fe0c8", StatusCode::OK),
+            (
+                "67e55044-10b1-426f-9247-bb680e5fe0cg",
+                StatusCode::BAD_REQUEST,
+            ),
+            ("not-a-uuid", StatusCode::BAD_REQUEST),
+        ] {
+            let response = app.clone().oneshot(request(key)).await.unwrap();
+            assert_eq!(response.status(), status, "{key}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_max_body_bytes() {
+        let store = HashMapStore::default();
+        let app = |options: IdempotentOptions| {
+            Router::new()
+                .route("/echo", post(|body: String| async move { body }))
+                .layer(IdempotentLayer::with_store(store.clone(), options))
+        };
+        let request = |body: &'static str| {
+            Request::builder()
+                .uri("/echo")
+                .method("POST")
+                .body(Body::from(body))
+                .unwrap()
+        };
+        let options = IdempotentOptions::default().max_body_bytes(8);
+
+        // Oversized requests are forwarded unaltered
+        let bypassing = app(options.clone());
+        for _ in 0..2 {
+            let response = bypassing
+                .clone()
+                .oneshot(request("too large"))
+                .await
+                .unwrap();
+            assert!(response.headers().get("idempotency-replayed").is_none());
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(&body[..], b"too large");
+        }
+        assert!(store.0.lock().unwrap().is_empty());
+
+        let rejecting = app(options.on_oversized_body(OversizedBody::Reject));
+        let response = rejecting.oneshot(request("too large")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+
+        // Oversized responses are not cached
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .max_body_bytes(8);
+        let app = app(options);
+        for _ in 0..2 {
+            let response = app
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/echo")
+                        .method("POST")
+                        .header("idempotency-key", "large-response")
+                        .body(Body::from("too large"))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert!(response.headers().get("idempotency-replayed").is_none());
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(&body[..], b"too large");
+        }
+        assert!(store.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_hash_content_digest() {
+        const HELLO_SHA256: &str = "sha-256=:LPJNul+wow4m6DsqxbninhsWHlwfp0JecwQzYpOLmCQ=:";
+        const HELLO_SHA512: &str = "SHA-512=m3HSJL1i83hdltRq0+o9czGb+8KJDKra4t/3JRlnPKcjI8PZm6XBHXx6zG4UuMXaDEZjR1wuXDre9G9zvN7AQw==";
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = |options: IdempotentOptions| {
+            let calls = calls.clone();
+            Router::new()
+                .route(
+                    "/upload",
+                    post(move |body: axum::body::Bytes| async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        body
+                    }),
+                )
+                .layer(IdempotentLayer::with_store(
+                    HashMapStore::default(),
+                    options,
+                ))
+        };
+        let request = |digest: (&str, &str), body: &'static str| {
+            Request::builder()
+                .uri("/upload")
+                .method("POST")
+                .header(digest.0, digest.1)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        // The digest is hashed in place of the body
+        let trusting = app(IdempotentOptions::default().hash_content_digest(false));
+        let digest = ("content-digest", HELLO_SHA256);
+        let response = trusting
+            .clone()
+            .oneshot(request(digest, "hello"))
+            .await
+            .unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+        let response = trusting.oneshot(request(digest, "other")).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "hello");
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        let verifying = app(IdempotentOptions::default().hash_content_digest(true));
+        let response = verifying
+            .clone()
+            .oneshot(request(("digest", HELLO_SHA512), "hello"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, "hello");
+
+        // Bodies not matching their digest fail, and their response is not cached
+        let response = verifying
+            .clone()
+            .oneshot(request(digest, "tampered"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        let response = verifying.oneshot(request(digest, "hello")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("idempotency-replayed").is_none());
+        // The extractor rejected the tampered body before the handler ran
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_max_response_bytes() {
+        let store = HashMapStore::default();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = |options: IdempotentOptions| {
+            let calls = calls.clone();
+            Router::new()
+                .route(
+                    "/export",
+                    post(move || async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        "a large export"
+                    }),
+                )
+                .layer(IdempotentLayer::with_store(store.clone(), options))
+        };
+        let request = |key: &str| {
+            Request::builder()
+                .uri("/export")
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .max_response_bytes(8);
+
+        // Oversized responses are skipped by default, so retries are executed again
+        let skipping = app(options.clone());
+        for _ in 0..2 {
+            let response = skipping.clone().oneshot(request("skip")).await.unwrap();
+            assert!(response.headers().get("idempotency-replayed").is_none());
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(&body[..], b"a large export");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(store.0.lock().unwrap().is_empty());
+
+        // With a tombstone, retries are rejected
+        let tombstoning =
+            app(options.on_oversized_response(OversizedResponse::Tombstone(StatusCode::GONE)));
+        let response = tombstoning
+            .clone()
+            .oneshot(request("tombstone"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"a large export");
+
+        let response = tombstoning.oneshot(request("tombstone")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::GONE);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "response_not_replayable");
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+    }
+
+    /// A `BlobStore` backed by a `HashMap`, recording TTLs.
+    #[derive(Clone, Default)]
+    struct HashMapBlobStore(Arc<Mutex<HashMap<String, (Bytes, i64)>>>);
+
+    impl BlobStore for HashMapBlobStore {
+        async fn put(
+            &self,
+            key: &str,
+            body: Bytes,
+            ttl_secs: i64,
+        ) -> Result<(), Box<dyn Error + Send + Sync>> {
+            self.0
+                .lock()
+                .unwrap()
+                .insert(key.to_owned(), (body, ttl_secs));
+            Ok(())
+        }
+
+        async fn get(&self, key: &str) -> Result<Option<Body>, Box<dyn Error + Send + Sync>> {
+            let blobs = self.0.lock().unwrap();
+            Ok(blobs.get(key).map(|(body, _)| Body::from(body.clone())))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_blob_store() {
+        let store = HashMapStore::default();
+        let blobs = HashMapBlobStore::default();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = {
+            let calls = calls.clone();
+            Router::new()
+                .route(
+                    "/export",
+                    post(move |body: String| async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        body
+                    }),
+                )
+                .layer(IdempotentLayer::with_store(
+                    store.clone(),
+                    IdempotentOptions::default()
+                        .use_idempotency_key_header(None)
+                        .expire_after(60)
+                        .blob_store(blobs.clone(), 8),
+                ))
+        };
+        let request = |key: &str, body: &'static str| {
+            Request::builder()
+                .uri("/export")
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        // Small bodies stay in the entry
+        app.clone()
+            .oneshot(request("small", "small"))
+            .await
+            .unwrap();
+        let (entry, _) = store.0.lock().unwrap()["small"].clone();
+        let cached = CachedResponse::from_bytes(&entry).unwrap();
+        assert_eq!(&cached.body[..], b"small");
+        assert!(cached.blob.is_none());
+        assert!(blobs.0.lock().unwrap().is_empty());
+
+        // Large bodies are uploaded, and the entry points to them
+        let response = app
+            .clone()
+            .oneshot(request("large", "a large export"))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"a large export");
+        let (entry, _) = store.0.lock().unwrap()["large"].clone();
+        let cached = CachedResponse::from_bytes(&entry).unwrap();
+        assert!(cached.body.is_empty());
+        let pointer = cached.blob.unwrap();
+        assert_eq!(pointer.len, 14);
+        assert_eq!(
+            blobs.0.lock().unwrap()[&pointer.key],
+            (Bytes::from("a large export"), 60)
+        );
+
+        // Replays stream the body back from the blob store
+        let response = app
+            .clone()
+            .oneshot(request("large", "a large export"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()["idempotency-replayed"], "true");
+        assert_eq!(response.headers()[header::CONTENT_LENGTH], "14");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"a large export");
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        // Missing blobs are store errors, but the request is not executed again, even though
+        // store errors fail open by default
+        blobs.0.lock().unwrap().clear();
+        let response = app
+            .oneshot(request("large", "a large export"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(response.headers().get("idempotency-replayed").is_none());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert!(store.0.lock().unwrap().contains_key("large"));
+
+        // Failing closed responds with the configured status code instead
+        let app = Router::new()
+            .route("/export", post(|body: String| async move { body }))
+            .layer(IdempotentLayer::with_store(
+                store.clone(),
+                IdempotentOptions::default()
+                    .use_idempotency_key_header(None)
+                    .on_store_error(StoreErrorPolicy::FailClosed(StatusCode::BAD_GATEWAY))
+                    .blob_store(blobs.clone(), 8),
+            ));
+        let response = app
+            .oneshot(request("large", "a large export"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_GATEWAY);
+    }
+
+    #[tokio::test]
+    async fn test_custom_store_without_session() {
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+        let app = Router::new()
+            .route("/plain", post(|| async { "plain" }))
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = |key: &str| {
+            Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request("store")).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+        assert!(store.0.lock().unwrap().contains_key("store"));
+        assert!(!store.set_if_absent("store", Vec::new(), 10).await.unwrap());
+        assert!(store.set_if_absent("absent", Vec::new(), 10).await.unwrap());
+
+        let response = app.clone().oneshot(request("store")).await.unwrap();
+        assert_eq!(
+            response.headers().get("idempotency-replayed").unwrap(),
+            "true"
+        );
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"plain");
+
+        let response = app.oneshot(request("other")).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_health_check() {
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+        let layer = IdempotentLayer::with_store(HashMapStore::default(), options.clone());
+        assert!(layer.health_check().await.is_ok());
+
+        let layer = IdempotentLayer::with_store(UnavailableStore, options);
+        let err = layer.health_check().await.unwrap_err();
+        assert!(matches!(
+            err,
+            IdempotencyError::Store {
+                operation: StoreOperation::Ping,
+                ..
+            }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_memory_idempotency_store() {
+        let store = MemoryIdempotencyStore::new();
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+        let app = Router::new()
+            .route("/plain", post(|| async { "plain" }))
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = || {
+            Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", "memory")
+                .body(Body::empty())
+                .unwrap()
+        };
+        app.clone().oneshot(request()).await.unwrap();
+        let response = app.oneshot(request()).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        store.remove("memory").await.unwrap();
+        assert!(store.is_empty());
+
+        let size = |key: &str, value: usize| {
+            2 * key.len() + value + MemoryIdempotencyStore::ENTRY_OVERHEAD
+        };
+        let max_bytes = 2 * size("a", 31);
+        let store = MemoryIdempotencyStore::new()
+            .max_entries(2)
+            .max_bytes(max_bytes);
+        // The least recently used entry is evicted
+        store.set("a", b"1".to_vec(), 60).await.unwrap();
+        store.set("b", b"2".to_vec(), 60).await.unwrap();
+        assert_eq!(store.bytes(), 2 * size("a", 1));
+        assert!(store.get("a").await.unwrap().is_some());
+        store.set("c", b"3".to_vec(), 60).await.unwrap();
+        assert_eq!(store.len(), 2);
+        assert!(store.get("b").await.unwrap().is_none());
+        assert!(store.get("a").await.unwrap().is_some());
+
+        // Entries larger than the store fail to be written, and large ones evict others
+        store
+            .set("large", vec![0; max_bytes], 60)
+            .await
+            .unwrap_err();
+        assert!(store.get("large").await.unwrap().is_none());
+        store.set("d", vec![0; 62], 60).await.unwrap();
+        assert_eq!(store.len(), 1);
+        assert_eq!(store.bytes(), size("d", 62));
+
+        // Expired entries are gone
+        store.set("e", b"5".to_vec(), 0).await.unwrap();
+        assert!(store.get("e").await.unwrap().is_none());
+        assert!(store.set_if_absent("e", b"6".to_vec(), 60).await.unwrap());
+        assert!(!store.set_if_absent("e", b"7".to_vec(), 60).await.unwrap());
+        assert_eq!(store.get("e").await.unwrap().as_deref(), Some(&b"6"[..]));
+
+        // TTLs too long to be represented never expire
+        store.set("f", b"8".to_vec(), i64::MAX).await.unwrap();
+        assert_eq!(store.get("f").await.unwrap().as_deref(), Some(&b"8"[..]));
+        assert_eq!(
+            store.list_prefix("f").await.unwrap(),
+            [(String::from("f"), i64::MAX)]
+        );
+
+        // Expired entries are purged by writes, even when they are never read again
+        let store = MemoryIdempotencyStore::new();
+        store.set("a", b"1".to_vec(), 1).await.unwrap();
+        store.set("b", b"2".to_vec(), 1).await.unwrap();
+        assert_eq!(store.len(), 2);
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        store.set("c", b"3".to_vec(), 60).await.unwrap();
+        assert_eq!(store.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_memory_store_eviction() {
+        let size = |key: &str, value: usize| {
+            2 * key.len() + value + MemoryIdempotencyStore::ENTRY_OVERHEAD
+        };
+        let evicted = Arc::new(Mutex::new(Vec::new()));
+        let store = {
+            let evicted = evicted.clone();
+            MemoryIdempotencyStore::new()
+                .max_bytes(size("a", 10) + size("b", 100) + size("c", 10))
+                .on_evict(move |key, value, cause| {
+                    evicted
+                        .lock()
+                        .unwrap()
+                        .push((key.to_owned(), value.len(), cause));
+                })
+        };
+        let evicted = move || std::mem::take(&mut *evicted.lock().unwrap());
+
+        // The least recently used entry freeing enough room goes, not every smaller one
+        store.set("a", vec![0; 10], 60).await.unwrap();
+        store.set("b", vec![0; 100], 60).await.unwrap();
+        store.set("c", vec![0; 10], 60).await.unwrap();
+        assert!(evicted().is_empty());
+        store.set("d", vec![0; 50], 60).await.unwrap();
+        assert_eq!(
+            evicted(),
+            [(String::from("b"), 100, EvictionCause::Capacity)]
+        );
+        assert_eq!(store.len(), 3);
+        assert!(store.get("a").await.unwrap().is_some());
+        assert!(store.get("c").await.unwrap().is_some());
+
+        // Or the largest one, if none does
+        store.set("e", vec![0; 150], 60).await.unwrap();
+        assert_eq!(
+            evicted()[0],
+            (String::from("d"), 50, EvictionCause::Capacity)
+        );
+        assert!(store.get("e").await.unwrap().is_some());
+
+        // Entries too large to be stored and expired entries are reported
+        store.set("large", vec![0; 1000], 60).await.unwrap_err();
+        assert_eq!(
+            evicted(),
+            [(String::from("large"), 1000, EvictionCause::Capacity)]
+        );
+        store.set("f", vec![0; 1], 0).await.unwrap();
+        assert!(store.get("f").await.unwrap().is_none());
+        assert_eq!(evicted(), [(String::from("f"), 1, EvictionCause::Expired)]);
+
+        // Replaced and removed entries are not
+        store.set("e", vec![0; 1], 60).await.unwrap();
+        store.remove_prefix("").await.unwrap();
+        assert!(evicted().is_empty());
+        assert_eq!(store.bytes(), 0);
+
+        // Including evictions by the layer
+        let evictions = Arc::new(AtomicUsize::new(0));
+        let store = {
+            let evictions = evictions.clone();
+            MemoryIdempotencyStore::new()
+                .max_entries(1)
+                .on_evict(move |_, _, cause| {
+                    assert_eq!(cause, EvictionCause::Capacity);
+                    evictions.fetch_add(1, Ordering::SeqCst);
+                })
+        };
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+        let app = Router::new()
+            .route("/evict", post(|| async { "evict" }))
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        for key in ["first", "second"] {
+            let request = Request::builder()
+                .uri("/evict")
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::empty())
+                .unwrap();
+            app.clone().oneshot(request).await.unwrap();
+        }
+        assert_eq!(evictions.load(Ordering::SeqCst), 1);
+        assert_eq!(store.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_binary_keys() {
+        let replicated = Arc::new(Mutex::new(Vec::new()));
+        let store = BinaryStore::default();
+        let options = {
+            let replicated = replicated.clone();
+            IdempotentOptions::default()
+                .key_prefix("idem:")
+                .replicate_with(move |entry| replicated.lock().unwrap().push(entry))
+        };
+        let layer = IdempotentLayer::with_store(store.clone(), options);
+        let manager = layer.manager();
+        let app = Router::new()
+            .route("/binary", post(|| async { "binary" }))
+            .layer(layer);
+        let request = || {
+            Request::builder()
+                .uri("/binary")
+                .method("POST")
+                .body(Body::from("binary"))
+                .unwrap()
+        };
+
+        // The request hash follows the text prefix as raw bytes
+        app.clone().oneshot(request()).await.unwrap();
+        let keys = store.keys();
+        assert_eq!(keys.len(), 1);
+        let (prefix, digest) = keys[0].split_at("idem:".len());
+        assert_eq!(prefix, b"idem:");
+        assert_eq!(digest.len(), 32);
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.headers()["idempotency-replayed"], "true");
+
+        // Replicated entries are written under the same key
+        let other = BinaryStore::default();
+        let entry = replicated.lock().unwrap()[0].clone();
+        assert_eq!(entry.key.len(), "idem:".len() + 64);
+        entry.apply_to_store(&other).await.unwrap();
+        assert_eq!(other.keys(), keys);
+
+        // The manager takes the hash in hex
+        let hash: String = digest.iter().map(|byte| format!("{byte:02x}")).collect();
+        assert_eq!(&entry.key["idem:".len()..], hash);
+        let info = manager.inspect(&hash, false).await.unwrap().unwrap();
+        assert_eq!(info.state, KeyState::Completed);
+        manager.invalidate(&hash).await.unwrap();
+        assert!(store.keys().is_empty());
+
+        // Keys are text otherwise
+        let store = BinaryStore::default();
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+        let app = Router::new()
+            .route("/binary", post(|| async { "binary" }))
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = Request::builder()
+            .uri("/binary")
+            .method("POST")
+            .header("idempotency-key", "order-1")
+            .body(Body::empty())
+            .unwrap();
+        app.oneshot(request).await.unwrap();
+        assert_eq!(store.keys(), [b"order-1".to_vec()]);
+    }
+
+    #[tokio::test]
+    async fn test_tiered_store() {
+        let hot = MemoryIdempotencyStore::new();
+        let cold = MemoryIdempotencyStore::new();
+        let store = TieredStore::new(hot.clone(), cold.clone()).hot_ttl(60);
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+        let app = Router::new()
+            .route("/tiered", post(|| async { "tiered" }))
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = || {
+            Request::builder()
+                .uri("/tiered")
+                .method("POST")
+                .header("idempotency-key", "tiered")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Written through to both tiers
+        app.clone().oneshot(request()).await.unwrap();
+        assert!(hot.get("tiered").await.unwrap().is_some());
+        assert!(cold.get("tiered").await.unwrap().is_some());
+
+        // Copied back into the hot tier when only the cold tier has it
+        hot.remove("tiered").await.unwrap();
+        let response = app.oneshot(request()).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        assert!(hot.get("tiered").await.unwrap().is_some());
+
+        store.remove("tiered").await.unwrap();
+        assert!(hot.is_empty() && cold.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_soft_ttl() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .soft_ttl(0);
+        let app = Router::new()
+            .route(
+                "/refreshable",
+                post({
+                    let calls = calls.clone();
+                    move || async move { (calls.fetch_add(1, Ordering::SeqCst) + 1).to_string() }
+                }),
+            )
+            .layer(IdempotentLayer::with_store(
+                HashMapStore::default(),
+                options,
+            ));
+        let request = || {
+            Request::builder()
+                .uri("/refreshable")
+                .method("POST")
+                .header("idempotency-key", "refreshable")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request()).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"1");
+
+        // Stale responses are replayed, and refreshed in the background
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"1");
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let response = app.oneshot(request()).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"2");
+    }
+
+    #[tokio::test]
+    async fn test_clock() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let offset_secs = Arc::new(AtomicU64::new(0));
+        let clock = {
+            let offset_secs = offset_secs.clone();
+            move || SystemTime::now() + Duration::from_secs(offset_secs.load(Ordering::SeqCst))
+        };
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .soft_ttl(60)
+            .clock(clock);
+        let layer = IdempotentLayer::with_store(HashMapStore::default(), options);
+        let shutdown = layer.shutdown_handle();
+        let app = Router::new()
+            .route(
+                "/refreshable",
+                post({
+                    let calls = calls.clone();
+                    move || async move { (calls.fetch_add(1, Ordering::SeqCst) + 1).to_string() }
+                }),
+            )
+            .layer(layer);
+        let request = || {
+            Request::builder()
+                .uri("/refreshable")
+                .method("POST")
+                .header("idempotency-key", "clock")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        app.clone().oneshot(request()).await.unwrap();
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.headers()[header::AGE], "0");
+        shutdown.shutdown().await;
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        // Two minutes later, without waiting
+        offset_secs.store(120, Ordering::SeqCst);
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.headers()[header::AGE], "120");
+        shutdown.shutdown().await;
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_replay_date_and_age() {
+        let now_secs = Arc::new(AtomicU64::new(1_700_000_000));
+        let clock = {
+            let now_secs = now_secs.clone();
+            move || SystemTime::UNIX_EPOCH + Duration::from_secs(now_secs.load(Ordering::SeqCst))
+        };
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .clock(clock);
+        let app = Router::new()
+            .route(
+                "/dated",
+                post(|| async { ([(header::DATE, "Tue, 14 Nov 2023 22:13:20 GMT")], "dated") }),
+            )
+            .layer(IdempotentLayer::with_store(
+                HashMapStore::default(),
+                options,
+            ));
+        let request = || {
+            Request::builder()
+                .uri("/dated")
+                .method("POST")
+                .header("idempotency-key", "dated")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // The original response is untouched
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(
+            response.headers()[header::DATE],
+            "Tue, 14 Nov 2023 22:13:20 GMT"
+        );
+        assert!(response.headers().get(header::AGE).is_none());
+
+        // Replays are dated when they are served, with the time spent in the cache as their age
+        now_secs.fetch_add(90, Ordering::SeqCst);
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(
+            response.headers()[header::DATE],
+            "Tue, 14 Nov 2023 22:14:50 GMT"
+        );
+        assert_eq!(response.headers()[header::AGE], "90");
+        assert_eq!(response.headers().get_all(header::DATE).iter().count(), 1);
+
+        now_secs.fetch_add(3_600, Ordering::SeqCst);
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(
+            response.headers()[header::DATE],
+            "Tue, 14 Nov 2023 23:14:50 GMT"
+        );
+        assert_eq!(response.headers()[header::AGE], "3690");
+    }
+
+    #[tokio::test]
+    async fn test_unknown_format_version() {
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+        let app = Router::new()
+            .route("/plain", post(|| async { "plain" }))
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = || {
+            Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", "versioned")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // An entry written by a future release is treated as a miss, and overwritten
+        store
+            .set("versioned", vec![0xfe, 0xed, 0x7f, 0, 1, 2], 10)
+            .await
+            .unwrap();
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("idempotency-replayed").is_none());
+
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(
+            response.headers().get("idempotency-replayed").unwrap(),
+            "true"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_manager() {
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .key_prefix("payments:");
+        let layer = IdempotentLayer::with_store(store.clone(), options);
+        let manager = layer.manager();
+        let app = Router::new()
+            .route("/pay", post(|| async { "paid" }))
+            .layer(layer);
+        let request = |key: &str| {
+            Request::builder()
+                .uri("/pay")
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        for key in ["order-1", "order-2", "refund-1"] {
+            app.clone().oneshot(request(key)).await.unwrap();
+        }
+        let cached = manager.get_cached("order-1").await.unwrap().unwrap();
+        assert_eq!(cached.status, StatusCode::OK);
+        assert_eq!(&cached.body[..], b"paid");
+        assert!(manager.get_cached("unknown").await.unwrap().is_none());
+
+        // An invalidated key is executed again
+        manager.invalidate("order-1").await.unwrap();
+        assert!(manager.get_cached("order-1").await.unwrap().is_none());
+        let response = app.clone().oneshot(request("order-1")).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+
+        manager.invalidate_prefix("order-").await.unwrap();
+        let entries = store.0.lock().unwrap();
+        assert_eq!(entries.keys().collect::<Vec<_>>(), ["payments:refund-1"]);
+    }
+
+    #[tokio::test]
+    async fn test_inspect_router() {
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .validate_fingerprint(true)
+            .count_replays(true)
+            .lock_in_flight(5);
+        let layer = IdempotentLayer::with_store(MemoryIdempotencyStore::new(), options);
+        let manager = layer.manager();
+        let app = Router::new()
+            .route("/pay", post(|| async { (StatusCode::CREATED, "paid") }))
+            .route(
+                "/slow",
+                post(|| async {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    "slow"
+                }),
+            )
+            .layer(layer)
+            .nest("/admin", inspect_router(manager.clone()));
+        let request = |uri: &str, key: &str| {
+            Request::builder()
+                .uri(uri)
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let inspect = |uri: &str| Request::builder().uri(uri).body(Body::empty()).unwrap();
+
+        app.clone()
+            .oneshot(request("/pay", "order-1"))
+            .await
+            .unwrap();
+        app.clone()
+            .oneshot(request("/pay", "order-1"))
+            .await
+            .unwrap();
+        let response = app
+            .clone()
+            .oneshot(inspect("/admin/idempotency/order-1"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["key"], "order-1");
+        assert_eq!(info["state"], "completed");
+        assert_eq!(info["status"], 201);
+        assert_eq!(info["replays"], 1);
+        assert!(info["created_at"].as_u64().unwrap() > 0);
+        assert!(info["ttl_remaining"].as_u64().unwrap() <= 300);
+        assert!(info["fingerprint"].is_string());
+        assert!(info.get("body").is_none());
+
+        let response = app
+            .clone()
+            .oneshot(inspect("/admin/idempotency/order-1?body=true"))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let info: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(info["body"], "cGFpZA==");
+
+        let response = app
+            .clone()
+            .oneshot(inspect("/admin/idempotency/unknown"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+        let slow = app.clone().oneshot(request("/slow", "order-2"));
+        let in_flight = async {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            manager.inspect("order-2", false).await.unwrap().unwrap()
+        };
+        let (_, info) = tokio::join!(slow, in_flight);
+        assert_eq!(info.state, KeyState::InFlight);
+        assert_eq!(info.status, None);
+    }
+
+    /// Serializes a response as releases before the versioned format did: the status code, the
+    /// header lines separated by CRLFs, a blank line and the body.
+    fn baseline_entry(status: StatusCode, headers: &[(&str, &str)], body: &str) -> Vec<u8> {
+        let mut entry = status.as_u16().to_be_bytes().to_vec();
+        let lines: Vec<_> = headers
+            .iter()
+            .map(|(name, value)| format!("{name}: {value}"))
+            .collect();
+        entry.extend_from_slice(lines.join("\r\n").as_bytes());
+        entry.extend_from_slice(b"\r\n\r\n");
+        entry.extend_from_slice(body.as_bytes());
+        entry
+    }
+
+    #[tokio::test]
+    async fn test_legacy_and_corrupted_entries() {
+        let store = HashMapStore::default();
+        let calls = Arc::new(AtomicUsize::new(0));
+        let app = {
+            let calls = calls.clone();
+            Router::new()
+                .route(
+                    "/orders",
+                    post(move || async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        (StatusCode::CREATED, "executed")
+                    }),
+                )
+                .layer(IdempotentLayer::with_store(
+                    store.clone(),
+                    IdempotentOptions::default()
+                        .use_idempotency_key_header(None)
+                        .original_date_header(true)
+                        .original_timestamp_header(true)
+                        .lock_in_flight(5),
+                ))
+        };
+        let request = |key: &str| {
+            Request::builder()
+                .uri("/orders")
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::empty())
+                .unwrap()
+        };
+        let seed = |key: &str, entry: Vec<u8>| {
+            store.0.lock().unwrap().insert(key.to_owned(), (entry, 60));
+        };
+
+        // Entries written by earlier releases are replayed, as if stored when read
+        let legacy = baseline_entry(
+            StatusCode::CREATED,
+            &[("content-type", "application/json"), ("x-order", "1")],
+            r#"{"id":1}"#,
+        );
+        seed("legacy", legacy);
+        let response = app.clone().oneshot(request("legacy")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(response.headers()["idempotency-replayed"], "true");
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+        assert_eq!(response.headers()["x-order"], "1");
+        assert_eq!(response.headers()[header::AGE], "0");
+        let timestamp: u64 = response.headers()["idempotency-original-timestamp"]
+            .to_str()
+            .unwrap()
+            .parse()
+            .unwrap();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        assert!(now - timestamp < 5);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], br#"{"id":1}"#);
+        assert_eq!(calls.load(Ordering::SeqCst), 0);
+
+        // Entries with an impossible timestamp, or that cannot be decoded, are cache misses
+        let mut far_future = vec![0xfe, 0xed, 1];
+        far_future.extend_from_slice(&201u16.to_be_bytes());
+        far_future.extend_from_slice(&u64::MAX.to_be_bytes());
+        far_future.extend_from_slice(b"\r\n\r\nstale");
+        seed("far-future", far_future);
+        seed("garbage", b"\x00\xc8garbage".to_vec());
+        for key in ["far-future", "garbage"] {
+            let response = app.clone().oneshot(request(key)).await.unwrap();
+            assert!(response.headers().get("idempotency-replayed").is_none());
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(&body[..], b"executed");
+
+            // The entry was replaced with the new response
+            let response = app.clone().oneshot(request(key)).await.unwrap();
+            assert_eq!(response.headers()["idempotency-replayed"], "true");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_upgrade_entries() {
+        let store = MemoryIdempotencyStore::new();
+        // An entry in version 1 of the format: status code, `stored_at`, headers and body
+        let mut v1 = vec![0xfe, 0xed, 1];
+        v1.extend_from_slice(&201u16.to_be_bytes());
+        v1.extend_from_slice(&1_000u64.to_be_bytes());
+        v1.extend_from_slice(b"content-type: text/plain\r\n\r\ncreated");
+        store.set("old", v1, 3_600).await.unwrap();
+        let current = CachedResponse::new(StatusCode::OK, http::HeaderMap::new(), "current");
+        store.set("current", current.to_bytes(), 60).await.unwrap();
+        store
+            .set("other", b"not an entry".to_vec(), 60)
+            .await
+            .unwrap();
+
+        let report = upgrade_entries(&store, 1, FORMAT_VERSION).await.unwrap();
+        assert_eq!((report.upgraded, report.skipped), (1, 2));
+
+        let upgraded = store.get("old").await.unwrap().unwrap();
+        assert_eq!(upgraded[..3], [0xfe, 0xed, FORMAT_VERSION]);
+        let cached = CachedResponse::from_bytes(&upgraded).unwrap();
+        assert_eq!(cached.status, StatusCode::CREATED);
+        assert_eq!(cached.headers[header::CONTENT_TYPE], "text/plain");
+        assert_eq!(&cached.body[..], b"created");
+        assert_eq!(
+            cached.stored_at,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(1_000)
+        );
+        assert!(cached.expires_at.unwrap() > SystemTime::now() + Duration::from_secs(3_590));
+        assert_eq!(
+            store.get("current").await.unwrap().unwrap(),
+            current.to_bytes()
+        );
+        assert_eq!(store.get("other").await.unwrap().unwrap(), b"not an entry");
+
+        // Running it again finds nothing to upgrade
+        let report = upgrade_entries(&store, 1, FORMAT_VERSION).await.unwrap();
+        assert_eq!((report.upgraded, report.skipped), (0, 3));
+
+        // Entries written by releases before the format was versioned
+        let baseline = baseline_entry(
+            StatusCode::CREATED,
+            &[
+                ("content-type", "application/json"),
+                ("location", "/orders/1"),
+            ],
+            r#"{"id":1}"#,
+        );
+        store.set("baseline", baseline, 86_400).await.unwrap();
+        let before = SystemTime::now() - Duration::from_secs(1);
+        let report = upgrade_entries(&store, 0, FORMAT_VERSION).await.unwrap();
+        assert_eq!((report.upgraded, report.skipped), (1, 3));
+
+        let upgraded = store.get("baseline").await.unwrap().unwrap();
+        assert_eq!(upgraded[..3], [0xfe, 0xed, FORMAT_VERSION]);
+        let cached = CachedResponse::from_bytes(&upgraded).unwrap();
+        assert_eq!(cached.status, StatusCode::CREATED);
+        assert_eq!(cached.headers.len(), 2);
+        assert_eq!(cached.headers[header::CONTENT_TYPE], "application/json");
+        assert_eq!(cached.headers[header::LOCATION], "/orders/1");
+        assert!(cached.trailers.is_none());
+        assert_eq!(&cached.body[..], br#"{"id":1}"#);
+        assert!(cached.stored_at >= before && cached.stored_at <= SystemTime::now());
+        let expires_at = cached.expires_at.unwrap();
+        assert!(expires_at >= cached.stored_at + Duration::from_secs(86_390));
+
+        // Only older versions can be upgraded, to the current one
+        let err = upgrade_entries(&store, FORMAT_VERSION, FORMAT_VERSION + 1).await;
+        assert!(matches!(
+            err,
+            Err(MigrationError::UnsupportedVersions { .. })
+        ));
+
+        // Stores must be able to list their entries
+        let err = upgrade_entries(&HashMapStore::default(), 1, FORMAT_VERSION).await;
+        assert!(matches!(
+            err,
+            Err(MigrationError::Store {
+                operation: StoreOperation::List,
+                ..
+            })
+        ));
+    }
+
+    #[cfg(feature = "gzip")]
+    #[tokio::test]
+    async fn test_replay_negotiates_content_encoding() {
+        use std::io::Write;
+
+        let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+        encoder.write_all(b"compressed").unwrap();
+        let gzipped = encoder.finish().unwrap();
+        let app = Router::new()
+            .route(
+                "/gzip",
+                post(move || async move {
+                    (
+                        [(header::CONTENT_ENCODING, "gzip"), (header::ETAG, "\"v1\"")],
+                        gzipped,
+                    )
+                }),
+            )
+            .layer(IdempotentLayer::with_store(
+                MemoryIdempotencyStore::new(),
+                IdempotentOptions::default().use_idempotency_key_header(None),
+            ));
+        let request = |accept_encoding: &str| {
+            Request::builder()
+                .uri("/gzip")
+                .method("POST")
+                .header("idempotency-key", "gzip")
+                .header(header::ACCEPT_ENCODING, accept_encoding)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        app.clone().oneshot(request("gzip, br")).await.unwrap();
+        // Replayed as cached to clients accepting gzip
+        let response = app
+            .clone()
+            .oneshot(request("br;q=1, *;q=0.5"))
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+
+        // Decoded for the others
+        let response = app.oneshot(request("br, gzip;q=0")).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(response.headers()[header::ETAG], "W/\"v1\"");
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"compressed");
+    }
+
+    #[cfg(feature = "test-util")]
+    #[tokio::test]
+    async fn test_mock_store() {
+        use axum_idempotent::test_util::MockStore;
+
+        reset_counter();
+        let store = MockStore::new();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .expire_after(60)
+            .on_store_error(StoreErrorPolicy::FailClosed(
+                StatusCode::SERVICE_UNAVAILABLE,
+            ));
+        let app = Router::new()
+            .route("/counter", post(increment_counter))
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = || {
+            Request::builder()
+                .uri("/counter")
+                .method("POST")
+                .header("idempotency-key", "mock")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let body = |response: axum::response::Response| async {
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            String::from_utf8(body.to_vec()).unwrap()
+        };
+
+        store.fail_next(1);
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(body(response).await, "Response #0");
+        assert_eq!(store.ttl("mock"), Some(Duration::from_secs(60)));
+
+        // Expired entries are re-executed, without waiting
+        store.clock().advance(Duration::from_secs(61));
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert_eq!(body(response).await, "Response #1");
+
+        // Corrupted entries are not replayed
+        store.partial_writes(true);
+        store.clock().advance(Duration::from_secs(61));
+        app.clone().oneshot(request()).await.unwrap();
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(body(response).await, "Response #3");
+    }
+
+    #[cfg(feature = "front-cache")]
+    #[tokio::test]
+    async fn test_front_cache() {
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .expire_after(1)
+            .front_cache(FrontCacheLimit::Entries(100));
+        let layer = IdempotentLayer::with_store(store.clone(), options);
+        let manager = layer.manager();
+        let counter = Arc::new(AtomicUsize::new(0));
+        let app = Router::new()
+            .route(
+                "/pay",
+                post({
+                    let counter = counter.clone();
+                    move || async move { counter.fetch_add(1, Ordering::SeqCst).to_string() }
+                }),
+            )
+            .layer(layer);
+        let request = || {
+            Request::builder()
+                .uri("/pay")
+                .method("POST")
+                .header("idempotency-key", "hot")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        app.clone().oneshot(request()).await.unwrap();
+        // Replays are served from memory, without reading the store
+        store.0.lock().unwrap().clear();
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        assert_eq!(counter.load(Ordering::SeqCst), 1);
+
+        // Invalidating through the manager evicts the in-memory copy too
+        manager.invalidate("hot").await.unwrap();
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+        assert_eq!(counter.load(Ordering::SeqCst), 2);
+
+        // Entries expire together with the store's copy
+        store.0.lock().unwrap().clear();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        let response = app.oneshot(request()).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+        assert_eq!(counter.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_key_prefix() {
+        let store = HashMapStore::default();
+        let app = |prefix: &str| {
+            let options = IdempotentOptions::default()
+                .use_idempotency_key_header(None)
+                .key_prefix(prefix);
+            Router::new()
+                .route(
+                    "/plain",
+                    post(|IdempotencyKey(key): IdempotencyKey| async move { key }),
+                )
+                .layer(IdempotentLayer::with_store(store.clone(), options))
+        };
+        let request = || {
+            Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", "shared")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app("payments:prod:").oneshot(request()).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"shared");
+        assert!(store.0.lock().unwrap().contains_key("payments:prod:shared"));
+        assert!(!store.0.lock().unwrap().contains_key("shared"));
+
+        // Another service sharing the store does not see the entry
+        let response = app("orders:prod:").oneshot(request()).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+
+        let response = app("payments:prod:").oneshot(request()).await.unwrap();
+        assert_eq!(
+            response.headers().get("idempotency-replayed").unwrap(),
+            "true"
+        );
+        let info = response.extensions().get::<ReplayInfo>().unwrap();
+        assert_eq!(info.key, "shared");
+    }
+
+    #[tokio::test]
+    async fn test_namespace() {
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+        let app = Router::new()
+            .route("/payments", post(|| async { "payment" }))
+            .layer(IdempotentLayer::with_store(
+                store.clone(),
+                options.clone().namespace("payments"),
+            ))
+            .merge(
+                Router::new()
+                    .route("/webhooks", post(|| async { "webhook" }))
+                    .layer(IdempotentLayer::with_store(
+                        store.clone(),
+                        options.clone().namespace("webhooks"),
+                    )),
+            );
+        let request = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .method("POST")
+                .header("idempotency-key", "shared")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        app.clone().oneshot(request("/payments")).await.unwrap();
+        let response = app.clone().oneshot(request("/webhooks")).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"webhook");
+        assert!(store.0.lock().unwrap().contains_key("payments:shared"));
+        assert!(store.0.lock().unwrap().contains_key("webhooks:shared"));
+
+        let manager =
+            IdempotentLayer::with_store(store.clone(), options.namespace("payments")).manager();
+        assert!(manager.get_cached("shared").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_key_scope() {
+        #[derive(Clone)]
+        struct UserId(&'static str);
+
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .key_scope(KeyScope::custom(|extensions| {
+                extensions.get::<UserId>().map(|user| user.0.to_owned())
+            }));
+        let app = Router::new()
+            .route("/plain", post(|| async { "plain" }))
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = |user: Option<&'static str>| {
+            let mut req = Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", "1")
+                .body(Body::empty())
+                .unwrap();
+            if let Some(user) = user {
+                req.extensions_mut().insert(UserId(user));
+            }
+            req
+        };
+
+        let response = app.clone().oneshot(request(Some("alice"))).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+        assert!(store.0.lock().unwrap().contains_key("5:alice:1"));
+
+        // Another user reusing the key does not get the first user's response
+        let response = app.clone().oneshot(request(Some("bob"))).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+
+        let response = app.clone().oneshot(request(Some("alice"))).await.unwrap();
+        assert_eq!(
+            response.headers().get("idempotency-replayed").unwrap(),
+            "true"
+        );
+        assert_eq!(response.extensions().get::<ReplayInfo>().unwrap().key, "1");
+
+        // Requests without a principal are rejected, or forwarded if configured so
+        let response = app.oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "scope_missing");
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .key_scope(KeyScope::custom(|extensions| {
+                extensions.get::<UserId>().map(|user| user.0.to_owned())
+            }))
+            .on_missing_scope(MissingScope::Forward);
+        let app = Router::new()
+            .route("/plain", post(|| async { "plain" }))
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let response = app.oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert!(response.headers().get("idempotency-replayed").is_none());
+        assert_eq!(store.0.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_key_scope_does_not_collide_with_keys() {
+        #[derive(Clone)]
+        struct TenantId(&'static str);
+
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .key_scope(KeyScope::custom(|extensions| {
+                extensions
+                    .get::<TenantId>()
+                    .map(|tenant| tenant.0.to_owned())
+            }));
+        let app = Router::new()
+            .route(
+                "/plain",
+                post(|Extension(tenant): Extension<TenantId>| async move { tenant.0 }),
+            )
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = |tenant: &'static str, key: &'static str| {
+            let mut req = Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::empty())
+                .unwrap();
+            req.extensions_mut().insert(TenantId(tenant));
+            req
+        };
+
+        app.clone().oneshot(request("user:1", "x")).await.unwrap();
+        // Another tenant whose scope and key join into the same string
+        let response = app.oneshot(request("user", "1:x")).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"user");
+        assert!(store.0.lock().unwrap().contains_key("6:user:1:x"));
+        assert!(store.0.lock().unwrap().contains_key("4:user:1:x"));
+    }
+
+    #[tokio::test]
+    async fn test_scope_by_extension() {
+        #[derive(Clone)]
+        struct TenantId(u32);
+
+        impl std::fmt::Display for TenantId {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "tenant-{}", self.0)
+            }
+        }
+
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .scope_by_extension::<TenantId>();
+        let app = Router::new()
+            .route("/plain", post(|| async { "plain" }))
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = |tenant: Option<u32>| {
+            let mut req = Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", "1")
+                .body(Body::empty())
+                .unwrap();
+            if let Some(tenant) = tenant {
+                req.extensions_mut().insert(TenantId(tenant));
+            }
+            req
+        };
+
+        app.clone().oneshot(request(Some(1))).await.unwrap();
+        let response = app.clone().oneshot(request(Some(2))).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+        let response = app.clone().oneshot(request(Some(1))).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        assert!(store.0.lock().unwrap().contains_key("8:tenant-1:1"));
+        assert!(store.0.lock().unwrap().contains_key("8:tenant-2:1"));
+
+        // A missing tenant does not disable idempotency
+        let response = app.oneshot(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(store.0.lock().unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_session_and_global_key_scopes() {
+        let app = |scope: KeyScope| {
+            let options = IdempotentOptions::default()
+                .use_idempotency_key_header(None)
+                .hash_seed([1; 32])
+                .key_scope(scope);
+            Router::new()
+                .route("/plain", post(|| async { "plain" }))
+                .layer(IdempotentLayer::<MemoryStore>::try_new(options).unwrap())
+                .layer(
+                    SessionLayer::new(Arc::new(MemoryStore::new()))
+                        .with_cookie_options(CookieOptions::build().name("session").max_age(10)),
+                )
+                .layer(CookieManagerLayer::new())
+        };
+        let request = |cookie: Option<axum::http::HeaderValue>| {
+            let mut builder = Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", "shared");
+            if let Some(cookie) = cookie {
+                builder = builder.header("cookie", cookie);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        // The same key from another session misses the cache
+        let app_session = app(KeyScope::Session);
+        let response = app_session.clone().oneshot(request(None)).await.unwrap();
+        let session_cookie = get_session_cookie(&response);
+        let response = app_session
+            .clone()
+            .oneshot(request(Some(session_cookie)))
+            .await
+            .unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        let response = app_session.oneshot(request(None)).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_none());
+
+        // Clients without a session share the key space
+        let app_global = app(KeyScope::Global);
+        let response = app_global.clone().oneshot(request(None)).await.unwrap();
+        assert!(response.headers().get("set-cookie").is_none());
+        let response = app_global.oneshot(request(None)).await.unwrap();
+        assert!(response.headers().get("idempotency-replayed").is_some());
+
+        // Scopes shared by all sessions require a seed to derive their id from
+        let layer = IdempotentLayer::<MemoryStore>::try_new(
+            IdempotentOptions::default().key_scope(KeyScope::Global),
+        );
+        assert_eq!(layer.err(), Some(ConfigError::MissingHashSeed));
+
+        // Stores without sessions cannot scope keys by session
+        let layer = IdempotentLayer::try_with_store(
+            HashMapStore::default(),
+            IdempotentOptions::default()
+                .use_idempotency_key_header(None)
+                .key_scope(KeyScope::Session),
+        );
+        assert_eq!(layer.err(), Some(ConfigError::SessionScopeWithoutSession));
+    }
+
+    #[tokio::test]
+    async fn test_on_store_error() {
+        static CALLS: AtomicU64 = AtomicU64::new(0);
+
+        let app = |policy: StoreErrorPolicy| {
+            let options = IdempotentOptions::default()
+                .use_idempotency_key_header(None)
+                .lock_in_flight(10)
+                .on_store_error(policy);
+            Router::new()
+                .route(
+                    "/charge",
+                    post(|| async {
+                        CALLS.fetch_add(1, Ordering::SeqCst);
+                        "charged"
+                    }),
+                )
+                .layer(IdempotentLayer::with_store(UnavailableStore, options))
+        };
+        let request = || {
+            Request::builder()
+                .uri("/charge")
+                .method("POST")
+                .header("idempotency-key", "charge-1")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app(StoreErrorPolicy::FailOpen)
+            .oneshot(request())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+
+        let response = app(StoreErrorPolicy::FailClosed(
+            StatusCode::SERVICE_UNAVAILABLE,
+        ))
+        .oneshot(request())
+        .await
+        .unwrap();
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"], "store_unavailable");
+        assert_eq!(CALLS.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_observer() {
+        /// Records the events it observes.
+        #[derive(Clone, Default)]
+        struct RecordingObserver(Arc<Mutex<Vec<String>>>);
+
+        impl RecordingObserver {
+            fn record(&self, kind: &str, event: &IdempotencyEvent<'_>) {
+                let route = event.route.unwrap_or("-");
+                let event = format!("{kind} {} {route}", event.key);
+                self.0.lock().unwrap().push(event);
+            }
+
+            fn take(&self) -> Vec<String> {
+                std::mem::take(&mut self.0.lock().unwrap())
+            }
+        }
+
+        impl IdempotencyObserver for RecordingObserver {
+            fn on_cache_hit(&self, event: &IdempotencyEvent<'_>) {
+                self.record("hit", event);
+            }
+
+            fn on_cache_miss(&self, event: &IdempotencyEvent<'_>) {
+                self.record("miss", event);
+            }
+
+            fn on_store_write(&self, event: &IdempotencyEvent<'_>) {
+                self.record("write", event);
+            }
+
+            fn on_store_error(&self, event: &IdempotencyEvent<'_>, operation: StoreOperation) {
+                self.record(&format!("error({operation})"), event);
+            }
+
+            fn on_conflict(&self, event: &IdempotencyEvent<'_>) {
+                self.record("conflict", event);
+            }
+        }
+
+        let observer = RecordingObserver::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .lock_in_flight(5)
+            .observer(observer.clone());
+        let app = |store| {
+            Router::new()
+                .route(
+                    "/slow",
+                    post(|| async {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                        "slow"
+                    }),
+                )
+                .layer(IdempotentLayer::with_store(store, options.clone()))
+        };
+        let request = || {
+            Request::builder()
+                .uri("/slow")
+                .method("POST")
+                .header("idempotency-key", "observed")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let app = app(HashMapStore::default());
+        let first = app.clone().oneshot(request());
+        let second = async {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            app.clone().oneshot(request()).await
+        };
+        let (first, second) = tokio::join!(first, second);
+        assert_eq!(first.unwrap().status(), StatusCode::OK);
+        assert_eq!(second.unwrap().status(), StatusCode::CONFLICT);
+        app.oneshot(request()).await.unwrap();
+        assert_eq!(
+            observer.take(),
+            [
+                "miss observed /slow",
+                "conflict observed /slow",
+                "write observed /slow",
+                "hit observed /slow",
+            ]
+        );
+
+        let options = options.on_store_error(StoreErrorPolicy::FailOpen);
+        let app = Router::new()
+            .route("/slow", post(|| async { "slow" }))
+            .layer(IdempotentLayer::with_store(UnavailableStore, options));
+        app.oneshot(request()).await.unwrap();
+        let events = observer.take();
+        assert_eq!(events[0], "error(lock) observed /slow");
+    }
+
+    /// Records the spans and events of the requests handled while it is the default subscriber.
+    #[derive(Clone, Default)]
+    struct CapturedTrace {
+        records: Arc<Mutex<Vec<TraceRecord>>>,
+        /// The entered spans, for `Span::current()`.
+        entered: Arc<Mutex<Vec<tracing::span::Id>>>,
+    }
+
+    /// A span or an event with its recorded fields, formatted with `Debug` except for strings.
+    #[derive(Debug)]
+    struct TraceRecord {
+        name: String,
+        metadata: &'static tracing::Metadata<'static>,
+        fields: HashMap<String, String>,
+    }
+
+    struct FieldRecorder<'a>(&'a mut HashMap<String, String>);
+
+    impl tracing::field::Visit for FieldRecorder<'_> {
+        fn record_str(&mut self, field: &tracing::field::Field, value: &str) {
+            self.0.insert(field.name().to_owned(), value.to_owned());
+        }
+
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name().to_owned(), format!("{value:?}"));
+        }
+    }
+
+    impl CapturedTrace {
+        /// The fields of the spans named `name`.
+        fn spans(&self, name: &str) -> Vec<HashMap<String, String>> {
+            let records = self.records.lock().unwrap();
+            records
+                .iter()
+                .filter(|record| record.name == name)
+                .map(|record| record.fields.clone())
+                .collect()
+        }
+
+        /// The fields of the events, with their message as the `message` field.
+        fn events(&self) -> Vec<HashMap<String, String>> {
+            let records = self.records.lock().unwrap();
+            records
+                .iter()
+                .filter(|record| record.name.starts_with("event "))
+                .map(|record| record.fields.clone())
+                .collect()
+        }
+    }
+
+    impl tracing::Subscriber for CapturedTrace {
+        fn enabled(&self, _: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            let mut fields = HashMap::new();
+            span.record(&mut FieldRecorder(&mut fields));
+            let mut records = self.records.lock().unwrap();
+            records.push(TraceRecord {
+                name: span.metadata().name().to_owned(),
+                metadata: span.metadata(),
+                fields,
+            });
+            tracing::span::Id::from_u64(records.len() as u64)
+        }
+
+        fn record(&self, span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+            let mut records = self.records.lock().unwrap();
+            let record = &mut records[span.into_u64() as usize - 1];
+            values.record(&mut FieldRecorder(&mut record.fields));
+        }
+
+        fn record_follows_from(&self, _: &tracing::span::Id, _: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            let mut fields = HashMap::new();
+            event.record(&mut FieldRecorder(&mut fields));
+            self.records.lock().unwrap().push(TraceRecord {
+                name: format!("event {}", event.metadata().name()),
+                metadata: event.metadata(),
+                fields,
+            });
+        }
+
+        fn enter(&self, span: &tracing::span::Id) {
+            self.entered.lock().unwrap().push(span.clone());
+        }
+
+        fn exit(&self, span: &tracing::span::Id) {
+            let mut entered = self.entered.lock().unwrap();
+            if let Some(position) = entered.iter().rposition(|id| id == span) {
+                entered.remove(position);
+            }
+        }
+
+        fn current_span(&self) -> tracing_core::span::Current {
+            match self.entered.lock().unwrap().last() {
+                Some(span) => {
+                    let records = self.records.lock().unwrap();
+                    let metadata = records[span.into_u64() as usize - 1].metadata;
+                    tracing_core::span::Current::new(span.clone(), metadata)
+                }
+                None => tracing_core::span::Current::none(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_route_labels() {
+        #[derive(Clone, Default)]
+        struct RouteObserver(Arc<Mutex<Vec<Option<String>>>>);
+
+        impl IdempotencyObserver for RouteObserver {
+            fn on_cache_miss(&self, event: &IdempotencyEvent<'_>) {
+                let route = event.route.map(str::to_owned);
+                self.0.lock().unwrap().push(route);
+            }
+        }
+
+        let trace = CapturedTrace::default();
+        let _guard = tracing::subscriber::set_default(trace.clone());
+        let observer = RouteObserver::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .observer(observer.clone());
+        let layer = IdempotentLayer::with_store(HashMapStore::default(), options);
+        let request = |uri: &str, key: &str| {
+            Request::builder()
+                .uri(uri)
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Behind the router, events are labelled with the route template, not the path
+        let app = Router::new()
+            .route("/orders/{id}", post(|| async { "order" }))
+            .route_layer(layer.clone());
+        app.clone()
+            .oneshot(request("/orders/42", "a"))
+            .await
+            .unwrap();
+        app.oneshot(request("/orders/42", "a")).await.unwrap();
+        let events = trace.events();
+        assert!(
+            events.iter().any(|event| {
+                event["message"] == "No cached idempotent response"
+                    && event["route"] == "/orders/{id}"
+            }),
+            "{events:?}"
+        );
+        assert!(
+            events.iter().all(|event| event
+                .get("route")
+                .is_none_or(|route| route == "/orders/{id}")),
+            "{events:?}"
+        );
+        assert_eq!(
+            std::mem::take(&mut *observer.0.lock().unwrap()),
+            [Some(String::from("/orders/{id}"))]
+        );
+
+        // In front of the router, there is no route to label them with
+        let app = Router::new().route("/orders/{id}", post(|| async { "order" }));
+        let app = tower::Layer::layer(&layer, app);
+        app.oneshot(request("/orders/7", "b")).await.unwrap();
+        assert_eq!(*observer.0.lock().unwrap(), [None]);
+    }
+
+    #[tokio::test]
+    async fn test_check_span() {
+        let trace = CapturedTrace::default();
+        let _guard = tracing::subscriber::set_default(trace.clone());
+        let app = |options: IdempotentOptions| {
+            Router::new()
+                .route("/test", post(|| async { "test" }))
+                .layer(IdempotentLayer::with_store(
+                    HashMapStore::default(),
+                    options,
+                ))
+        };
+        let request = |key: &str| {
+            Request::builder()
+                .uri("/test")
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::from("body"))
+                .unwrap()
+        };
+
+        // Direct keys: the span records the length of the key and the outcome of the lookup
+        let direct = app(IdempotentOptions::default().use_idempotency_key_header(None));
+        direct.clone().oneshot(request("a-key")).await.unwrap();
+        direct.oneshot(request("a-key")).await.unwrap();
+        let spans = trace.spans("idempotency.check");
+        assert_eq!(spans.len(), 2);
+        for (span, hit) in spans.iter().zip(["false", "true"]) {
+            assert_eq!(span["key.mode"], "direct");
+            assert_eq!(span["key.len"], "5");
+            assert_eq!(span["cache.hit"], hit);
+            let latency: f64 = span["store.latency_ms"].parse().unwrap();
+            assert!(latency >= 0.0);
+        }
+
+        // Hashed keys: the length is that of the hash
+        let hashed = app(IdempotentOptions::default());
+        hashed.oneshot(request("ignored")).await.unwrap();
+        let spans = trace.spans("idempotency.check");
+        assert_eq!(spans[2]["key.mode"], "hash");
+        assert_eq!(spans[2]["key.len"], "64");
+        assert_eq!(spans[2]["cache.hit"], "false");
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn test_metrics() {
+        /// The values recorded for each metric, by name and labels.
+        type Samples = Arc<Mutex<Vec<(String, Vec<(String, String)>, f64)>>>;
+
+        /// Records every sample of the metrics registered through it.
+        #[derive(Default)]
+        struct RecordingRecorder(Samples);
+
+        struct Handle(metrics::Key, Samples);
+
+        impl Handle {
+            fn push(&self, value: f64) {
+                let labels = self
+                    .0
+                    .labels()
+                    .map(|label| (label.key().to_owned(), label.value().to_owned()))
+                    .collect();
+                let name = self.0.name().to_owned();
+                self.1.lock().unwrap().push((name, labels, value));
+            }
+        }
+
+        impl metrics::CounterFn for Handle {
+            fn increment(&self, value: u64) {
+                self.push(value as f64);
+            }
+
+            fn absolute(&self, value: u64) {
+                self.push(value as f64);
+            }
+        }
+
+        impl metrics::HistogramFn for Handle {
+            fn record(&self, value: f64) {
+                self.push(value);
+            }
+        }
+
+        impl metrics::Recorder for RecordingRecorder {
+            fn describe_counter(
+                &self,
+                _: metrics::KeyName,
+                _: Option<metrics::Unit>,
+                _: metrics::SharedString,
+            ) {
+            }
+
+            fn describe_gauge(
+                &self,
+                _: metrics::KeyName,
+                _: Option<metrics::Unit>,
+                _: metrics::SharedString,
+            ) {
+            }
+
+            fn describe_histogram(
+                &self,
+                _: metrics::KeyName,
+                _: Option<metrics::Unit>,
+                _: metrics::SharedString,
+            ) {
+            }
+
+            fn register_counter(
+                &self,
+                key: &metrics::Key,
+                _: &metrics::Metadata<'_>,
+            ) -> metrics::Counter {
+                metrics::Counter::from_arc(Arc::new(Handle(key.clone(), self.0.clone())))
+            }
+
+            fn register_gauge(
+                &self,
+                _: &metrics::Key,
+                _: &metrics::Metadata<'_>,
+            ) -> metrics::Gauge {
+                metrics::Gauge::noop()
+            }
+
+            fn register_histogram(
+                &self,
+                key: &metrics::Key,
+                _: &metrics::Metadata<'_>,
+            ) -> metrics::Histogram {
+                metrics::Histogram::from_arc(Arc::new(Handle(key.clone(), self.0.clone())))
+            }
+        }
+
+        let recorder = RecordingRecorder::default();
+        let _guard = metrics::set_default_local_recorder(&recorder);
+        let app = Router::new()
+            .route("/orders/{id}", post(|| async { "order" }))
+            .route_layer(IdempotentLayer::with_store(
+                HashMapStore::default(),
+                IdempotentOptions::default().use_idempotency_key_header(None),
+            ));
+        let request = || {
+            Request::builder()
+                .uri("/orders/42")
+                .method("POST")
+                .header("idempotency-key", "metered")
+                .body(Body::empty())
+                .unwrap()
+        };
+        app.clone().oneshot(request()).await.unwrap();
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.headers()["idempotency-replayed"], "true");
+
+        let samples = std::mem::take(&mut *recorder.0.lock().unwrap());
+        let labels = |extra: &[(&str, &str)]| {
+            let mut labels = vec![
+                (String::from("method"), String::from("POST")),
+                (String::from("route"), String::from("/orders/{id}")),
+            ];
+            labels.extend(extra.iter().map(|(k, v)| (k.to_string(), v.to_string())));
+            labels
+        };
+        let values = |name: &str, labels: Vec<(String, String)>| {
+            samples
+                .iter()
+                .filter(|sample| sample.0 == name && sample.1 == labels)
+                .map(|sample| sample.2)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(values("idempotency_cache_miss_total", labels(&[])), [1.0]);
+        assert_eq!(values("idempotency_cache_hit_total", labels(&[])), [1.0]);
+        assert!(
+            values(
+                "idempotency_store_error_total",
+                labels(&[("operation", "get")])
+            )
+            .is_empty()
+        );
+        for operation in ["get", "set"] {
+            let latencies = values(
+                "idempotency_store_duration_seconds",
+                labels(&[("operation", operation)]),
+            );
+            assert!(
+                !latencies.is_empty(),
+                "no {operation} latency in {samples:?}"
+            );
+            assert!(latencies.iter().all(|latency| *latency >= 0.0));
+        }
+        assert_eq!(
+            values("idempotency_hash_duration_seconds", labels(&[])).len(),
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn test_admin_router() {
+        let stats = IdempotencyStats::new();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .lock_in_flight(5)
+            .stats(stats.clone());
+        let app = Router::new()
+            .route(
+                "/slow",
+                post(|| async {
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    "slow"
+                }),
+            )
+            .layer(IdempotentLayer::with_store(
+                MemoryIdempotencyStore::new(),
+                options,
+            ))
+            .nest(
+                "/admin",
+                admin_router(stats.clone()).layer(axum::middleware::from_fn(
+                    |req: Request, next: axum::middleware::Next| async move {
+                        match req.headers().get("x-admin-token") {
+                            Some(token) if token == "secret" => next.run(req).await,
+                            _ => StatusCode::UNAUTHORIZED.into_response(),
+                        }
+                    },
+                )),
+            );
+        let request = || {
+            Request::builder()
+                .uri("/slow")
+                .method("POST")
+                .header("idempotency-key", "counted")
+                .body(Body::empty())
+                .unwrap()
+        };
+        let admin = |uri: &str, token: &str| {
+            Request::builder()
+                .uri(uri)
+                .header("x-admin-token", token)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let first = app.clone().oneshot(request());
+        let second = async {
+            tokio::time::sleep(Duration::from_millis(30)).await;
+            let in_flight = stats.in_flight();
+            assert_eq!(in_flight.len(), 1);
+            assert_eq!(in_flight[0].route.as_deref(), Some("/slow"));
+            app.clone().oneshot(request()).await
+        };
+        let (first, second) = tokio::join!(first, second);
+        assert_eq!(first.unwrap().status(), StatusCode::OK);
+        assert_eq!(second.unwrap().status(), StatusCode::CONFLICT);
+        app.clone().oneshot(request()).await.unwrap();
+        assert!(stats.in_flight().is_empty());
+
+        let response = app
+            .clone()
+            .oneshot(admin("/admin/idempotency/stats", "wrong"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+        let response = app
+            .clone()
+            .oneshot(admin("/admin/idempotency/stats", "secret"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let snapshot: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let counters = serde_json::json!({
+            "hits": 1,
+            "misses": 1,
+            "conflicts": 1,
+            "store_errors": 0,
+        });
+        assert_eq!(snapshot["hits"], 1);
+        assert_eq!(snapshot["misses"], 1);
+        assert_eq!(snapshot["conflicts"], 1);
+        assert_eq!(snapshot["in_flight"], 0);
+        assert_eq!(snapshot["routes"]["/slow"], counters);
+
+        let response = app
+            .oneshot(admin("/admin/idempotency/in-flight", "secret"))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"[]");
+    }
+
+    #[tokio::test]
+    async fn test_on_error() {
+        let reported = Arc::new(Mutex::new(Vec::new()));
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .max_key_length(Some(8))
+            .on_error({
+                let reported = reported.clone();
+                move |err| {
+                    reported.lock().unwrap().push(err.to_string());
+                    match err {
+                        IdempotencyError::Store {
+                            operation: StoreOperation::Get,
+                            ..
+                        } => ErrorAction::Respond(StatusCode::IM_A_TEAPOT.into_response()),
+                        IdempotencyError::KeyTooLong { len: 9, max_len: 8 } => ErrorAction::Forward,
+                        _ => ErrorAction::Default,
+                    }
+                }
+            });
+        let app = |options: IdempotentOptions| {
+            Router::new()
+                .route("/plain", post(|| async { "plain" }))
+                .layer(IdempotentLayer::with_store(UnavailableStore, options))
+        };
+        let request = |key: &str| {
+            Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app(options.clone()).oneshot(request("key")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::IM_A_TEAPOT);
+        assert_eq!(
+            reported.lock().unwrap().pop().unwrap(),
+            "idempotency store get failed: connection refused"
+        );
+
+        let response = app(options.clone())
+            .oneshot(request("too-long!"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        // Errors the hook leaves to the default handling are still reported
+        let response = app(options.key_format(KeyFormat::Uuid))
+            .oneshot(request("not-uuid"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            *reported.lock().unwrap(),
+            [
+                "idempotency key is 9 bytes long, longer than the maximum of 8",
+                "idempotency key must be a UUID",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_ttl_extension() {
+        async fn bulk_import() -> impl IntoResponse {
+            (
+                Extension(IdempotencyTtl(Duration::from_secs(60))),
+                "imported",
+            )
+        }
+
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .expire_after(5);
+        let app = |ttl: Option<IdempotencyTtl>| {
+            let router = Router::new()
+                .route("/plain", post(|| async { "plain" }))
+                .route("/imports", post(bulk_import))
+                .layer(IdempotentLayer::with_store(store.clone(), options.clone()));
+            match ttl {
+                Some(ttl) => router.layer(Extension(ttl)),
+                None => router,
+            }
+        };
+        let request = |uri: &str, key: &str| {
+            Request::builder()
+                .uri(uri)
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        app(None)
+            .oneshot(request("/plain", "default"))
+            .await
+            .unwrap();
+        assert_eq!(store.ttl("default"), Some(5));
+
+        let app = app(Some(IdempotencyTtl(Duration::from_secs(60 * 60))));
+        app.clone()
+            .oneshot(request("/plain", "route"))
+            .await
+            .unwrap();
+        assert_eq!(store.ttl("route"), Some(60 * 60));
+
+        app.oneshot(request("/imports", "handler")).await.unwrap();
+        assert_eq!(store.ttl("handler"), Some(60));
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_directive() {
+        async fn pending() -> impl IntoResponse {
+            (Extension(IdempotencyDirective::NoStore), "pending")
+        }
+
+        async fn settled() -> impl IntoResponse {
+            (
+                Extension(IdempotencyDirective::ExpireAfter(120)),
+                Extension(IdempotencyTtl(Duration::from_secs(60))),
+                "settled",
+            )
+        }
+
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .lock_in_flight(5);
+        let app = Router::new()
+            .route("/pending", post(pending))
+            .route("/settled", post(settled))
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = |uri: &str| {
+            Request::builder()
+                .uri(uri)
+                .method("POST")
+                .header("idempotency-key", uri)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        app.clone().oneshot(request("/pending")).await.unwrap();
+        assert!(store.ttl("/pending").is_none());
+
+        app.oneshot(request("/settled")).await.unwrap();
+        assert_eq!(store.ttl("/settled"), Some(120));
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_extractor() {
+        async fn charge(idempotency: Idempotency) -> String {
+            match idempotency.key() {
+                "pending" => idempotency.mark_no_store(),
+                _ => idempotency.set_ttl(120),
+            }
+            format!("{} {}", idempotency.key(), idempotency.is_fresh())
+        }
+
+        let store = HashMapStore::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .soft_ttl(0);
+        let app = Router::new()
+            .route("/charge", post(charge))
+            .layer(IdempotentLayer::with_store(store.clone(), options));
+        let request = |key: &str| {
+            Request::builder()
+                .uri("/charge")
+                .method("POST")
+                .header("idempotency-key", key)
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request("pending")).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"pending true");
+        assert!(store.ttl("pending").is_none());
+
+        app.clone().oneshot(request("settled")).await.unwrap();
+        assert_eq!(store.ttl("settled"), Some(120));
+
+        // The stale response is refreshed in the background, by a second execution
+        app.clone().oneshot(request("settled")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let response = app.oneshot(request("settled")).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"settled false");
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_key_extractor() {
+        let options = IdempotentOptions::default().use_idempotency_key_header(None);
+        let app = Router::new()
+            .route(
+                "/key",
+                post(|IdempotencyKey(key): IdempotencyKey| async move { key }),
+            )
+            .route(
+                "/optional",
+                post(|key: Option<IdempotencyKey>| async move { format!("{key:?}") }),
+            )
+            .layer(IdempotentLayer::with_store(
+                HashMapStore::default(),
+                options,
+            ));
+        let request = |uri: &str, key: Option<&str>| {
+            let mut builder = Request::builder().uri(uri).method("POST");
+            if let Some(key) = key {
+                builder = builder.header("idempotency-key", key);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let response = app
+            .clone()
+            .oneshot(request("/key", Some("extracted")))
+            .await
+            .unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"extracted");
+
+        let response = app.clone().oneshot(request("/key", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let response = app.oneshot(request("/optional", None)).await.unwrap();
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"None");
+    }
+
+    #[tokio::test]
+    async fn test_conditional_replay() {
+        let app = Router::new()
+            .route(
+                "/orders/{id}/result",
+                get(|| async { ([(header::ETAG, r#""v1""#)], "created") }),
+            )
+            .layer(IdempotentLayer::with_store(
+                HashMapStore::default(),
+                IdempotentOptions::default()
+                    .use_idempotency_key_header(None)
+                    .only_methods([Method::GET]),
+            ));
+        let request = |if_none_match: Option<&str>| {
+            let mut builder = Request::builder()
+                .uri("/orders/1/result")
+                .header("idempotency-key", "poll");
+            if let Some(tags) = if_none_match {
+                builder = builder.header(header::IF_NONE_MATCH, tags);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        let response = app.clone().oneshot(request(Some(r#""v1""#))).await.unwrap();
+        assert_eq!(
+            response.status(),
+            StatusCode::OK,
+            "fresh executions are untouched"
+        );
+
+        let response = app
+            .clone()
+            .oneshot(request(Some(r#"W/"v1""#)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[header::ETAG], r#""v1""#);
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+
+        for tags in [Some(r#""v0""#), None] {
+            let response = app.clone().oneshot(request(tags)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::OK);
+            let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(&body[..], b"created");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay_info() {
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .original_date_header(true);
+        let app = Router::new()
+            .route(
+                "/plain",
+                post(|| async { ([(header::DATE, "Thu, 01 Jan 1970 00:00:00 GMT")], "plain") }),
+            )
+            .layer(IdempotentLayer::with_store(
+                HashMapStore::default(),
+                options,
+            ));
+        let request = || {
+            Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", "info")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        let response = app.clone().oneshot(request()).await.unwrap();
+        assert!(response.extensions().get::<ReplayInfo>().is_none());
+        for name in [
+            "idempotency-original-date",
+            "idempotency-original-timestamp",
+        ] {
+            assert!(response.headers().get(name).is_none());
+        }
+
+        let response = app.oneshot(request()).await.unwrap();
+        let info = response.extensions().get::<ReplayInfo>().unwrap();
+        assert_eq!(info.key, "info");
+        assert!(info.age < Duration::from_secs(5));
+        let date = response.headers().get("idempotency-original-date").unwrap();
+        assert_eq!(
+            httpdate::parse_http_date(date.to_str().unwrap()).unwrap(),
+            info.original_timestamp
+        );
+        let timestamp = response
+            .headers()
+            .get("idempotency-original-timestamp")
+            .unwrap();
+        let timestamp = timestamp.to_str().unwrap().parse().unwrap();
+        assert_eq!(
+            std::time::UNIX_EPOCH + Duration::from_secs(timestamp),
+            info.original_timestamp
+        );
+
+        // Date is regenerated, and Age tells how long the response was cached
+        let date = response.headers().get(header::DATE).unwrap();
+        assert!(
+            httpdate::parse_http_date(date.to_str().unwrap()).unwrap() >= info.original_timestamp
+        );
+        let age = response.headers().get(header::AGE).unwrap();
+        assert_eq!(age.to_str().unwrap(), info.age.as_secs().to_string());
+    }
+
+    #[tokio::test]
+    async fn test_original_timestamp_header() {
+        let now_secs = Arc::new(AtomicU64::new(1_700_000_000));
+        let clock = {
+            let now_secs = now_secs.clone();
+            move || SystemTime::UNIX_EPOCH + Duration::from_secs(now_secs.load(Ordering::SeqCst))
+        };
+        let app = |options: IdempotentOptions| {
+            Router::new()
+                .route("/plain", post(|| async { "plain" }))
+                .layer(IdempotentLayer::with_store(
+                    HashMapStore::default(),
+                    options
+                        .use_idempotency_key_header(None)
+                        .clock(clock.clone()),
+                ))
+        };
+        let request = || {
+            Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("idempotency-key", "timestamped")
+                .body(Body::empty())
+                .unwrap()
+        };
+
+        // Replays carry the Unix time at which the response was stored, by default
+        let enabled = app(IdempotentOptions::default());
+        let response = enabled.clone().oneshot(request()).await.unwrap();
+        assert!(
+            response
+                .headers()
+                .get("idempotency-original-timestamp")
+                .is_none()
+        );
+        now_secs.fetch_add(42, Ordering::SeqCst);
+        let response = enabled.clone().oneshot(request()).await.unwrap();
+        assert_eq!(
+            response.headers()["idempotency-original-timestamp"],
+            "1700000000"
+        );
+        assert!(
+            response
+                .headers()
+                .get("idempotency-original-date")
+                .is_none()
+        );
+        now_secs.fetch_add(42, Ordering::SeqCst);
+        let response = enabled.oneshot(request()).await.unwrap();
+        assert_eq!(
+            response.headers()["idempotency-original-timestamp"],
+            "1700000000"
+        );
+
+        let disabled = app(IdempotentOptions::default().original_timestamp_header(false));
+        disabled.clone().oneshot(request()).await.unwrap();
+        let response = disabled.oneshot(request()).await.unwrap();
+        assert_eq!(response.headers()["idempotency-replayed"], "true");
+        assert!(
+            response
+                .headers()
+                .get("idempotency-original-timestamp")
+                .is_none()
+        );
+    }
+
+    #[cfg(feature = "jwt")]
+    #[tokio::test]
+    async fn test_jwt_claim_key() {
+        use axum_idempotent::JwtClaimKey;
+        use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+
+        let token = |jti: &str| {
+            let claims = serde_json::json!({ "jti": jti, "sub": "user-1", "exp": 4102444800u64 });
+            let token = jsonwebtoken::encode(
+                &Header::default(),
+                &claims,
+                &EncodingKey::from_secret(b"secret"),
+            )
+            .unwrap();
+            format!("Bearer {token}")
+        };
+
+        let options = IdempotentOptions::default().jwt_claim_key(JwtClaimKey::derive(
+            "jti",
+            DecodingKey::from_secret(b"secret"),
+            Validation::default(),
+        ));
+        let app = create_test_app(options).await;
+
+        let response1 = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/plain")
+                    .method("POST")
+                    .header("authorization", token("jti-1"))
+                    .body(Body::from("body A"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let session_cookie = get_session_cookie(&response1);
+
+        // Same `jti` with a different body is a replay.
+        let response2 = app
+            .clone()
+            .oneshot(
+                Request::builder()
+                    .uri("/plain")
+                    .method("POST")
+                    .header("cookie", session_cookie.clone())
+                    .header("authorization", token("jti-1"))
+                    .body(Body::from("body B"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(response2.headers().get("idempotency-replayed").is_some());
+
+        // A token signed with another secret is not trusted.
+        let forged = jsonwebtoken::encode(
+            &Header::default(),
+            &serde_json::json!({ "jti": "jti-1", "exp": 4102444800u64 }),
+            &EncodingKey::from_secret(b"other"),
+        )
+        .unwrap();
+        let response3 = app
+            .oneshot(
+                Request::builder()
+                    .uri("/plain")
+                    .method("POST")
+                    .header("cookie", session_cookie)
+                    .header("authorization", format!("Bearer {forged}"))
+                    .body(Body::from("body A"))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert!(response3.headers().get("idempotency-replayed").is_none());
+    }
+
+    #[cfg(feature = "jwt")]
+    #[tokio::test]
+    async fn test_jwt_scoped_hash_keys() {
+        use axum_idempotent::JwtClaimKey;
+        use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation};
+
+        let claims = serde_json::json!({ "sub": "user-1", "exp": 4102444800u64 });
+        let token = jsonwebtoken::encode(
+            &Header::default(),
+            &claims,
+            &EncodingKey::from_secret(b"secret"),
+        )
+        .unwrap();
+        let store = BinaryStore::default();
+        let options = IdempotentOptions::default().jwt_claim_key(JwtClaimKey::scope(
+            "sub",
+            DecodingKey::from_secret(b"secret"),
+            Validation::default(),
+        ));
+        let layer = IdempotentLayer::with_store(store.clone(), options);
+        let manager = layer.manager();
+        let app = Router::new()
+            .route("/plain", post(|| async { "plain" }))
+            .layer(layer);
+        let request = || {
+            Request::builder()
+                .uri("/plain")
+                .method("POST")
+                .header("authorization", format!("Bearer {token}"))
+                .body(Body::from("body"))
+                .unwrap()
+        };
+
+        // The request hash follows the claim as raw bytes, as in hashing mode
+        app.clone().oneshot(request()).await.unwrap();
+        let keys = store.keys();
+        assert_eq!(keys.len(), 1);
+        let (claim, digest) = keys[0].split_at("user-1:".len());
+        assert_eq!(claim, b"user-1:");
+        assert_eq!(digest.len(), 32);
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.headers()["idempotency-replayed"], "true");
+
+        // The key fingerprints the request
+        let hash: String = digest.iter().map(|byte| format!("{byte:02x}")).collect();
+        let key = format!("user-1:{hash}");
+        let info = manager.inspect(&key, false).await.unwrap().unwrap();
+        assert_eq!(info.fingerprint.as_deref(), Some(key.as_str()));
+    }
+
+    #[cfg(feature = "webhook")]
+    #[tokio::test]
+    async fn test_webhook_dedup() {
+        use axum_idempotent::WebhookDedup;
+
+        let app = |webhook: WebhookDedup, calls: Arc<AtomicUsize>| {
+            Router::new()
+                .route(
+                    "/webhooks",
+                    post(move || async move {
+                        calls.fetch_add(1, Ordering::SeqCst);
+                        (StatusCode::ACCEPTED, "processed")
+                    }),
+                )
+                .layer(IdempotentLayer::with_store(
+                    HashMapStore::default(),
+                    IdempotentOptions::default().webhook_dedup(webhook),
+                ))
+        };
+        let request = |delivery: &str, body: &'static str| {
+            Request::builder()
+                .uri("/webhooks")
+                .method("POST")
+                .header("x-github-delivery", delivery)
+                .body(Body::from(body))
+                .unwrap()
+        };
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let github = app(WebhookDedup::github(), calls.clone());
+        github
+            .clone()
+            .oneshot(request("delivery-1", "{}"))
+            .await
+            .unwrap();
+        let response = github
+            .clone()
+            .oneshot(request("delivery-1", r#"{"changed":true}"#))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+        assert!(response.headers().get("idempotency-replayed").is_some());
+        // Only the acknowledgment status is cached
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert!(body.is_empty());
+        github.oneshot(request("delivery-2", "{}")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let stripe = app(WebhookDedup::stripe(), calls.clone());
+        for delivery in ["attempt-1", "attempt-2"] {
+            let response = stripe
+                .clone()
+                .oneshot(request(
+                    delivery,
+                    r#"{"id":"evt_1","type":"charge.succeeded"}"#,
+                ))
+                .await
+                .unwrap();
+            assert_eq!(response.status(), StatusCode::ACCEPTED);
+        }
+        stripe
+            .clone()
+            .oneshot(request("attempt-1", r#"{"id":"evt_2"}"#))
+            .await
+            .unwrap();
+        // Events without an ID are processed every time
+        stripe
+            .clone()
+            .oneshot(request("attempt-1", "{}"))
+            .await
+            .unwrap();
+        stripe.oneshot(request("attempt-1", "{}")).await.unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 4);
+    }
+
+    #[cfg(feature = "audit")]
+    #[tokio::test]
+    async fn test_audit_sink() {
+        use axum_idempotent::{AuditOutcome, AuditRecord, AuditSink};
+
+        #[derive(Clone, Default)]
+        struct VecSink(Arc<Mutex<Vec<AuditRecord>>>);
+
+        impl AuditSink for VecSink {
+            fn record(&self, record: AuditRecord) {
+                self.0.lock().unwrap().push(record);
+            }
+        }
+
+        let sink = VecSink::default();
+        let options = IdempotentOptions::default()
+            .use_idempotency_key_header(None)
+            .audit_sink(sink.clone());
+        let app = Router::new()
+            .route("/charge", post(|| async { "charged" }))
+            .route("/plain", post(|| async { "plain" }))
+            .layer(IdempotentLayer::with_store(
+                HashMapStore::default(),
+                options,
+            ));
+        let request = |uri: &str, key: Option<&str>| {
+            let mut builder = Request::builder().uri(uri).method("POST");
+            if let Some(key) = key {
+                builder = builder.header("idempotency-key", key);
+            }
+            builder.body(Body::empty()).unwrap()
+        };
+
+        app.clone()
+            .oneshot(request("/charge", Some("audit-1")))
+            .await
+            .unwrap();
+        app.clone()
+            .oneshot(request("/charge", Some("audit-1")))
+            .await
+            .unwrap();
+        // Requests without a key are not audited
+        app.clone().oneshot(request("/plain", None)).await.unwrap();
+
+        let records = sink.0.lock().unwrap().clone();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].key, "audit-1");
+        assert_eq!(records[0].method, Method::POST);
+        assert_eq!(records[0].path, "/charge");
+        assert_eq!(
+            records[0].outcome,
+            AuditOutcome::Executed {
+                status: StatusCode::OK,
+                cached: true
+            }
+        );
+        assert!(records[0].received_at <= records[0].completed_at);
+        assert_eq!(records[1].outcome, AuditOutcome::Replayed(StatusCode::OK));
+    }
 }
@@ -0,0 +1,46 @@
+//! Tests of [`DynamoDbStore`] against a DynamoDB endpoint, e.g. DynamoDB Local, whose URL is
+//! read from the `DYNAMODB_ENDPOINT` environment variable. The tests are skipped when it is not
+//! set.
+//!
+//! ```sh
+//! DYNAMODB_ENDPOINT=http://127.0.0.1:8000 cargo test --features dynamodb --test dynamodb
+//! ```
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_dynamodb::Client;
+    use aws_sdk_dynamodb::config::{BehaviorVersion, Credentials, Region};
+    use axum_idempotent::DynamoDbStore;
+    use axum_idempotent::test_util::conformance;
+
+    /// Connects to the endpoint of `DYNAMODB_ENDPOINT`, and returns a store keeping its entries
+    /// in a freshly created `table`, or `None` if the variable is not set.
+    async fn store(table: &str) -> Option<DynamoDbStore> {
+        let Ok(endpoint) = std::env::var("DYNAMODB_ENDPOINT") else {
+            eprintln!("DYNAMODB_ENDPOINT is not set, skipping");
+            return None;
+        };
+        let config = aws_sdk_dynamodb::Config::builder()
+            .behavior_version(BehaviorVersion::latest())
+            .region(Region::new("us-east-1"))
+            .endpoint_url(endpoint)
+            .credentials_provider(Credentials::new("test", "test", None, None, "test"))
+            .build();
+        let client = Client::from_conf(config);
+        let _ = client.delete_table().table_name(table).send().await;
+        let store = DynamoDbStore::new(client, table);
+        store.migrate().await.unwrap();
+        // Migrating twice is harmless
+        store.migrate().await.unwrap();
+        Some(store)
+    }
+
+    #[tokio::test]
+    async fn test_conformance() {
+        let Some(store) = store("idempotency_conformance").await else {
+            return;
+        };
+
+        conformance(store, "").await;
+    }
+}
@@ -0,0 +1,123 @@
+//! Tests of [`MemcachedStore`] against a memcached server, whose URL is read from the
+//! `MEMCACHED_URL` environment variable. The tests are skipped when it is not set.
+//!
+//! ```sh
+//! MEMCACHED_URL=memcache://127.0.0.1:11211 cargo test --features memcached --test memcached
+//! ```
+
+#[cfg(test)]
+mod tests {
+    use axum_idempotent::test_util::conformance;
+    use axum_idempotent::{IdempotencyStore, MemcachedStore};
+    use std::time::Duration;
+    use vmemcached::{Client, ConnectionManager, Pool, Settings};
+
+    /// Connects to the server of `MEMCACHED_URL` and removes the entries of `keys`, returning a
+    /// store, or `None` if the variable is not set.
+    async fn store(keys: &[&str]) -> Option<MemcachedStore> {
+        let Ok(url) = std::env::var("MEMCACHED_URL") else {
+            eprintln!("MEMCACHED_URL is not set, skipping");
+            return None;
+        };
+        let manager = ConnectionManager::try_from(url.as_str()).unwrap();
+        // `vmemcached` rejects idle connections when they are checked
+        let pool = Pool::builder()
+            .max_size(16)
+            .test_on_check_out(false)
+            .build(manager)
+            .await
+            .unwrap();
+        let store = MemcachedStore::new(Client::with_pool(pool, Settings::new()));
+        for key in keys {
+            store.remove(key).await.unwrap();
+        }
+        Some(store)
+    }
+
+    #[tokio::test]
+    async fn test_conformance() {
+        let Some(store) = store(&[]).await else {
+            return;
+        };
+
+        conformance(store, "test:conformance:").await;
+    }
+
+    #[tokio::test]
+    async fn test_hashed_keys() {
+        let key = "test:hashed:key";
+        let long = format!("test:hashed:{}", "k".repeat(300));
+        let spaced = "test:hashed:key 0 0 1 noreply";
+        let Some(store) = store(&[key, &long, spaced]).await else {
+            return;
+        };
+
+        // Keys over memcached's limit are hashed
+        store.set(&long, b"long".to_vec(), 60).await.unwrap();
+        assert_eq!(
+            store.get(&long).await.unwrap().as_deref(),
+            Some(&b"long"[..])
+        );
+
+        // So are keys holding spaces, which would otherwise add arguments to the command
+        store.set(spaced, b"spaced".to_vec(), 60).await.unwrap();
+        assert_eq!(
+            store.get(spaced).await.unwrap().as_deref(),
+            Some(&b"spaced"[..])
+        );
+        assert_eq!(store.get(key).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_lock_taken_over() {
+        let key = "test:taken_over:key";
+        let (Some(first), Some(second)) = (store(&[key]).await, store(&[]).await) else {
+            return;
+        };
+
+        // A request whose lock expired, here through another store, e.g. in another process,
+        // neither overwrites nor releases the lock acquired since
+        assert_eq!(
+            first.get_or_lock(key, b"first".to_vec(), 1).await.unwrap(),
+            None
+        );
+        tokio::time::sleep(Duration::from_millis(2100)).await;
+        assert_eq!(
+            second
+                .get_or_lock(key, b"second".to_vec(), 60)
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(first.set(key, b"response".to_vec(), 60).await.is_err());
+        first.remove(key).await.unwrap();
+        assert_eq!(
+            second.get(key).await.unwrap().as_deref(),
+            Some(&b"second"[..])
+        );
+
+        // The request holding it stores its response, and releases its next lock
+        second.set(key, b"response".to_vec(), 60).await.unwrap();
+        assert_eq!(
+            first.get(key).await.unwrap().as_deref(),
+            Some(&b"response"[..])
+        );
+        second.remove(key).await.unwrap();
+        assert_eq!(
+            second.get_or_lock(key, b"lock".to_vec(), 60).await.unwrap(),
+            None
+        );
+        second.remove(key).await.unwrap();
+        assert_eq!(first.get(key).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_prefixes_are_unsupported() {
+        let Some(store) = store(&[]).await else {
+            return;
+        };
+
+        assert!(store.remove_prefix("test:").await.is_err());
+        assert!(store.list_prefix("test:").await.is_err());
+    }
+}
@@ -0,0 +1,71 @@
+//! Tests of [`PostgresStore`] against a PostgreSQL server, whose URL is read from the
+//! `DATABASE_URL` environment variable. The tests are skipped when it is not set.
+//!
+//! ```sh
+//! DATABASE_URL=postgres://postgres@localhost/postgres cargo test --features postgres --test postgres
+//! ```
+
+#[cfg(test)]
+mod tests {
+    use axum_idempotent::test_util::conformance;
+    use axum_idempotent::{IdempotencyStore, PostgresStore};
+    use sqlx::PgPool;
+    use std::time::Duration;
+
+    /// Connects to the server of `DATABASE_URL`, and returns a store keeping its entries in a
+    /// freshly created `table`, which may be schema-qualified, or `None` if the variable is not
+    /// set.
+    async fn store(table: &str) -> Option<PostgresStore> {
+        let Ok(url) = std::env::var("DATABASE_URL") else {
+            eprintln!("DATABASE_URL is not set, skipping");
+            return None;
+        };
+        let pool = PgPool::connect(&url).await.unwrap();
+        if let Some((schema, _)) = table.split_once('.') {
+            sqlx::query(&format!("CREATE SCHEMA IF NOT EXISTS {schema}"))
+                .execute(&pool)
+                .await
+                .unwrap();
+        }
+        sqlx::query(&format!("DROP TABLE IF EXISTS {table}"))
+            .execute(&pool)
+            .await
+            .unwrap();
+        let store = PostgresStore::new(pool).with_table(table);
+        store.migrate().await.unwrap();
+        // Migrating twice is harmless
+        store.migrate().await.unwrap();
+        Some(store)
+    }
+
+    #[tokio::test]
+    async fn test_conformance() {
+        let Some(store) = store("app.idempotency_conformance").await else {
+            return;
+        };
+
+        conformance(store, "").await;
+    }
+
+    #[tokio::test]
+    async fn test_remove_expired() {
+        let Some(store) = store("idempotency_remove_expired").await else {
+            return;
+        };
+
+        // Expired entries are kept until they are removed
+        store.set("short", b"value".to_vec(), 1).await.unwrap();
+        store.set("long", b"value".to_vec(), 60).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(store.remove_expired().await.unwrap(), 1);
+        assert_eq!(store.remove_expired().await.unwrap(), 0);
+        assert!(store.get("long").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "invalid table name")]
+    async fn test_invalid_table_name() {
+        let pool = PgPool::connect_lazy("postgres://localhost/app").unwrap();
+        let _ = PostgresStore::new(pool).with_table("entries; DROP TABLE users");
+    }
+}
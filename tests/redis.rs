@@ -0,0 +1,96 @@
+//! Tests of [`RedisStore`] against a Redis server, whose URL is read from the `REDIS_URL`
+//! environment variable. The tests are skipped when it is not set.
+//!
+//! ```sh
+//! REDIS_URL=redis://127.0.0.1:6379 cargo test --features redis-store --test redis
+//! ```
+
+#[cfg(test)]
+mod tests {
+    use axum::Router;
+    use axum::body::Body;
+    use axum::extract::Request;
+    use axum::routing::post;
+    use axum_idempotent::test_util::conformance;
+    use axum_idempotent::{IdempotencyStore, IdempotentLayer, IdempotentOptions, RedisStore};
+    use fred::prelude::{ClientLike, Config, KeysInterface, Pool};
+    use std::sync::Arc;
+    use tower::ServiceExt;
+
+    /// Connects to the server of `REDIS_URL` and removes the keys starting with `prefix`,
+    /// returning a store and its client, or `None` if the variable is not set.
+    async fn store(prefix: &str) -> Option<(RedisStore, Arc<Pool>)> {
+        let Ok(url) = std::env::var("REDIS_URL") else {
+            eprintln!("REDIS_URL is not set, skipping");
+            return None;
+        };
+        let pool = Pool::new(Config::from_url(&url).unwrap(), None, None, None, 4).unwrap();
+        pool.init().await.unwrap();
+        let pool = Arc::new(pool);
+        let store = RedisStore::new(pool.clone());
+        store.remove_prefix(prefix).await.unwrap();
+        Some((store, pool))
+    }
+
+    #[tokio::test]
+    async fn test_conformance() {
+        let Some((store, _)) = store("test:conformance:").await else {
+            return;
+        };
+
+        conformance(store, "test:conformance:").await;
+    }
+
+    #[tokio::test]
+    async fn test_lock_ttl() {
+        let Some((store, pool)) = store("test:lock_ttl:").await else {
+            return;
+        };
+
+        // The lock is acquired with the TTL of the lock
+        let key = "test:lock_ttl:key";
+        assert_eq!(
+            store.get_or_lock(key, b"lock".to_vec(), 30).await.unwrap(),
+            None
+        );
+        let ttl_secs: i64 = pool.ttl(key).await.unwrap();
+        assert!((29..=30).contains(&ttl_secs), "TTL of {ttl_secs}s");
+    }
+
+    #[tokio::test]
+    async fn test_binary_keys() {
+        let Some((store, pool)) = store("test:binary:").await else {
+            return;
+        };
+        let store = store.binary_keys();
+        let app = Router::new()
+            .route("/charge", post(|| async { "charged" }))
+            .layer(IdempotentLayer::with_store(
+                store.clone(),
+                IdempotentOptions::default()
+                    .key_prefix("test:binary:")
+                    .lock_in_flight(5),
+            ));
+        let request = || {
+            Request::builder()
+                .uri("/charge")
+                .method("POST")
+                .body(Body::from("charge"))
+                .unwrap()
+        };
+
+        // The request hash follows the text prefix as raw bytes
+        app.clone().oneshot(request()).await.unwrap();
+        let entries = store.list_prefix(b"test:binary:").await.unwrap();
+        assert_eq!(entries.len(), 1);
+        let key = &entries[0].0;
+        assert_eq!(key.len(), "test:binary:".len() + 32);
+        let ttl_secs: i64 = pool.ttl(key.as_slice()).await.unwrap();
+        assert!(ttl_secs > 0);
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.headers()["idempotency-replayed"], "true");
+
+        store.remove_prefix(b"test:binary:").await.unwrap();
+        assert!(store.list_prefix(b"test:binary:").await.unwrap().is_empty());
+    }
+}
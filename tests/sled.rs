@@ -0,0 +1,71 @@
+//! Tests of [`SledStore`] on temporary `sled` databases.
+
+#[cfg(test)]
+mod tests {
+    use axum::Router;
+    use axum::body::Body;
+    use axum::extract::Request;
+    use axum::routing::post;
+    use axum_idempotent::test_util::conformance;
+    use axum_idempotent::{IdempotencyStore, IdempotentLayer, IdempotentOptions, SledStore};
+    use std::time::Duration;
+    use tower::ServiceExt;
+
+    /// A store on a temporary database, deleted when it is dropped.
+    fn store() -> SledStore {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        SledStore::new(db.open_tree("idempotency").unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_conformance() {
+        conformance(store(), "").await;
+    }
+
+    #[tokio::test]
+    async fn test_remove_expired() {
+        let store = store();
+
+        store.set("short", b"value".to_vec(), 1).await.unwrap();
+        store.set("long", b"value".to_vec(), 60).await.unwrap();
+        store.set("other", b"value".to_vec(), 1).await.unwrap();
+
+        // Expired entries are deleted when read or cleaned up
+        tokio::time::sleep(Duration::from_millis(1100)).await;
+        assert_eq!(store.get("short").await.unwrap(), None);
+        assert_eq!(store.remove_expired().unwrap(), 1);
+        assert_eq!(store.remove_expired().unwrap(), 0);
+        assert!(store.get("long").await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_binary_keys() {
+        let store = store().binary_keys();
+        let app = Router::new()
+            .route("/charge", post(|| async { "charged" }))
+            .layer(IdempotentLayer::with_store(
+                store.clone(),
+                IdempotentOptions::default()
+                    .key_prefix("idem:")
+                    .lock_in_flight(5),
+            ));
+        let request = || {
+            Request::builder()
+                .uri("/charge")
+                .method("POST")
+                .body(Body::from("charge"))
+                .unwrap()
+        };
+
+        // The request hash follows the text prefix as raw bytes
+        app.clone().oneshot(request()).await.unwrap();
+        let entries = store.list_prefix(b"idem:").await.unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].0.len(), "idem:".len() + 32);
+        let response = app.oneshot(request()).await.unwrap();
+        assert_eq!(response.headers()["idempotency-replayed"], "true");
+
+        store.remove_prefix(b"idem:").await.unwrap();
+        assert!(store.list_prefix(b"").await.unwrap().is_empty());
+    }
+}